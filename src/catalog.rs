@@ -0,0 +1,1220 @@
+use std::rc::Rc;
+
+use dl;
+use matrix;
+
+use super::codegen::{float_literal, EmitNode};
+use super::dtype::{self, DType};
+use super::kernels;
+use super::op::{AttrValue, Attrs, Operation};
+use super::shape::{Dim, Shape};
+use super::tensor::{self, Tensor};
+use super::var_store::{VarIndex, VarStore};
+
+/// All the operations that can be placed in a graph, looked up by name.
+pub struct OpCatalog {
+    ops: Vec<Rc<Operation>>,
+}
+
+impl OpCatalog {
+    pub fn new() -> Self {
+        OpCatalog {
+            ops: vec![],
+        }
+    }
+
+    /// A catalog with the operations DeepLab ships with.
+    pub fn builtin() -> Self {
+        let mut catalog = OpCatalog::new();
+        add_variable_ops(&mut catalog);
+        add_math_ops(&mut catalog);
+        add_activation_ops(&mut catalog);
+        add_layer_ops(&mut catalog);
+        add_regularization_ops(&mut catalog);
+        add_conv_ops(&mut catalog);
+        add_shape_ops(&mut catalog);
+        add_recurrent_ops(&mut catalog);
+        add_loss_ops(&mut catalog);
+        catalog
+    }
+
+    pub fn add(&mut self, op: Operation) -> Rc<Operation> {
+        let op = Rc::new(op);
+        self.ops.push(op.clone());
+        op
+    }
+
+    /// Add the operation unless there's already one of the same name.
+    pub fn try_add(&mut self, op: Operation) -> Result<Rc<Operation>, String> {
+        if self.get(&op.name).is_some() {
+            return Err(format!("there's already an operation called '{}'", op.name));
+        }
+        Ok(self.add(op))
+    }
+
+    /// Add the operation, replacing any operation of the same name.
+    pub fn replace(&mut self, op: Operation) -> Rc<Operation> {
+        let op = Rc::new(op);
+        match self.ops.iter().position(|o| o.name == op.name) {
+            Some(i) => { self.ops[i] = op.clone(); },
+            None => { self.ops.push(op.clone()); },
+        }
+        op
+    }
+
+    pub fn get(&self, name: &str) -> Option<Rc<Operation>> {
+        self.ops.iter().find(|op| op.name == name).cloned()
+    }
+
+    pub fn ops(&self) -> &[Rc<Operation>] {
+        &self.ops
+    }
+
+    /// The operations grouped by category, in the order each category was first added.
+    pub fn categories(&self) -> Vec<(String, Vec<Rc<Operation>>)> {
+        self.search_categories("")
+    }
+
+    /// The operations matching `query`, grouped by category like `categories`.
+    pub fn search_categories(&self, query: &str) -> Vec<(String, Vec<Rc<Operation>>)> {
+        let query = query.to_lowercase();
+        let mut categories: Vec<(String, Vec<Rc<Operation>>)> = vec![];
+        for op in self.ops.iter().filter(|op| search_rank(op, &query).is_some()) {
+            match categories.iter().position(|&(ref name, _)| *name == op.category) {
+                Some(i) => { categories[i].1.push(op.clone()); },
+                None => { categories.push((op.category.clone(), vec![op.clone()])); },
+            }
+        }
+        categories
+    }
+
+    /// The operations whose name or category contains `query`, ignoring case. Those whose name
+    /// starts with it come first, then those whose name contains it.
+    pub fn search(&self, query: &str) -> Vec<Rc<Operation>> {
+        let query = query.to_lowercase();
+        let mut found: Vec<(usize, Rc<Operation>)> = self.ops.iter().filter_map(|op| {
+            search_rank(op, &query).map(|rank| (rank, op.clone()))
+        }).collect();
+        found.sort_by(|a, b| a.0.cmp(&b.0));
+        found.into_iter().map(|(_, op)| op).collect()
+    }
+}
+
+/// How well an operation matches a lowercase search query, lower being better, or None if it
+/// doesn't.
+fn search_rank(op: &Operation, query: &str) -> Option<usize> {
+    let name = op.name.to_lowercase();
+    if name.starts_with(query) {
+        Some(0)
+    } else if name.contains(query) {
+        Some(1)
+    } else if op.category.to_lowercase().contains(query) {
+        Some(2)
+    } else {
+        None
+    }
+}
+
+fn add_variable_ops(catalog: &mut OpCatalog) {
+    // Trained unless `trainable` is 0, with L1 and L2 weight decay of strength `l1` and `l2`
+    catalog.add(Operation::new("Variable", "Variables", &[], &["value"])
+        .with_attr("dtype", AttrValue::Str("f32".to_string()))
+        .with_attr("trainable", AttrValue::Int(1))
+        .with_attr("l1", AttrValue::Float(0.0))
+        .with_attr("l2", AttrValue::Float(0.0))
+        .with_dtype_fn(|attrs, _| {
+            let name = attrs.str("dtype");
+            DType::from_name(name).map(|dtype| vec![dtype]).ok_or(format!("Unknown type '{}'", name))
+        })
+        .with_gpu_build(|ctx: &matrix::Context,
+                         graph: &mut dl::Graph,
+                         vars: &mut VarStore,
+                         _in: &[Option<VarIndex>],
+                         _out: &[VarIndex]| {
+            let gpu = graph.add_variable(ctx, _out[0].get(vars).shape_2d(), dl::init::Normal(0.5, 0.2));
+            if let Some(ref value) = _out[0].get(vars).value {
+                gpu.get(graph).set(ctx, &value.to_matrix());
+            }
+            _out[0].get_mut(vars).gpu = Some(gpu);
+            None
+        }).with_codegen(|n: &EmitNode| {
+            format!("let {} = graph.add_variable(ctx, {:?}, dl::init::Normal(0.5, 0.2));",
+                    n.outputs[0].0, n.outputs[0].1)
+        })
+        // The model holds the values of variables itself
+        .with_cpu_codegen(|_, _| String::new()));
+}
+
+fn add_math_ops(catalog: &mut OpCatalog) {
+    catalog.add(Operation::new("MatMul", "Math", &["a", "b"], &["out"])
+        .with_shape_fn(|_, shapes| {
+            let (a, b) = (try!(shapes[0].as_matrix()), try!(shapes[1].as_matrix()));
+            if a.1 != b.0 {
+                return Err(format!("Can't multiply {} by {}", shapes[0], shapes[1]));
+            }
+            Ok(vec![Shape::new(vec![a.0, b.1])])
+        })
+        .with_gpu_build(|ctx: &matrix::Context,
+                         graph: &mut dl::Graph,
+                         vars: &mut VarStore,
+                         _in: &[Option<VarIndex>],
+                         _out: &[VarIndex]| {
+            let (a, a_shape) = { let a = _in[0].unwrap().get(vars); (a.gpu.unwrap(), a.shape_2d()) };
+            let (b, b_shape) = { let b = _in[1].unwrap().get(vars); (b.gpu.unwrap(), b.shape_2d()) };
+            let op = dl::op::MatMul::new(&ctx, a_shape, b_shape);
+            let node = graph.add_node(ctx, op,
+                                      vec![a, b],
+                                      &[_out[0].get(vars).shape_2d()]);
+            _out[0].get_mut(vars).gpu = Some(node.get(&graph).outputs[0]);
+            Some(node)
+        })
+        .with_kernel(|_, i, o| {
+            kernels::matmul(i[0], i[1], &mut o[0]);
+        }, |_, i, _, g, gi| {
+            let (ga, gb) = gi.split_at_mut(1);
+            kernels::matmul_backward(i[0], i[1], g[0], &mut ga[0], &mut gb[0]);
+        })
+        .with_codegen(|n: &EmitNode| {
+            let op = format!("dl::op::MatMul::new(ctx, {:?}, {:?})", n.inputs[0].1, n.inputs[1].1);
+            format!("let node = graph.add_node(ctx, {}, vec![{}, {}], &[{:?}]);\n\
+                     let {} = node.get(&graph).outputs[0];",
+                    op, n.inputs[0].0, n.inputs[1].0, n.outputs[0].1, n.outputs[0].0)
+        })
+        .with_cpu_codegen(|_, n| {
+            let ((rows, inner), columns) = (n.inputs[0].1, (n.inputs[1].1).1);
+            format!("{} = matmul(&{}, &{}, {}, {}, {});", n.outputs[0].0, n.inputs[0].0, n.inputs[1].0, rows, inner, columns)
+        }));
+
+    catalog.add(binary_op("Add", |a, b| a + b, |_, _| (1.0, 1.0))
+        .with_cpu_codegen(|_, n| emit_zip(n, "a + b")));
+    catalog.add(binary_op("Sub", |a, b| a - b, |_, _| (1.0, -1.0))
+        .with_cpu_codegen(|_, n| emit_zip(n, "a - b")));
+    catalog.add(binary_op("Mul", |a, b| a * b, |a, b| (b, a))
+        .with_cpu_codegen(|_, n| emit_zip(n, "a * b")));
+
+    // Convert to another type, e.g. integer labels to floats. Gradients only flow between floats.
+    catalog.add(Operation::new("Cast", "Math", &["x"], &["out"])
+        .with_attr("to", AttrValue::Str("f32".to_string()))
+        .with_dtype_fn(|attrs, _| {
+            let name = attrs.str("to");
+            DType::from_name(name).map(|dtype| vec![dtype]).ok_or(format!("Unknown type '{}'", name))
+        })
+        .with_shape_fn(|_, shapes| Ok(vec![shapes[0].clone()]))
+        .with_kernel(|attrs, i, o| {
+            let to = DType::from_name(attrs.str("to")).unwrap();
+            kernels::unary(i[0], &mut o[0], |x| to.convert(x));
+        }, |attrs, i, o, g, gi| {
+            if DType::from_name(attrs.str("to")).unwrap().is_float() {
+                kernels::unary_backward(i[0], o[0], g[0], &mut gi[0], |_, _| 1.0);
+            }
+        })
+        .with_cpu_codegen(|attrs, n| {
+            let max = float_literal(dtype::MAX_EXACT_INT);
+            match DType::from_name(attrs.str("to")).unwrap() {
+                DType::F32 | DType::F64 => format!("{} = {}.clone();", n.outputs[0].0, n.inputs[0].0),
+                DType::I32 => emit_map(n, &format!("x.trunc().max(-{}).min({})", max, max)),
+                DType::Bool => emit_map(n, "if x != 0.0 { 1.0 } else { 0.0 }"),
+            }
+        }));
+
+    catalog.add(Operation::new("BiasAdd", "Math", &["x", "b"], &["out"])
+        .with_shape_fn(|_, shapes| {
+            if !is_bias_for(&shapes[1], &shapes[0].last()) {
+                return Err(format!("Bias should have {} values to add to {}, not shape {}",
+                                   shapes[0].last(), shapes[0], shapes[1]));
+            }
+            Ok(vec![shapes[0].clone()])
+        })
+        .with_kernel(|_, i, o| {
+            kernels::binary(i[0], i[1], &mut o[0], |x, b| x + b);
+        }, |_, i, _, g, gi| {
+            let (gx, gb) = gi.split_at_mut(1);
+            kernels::binary_backward(i[0], i[1], g[0], &mut gx[0], &mut gb[0], |_, _| (1.0, 1.0));
+        })
+        .with_cpu_codegen(|_, n| emit_zip(n, "a + b")));
+}
+
+fn add_activation_ops(catalog: &mut OpCatalog) {
+    catalog.add(unary_op("ReLU", |_, x| x.max(0.0), |_, _, y| if y > 0.0 { 1.0 } else { 0.0 })
+        .with_gpu_build(|ctx: &matrix::Context,
+                         graph: &mut dl::Graph,
+                         vars: &mut VarStore,
+                         _in: &[Option<VarIndex>],
+                         _out: &[VarIndex]| {
+            let a = _in[0].unwrap().get(vars).gpu.unwrap();
+            let op = dl::op::Relu::new();
+            let node = graph.add_node(ctx, op,
+                                      vec![a],
+                                      &[_out[0].get(vars).shape_2d()]);
+            _out[0].get_mut(vars).gpu = Some(node.get(&graph).outputs[0]);
+            Some(node)
+        })
+        .with_codegen(|n: &EmitNode| {
+            format!("let node = graph.add_node(ctx, dl::op::Relu::new(), vec![{}], &[{:?}]);\n\
+                     let {} = node.get(&graph).outputs[0];",
+                    n.inputs[0].0, n.outputs[0].1, n.outputs[0].0)
+        })
+        .with_cpu_codegen(|_, n| emit_map(n, "x.max(0.0)")));
+    catalog.add(unary_op("Sigmoid", |_, x| kernels::sigmoid(x), |_, _, y| y * (1.0 - y))
+        .with_cpu_codegen(|_, n| emit_map(n, "sigmoid(x)")));
+    catalog.add(unary_op("Tanh", |_, x| x.tanh(), |_, _, y| 1.0 - y*y)
+        .with_cpu_codegen(|_, n| emit_map(n, "x.tanh()")));
+    catalog.add(unary_op("LeakyReLU",
+                         |attrs, x| if x > 0.0 { x } else { attrs.float("alpha") * x },
+                         |attrs, x, _| if x > 0.0 { 1.0 } else { attrs.float("alpha") })
+        .with_attr("alpha", AttrValue::Float(0.01))
+        .with_cpu_codegen(|attrs, n| {
+            emit_map(n, &format!("if x > 0.0 {{ x }} else {{ {} * x }}", float_literal(attrs.float("alpha"))))
+        }));
+    catalog.add(unary_op("ELU",
+                         |attrs, x| if x > 0.0 { x } else { attrs.float("alpha") * (x.exp() - 1.0) },
+                         |attrs, x, y| if x > 0.0 { 1.0 } else { y + attrs.float("alpha") })
+        .with_attr("alpha", AttrValue::Float(1.0))
+        .with_cpu_codegen(|attrs, n| {
+            emit_map(n, &format!("if x > 0.0 {{ x }} else {{ {} * (x.exp() - 1.0) }}", float_literal(attrs.float("alpha"))))
+        }));
+
+    catalog.add(Operation::new("Softmax", "Activations", &["x"], &["out"])
+        .with_shape_fn(|_, shapes| Ok(vec![shapes[0].clone()]))
+        .with_kernel(|_, i, o| {
+            kernels::softmax(i[0], &mut o[0]);
+        }, |_, _, o, g, gi| {
+            kernels::softmax_backward(o[0], g[0], &mut gi[0]);
+        })
+        .with_cpu_codegen(|_, n| format!("{} = softmax(&{}, {});", n.outputs[0].0, n.inputs[0].0, (n.inputs[0].1).1)));
+}
+
+fn add_layer_ops(catalog: &mut OpCatalog) {
+    // A fully connected layer: activation(x * w + b)
+    catalog.add(Operation::new("Dense", "Layers", &["x", "w", "b"], &["out"])
+        .with_attr("activation", AttrValue::Str("relu".to_string()))
+        .with_shape_fn(|attrs, shapes| {
+            let (x, w) = (try!(shapes[0].as_matrix()), try!(shapes[1].as_matrix()));
+            if x.1 != w.0 {
+                return Err(format!("Can't multiply {} by {}", shapes[0], shapes[1]));
+            }
+            if !is_bias_for(&shapes[2], &w.1) {
+                return Err(format!("Bias should have {} values, not shape {}", w.1, shapes[2]));
+            }
+            let activation = attrs.str("activation");
+            if !kernels::ACTIVATIONS.contains(&activation) {
+                return Err(format!("Unknown activation '{}', expected one of {}",
+                                   activation, kernels::ACTIVATIONS.join(", ")));
+            }
+            Ok(vec![Shape::new(vec![x.0, w.1])])
+        })
+        .with_kernel(|attrs, i, o| {
+            let activation = attrs.str("activation");
+            kernels::matmul(i[0], i[1], &mut o[0]);
+            let pre = o[0].clone();
+            kernels::binary(&pre, i[2], &mut o[0], |x, b| kernels::activation(activation, x + b));
+        }, |attrs, i, o, g, gi| {
+            let activation = attrs.str("activation");
+            // Gradient of the pre-activation, which is also the gradient of the bias before
+            // it's summed over the rows
+            let mut g_pre = g[0].clone();
+            for (gp, (&g, &y)) in g_pre.data.iter_mut().zip(g[0].data.iter().zip(&o[0].data)) {
+                *gp = g * kernels::activation_grad(activation, y);
+            }
+            let (gxw, gb) = gi.split_at_mut(2);
+            let (gx, gw) = gxw.split_at_mut(1);
+            kernels::matmul_backward(i[0], i[1], &g_pre, &mut gx[0], &mut gw[0]);
+            let columns = gb[0].len();
+            for (j, &gp) in g_pre.data.iter().enumerate() {
+                gb[0].data[j % columns] += gp;
+            }
+        })
+        .with_cpu_codegen(|attrs, n| {
+            let ((rows, inner), columns) = (n.inputs[0].1, (n.inputs[1].1).1);
+            format!("{} = zip(&matmul(&{}, &{}, {}, {}, {}), &{:?}, &{}, &{:?}, &{:?}, |a, b| {});",
+                    n.outputs[0].0, n.inputs[0].0, n.inputs[1].0, rows, inner, columns, n.outputs[0].2,
+                    n.inputs[2].0, n.inputs[2].2, n.outputs[0].2, activation_code(attrs.str("activation"), "a + b"))
+        }));
+}
+
+fn add_regularization_ops(catalog: &mut OpCatalog) {
+    // Zeroes a `rate` fraction of its input while training, passes it through when evaluating
+    catalog.add(Operation::new("Dropout", "Regularization", &["x"], &["out", "mask"])
+        .with_attr("rate", AttrValue::Float(0.5))
+        .with_shape_fn(|attrs, shapes| {
+            let rate = attrs.float("rate");
+            if rate < 0.0 || rate >= 1.0 {
+                return Err(format!("Rate should be at least 0 and less than 1, not {}", rate));
+            }
+            Ok(vec![shapes[0].clone(), shapes[0].clone()])
+        })
+        .with_kernel(|attrs, i, o| {
+            let (out, mask) = o.split_at_mut(1);
+            kernels::dropout(i[0], attrs.float("rate"), &mut out[0], &mut mask[0]);
+        }, |_, _, o, g, gi| {
+            for (gx, (&g, &m)) in gi[0].data.iter_mut().zip(g[0].data.iter().zip(&o[1].data)) {
+                *gx += g * m;
+            }
+        })
+        .with_eval_kernel(|_, i, o| {
+            o[0].data = i[0].data.clone();
+            for m in &mut o[1].data {
+                *m = 1.0;
+            }
+        })
+        .with_cpu_codegen(|_, n| {
+            format!("{} = {}.clone();\n{} = vec![1.0; {}];", n.outputs[0].0, n.inputs[0].0,
+                    n.outputs[1].0, tensor::num_elements(&n.outputs[1].2))
+        }));
+
+    // Normalizes each channel (dimension 1) of x with the statistics of the batch while training
+    // and the running statistics when evaluating. Training replaces the running statistics with
+    // the `mean` and `var` outputs instead of descending their gradient, so the variables
+    // connected to them are never trainable.
+    catalog.add(Operation::new("BatchNorm", "Regularization",
+                               &["x", "gamma", "beta", "running_mean", "running_var"],
+                               &["out", "mean", "var"])
+        .with_attr("momentum", AttrValue::Float(0.9))
+        .with_attr("epsilon", AttrValue::Float(1e-5))
+        .with_update(1, 3)
+        .with_update(2, 4)
+        .with_shape_fn(|_, shapes| {
+            if shapes[0].rank() < 2 {
+                return Err(format!("Expected channels in dimension 1 of x, not shape {}", shapes[0]));
+            }
+            let channels = shapes[0].dim(1);
+            for (name, shape) in ["gamma", "beta", "running_mean", "running_var"].iter().zip(&shapes[1..]) {
+                if !is_bias_for(shape, channels) {
+                    return Err(format!("'{}' should have {} values, not shape {}", name, channels, shape));
+                }
+            }
+            Ok(vec![shapes[0].clone(), shapes[3].clone(), shapes[4].clone()])
+        })
+        .with_kernel(|attrs, i, o| {
+            let momentum = attrs.float("momentum");
+            let (mean, var) = kernels::channel_moments(i[0]);
+            kernels::batch_norm(i[0], i[1], i[2], &mean, &var, attrs.float("epsilon"), &mut o[0]);
+            for c in 0..mean.len() {
+                o[1].data[c] = momentum*i[3].data[c] + (1.0 - momentum)*mean[c];
+                o[2].data[c] = momentum*i[4].data[c] + (1.0 - momentum)*var[c];
+            }
+        }, |attrs, i, _, g, gi| {
+            let (gx, rest) = gi.split_at_mut(1);
+            let (ggamma, gbeta) = rest.split_at_mut(1);
+            kernels::batch_norm_backward(i[0], i[1], g[0], attrs.float("epsilon"),
+                                         &mut gx[0], &mut ggamma[0], &mut gbeta[0]);
+        })
+        .with_eval_kernel(|attrs, i, o| {
+            kernels::batch_norm(i[0], i[1], i[2], &i[3].data, &i[4].data, attrs.float("epsilon"), &mut o[0]);
+            o[1].data = i[3].data.clone();
+            o[2].data = i[4].data.clone();
+        })
+        .with_cpu_codegen(|attrs, n| {
+            format!("{} = batch_norm(&{}, &{:?}, &{}, &{}, &{}, &{}, {});\n{} = {}.clone();\n{} = {}.clone();",
+                    n.outputs[0].0, n.inputs[0].0, n.inputs[0].2, n.inputs[1].0, n.inputs[2].0, n.inputs[3].0,
+                    n.inputs[4].0, float_literal(attrs.float("epsilon")),
+                    n.outputs[1].0, n.inputs[3].0, n.outputs[2].0, n.inputs[4].0)
+        }));
+}
+
+fn add_conv_ops(catalog: &mut OpCatalog) {
+    // 2-D convolution of NCHW images with FCHW filters
+    catalog.add(Operation::new("Conv2D", "Convolution", &["x", "w"], &["out"])
+        .with_attr("stride", AttrValue::Int(1))
+        .with_attr("padding", AttrValue::Int(0))
+        .with_attr("dilation", AttrValue::Int(1))
+        .with_shape_fn(|attrs, shapes| {
+            if shapes[0].rank() != 4 || shapes[1].rank() != 4 {
+                return Err(format!("Expected NCHW input and FCHW filters, not {} and {}",
+                                   shapes[0], shapes[1]));
+            }
+            let w = try!(known(&shapes[1]));
+            let x = shapes[0].dims();
+            if x[1] != Dim::Known(w[1]) {
+                return Err(format!("Input has {} channels but the filters have {}", x[1], w[1]));
+            }
+            if w[2] == 0 || w[3] == 0 || attrs.int("dilation") < 1 {
+                return Err("Filters and their dilation must be at least 1".to_string());
+            }
+            if attrs.int("stride") < 1 || attrs.int("padding") < 0 {
+                return Err("Stride must be at least 1 and padding can't be negative".to_string());
+            }
+            let size = (try!(known_dim(&x[2])), try!(known_dim(&x[3])));
+            let (h, w_out) = try!(conv_window(attrs, &w).output_shape(size));
+            Ok(vec![Shape::new(vec![x[0].clone(), Dim::Known(w[0]), Dim::Known(h), Dim::Known(w_out)])])
+        })
+        .with_kernel(|attrs, i, o| {
+            kernels::conv2d(i[0], i[1], &mut o[0], &conv_window(attrs, &i[1].shape));
+        }, |attrs, i, _, g, gi| {
+            let (gx, gw) = gi.split_at_mut(1);
+            kernels::conv2d_backward(i[0], i[1], g[0], &mut gx[0], &mut gw[0],
+                                     &conv_window(attrs, &i[1].shape));
+        }));
+
+    catalog.add(pool_op("MaxPool", kernels::max_pool, kernels::max_pool_backward));
+    catalog.add(pool_op("AvgPool", kernels::avg_pool, kernels::avg_pool_backward));
+}
+
+fn add_shape_ops(catalog: &mut OpCatalog) {
+    // Flatten into a matrix, with the dimensions before `axis` becoming the rows
+    catalog.add(Operation::new("Flatten", "Shape", &["x"], &["out"])
+        .with_attr("axis", AttrValue::Int(1))
+        .with_shape_fn(|attrs, shapes| {
+            let axis = attrs.int("axis");
+            if axis < 0 || axis as usize > shapes[0].rank() {
+                return Err(format!("Can't flatten {} at axis {}", shapes[0], axis));
+            }
+            let (rows, columns) = shapes[0].dims().split_at(axis as usize);
+            Ok(vec![Shape::new(vec![try!(product(rows)), try!(product(columns))])])
+        })
+        .with_kernel(copy_forward, copy_backward)
+        .with_cpu_codegen(emit_copy));
+
+    // Reshape to `shape`, where one dimension may be -1 to take up whatever's left
+    catalog.add(Operation::new("Reshape", "Shape", &["x"], &["out"])
+        .with_attr("shape", AttrValue::Ints(vec![-1]))
+        .with_shape_fn(|attrs, shapes| {
+            let target = attrs.ints("shape");
+            let len = try!(shapes[0].num_elements()
+                                    .ok_or(format!("Can't reshape {}, its size isn't known", shapes[0])));
+            let known = target.iter().filter(|&&d| d >= 0).fold(1, |n, &d| n * d as usize);
+            let mut shape = vec![];
+            for &d in target {
+                shape.push(match d {
+                    -1 if known > 0 && target.iter().filter(|&&d| d == -1).count() == 1 => len / known,
+                    d if d >= 0 => d as usize,
+                    _ => { return Err(format!("Invalid shape {:?}", target)); },
+                });
+            }
+            if tensor::num_elements(&shape) != len {
+                return Err(format!("Can't reshape {} to {:?}", shapes[0], target));
+            }
+            Ok(vec![Shape::from_dims(&shape)])
+        })
+        .with_kernel(copy_forward, copy_backward)
+        .with_cpu_codegen(emit_copy));
+}
+
+fn add_loss_ops(catalog: &mut OpCatalog) {
+    catalog.add(Operation::new("MSE", "Losses", &["pred", "target"], &["loss"])
+        .as_loss()
+        .with_shape_fn(loss_shape)
+        .with_gpu_build(|ctx: &matrix::Context,
+                         graph: &mut dl::Graph,
+                         vars: &mut VarStore,
+                         _in: &[Option<VarIndex>],
+                         _out: &[VarIndex]| {
+            let a = _in[0].unwrap().get(vars).gpu.unwrap();
+            let b = _in[1].unwrap().get(vars).gpu.unwrap();
+            let out_shape = _out[0].get(vars).shape_2d();
+            let op = dl::op::Mse::new();
+            let node = graph.add_node(ctx, op,
+                                      vec![a, b],
+                                      &[out_shape]);
+            _out[0].get_mut(vars).gpu = Some(node.get(&graph).outputs[0]);
+            Some(node)
+        })
+        .with_kernel(|_, i, o| {
+            let n = i[0].len() as f32;
+            o[0].data[0] = i[0].data.iter().zip(&i[1].data).fold(0.0, |sum, (&p, &t)| sum + (p-t)*(p-t)) / n;
+        }, |_, i, _, g, gi| {
+            let scale = 2.0 * g[0].data[0] / i[0].len() as f32;
+            for (j, (&p, &t)) in i[0].data.iter().zip(&i[1].data).enumerate() {
+                gi[0].data[j] += scale * (p - t);
+                gi[1].data[j] -= scale * (p - t);
+            }
+        })
+        .with_codegen(|n: &EmitNode| {
+            format!("let node = graph.add_node(ctx, dl::op::Mse::new(), vec![{}, {}], &[{:?}]);\n\
+                     let {} = node.get(&graph).outputs[0];",
+                    n.inputs[0].0, n.inputs[1].0, n.outputs[0].1, n.outputs[0].0)
+        })
+        .with_cpu_codegen(|_, n| emit_mean(n, "(p - t)*(p - t)")));
+
+    // Cross-entropy between the softmax of each row of `logits` and a row of class probabilities,
+    // usually one-hot, averaged over the rows
+    catalog.add(Operation::new("SoftmaxCrossEntropy", "Losses", &["logits", "labels"], &["loss"])
+        .as_loss()
+        .with_shape_fn(loss_shape)
+        .with_kernel(|_, i, o| {
+            let log_probs = kernels::log_softmax(i[0]);
+            let rows = i[0].shape_2d().0 as f32;
+            o[0].data[0] = -log_probs.data.iter().zip(&i[1].data).fold(0.0, |sum, (&lp, &y)| sum + y*lp) / rows;
+        }, |_, i, _, g, gi| {
+            let log_probs = kernels::log_softmax(i[0]);
+            let scale = g[0].data[0] / i[0].shape_2d().0 as f32;
+            for (j, (&lp, &y)) in log_probs.data.iter().zip(&i[1].data).enumerate() {
+                gi[0].data[j] += scale * (lp.exp() - y);
+                gi[1].data[j] -= scale * lp;
+            }
+        })
+        .with_cpu_codegen(|_, n| {
+            let (rows, columns) = n.inputs[0].1;
+            format!("{} = vec![-log_softmax(&{}, {}).iter().zip(&{}).fold(0.0, |sum, (&lp, &y)| sum + y*lp) / {}.0];",
+                    n.outputs[0].0, n.inputs[0].0, columns, n.inputs[1].0, rows)
+        }));
+
+    // Cross-entropy between predicted probabilities, e.g. from a sigmoid, and 0/1 targets
+    catalog.add(Operation::new("BinaryCrossEntropy", "Losses", &["pred", "target"], &["loss"])
+        .as_loss()
+        .with_shape_fn(loss_shape)
+        .with_kernel(|_, i, o| {
+            let n = i[0].len() as f32;
+            o[0].data[0] = -i[0].data.iter().zip(&i[1].data).fold(0.0, |sum, (&p, &t)| {
+                let p = clamp_probability(p);
+                sum + t*p.ln() + (1.0 - t)*(1.0 - p).ln()
+            }) / n;
+        }, |_, i, _, g, gi| {
+            let scale = g[0].data[0] / i[0].len() as f32;
+            for (j, (&p, &t)) in i[0].data.iter().zip(&i[1].data).enumerate() {
+                let p = clamp_probability(p);
+                gi[0].data[j] += scale * (p - t) / (p * (1.0 - p));
+                gi[1].data[j] -= scale * (p.ln() - (1.0 - p).ln());
+            }
+        })
+        .with_cpu_codegen(|_, n| {
+            emit_mean(n, "{ let p = p.max(1e-7).min(1.0 - 1e-7); -(t*p.ln() + (1.0 - t)*(1.0 - p).ln()) }")
+        }));
+
+    // Hinge loss between scores and -1/1 targets
+    catalog.add(Operation::new("Hinge", "Losses", &["pred", "target"], &["loss"])
+        .as_loss()
+        .with_shape_fn(loss_shape)
+        .with_kernel(|_, i, o| {
+            let n = i[0].len() as f32;
+            o[0].data[0] = i[0].data.iter().zip(&i[1].data).fold(0.0, |sum, (&p, &t)| sum + (1.0 - t*p).max(0.0)) / n;
+        }, |_, i, _, g, gi| {
+            let scale = g[0].data[0] / i[0].len() as f32;
+            for (j, (&p, &t)) in i[0].data.iter().zip(&i[1].data).enumerate() {
+                if 1.0 - t*p > 0.0 {
+                    gi[0].data[j] -= scale * t;
+                    gi[1].data[j] -= scale * p;
+                }
+            }
+        })
+        .with_cpu_codegen(|_, n| emit_mean(n, "(1.0 - t*p).max(0.0)")));
+}
+
+fn add_recurrent_ops(catalog: &mut OpCatalog) {
+    // h' = activation(x*w + h*u + b)
+    catalog.add(Operation::new("RNNCell", "Recurrent", &["x", "h", "w", "u", "b"], &["h"])
+        .as_cell(0, &[(0, 1)])
+        .with_attr("activation", AttrValue::Str("tanh".to_string()))
+        .with_shape_fn(|attrs, shapes| {
+            let activation = attrs.str("activation");
+            if !kernels::ACTIVATIONS.contains(&activation) {
+                return Err(format!("Unknown activation '{}', expected one of {}",
+                                   activation, kernels::ACTIVATIONS.join(", ")));
+            }
+            cell_shapes(shapes, 1, 1)
+        })
+        .with_kernel(|attrs, i, o| {
+            let activation = attrs.str("activation");
+            let (xw, hu) = kernels::gate_inputs(i[0], i[1], i[2], i[3], i[4]);
+            kernels::binary(&xw, &hu, &mut o[0], |a, b| kernels::activation(activation, a + b));
+        }, |attrs, i, o, g, gi| {
+            let activation = attrs.str("activation");
+            let mut g_pre = g[0].clone();
+            for (gp, &y) in g_pre.data.iter_mut().zip(&o[0].data) {
+                *gp *= kernels::activation_grad(activation, y);
+            }
+            let mut gi = gi.iter_mut();
+            let (gx, gh) = (gi.next().unwrap(), gi.next().unwrap());
+            let (gw, gu, gb) = (gi.next().unwrap(), gi.next().unwrap(), gi.next().unwrap());
+            kernels::gate_inputs_backward(i[0], i[1], i[2], i[3], &g_pre, &g_pre, gx, gh, gw, gu, gb);
+        }));
+
+    // Gates side by side in the columns of w, u and b: input, forget, cell, output
+    catalog.add(Operation::new("LSTMCell", "Recurrent", &["x", "h", "c", "w", "u", "b"], &["h", "c"])
+        .as_cell(0, &[(0, 1), (1, 2)])
+        .with_shape_fn(|_, shapes| cell_shapes(shapes, 4, 2))
+        .with_kernel(|_, i, o| {
+            let (xw, hu) = kernels::gate_inputs(i[0], i[1], i[3], i[4], i[5]);
+            let mut pre = xw.clone();
+            kernels::binary(&xw, &hu, &mut pre, |a, b| a + b);
+            let (h, c) = o.split_at_mut(1);
+            kernels::lstm_cell(&pre, i[2], &mut h[0], &mut c[0]);
+        }, |_, i, o, g, gi| {
+            let (xw, hu) = kernels::gate_inputs(i[0], i[1], i[3], i[4], i[5]);
+            let mut pre = xw.clone();
+            kernels::binary(&xw, &hu, &mut pre, |a, b| a + b);
+            let mut g_pre = Tensor::zeros(pre.shape.clone());
+            let mut gi = gi.iter_mut();
+            let (gx, gh, gc) = (gi.next().unwrap(), gi.next().unwrap(), gi.next().unwrap());
+            let (gw, gu, gb) = (gi.next().unwrap(), gi.next().unwrap(), gi.next().unwrap());
+            kernels::lstm_cell_backward(&pre, i[2], o[1], g[0], g[1], &mut g_pre, gc);
+            kernels::gate_inputs_backward(i[0], i[1], i[3], i[4], &g_pre, &g_pre, gx, gh, gw, gu, gb);
+        }));
+
+    // Gates side by side in the columns of w, u and b: update, reset, candidate
+    catalog.add(Operation::new("GRUCell", "Recurrent", &["x", "h", "w", "u", "b"], &["h"])
+        .as_cell(0, &[(0, 1)])
+        .with_shape_fn(|_, shapes| cell_shapes(shapes, 3, 1))
+        .with_kernel(|_, i, o| {
+            let (xw, hu) = kernels::gate_inputs(i[0], i[1], i[2], i[3], i[4]);
+            kernels::gru_cell(&xw, &hu, i[1], &mut o[0]);
+        }, |_, i, _, g, gi| {
+            let (xw, hu) = kernels::gate_inputs(i[0], i[1], i[2], i[3], i[4]);
+            let (mut g_xw, mut g_hu) = (Tensor::zeros(xw.shape.clone()), Tensor::zeros(hu.shape.clone()));
+            let mut gi = gi.iter_mut();
+            let (gx, gh) = (gi.next().unwrap(), gi.next().unwrap());
+            let (gw, gu, gb) = (gi.next().unwrap(), gi.next().unwrap(), gi.next().unwrap());
+            kernels::gru_cell_backward(&xw, &hu, i[1], g[0], &mut g_xw, &mut g_hu, gh);
+            kernels::gate_inputs_backward(i[0], i[1], i[2], i[3], &g_xw, &g_hu, gx, gh, gw, gu, gb);
+        }));
+
+    // One timestep of a sequence whose first dimension is time
+    catalog.add(Operation::new("TimeStep", "Recurrent", &["seq"], &["x"])
+        .with_attr("t", AttrValue::Int(0))
+        .with_dtypes(dtype::ALL)
+        .with_shape_fn(|attrs, shapes| {
+            if shapes[0].rank() < 2 {
+                return Err(format!("Expected a sequence with time as its first dimension, not {}", shapes[0]));
+            }
+            let t = attrs.int("t");
+            let steps = shapes[0].dim(0);
+            if t < 0 || steps.known().map_or(false, |steps| t as usize >= steps) {
+                return Err(format!("Timestep {} is outside a sequence of {} steps", t, steps));
+            }
+            Ok(vec![Shape::new(shapes[0].dims()[1..].to_vec())])
+        })
+        .with_kernel(|attrs, i, o| {
+            let size = o[0].len();
+            let start = attrs.int("t") as usize * size;
+            o[0].data = i[0].data[start..start + size].to_vec();
+        }, |attrs, _, o, g, gi| {
+            let size = o[0].len();
+            let start = attrs.int("t") as usize * size;
+            for (gx, &g) in gi[0].data[start..start + size].iter_mut().zip(&g[0].data) {
+                *gx += g;
+            }
+        })
+        .with_cpu_codegen(|attrs, n| {
+            let size = tensor::num_elements(&n.outputs[0].2);
+            let start = attrs.int("t") as usize * size;
+            format!("{} = {}[{}..{}].to_vec();", n.outputs[0].0, n.inputs[0].0, start, start + size)
+        }));
+}
+
+/// Stacks `steps` tensors of the same shape along a new first dimension, e.g. the outputs of the
+/// timesteps of an unrolled cell. It isn't in the catalog since its number of inputs varies.
+pub fn stack_op(steps: usize) -> Operation {
+    let inputs: Vec<String> = (0..steps).map(|t| format!("t{}", t)).collect();
+    let inputs: Vec<&str> = inputs.iter().map(|s| s.as_ref()).collect();
+    Operation::new("Stack", "Recurrent", &inputs, &["seq"])
+        .with_dtypes(dtype::ALL)
+        .with_shape_fn(|_, shapes| {
+            if let Some(shape) = shapes.iter().find(|&shape| *shape != shapes[0]) {
+                return Err(format!("Can't stack {} with {}", shapes[0], shape));
+            }
+            let mut dims = vec![Dim::Known(shapes.len())];
+            dims.extend(shapes[0].dims().iter().cloned());
+            Ok(vec![Shape::new(dims)])
+        })
+        .with_kernel(|_, i, o| {
+            let size = i[0].len();
+            for (t, x) in i.iter().enumerate() {
+                for (j, &v) in x.data.iter().enumerate() {
+                    o[0].data[t*size + j] = v;
+                }
+            }
+        }, |_, i, _, g, gi| {
+            let size = i[0].len();
+            for (t, gx) in gi.iter_mut().enumerate() {
+                for (j, v) in gx.data.iter_mut().enumerate() {
+                    *v += g[0].data[t*size + j];
+                }
+            }
+        })
+        .with_cpu_codegen(|_, n| {
+            let steps: Vec<String> = n.inputs.iter().map(|&(ref ident, _, _)| format!("&{}[..]", ident)).collect();
+            format!("{} = [{}].concat();", n.outputs[0].0, steps.join(", "))
+        })
+}
+
+/// Recurrent cells take `x`, then `states` previous states of the same shape, then input weights
+/// `w`, state weights `u` and a bias `b` for each of their `gates` gates side by side. They output
+/// the new states.
+fn cell_shapes(shapes: &[Shape], gates: usize, states: usize) -> Result<Vec<Shape>, String> {
+    let (x, h) = (try!(shapes[0].as_matrix()), try!(shapes[1].as_matrix()));
+    if let Some(state) = shapes[2..1 + states].iter().find(|&state| *state != shapes[1]) {
+        return Err(format!("States {} and {} should have the same shape", shapes[1], state));
+    }
+    if x.0 != h.0 {
+        return Err(format!("Input {} and state {} should have the same number of rows", shapes[0], shapes[1]));
+    }
+    let columns = Dim::Known(gates * try!(known_dim(&h.1)));
+    let (w, u, b) = (&shapes[1 + states], &shapes[2 + states], &shapes[3 + states]);
+    let expected_w = Shape::new(vec![x.1, columns.clone()]);
+    if *w != expected_w {
+        return Err(format!("Input weights should have shape {}, not {}", expected_w, w));
+    }
+    let expected_u = Shape::new(vec![h.1, columns.clone()]);
+    if *u != expected_u {
+        return Err(format!("State weights should have shape {}, not {}", expected_u, u));
+    }
+    if !is_bias_for(b, &columns) {
+        return Err(format!("Bias should have {} values, not shape {}", columns, b));
+    }
+    Ok(vec![shapes[1].clone(); states])
+}
+
+/// Losses compare a prediction with a target of the same shape and output a scalar.
+fn loss_shape(_: &Attrs, shapes: &[Shape]) -> Result<Vec<Shape>, String> {
+    if shapes[0] != shapes[1] {
+        return Err(format!("Prediction {} and target {} should have the same shape",
+                           shapes[0], shapes[1]));
+    }
+    Ok(vec![Shape::matrix(1, 1)])
+}
+
+/// Whether `bias` can be added to the rows of something with `columns` columns: it has to be a
+/// vector or a single row.
+fn is_bias_for(bias: &Shape, columns: &Dim) -> bool {
+    bias.rank() >= 1 && bias.rank() <= 2 &&
+        bias.last() == *columns &&
+        bias.dims()[..bias.rank()-1].iter().all(|d| *d == Dim::Known(1))
+}
+
+fn known_dim(dim: &Dim) -> Result<usize, String> {
+    dim.known().ok_or(format!("The size of dimension {} has to be known", dim))
+}
+
+fn known(shape: &Shape) -> Result<Vec<usize>, String> {
+    shape.known().ok_or(format!("Every dimension of {} has to be known", shape))
+}
+
+/// A dimension the size of all of `dims` together. Symbolic dimensions can only be kept if
+/// they're alone with dimensions of 1.
+fn product(dims: &[Dim]) -> Result<Dim, String> {
+    let symbolic: Vec<&Dim> = dims.iter().filter(|d| d.known().is_none()).collect();
+    let known = dims.iter().filter_map(|d| d.known()).fold(1, |n, d| n*d);
+    match symbolic.len() {
+        0 => Ok(Dim::Known(known)),
+        1 if known == 1 => Ok(symbolic[0].clone()),
+        _ => Err(format!("Can't combine {} with other dimensions", symbolic[0])),
+    }
+}
+
+/// Keep probabilities away from 0 and 1 so their logs stay finite.
+fn clamp_probability(p: f32) -> f32 {
+    p.max(1e-7).min(1.0 - 1e-7)
+}
+
+/// The window of a convolution with filters of shape `w`.
+fn conv_window(attrs: &Attrs, w: &[usize]) -> kernels::Window {
+    kernels::Window {
+        kernel: (w[2], w[3]),
+        stride: attrs.int("stride") as usize,
+        padding: attrs.int("padding") as usize,
+        dilation: attrs.int("dilation") as usize,
+    }
+}
+
+fn pool_window(attrs: &Attrs) -> kernels::Window {
+    let kernel = attrs.int("kernel") as usize;
+    kernels::Window {
+        kernel: (kernel, kernel),
+        stride: attrs.int("stride") as usize,
+        padding: attrs.int("padding") as usize,
+        dilation: 1,
+    }
+}
+
+/// Pooling over square windows of NCHW images.
+fn pool_op(name: &str,
+           forward: fn(&Tensor, &mut Tensor, &kernels::Window),
+           backward: fn(&Tensor, &Tensor, &mut Tensor, &kernels::Window)) -> Operation {
+    Operation::new(name, "Convolution", &["x"], &["out"])
+        .with_attr("kernel", AttrValue::Int(2))
+        .with_attr("stride", AttrValue::Int(2))
+        .with_attr("padding", AttrValue::Int(0))
+        .with_shape_fn(|attrs, shapes| {
+            if shapes[0].rank() != 4 {
+                return Err(format!("Expected an NCHW input, not {}", shapes[0]));
+            }
+            let x = shapes[0].dims();
+            if attrs.int("kernel") < 1 || attrs.int("stride") < 1 || attrs.int("padding") < 0 {
+                return Err("Kernel and stride must be at least 1 and padding can't be negative".to_string());
+            }
+            let size = (try!(known_dim(&x[2])), try!(known_dim(&x[3])));
+            let (h, w) = try!(pool_window(attrs).output_shape(size));
+            Ok(vec![Shape::new(vec![x[0].clone(), x[1].clone(), Dim::Known(h), Dim::Known(w)])])
+        })
+        .with_kernel(move |attrs, i, o| {
+            forward(i[0], &mut o[0], &pool_window(attrs));
+        }, move |attrs, i, _, g, gi| {
+            backward(i[0], g[0], &mut gi[0], &pool_window(attrs));
+        })
+}
+
+/// Kernels of operations that only change the shape, not the values.
+fn copy_forward(_: &Attrs, i: &[&Tensor], o: &mut [Tensor]) {
+    o[0].data = i[0].data.clone();
+}
+
+fn copy_backward(_: &Attrs, _: &[&Tensor], _: &[&Tensor], g: &[&Tensor], gi: &mut [Tensor]) {
+    for (gx, &g) in gi[0].data.iter_mut().zip(&g[0].data) {
+        *gx += g;
+    }
+}
+
+fn emit_copy(_: &Attrs, n: &EmitNode) -> String {
+    format!("{} = {}.clone();", n.outputs[0].0, n.inputs[0].0)
+}
+
+/// Generated code applying `expr`, in terms of `x`, to each element of the node's input.
+fn emit_map(n: &EmitNode, expr: &str) -> String {
+    format!("{} = map(&{}, |x| {});", n.outputs[0].0, n.inputs[0].0, expr)
+}
+
+/// Generated code computing `expr`, in terms of `a` and `b`, from the node's broadcast inputs.
+fn emit_zip(n: &EmitNode, expr: &str) -> String {
+    format!("{} = zip(&{}, &{:?}, &{}, &{:?}, &{:?}, |a, b| {});", n.outputs[0].0,
+            n.inputs[0].0, n.inputs[0].2, n.inputs[1].0, n.inputs[1].2, n.outputs[0].2, expr)
+}
+
+/// Generated code for a loss averaging `expr`, in terms of the prediction `p` and target `t`.
+fn emit_mean(n: &EmitNode, expr: &str) -> String {
+    format!("{} = mean(&{}, &{}, |p, t| {});", n.outputs[0].0, n.inputs[0].0, n.inputs[1].0, expr)
+}
+
+/// Generated code for the activation called `name` of `x`; see `kernels::activation`.
+fn activation_code(name: &str, x: &str) -> String {
+    match name {
+        "relu" => format!("({}).max(0.0)", x),
+        "sigmoid" => format!("sigmoid({})", x),
+        "tanh" => format!("({}).tanh()", x),
+        _ => x.to_string(),
+    }
+}
+
+/// An element-wise activation. `f` computes the output from the input and `df` the derivative
+/// from the input and output.
+fn unary_op<F, D>(name: &str, f: F, df: D) -> Operation
+    where F: Fn(&Attrs, f32) -> f32 + 'static,
+          D: Fn(&Attrs, f32, f32) -> f32 + 'static {
+    Operation::new(name, "Activations", &["x"], &["out"])
+        .with_shape_fn(|_, shapes| Ok(vec![shapes[0].clone()]))
+        .with_kernel(move |attrs, i, o| {
+            kernels::unary(i[0], &mut o[0], |x| f(attrs, x));
+        }, move |attrs, i, o, g, gi| {
+            kernels::unary_backward(i[0], o[0], g[0], &mut gi[0], |x, y| df(attrs, x, y));
+        })
+}
+
+/// An element-wise operation on two broadcast inputs. `df` gives the partial derivatives with
+/// respect to each input.
+fn binary_op<F, D>(name: &str, f: F, df: D) -> Operation
+    where F: Fn(f32, f32) -> f32 + 'static,
+          D: Fn(f32, f32) -> (f32, f32) + 'static {
+    Operation::new(name, "Math", &["a", "b"], &["out"])
+        .with_shape_fn(|_, shapes| Ok(vec![try!(shapes[0].broadcast(&shapes[1]))]))
+        .with_kernel(move |_, i, o| {
+            kernels::binary(i[0], i[1], &mut o[0], &f);
+        }, move |_, i, _, g, gi| {
+            let (ga, gb) = gi.split_at_mut(1);
+            kernels::binary_backward(i[0], i[1], g[0], &mut ga[0], &mut gb[0], &df);
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{stack_op, OpCatalog};
+    use super::super::graph_builder::GraphBuilder;
+    use super::super::op::{AttrValue, Attrs, Operation};
+    use super::super::shape::Shape;
+    use super::super::tensor::Tensor;
+
+    /// Values of `shape` spread over `scale` either side of zero, none closer to zero than a fifth
+    /// of `scale`, so kinks at zero stay out of reach of the finite differences.
+    fn values(shape: &[usize], seed: f32, scale: f32) -> Tensor {
+        let len = shape.iter().fold(1, |n, &d| n*d);
+        let data = (0..len).map(|i| {
+            let x = ((i as f32 + seed) * 2.3).sin();
+            scale * (x + 0.2 * x.signum())
+        }).collect();
+        Tensor::new(shape.to_vec(), data)
+    }
+
+    /// `attrs` on top of the defaults of `op`.
+    fn attrs(op: &Operation, attrs: &[(&str, AttrValue)]) -> Attrs {
+        let mut all = op.attrs.clone();
+        for &(name, ref value) in attrs {
+            all.set(name, value.clone());
+        }
+        all
+    }
+
+    fn forward(op: &Operation, attrs: &Attrs, inputs: &[Tensor]) -> Vec<Tensor> {
+        let shapes: Vec<Shape> = inputs.iter().map(|x| Shape::from_dims(&x.shape)).collect();
+        let infer_shapes = op.infer_shapes.as_ref().unwrap();
+        let mut outputs: Vec<Tensor> = infer_shapes(attrs, &shapes).unwrap().iter()
+                                                                        .map(|shape| Tensor::zeros(shape.known().unwrap()))
+                                                                        .collect();
+        let inputs: Vec<&Tensor> = inputs.iter().collect();
+        let forward = op.forward.as_ref().unwrap();
+        forward(attrs, &inputs, &mut outputs);
+        outputs
+    }
+
+    /// Check the gradients the kernel of `op` gives every input against central differences. The
+    /// first `outputs` outputs are summed with a different weight for each element; the others,
+    /// such as batch statistics, get no gradient.
+    fn check_gradients(op: &Operation, attrs: &Attrs, inputs: Vec<Tensor>, outputs: usize) {
+        let results = forward(op, attrs, &inputs);
+        let weights: Vec<Tensor> = results[..outputs].iter().enumerate().map(|(k, y)| {
+            Tensor::new(y.shape.clone(), (0..y.len()).map(|i| 0.5 + ((i + 3*k) % 5) as f32 * 0.25).collect())
+        }).collect();
+
+        let mut grads: Vec<Tensor> = inputs.iter().map(|x| Tensor::zeros(x.shape.clone())).collect();
+        {
+            let zeros: Vec<Tensor> = results[outputs..].iter().map(|y| Tensor::zeros(y.shape.clone())).collect();
+            let ins: Vec<&Tensor> = inputs.iter().collect();
+            let outs: Vec<&Tensor> = results.iter().collect();
+            let out_grads: Vec<&Tensor> = weights.iter().chain(&zeros).collect();
+            let backward = op.backward.as_ref().unwrap();
+            backward(attrs, &ins, &outs, &out_grads, &mut grads);
+        }
+
+        let loss = |inputs: &[Tensor]| -> f32 {
+            forward(op, attrs, inputs)[..outputs].iter().zip(&weights).fold(0.0, |sum, (y, w)| {
+                y.data.iter().zip(&w.data).fold(sum, |sum, (&y, &w)| sum + y*w)
+            })
+        };
+        let h = 1e-2;
+        for (j, grad) in grads.iter().enumerate() {
+            for e in 0..grad.len() {
+                let mut moved = inputs.clone();
+                moved[j].data[e] += h;
+                let up = loss(&moved);
+                moved[j].data[e] -= 2.0*h;
+                let down = loss(&moved);
+                let numeric = (up - down) / (2.0*h);
+                assert!((numeric - grad.data[e]).abs() <= 1e-2 * (1.0 + numeric.abs()),
+                        "{}: gradient of input {} at {} is {} but finite differences give {}",
+                        op.name, j, e, grad.data[e], numeric);
+            }
+        }
+    }
+
+    fn op(name: &str) -> ::std::rc::Rc<Operation> {
+        OpCatalog::builtin().get(name).unwrap()
+    }
+
+    fn check(name: &str, attr_values: &[(&str, AttrValue)], inputs: Vec<Tensor>) {
+        let op = op(name);
+        let attrs = attrs(&op, attr_values);
+        let outputs = op.outputs.len();
+        check_gradients(&op, &attrs, inputs, outputs);
+    }
+
+    #[test]
+    fn matmul_gradients() {
+        check("MatMul", &[], vec![values(&[3, 4], 0.0, 1.0), values(&[4, 2], 1.0, 1.0)]);
+    }
+
+    #[test]
+    fn element_wise_gradients() {
+        for name in &["Add", "Sub", "Mul"] {
+            check(name, &[], vec![values(&[2, 3], 0.0, 1.0), values(&[2, 3], 1.0, 1.0)]);
+            // Broadcasting a row and a column
+            check(name, &[], vec![values(&[2, 1], 0.0, 1.0), values(&[3], 1.0, 1.0)]);
+        }
+        check("BiasAdd", &[], vec![values(&[4, 3], 0.0, 1.0), values(&[3], 1.0, 1.0)]);
+    }
+
+    #[test]
+    fn activation_gradients() {
+        for name in &["ReLU", "Sigmoid", "Tanh", "LeakyReLU", "ELU", "Softmax"] {
+            check(name, &[], vec![values(&[3, 4], 0.0, 1.0)]);
+        }
+        check("LeakyReLU", &[("alpha", AttrValue::Float(0.3))], vec![values(&[3, 4], 0.0, 1.0)]);
+        check("ELU", &[("alpha", AttrValue::Float(0.5))], vec![values(&[3, 4], 0.0, 1.0)]);
+    }
+
+    #[test]
+    fn dense_gradients() {
+        // ReLU is left out: the sums feeding it can land on its kink
+        for activation in &["none", "sigmoid", "tanh"] {
+            check("Dense", &[("activation", AttrValue::Str(activation.to_string()))],
+                  vec![values(&[3, 4], 0.0, 1.0), values(&[4, 2], 1.0, 0.5), values(&[2], 2.0, 0.5)]);
+        }
+    }
+
+    #[test]
+    fn mse_gradients() {
+        check("MSE", &[], vec![values(&[3, 2], 0.0, 1.0), values(&[3, 2], 1.0, 1.0)]);
+    }
+
+    #[test]
+    fn softmax_rows_sum_to_one() {
+        let op = op("Softmax");
+        let out = forward(&op, &op.attrs, &[values(&[3, 4], 0.0, 5.0)]).remove(0);
+        for row in out.data.chunks(4) {
+            assert!((row.iter().fold(0.0, |sum, &p| sum + p) - 1.0).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn cross_entropy_gradients() {
+        // Rows of class probabilities
+        let labels = Tensor::new(vec![2, 3], vec![0.0, 1.0, 0.0, 0.2, 0.3, 0.5]);
+        check("SoftmaxCrossEntropy", &[], vec![values(&[2, 3], 0.0, 2.0), labels]);
+
+        let pred = Tensor::new(vec![2, 2], vec![0.2, 0.7, 0.5, 0.35]);
+        let target = Tensor::new(vec![2, 2], vec![0.0, 1.0, 1.0, 0.0]);
+        check("BinaryCrossEntropy", &[], vec![pred, target]);
+    }
+
+    #[test]
+    fn hinge_gradients() {
+        // Two scores inside the margin and two past it
+        let pred = Tensor::new(vec![4], vec![0.3, -0.5, 2.0, -1.7]);
+        let target = Tensor::new(vec![4], vec![1.0, -1.0, 1.0, -1.0]);
+        check("Hinge", &[], vec![pred, target]);
+    }
+
+    #[test]
+    fn conv2d_gradients() {
+        check("Conv2D", &[("stride", AttrValue::Int(2)), ("padding", AttrValue::Int(1))],
+              vec![values(&[2, 2, 5, 5], 0.0, 1.0), values(&[3, 2, 3, 3], 1.0, 0.5)]);
+        check("Conv2D", &[("dilation", AttrValue::Int(2))],
+              vec![values(&[1, 2, 6, 6], 0.0, 1.0), values(&[2, 2, 3, 3], 1.0, 0.5)]);
+    }
+
+    #[test]
+    fn pooling_gradients() {
+        // Values far enough apart that moving one doesn't change which is the largest
+        let len = 2*3*4*4;
+        let x = Tensor::new(vec![2, 3, 4, 4], (0..len).map(|i| ((i * 7) % len) as f32 * 0.1).collect());
+        for name in &["MaxPool", "AvgPool"] {
+            check(name, &[], vec![x.clone()]);
+            check(name, &[("kernel", AttrValue::Int(3)), ("stride", AttrValue::Int(1)), ("padding", AttrValue::Int(1))],
+                  vec![x.clone()]);
+        }
+    }
+
+    #[test]
+    fn reshaping_gradients() {
+        check("Flatten", &[("axis", AttrValue::Int(2))], vec![values(&[2, 3, 2], 0.0, 1.0)]);
+        check("Reshape", &[("shape", AttrValue::Ints(vec![-1, 6]))], vec![values(&[2, 3, 2], 0.0, 1.0)]);
+    }
+
+    #[test]
+    fn recurrent_cell_gradients() {
+        for activation in &["tanh", "sigmoid"] {
+            check("RNNCell", &[("activation", AttrValue::Str(activation.to_string()))],
+                  vec![values(&[2, 3], 0.0, 1.0), values(&[2, 4], 1.0, 0.5),
+                       values(&[3, 4], 2.0, 0.5), values(&[4, 4], 3.0, 0.5), values(&[4], 4.0, 0.5)]);
+        }
+        check("LSTMCell", &[],
+              vec![values(&[2, 3], 0.0, 1.0), values(&[2, 2], 1.0, 0.5), values(&[2, 2], 2.0, 0.5),
+                   values(&[3, 8], 3.0, 0.5), values(&[2, 8], 4.0, 0.5), values(&[8], 5.0, 0.5)]);
+        check("GRUCell", &[],
+              vec![values(&[2, 3], 0.0, 1.0), values(&[2, 2], 1.0, 0.5),
+                   values(&[3, 6], 2.0, 0.5), values(&[2, 6], 3.0, 0.5), values(&[6], 4.0, 0.5)]);
+    }
+
+    #[test]
+    fn sequence_gradients() {
+        check("TimeStep", &[("t", AttrValue::Int(1))], vec![values(&[3, 2, 2], 0.0, 1.0)]);
+
+        let stack = stack_op(3);
+        let inputs = (0..3).map(|t| values(&[2, 2], t as f32, 1.0)).collect();
+        check_gradients(&stack, &stack.attrs, inputs, 1);
+    }
+
+    #[test]
+    fn dropout_gradients() {
+        // Without dropping anything it's deterministic
+        let dropout = op("Dropout");
+        let attrs = attrs(&dropout, &[("rate", AttrValue::Float(0.0))]);
+        check_gradients(&dropout, &attrs, vec![values(&[3, 4], 0.0, 1.0)], 1);
+
+        // Otherwise the gradient goes through the same mask as the values
+        let x = values(&[10, 10], 0.0, 1.0);
+        let outputs = forward(&dropout, &dropout.attrs, &[x.clone()]);
+        let (out, mask) = (&outputs[0], &outputs[1]);
+        assert!(mask.data.iter().all(|&m| m == 0.0 || m == 2.0));
+        assert!(out.data.iter().zip(&x.data).zip(&mask.data).all(|((&y, &x), &m)| y == x*m));
+        let mut grads = vec![Tensor::zeros(vec![10, 10])];
+        let ones = Tensor::new(vec![10, 10], vec![1.0; 100]);
+        let backward = dropout.backward.as_ref().unwrap();
+        backward(&dropout.attrs, &[&x], &[out, mask], &[&ones, &ones], &mut grads);
+        assert_eq!(grads[0].data, mask.data);
+    }
+
+    #[test]
+    fn batch_norm_gradients() {
+        let statistics = |seed| {
+            let mean = values(&[3], seed, 0.5);
+            let var = Tensor::new(vec![3], vec![0.5, 1.0, 2.0]);
+            (mean, var)
+        };
+        let (mean, var) = statistics(3.0);
+        check_gradients(&op("BatchNorm"), &op("BatchNorm").attrs,
+                        vec![values(&[4, 3], 0.0, 1.0), values(&[3], 1.0, 1.0), values(&[3], 2.0, 1.0), mean, var],
+                        1);
+        // Images, with the channels in dimension 1
+        let (mean, var) = statistics(4.0);
+        check_gradients(&op("BatchNorm"), &op("BatchNorm").attrs,
+                        vec![values(&[2, 3, 2, 2], 0.0, 1.0), values(&[3], 1.0, 1.0), values(&[3], 2.0, 1.0), mean, var],
+                        1);
+    }
+
+    #[test]
+    fn batch_norm_statistics() {
+        let batch_norm = op("BatchNorm");
+        let attrs = attrs(&batch_norm, &[("momentum", AttrValue::Float(0.5)), ("epsilon", AttrValue::Float(0.0))]);
+        let x = Tensor::new(vec![2, 2], vec![1.0, 10.0, 3.0, 30.0]);
+        let gamma = Tensor::new(vec![2], vec![1.0, 2.0]);
+        let beta = Tensor::new(vec![2], vec![0.0, 1.0]);
+        let running_mean = Tensor::new(vec![2], vec![0.0, 0.0]);
+        let running_var = Tensor::new(vec![2], vec![1.0, 1.0]);
+        let inputs = vec![x, gamma, beta, running_mean, running_var];
+
+        // Training normalizes with the batch's moments and moves the running ones towards them
+        let outputs = forward(&batch_norm, &attrs, &inputs);
+        assert_eq!(outputs[0].data, vec![-1.0, -1.0, 1.0, 3.0]);
+        assert_eq!(outputs[1].data, vec![1.0, 10.0]);
+        assert_eq!(outputs[2].data, vec![1.0, 50.5]);
+
+        // Evaluating normalizes with the running moments and leaves them as they are
+        let eval_forward = batch_norm.eval_forward.as_ref().unwrap();
+        let mut outputs: Vec<Tensor> = outputs.iter().map(|y| Tensor::zeros(y.shape.clone())).collect();
+        let ins: Vec<&Tensor> = inputs.iter().collect();
+        eval_forward(&attrs, &ins, &mut outputs);
+        assert_eq!(outputs[0].data, vec![1.0, 21.0, 3.0, 61.0]);
+        assert_eq!(outputs[1].data, vec![0.0, 0.0]);
+        assert_eq!(outputs[2].data, vec![1.0, 1.0]);
+    }
+
+    #[test]
+    fn batch_norm_statistics_arent_trained() {
+        let catalog = OpCatalog::builtin();
+        let mut graph = GraphBuilder::new();
+        let variable = catalog.get("Variable").unwrap();
+        let batch_norm = graph.add_node("bn".to_string(), [0.0, 0.0], catalog.get("BatchNorm").unwrap());
+        let names = ["x", "gamma", "beta", "running_mean", "running_var"];
+        for (i, name) in names.iter().enumerate() {
+            let node = graph.add_node(name.to_string(), [0.0, 0.0], variable.clone());
+            graph.connect(node, 0, batch_norm, i);
+        }
+
+        let trained: Vec<String> = graph.trainable_variables().iter().map(|&(v, _)| graph.var_name(v)).collect();
+        assert_eq!(trained, vec!["x".to_string(), "gamma".to_string(), "beta".to_string()]);
+    }
+
+    #[test]
+    fn cast_gradients() {
+        check("Cast", &[], vec![values(&[2, 3], 0.0, 1.0)]);
+
+        // Nothing flows back through a cast to integers
+        let cast = op("Cast");
+        let attrs = attrs(&cast, &[("to", AttrValue::Str("i32".to_string()))]);
+        let x = Tensor::new(vec![3], vec![-1.5, 0.25, 2.75]);
+        let outputs = forward(&cast, &attrs, &[x.clone()]);
+        assert_eq!(outputs[0].data, vec![-1.0, 0.0, 2.0]);
+        let mut grads = vec![Tensor::zeros(vec![3])];
+        let ones = Tensor::new(vec![3], vec![1.0; 3]);
+        let backward = cast.backward.as_ref().unwrap();
+        backward(&attrs, &[&x], &[&outputs[0]], &[&ones], &mut grads);
+        assert_eq!(grads[0].data, vec![0.0; 3]);
+    }
+}
@@ -0,0 +1,199 @@
+//! The checkpoint file format: a list of named tensors.
+//!
+//! All numbers are little-endian. The file starts with the magic bytes `DLCK` and a u32 format
+//! version, followed by the u64 training iteration the checkpoint was taken at, a u32 tensor count
+//! and then for each tensor:
+//!
+//! - u32 name length, followed by the UTF-8 name
+//! - u32 rank, followed by one u32 per dimension
+//! - the f32 values, row-major
+
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use super::tensor::Tensor;
+
+pub const MAGIC: &'static [u8; 4] = b"DLCK";
+pub const VERSION: u32 = 2;
+
+pub struct Checkpoint {
+    pub iteration: u64,
+    pub tensors: Vec<(String, Tensor)>,
+}
+
+impl Checkpoint {
+    pub fn new() -> Self {
+        Checkpoint {
+            iteration: 0,
+            tensors: vec![],
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Tensor> {
+        self.tensors.iter().find(|&&(ref n, _)| n == name).map(|&(_, ref t)| t)
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let file = try!(File::open(path));
+        Checkpoint::read_from(&mut BufReader::new(file))
+    }
+
+    /// Write the checkpoint next to `path` and then move it into place, so a crash while saving
+    /// never leaves a truncated checkpoint behind. The file written first is named after the
+    /// whole of `path`, e.g. `run.ckpt.tmp`, so it can't be another file of the same name.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let tmp_path = tmp_path(path);
+        {
+            let file = try!(File::create(&tmp_path));
+            try!(self.write_to(&mut BufWriter::new(file)));
+        }
+        fs::rename(&tmp_path, path)
+    }
+
+    /// Read a checkpoint, trusting none of the sizes in it: nothing is allocated for more data
+    /// than has actually been read.
+    pub fn read_from<R: Read>(r: &mut R) -> io::Result<Self> {
+        let mut magic = [0; 4];
+        try!(r.read_exact(&mut magic));
+        if &magic != MAGIC {
+            return Err(invalid_data("not a DeepLab checkpoint".to_string()));
+        }
+        let version = try!(r.read_u32::<LittleEndian>());
+        if version != VERSION {
+            return Err(invalid_data(format!("unsupported checkpoint version {}", version)));
+        }
+
+        let mut checkpoint = Checkpoint::new();
+        checkpoint.iteration = try!(r.read_u64::<LittleEndian>());
+        let count = try!(r.read_u32::<LittleEndian>());
+        for _ in 0..count {
+            let name_len = try!(r.read_u32::<LittleEndian>()) as usize;
+            let mut name = vec![];
+            try!(r.by_ref().take(name_len as u64).read_to_end(&mut name));
+            if name.len() != name_len {
+                return Err(invalid_data("checkpoint ends in the middle of a name".to_string()));
+            }
+            let name = try!(String::from_utf8(name).map_err(|e| invalid_data(e.to_string())));
+
+            let rank = try!(r.read_u32::<LittleEndian>());
+            let mut shape = vec![];
+            for _ in 0..rank {
+                shape.push(try!(r.read_u32::<LittleEndian>()) as usize);
+            }
+            let len = try!(shape.iter().fold(Some(1usize), |n, &d| n.and_then(|n| n.checked_mul(d)))
+                                .ok_or(invalid_data(format!("'{}' has too many values: {:?}", name, shape))));
+            let mut data = vec![];
+            for _ in 0..len {
+                data.push(try!(r.read_f32::<LittleEndian>()));
+            }
+            checkpoint.tensors.push((name, Tensor::new(shape, data)));
+        }
+        Ok(checkpoint)
+    }
+
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        try!(w.write_all(MAGIC));
+        try!(w.write_u32::<LittleEndian>(VERSION));
+        try!(w.write_u64::<LittleEndian>(self.iteration));
+        try!(w.write_u32::<LittleEndian>(self.tensors.len() as u32));
+        for &(ref name, ref value) in &self.tensors {
+            try!(w.write_u32::<LittleEndian>(name.len() as u32));
+            try!(w.write_all(name.as_bytes()));
+            try!(w.write_u32::<LittleEndian>(value.shape.len() as u32));
+            for &d in &value.shape {
+                try!(w.write_u32::<LittleEndian>(d as u32));
+            }
+            for &x in &value.data {
+                try!(w.write_f32::<LittleEndian>(x));
+            }
+        }
+        w.flush()
+    }
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().map(|name| name.to_os_string()).unwrap_or(OsString::new());
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+
+    use super::{tmp_path, Checkpoint};
+    use super::super::tensor::Tensor;
+
+    fn checkpoint() -> Checkpoint {
+        let mut checkpoint = Checkpoint::new();
+        checkpoint.iteration = 42;
+        checkpoint.tensors.push(("w".to_string(), Tensor::new(vec![2, 3], vec![1.0, -2.0, 3.5, 0.0, 1e-9, 7.0])));
+        checkpoint.tensors.push(("b".to_string(), Tensor::new(vec![], vec![0.5])));
+        checkpoint
+    }
+
+    fn written(checkpoint: &Checkpoint) -> Vec<u8> {
+        let mut buf = vec![];
+        checkpoint.write_to(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn reads_what_was_saved() {
+        let path = env::temp_dir().join("deeplab-checkpoint-test.ckpt");
+        checkpoint().save(&path).unwrap();
+        let read = Checkpoint::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(read.iteration, 42);
+        assert_eq!(read.tensors, checkpoint().tensors);
+        assert_eq!(read.get("b"), Some(&Tensor::new(vec![], vec![0.5])));
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let mut buf = written(&checkpoint());
+        buf[0] = b'X';
+        assert!(Checkpoint::read_from(&mut &buf[..]).is_err());
+
+        let mut buf = written(&checkpoint());
+        buf[4] = 99;
+        assert!(Checkpoint::read_from(&mut &buf[..]).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_checkpoints() {
+        let buf = written(&checkpoint());
+        for len in 0..buf.len() {
+            assert!(Checkpoint::read_from(&mut &buf[..len]).is_err(), "read {} of {} bytes", len, buf.len());
+        }
+    }
+
+    #[test]
+    fn rejects_sizes_larger_than_the_file() {
+        // One tensor with a name of 4 GB
+        let mut buf = b"DLCK\x02\0\0\0\0\0\0\0\0\0\0\0\x01\0\0\0\xFF\xFF\xFF\xFF".to_vec();
+        assert!(Checkpoint::read_from(&mut &buf[..]).is_err());
+
+        // One tensor named "w" with shape [2^32-1, 2^32-1, 2^32-1] and no values
+        buf.truncate(20);
+        buf.extend_from_slice(b"\x01\0\0\0w\x03\0\0\0\xFF\xFF\xFF\xFF\xFF\xFF\xFF\xFF\xFF\xFF\xFF\xFF");
+        assert!(Checkpoint::read_from(&mut &buf[..]).is_err());
+    }
+
+    #[test]
+    fn writes_next_to_the_checkpoint_first() {
+        assert_eq!(tmp_path(Path::new("runs/run.ckpt")), Path::new("runs/run.ckpt.tmp"));
+        assert_eq!(tmp_path(Path::new("run")), Path::new("run.tmp"));
+    }
+}
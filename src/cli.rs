@@ -0,0 +1,215 @@
+//! Command line handling, including the modes that run without opening a window.
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use matrix;
+
+use super::catalog::OpCatalog;
+use super::checkpoint::Checkpoint;
+use super::codegen::{self, Target, Weights};
+use super::graph_builder::GraphBuilder;
+use super::graph_text;
+use super::onnx;
+use super::plugin::{self, PLUGIN_DIR};
+use super::script::Script;
+use super::trainer::Trainer;
+
+pub const USAGE: &'static str = "\
+Usage: deeplab [MODEL.onnx | MODEL.graph] [options]
+
+Options:
+    --train N               Train the model for N iterations and exit
+    --dim NAME=SIZE         Give symbolic dimension NAME, e.g. the batch size, a size
+    --script FILE           Run the script in FILE on the model before training or exporting
+    --checkpoint FILE       Save checkpoints to FILE while training
+    --checkpoint-every N    Save a checkpoint every N iterations (default 100)
+    --resume FILE           Restore variables from a checkpoint before training
+    --check-finite          Stop training at the first value or gradient that isn't finite
+                            and report the node that gave it
+    --emit-rust FILE        Write a Rust module building the model to FILE and exit
+    --weights FILE          With --emit-rust, load weights from a checkpoint written to FILE
+                            instead of inlining them
+    --target gpu|cpu        With --emit-rust, build the model as a dl::Graph or compute it in
+                            plain Rust on the CPU (default: gpu if dl can build every node)
+
+Without a window, the exit status is 0 on success, 2 if training stopped at a value that isn't
+finite and 1 for any other failure.";
+
+/// Exit status for a headless run that failed.
+pub const EXIT_FAILED: i32 = 1;
+/// Exit status for a headless run stopped by a value or gradient that isn't finite.
+pub const EXIT_ANOMALY: i32 = 2;
+
+/// Why a headless run failed, and the exit status to report it with.
+pub struct Failure {
+    pub message: String,
+    pub status: i32,
+}
+
+impl From<String> for Failure {
+    fn from(message: String) -> Self {
+        Failure { message: message, status: EXIT_FAILED }
+    }
+}
+
+pub struct Options {
+    pub model: Option<PathBuf>,
+    pub dims: Vec<(String, usize)>,
+    pub script: Option<PathBuf>,
+    pub train: Option<u64>,
+    pub checkpoint: Option<PathBuf>,
+    pub checkpoint_every: u64,
+    pub resume: Option<PathBuf>,
+    pub check_finite: bool,
+    pub emit_rust: Option<PathBuf>,
+    pub weights: Option<PathBuf>,
+    pub target: Option<Target>,
+}
+
+impl Options {
+    pub fn parse<I: Iterator<Item=String>>(mut args: I) -> Result<Options, String> {
+        let mut options = Options {
+            model: None,
+            dims: vec![],
+            script: None,
+            train: None,
+            checkpoint: None,
+            checkpoint_every: 100,
+            resume: None,
+            check_finite: false,
+            emit_rust: None,
+            weights: None,
+            target: None,
+        };
+        while let Some(arg) = args.next() {
+            match arg.as_ref() {
+                "--dim" => { options.dims.push(try!(dim_arg(&arg, args.next()))); },
+                "--script" => { options.script = Some(try!(path_arg(&arg, args.next()))); },
+                "--train" => { options.train = Some(try!(count_arg(&arg, args.next()))); },
+                "--checkpoint" => { options.checkpoint = Some(try!(path_arg(&arg, args.next()))); },
+                "--checkpoint-every" => { options.checkpoint_every = try!(count_arg(&arg, args.next())); },
+                "--resume" => { options.resume = Some(try!(path_arg(&arg, args.next()))); },
+                "--check-finite" => { options.check_finite = true; },
+                "--emit-rust" => { options.emit_rust = Some(try!(path_arg(&arg, args.next()))); },
+                "--weights" => { options.weights = Some(try!(path_arg(&arg, args.next()))); },
+                "--target" => { options.target = Some(try!(target_arg(&arg, args.next()))); },
+                _ if arg.starts_with("--") => { return Err(format!("Unknown option '{}'", arg)); },
+                _ => { options.model = Some(PathBuf::from(arg)); },
+            }
+        }
+        if options.weights.is_some() && options.emit_rust.is_none() {
+            return Err("--weights only makes sense with --emit-rust".to_string());
+        }
+        if options.target.is_some() && options.emit_rust.is_none() {
+            return Err("--target only makes sense with --emit-rust".to_string());
+        }
+        if (options.checkpoint.is_some() || options.resume.is_some()) && options.train.is_none() {
+            return Err("--checkpoint and --resume only make sense with --train".to_string());
+        }
+        if options.check_finite && options.train.is_none() {
+            return Err("--check-finite only makes sense with --train".to_string());
+        }
+        Ok(options)
+    }
+
+    /// Whether to do the work from the command line instead of opening the editor.
+    pub fn is_headless(&self) -> bool {
+        self.script.is_some() || self.train.is_some() || self.emit_rust.is_some()
+    }
+}
+
+pub fn run_headless(options: &Options) -> Result<(), Failure> {
+    let mut catalog = OpCatalog::builtin();
+    plugin::add_plugins(&mut catalog, Path::new(PLUGIN_DIR));
+    let ctx = matrix::Context::new();
+    let mut graph = GraphBuilder::new();
+
+    if let Some(ref model) = options.model {
+        if is_graph_text(model) {
+            try!(graph_text::load(model, &mut graph, &catalog));
+        } else {
+            let onnx_graph = try!(onnx::read_model(model));
+            let report = try!(onnx::import(&onnx_graph, &mut graph, &catalog));
+            if !report.unsupported.is_empty() {
+                return Err(Failure::from(format!("Unsupported operators: {}", report.unsupported.join(", "))));
+            }
+        }
+    }
+
+    for &(ref name, size) in &options.dims {
+        graph.set_dim(name, size);
+    }
+
+    if let Some(ref path) = options.script {
+        let mut src = String::new();
+        try!(File::open(path).and_then(|mut f| f.read_to_string(&mut src)).map_err(|e| e.to_string()));
+        let mut script = Script::new();
+        let result = script.run(&src, &mut graph, &catalog, &ctx);
+        for line in &script.output {
+            println!("{}", line);
+        }
+        try!(result.map_err(|e| format!("{}: {}", path.display(), e)));
+    }
+
+    if let Some(iterations) = options.train {
+        graph.set_check_finite(options.check_finite);
+        try!(graph.build(&ctx));
+        let mut trainer = Trainer::new(iterations);
+        trainer.checkpoint_path = options.checkpoint.clone();
+        trainer.checkpoint_every = options.checkpoint_every;
+        if let Some(ref resume) = options.resume {
+            let checkpoint = try!(Checkpoint::load(resume).map_err(|e| e.to_string()));
+            try!(trainer.resume(&ctx, &mut graph, &checkpoint));
+            println!("Resuming from iteration {}", trainer.iteration);
+        }
+        if let Err(e) = trainer.run(&ctx, &mut graph) {
+            let status = if graph.anomaly().is_some() { EXIT_ANOMALY } else { EXIT_FAILED };
+            return Err(Failure { message: e, status: status });
+        }
+    }
+
+    if let Some(ref out) = options.emit_rust {
+        let weights = if options.weights.is_some() { Weights::Checkpoint } else { Weights::Inline };
+        let target = options.target.unwrap_or(Target::for_graph(&graph));
+        let src = try!(codegen::generate(&graph, &ctx, target, weights));
+        try!(File::create(out).and_then(|mut f| f.write_all(src.as_bytes())).map_err(|e| e.to_string()));
+        if let Some(ref weights_path) = options.weights {
+            try!(graph.checkpoint(&ctx).save(weights_path).map_err(|e| e.to_string()));
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether a model is a graph written as text rather than an ONNX model.
+pub fn is_graph_text(path: &Path) -> bool {
+    path.extension().and_then(|ext| ext.to_str()) == Some(graph_text::EXTENSION)
+}
+
+fn count_arg(option: &str, value: Option<String>) -> Result<u64, String> {
+    value.and_then(|v| v.parse().ok()).ok_or(format!("{} needs a number", option))
+}
+
+fn dim_arg(option: &str, value: Option<String>) -> Result<(String, usize), String> {
+    value.and_then(|v| {
+        let mut parts = v.splitn(2, '=');
+        match (parts.next(), parts.next().and_then(|size| size.parse().ok())) {
+            (Some(name), Some(size)) if !name.is_empty() => Some((name.to_string(), size)),
+            _ => None,
+        }
+    }).ok_or(format!("{} needs NAME=SIZE", option))
+}
+
+fn target_arg(option: &str, value: Option<String>) -> Result<Target, String> {
+    match value.as_ref().map(|v| v.as_ref()) {
+        Some("gpu") => Ok(Target::Gpu),
+        Some("cpu") => Ok(Target::Cpu),
+        _ => Err(format!("{} needs gpu or cpu", option)),
+    }
+}
+
+fn path_arg(option: &str, value: Option<String>) -> Result<PathBuf, String> {
+    value.map(PathBuf::from).ok_or(format!("{} needs a file name", option))
+}
@@ -0,0 +1,207 @@
+//! Copying nodes within a graph and between graphs. Copied nodes are written as text: the nodes
+//! in the graph text format, then where they were placed and optionally the values of their
+//! variables:
+//!
+//! ```text
+//! w1 = Variable(shape=[784, 128], l2=0.001)
+//! h = ReLU(MatMul(_, w1))
+//! --- layout
+//! 160 80 w1
+//! 288 80 h
+//! --- values
+//! w1	784 128	0.013 -0.2 ...
+//! ```
+//!
+//! Value lines are the variable's name, its dimensions and its values, separated by tabs. The
+//! clipboard is kept in a file in the working directory, next to the graph and checkpoint, so
+//! nodes can be pasted into a graph open in another window started from the same directory.
+
+use std::fs::File;
+use std::io::{Read, Write};
+
+use matrix;
+
+use super::catalog::OpCatalog;
+use super::graph_builder::{GraphBuilder, NodeId};
+use super::graph_text;
+use super::tensor::Tensor;
+
+const CLIPBOARD_PATH: &'static str = "clipboard.graph";
+const LAYOUT_HEADER: &'static str = "--- layout";
+const VALUES_HEADER: &'static str = "--- values";
+/// How far duplicates are placed from the nodes they copy.
+const DUPLICATE_OFFSET: f64 = 32.0;
+
+/// The nodes as clipboard text, with the edges between them. With a context, the current values
+/// of their variables are included too.
+pub fn copy(graph: &GraphBuilder, nodes: &[NodeId], values: Option<&matrix::Context>) -> Result<String, String> {
+    let mut text = try!(graph_text::write_nodes(graph, nodes));
+    text.push_str(LAYOUT_HEADER);
+    text.push('\n');
+    text.push_str(&graph_text::write_nodes_layout(graph, nodes));
+
+    if let Some(ctx) = values {
+        text.push_str(VALUES_HEADER);
+        text.push('\n');
+        let variables = graph.variables();
+        for v in nodes.iter().flat_map(|node| node.get(graph).outputs.iter()).filter(|v| variables.contains(*v)) {
+            if let Some(value) = graph.var_value(ctx, *v) {
+                let dims: Vec<String> = value.shape.iter().map(|d| d.to_string()).collect();
+                let data: Vec<String> = value.data.iter().map(|x| x.to_string()).collect();
+                text.push_str(&format!("{}\t{}\t{}\n", graph.var_name(*v), dims.join(" "), data.join(" ")));
+            }
+        }
+    }
+    Ok(text)
+}
+
+/// Add the nodes in clipboard text to the graph with their top left corner at `pos`. Gives the
+/// new nodes.
+pub fn paste(text: &str, graph: &mut GraphBuilder, catalog: &OpCatalog, pos: [f64; 2]) -> Result<Vec<NodeId>, String> {
+    let (nodes, rest) = split_section(text, LAYOUT_HEADER);
+    let (layout, values) = split_section(rest, VALUES_HEADER);
+
+    // Read into a graph of their own first, so their names can't clash with the graph's
+    let mut copied = GraphBuilder::new();
+    try!(graph_text::read(nodes, &mut copied, catalog));
+    copied.auto_layout();
+    graph_text::read_layout(layout, &mut copied);
+    for line in values.lines().filter(|line| !line.is_empty()) {
+        let (name, value) = try!(read_value(line));
+        let v = try!(copied.var_by_name(&name).ok_or(format!("there's no variable '{}' to give a value", name)));
+        v.get_mut(&mut copied.vars).value = Some(value);
+    }
+
+    if copied.nodes().is_empty() {
+        return Ok(vec![]);
+    }
+    let origin = copied.nodes().iter().fold([::std::f64::INFINITY; 2], |origin, node| {
+        [origin[0].min(node.pos()[0]), origin[1].min(node.pos()[1])]
+    });
+    Ok(graph.insert(&copied, [pos[0] - origin[0], pos[1] - origin[1]]))
+}
+
+/// Copy the nodes, with the values of their variables, and add the copies next to them. Gives
+/// the copies.
+pub fn duplicate(graph: &mut GraphBuilder, nodes: &[NodeId], catalog: &OpCatalog, ctx: &matrix::Context)
+                 -> Result<Vec<NodeId>, String> {
+    let text = try!(copy(graph, nodes, Some(ctx)));
+    let origin = nodes.iter().fold([::std::f64::INFINITY; 2], |origin, node| {
+        let pos = node.get(graph).pos();
+        [origin[0].min(pos[0]), origin[1].min(pos[1])]
+    });
+    paste(&text, graph, catalog, [origin[0] + DUPLICATE_OFFSET, origin[1] + DUPLICATE_OFFSET])
+}
+
+/// Put text on the clipboard.
+pub fn store(text: &str) -> Result<(), String> {
+    File::create(CLIPBOARD_PATH).and_then(|mut f| f.write_all(text.as_bytes())).map_err(|e| e.to_string())
+}
+
+/// The text on the clipboard.
+pub fn retrieve() -> Result<String, String> {
+    let mut text = String::new();
+    try!(File::open(CLIPBOARD_PATH).and_then(|mut f| f.read_to_string(&mut text))
+                                    .map_err(|_| "The clipboard is empty".to_string()));
+    Ok(text)
+}
+
+/// The text before the line `header` and the text after it, which is empty if there's no such
+/// line.
+fn split_section<'a>(text: &'a str, header: &str) -> (&'a str, &'a str) {
+    let mut start = 0;
+    for line in text.split('\n') {
+        let end = start + line.len();
+        if line.trim_right() == header {
+            return (&text[..start], &text[(end + 1).min(text.len())..]);
+        }
+        start = end + 1;
+    }
+    (text, "")
+}
+
+/// A value line, `NAME<tab>DIMS<tab>VALUES`.
+fn read_value(line: &str) -> Result<(String, Tensor), String> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    if fields.len() != 3 {
+        return Err(format!("can't read the value line '{}'", line));
+    }
+    let mut dims = vec![];
+    for d in fields[1].split_whitespace() {
+        dims.push(try!(d.parse::<usize>().map_err(|_| format!("bad dimension '{}' for '{}'", d, fields[0]))));
+    }
+    let mut data = vec![];
+    for x in fields[2].split_whitespace() {
+        data.push(try!(x.parse::<f32>().map_err(|_| format!("bad value '{}' for '{}'", x, fields[0]))));
+    }
+    if dims.iter().fold(Some(1), |n, &d| n.and_then(|n: usize| n.checked_mul(d))) != Some(data.len()) {
+        return Err(format!("'{}' has {} values for dimensions {:?}", fields[0], data.len(), dims));
+    }
+    Ok((fields[0].to_string(), Tensor::new(dims, data)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{copy, paste, read_value, split_section};
+    use super::super::catalog::OpCatalog;
+    use super::super::graph_builder::GraphBuilder;
+    use super::super::graph_text;
+    use super::super::tensor::Tensor;
+
+    fn graph(text: &str) -> GraphBuilder {
+        let mut graph = GraphBuilder::new();
+        graph_text::read(text, &mut graph, &OpCatalog::builtin()).unwrap();
+        graph
+    }
+
+    #[test]
+    fn pastes_copies_under_new_names() {
+        let mut graph = graph("a = Variable(shape=[2])\nr = ReLU(a)\ns = Sigmoid(r)\n");
+        let r = graph.node_by_name("r").unwrap();
+        let s = graph.node_by_name("s").unwrap();
+        let text = copy(&graph, &[r, s], None).unwrap();
+        assert!(!text.contains("--- values"));
+
+        let pasted = paste(&text, &mut graph, &OpCatalog::builtin(), [400.0, 0.0]).unwrap();
+        assert_eq!(pasted.len(), 2);
+        let r1 = graph.node_by_name("r_1").unwrap();
+        let s1 = graph.node_by_name("s_1").unwrap();
+        assert!(graph.input_edge(s1, 0).map(|(node, _)| node) == Some(r1));
+        assert!(graph.input_edge(r1, 0).is_none());
+        let left = pasted.iter().map(|node| node.get(&graph).pos()[0]).fold(::std::f64::INFINITY, f64::min);
+        assert_eq!(left, 400.0);
+    }
+
+    #[test]
+    fn pastes_values() {
+        let text = "w = Variable(shape=[2, 1])\n--- layout\n10 20 w\n--- values\nw\t2 1\t0.5 -1\n";
+        let mut graph = GraphBuilder::new();
+        paste(text, &mut graph, &OpCatalog::builtin(), [0.0, 0.0]).unwrap();
+        let w = graph.var_by_name("w").unwrap();
+        assert_eq!(w.get(&graph.vars).value, Some(Tensor::new(vec![2, 1], vec![0.5, -1.0])));
+
+        let text = "w = Variable(shape=[1])\n--- layout\n--- values\nq\t1\t1\n";
+        assert_eq!(paste(text, &mut graph, &OpCatalog::builtin(), [0.0, 0.0]).err(),
+                   Some("there's no variable 'q' to give a value".to_string()));
+    }
+
+    #[test]
+    fn reads_value_lines() {
+        let (name, value) = read_value("w\t1 2\t3 4").unwrap();
+        assert_eq!(name, "w");
+        assert_eq!(value, Tensor::new(vec![1, 2], vec![3.0, 4.0]));
+
+        assert_eq!(read_value("w 2 1").err(), Some("can't read the value line 'w 2 1'".to_string()));
+        assert_eq!(read_value("w\tx\t1").err(), Some("bad dimension 'x' for 'w'".to_string()));
+        assert_eq!(read_value("w\t1\ty").err(), Some("bad value 'y' for 'w'".to_string()));
+        assert_eq!(read_value("w\t2\t1 2 3").err(), Some("'w' has 3 values for dimensions [2]".to_string()));
+        assert!(read_value("w\t4294967296 4294967296 4294967296\t1").is_err());
+    }
+
+    #[test]
+    fn splits_sections() {
+        assert_eq!(split_section("a\n--- layout\nb\n", "--- layout"), ("a\n", "b\n"));
+        assert_eq!(split_section("a\n--- layout", "--- layout"), ("a\n", ""));
+        assert_eq!(split_section("a\n", "--- layout"), ("a\n", ""));
+    }
+}
@@ -0,0 +1,616 @@
+//! Generating a standalone Rust module that builds the same graph as a `GraphBuilder`, either
+//! as a `dl::Graph` or as plain Rust running on the CPU.
+//!
+//! A `dl::Graph` module expects the crate using it to have `deeplearn` (as `dl`), `matrix` and,
+//! when weights come from a checkpoint, `byteorder` available at the crate root. It exposes a
+//! `Model` struct with the graph and a field for every variable, and a `build` function. Losses
+//! also get a `<name>_grad` field with their gradient. `dl` adds the gradients to the variables
+//! whenever the graph runs, so set it to the negative learning rate to train and to zero to only
+//! run the graph forward. Only operations `dl::Graph` itself runs can be generated: Variable,
+//! MatMul, ReLU and MSE.
+//!
+//! A CPU module only needs `byteorder`, and only when weights come from a checkpoint. Its `Model`
+//! has the values of every variable as a `Vec<f32>` field, and its `run` method computes them all
+//! the way the CPU backend does when evaluating, so it serves a trained model but can't train it.
+//! Variables without a value start as zeros. Every built-in operation except convolutions,
+//! pooling and recurrent cells can be generated for the CPU.
+//!
+//! Graphs using an operation the target doesn't cover are rejected with an error naming the first
+//! such node.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+
+use matrix;
+
+use super::graph_builder::GraphBuilder;
+use super::tensor::{self, Tensor};
+use super::var_store::VarIndex;
+
+/// The operations with code generators for `dl::Graph`, as listed in errors.
+const GPU_SUPPORTED: &'static str = "Variable, MatMul, ReLU and MSE";
+const CPU_UNSUPPORTED: &'static str = "convolutions, pooling and recurrent cells";
+
+/// Names the generated code itself binds, or that can't be identifiers.
+const RESERVED: &'static [&'static str] = &[
+    "graph", "ctx", "node", "weights", "checkpoint", "build", "read_checkpoint", "take_weight",
+    "take_values", "run",
+    "as", "break", "const", "continue", "crate", "else", "enum", "extern", "false", "fn", "for",
+    "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub", "ref", "return",
+    "self", "static", "struct", "super", "trait", "true", "type", "unsafe", "use", "where",
+    "while", "abstract", "alignof", "become", "box", "do", "final", "macro", "offsetof",
+    "override", "priv", "proc", "pure", "sizeof", "typeof", "unsized", "virtual", "yield",
+];
+
+/// What the generated module builds the graph as.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Target {
+    /// A `dl::Graph`, which runs on the GPU and can be trained.
+    Gpu,
+    /// Plain Rust running the graph forward on the CPU.
+    Cpu,
+}
+
+impl Target {
+    /// The GPU if every node of the graph can be generated for it, otherwise the CPU.
+    pub fn for_graph(builder: &GraphBuilder) -> Target {
+        if builder.nodes().iter().all(|node| node.op.subgraph.is_some() || node.op.codegen.is_some()) {
+            Target::Gpu
+        } else {
+            Target::Cpu
+        }
+    }
+}
+
+/// Where the generated code gets the values of the graph's variables from.
+pub enum Weights {
+    /// Values are written into the generated source as constants.
+    Inline,
+    /// Values are read from a checkpoint file, such as one saved while training, whose path is
+    /// passed to `build`.
+    Checkpoint,
+}
+
+/// What an operation's code generator gets to work with: the identifiers, shapes as matrices
+/// and dimensions of the node's inputs and outputs.
+pub struct EmitNode {
+    pub inputs: Vec<(String, (usize, usize), Vec<usize>)>,
+    pub outputs: Vec<(String, (usize, usize), Vec<usize>)>,
+}
+
+/// Generate Rust source building `builder`'s graph for `target`. Variables take their current
+/// values, so generating after training ships the trained weights.
+pub fn generate(builder: &GraphBuilder, ctx: &matrix::Context, target: Target, weights: Weights)
+                -> Result<String, String> {
+    generate_with_values(builder, &|v| builder.var_value(ctx, v), target, weights)
+}
+
+fn generate_with_values(builder: &GraphBuilder, values: &Fn(VarIndex) -> Option<Tensor>, target: Target,
+                        weights: Weights) -> Result<String, String> {
+    let order = try!(builder.build_order());
+    // The CPU module keeps every value in a field of the model
+    let prefix = match target {
+        Target::Gpu => "",
+        Target::Cpu => "self.",
+    };
+
+    let mut idents = Idents::new();
+    let mut consts = String::new();
+    let mut body = String::new();
+    let mut fields = vec![];
+    // The initial values of the CPU model's fields
+    let mut inits = vec![];
+    for id in order {
+        let node = id.get(builder);
+
+        // Inputs come from nodes earlier in the build order, so they already have identifiers
+        let port = |ident: String, v: VarIndex| {
+            let var = v.get(&builder.vars);
+            (ident, var.shape_2d(), var.dims.clone())
+        };
+        let emit_node = EmitNode {
+            inputs: node.inputs.iter().map(|input| {
+                let v = input.unwrap();
+                port(format!("{}{}", prefix, idents.var(builder, v)), v)
+            }).collect(),
+            outputs: node.outputs.iter().map(|&v| port(format!("{}{}", prefix, idents.var(builder, v)), v)).collect(),
+        };
+        let code = match target {
+            Target::Gpu => node.op.codegen.as_ref().map(|emit| emit(&emit_node)),
+            Target::Cpu => node.op.cpu_codegen.as_ref().map(|emit| emit(&node.attrs, &emit_node)),
+        };
+        let code = try!(code.ok_or(match target {
+            Target::Gpu => format!("Operation '{}' of node '{}' can't be generated as a dl::Graph, which only \
+                                    covers {}; generate for the CPU instead",
+                                   node.op.name, node.name(), GPU_SUPPORTED),
+            Target::Cpu => format!("Operation '{}' of node '{}' can't be generated for the CPU, which covers \
+                                    every built-in operation except {}",
+                                   node.op.name, node.name(), CPU_UNSUPPORTED),
+        }));
+
+        if !code.is_empty() {
+            writeln!(body, "{}// {} ({})", indent(target), node.name(), node.op.name).unwrap();
+            for line in code.lines() {
+                writeln!(body, "{}{}", indent(target), line).unwrap();
+            }
+        }
+        let loss_grad = if node.op.loss && target == Target::Gpu {
+            let ident = idents.fresh(&format!("{}_grad", emit_node.outputs[0].0));
+            writeln!(body, "    let {} = graph.add_gradient(ctx, node, 0);", ident).unwrap();
+            Some(ident)
+        } else {
+            None
+        };
+
+        for (&v, &(_, shape, ref dims)) in node.outputs.iter().zip(&emit_node.outputs) {
+            let ident = idents.var(builder, v);
+
+            // Nodes without inputs are the graph's variables; give them their current values
+            let value = if node.inputs.is_empty() { values(v) } else { None };
+            let init = match value {
+                Some(value) => Some(match weights {
+                    Weights::Inline => {
+                        if value.data.iter().any(|x| !x.is_finite()) {
+                            return Err(format!("Variable '{}' has non-finite values", ident));
+                        }
+                        // Idents are lower case, so this can't shadow one of them
+                        let const_name = format!("{}_VALUE", ident.to_uppercase());
+                        let literals: Vec<String> = value.data.iter().map(|&x| float_literal(x)).collect();
+                        writeln!(consts, "const {}: [f32; {}] = [{}];",
+                                 const_name, value.len(), literals.join(", ")).unwrap();
+                        match target {
+                            Target::Gpu => format!("{}.get(&graph).set(ctx, &matrix::Matrix::from_vec({}, {}, {}.to_vec()));",
+                                                   ident, shape.0, shape.1, const_name),
+                            Target::Cpu => format!("{}.to_vec()", const_name),
+                        }
+                    },
+                    Weights::Checkpoint => match target {
+                        Target::Gpu => format!("{}.get(&graph).set(ctx, &try!(take_weight(&mut weights, {:?}, {:?})));",
+                                               ident, builder.var_name(v), shape),
+                        Target::Cpu => format!("try!(take_values(&mut weights, {:?}, {}))",
+                                               builder.var_name(v), tensor::num_elements(dims)),
+                    },
+                }),
+                None => None,
+            };
+            match target {
+                Target::Gpu => {
+                    if let Some(init) = init {
+                        writeln!(body, "    {}", init).unwrap();
+                    }
+                },
+                Target::Cpu => {
+                    inits.push(init.unwrap_or(format!("vec![0.0; {}]", tensor::num_elements(dims))));
+                    fields.push(ident);
+                },
+            }
+        }
+        if target == Target::Gpu {
+            body.push('\n');
+            fields.extend(emit_node.outputs.into_iter().map(|(ident, _, _)| ident));
+            fields.extend(loss_grad);
+        }
+    }
+
+    let mut src = String::new();
+    writeln!(src, "// Generated by DeepLab. Do not edit.").unwrap();
+    writeln!(src, "").unwrap();
+    if target == Target::Cpu {
+        // Not every helper is used by every model
+        writeln!(src, "#![allow(dead_code)]").unwrap();
+        writeln!(src, "").unwrap();
+    }
+    if let Weights::Checkpoint = weights {
+        writeln!(src, "use std::collections::HashMap;").unwrap();
+        writeln!(src, "use std::fs::File;").unwrap();
+        writeln!(src, "use std::io::{{self, BufReader, Read}};").unwrap();
+        writeln!(src, "use std::path::Path;").unwrap();
+        writeln!(src, "").unwrap();
+        writeln!(src, "use byteorder::{{LittleEndian, ReadBytesExt}};").unwrap();
+    }
+    if target == Target::Gpu {
+        writeln!(src, "use dl;").unwrap();
+        writeln!(src, "use matrix;").unwrap();
+    }
+    if !src.ends_with("\n\n") {
+        writeln!(src, "").unwrap();
+    }
+
+    writeln!(src, "pub struct Model {{").unwrap();
+    match target {
+        Target::Gpu => {
+            writeln!(src, "    pub graph: dl::Graph,").unwrap();
+            for field in &fields {
+                writeln!(src, "    pub {}: dl::VarIndex,", field).unwrap();
+            }
+        },
+        Target::Cpu => {
+            for field in &fields {
+                writeln!(src, "    pub {}: Vec<f32>,", field).unwrap();
+            }
+        },
+    }
+    writeln!(src, "}}").unwrap();
+    writeln!(src, "").unwrap();
+
+    if !consts.is_empty() {
+        src.push_str(&consts);
+        writeln!(src, "").unwrap();
+    }
+
+    match (target, &weights) {
+        (Target::Gpu, &Weights::Inline) => {
+            writeln!(src, "pub fn build(ctx: &matrix::Context) -> Model {{").unwrap();
+        },
+        (Target::Gpu, &Weights::Checkpoint) => {
+            writeln!(src, "pub fn build(ctx: &matrix::Context, checkpoint: &Path) -> io::Result<Model> {{").unwrap();
+        },
+        (Target::Cpu, &Weights::Inline) => {
+            writeln!(src, "pub fn build() -> Model {{").unwrap();
+        },
+        (Target::Cpu, &Weights::Checkpoint) => {
+            writeln!(src, "pub fn build(checkpoint: &Path) -> io::Result<Model> {{").unwrap();
+        },
+    }
+    if let Weights::Checkpoint = weights {
+        writeln!(src, "    let mut weights = try!(read_checkpoint(checkpoint));").unwrap();
+    }
+
+    let model = match target {
+        Target::Gpu => {
+            writeln!(src, "    let mut graph = dl::Graph::new();").unwrap();
+            writeln!(src, "").unwrap();
+            src.push_str(&body);
+            format!("Model {{ graph: graph{} }}",
+                    fields.iter().map(|f| format!(", {}: {}", f, f)).collect::<String>())
+        },
+        Target::Cpu => {
+            let inits: Vec<String> = fields.iter().zip(&inits).map(|(f, init)| format!("        {}: {},\n", f, init)).collect();
+            format!("Model {{\n{}    }}", inits.concat())
+        },
+    };
+    match weights {
+        Weights::Inline => writeln!(src, "    {}", model).unwrap(),
+        Weights::Checkpoint => writeln!(src, "    Ok({})", model).unwrap(),
+    }
+    writeln!(src, "}}").unwrap();
+
+    if target == Target::Cpu {
+        writeln!(src, "").unwrap();
+        writeln!(src, "impl Model {{").unwrap();
+        writeln!(src, "    /// Compute every value from the variables, as when evaluating.").unwrap();
+        writeln!(src, "    pub fn run(&mut self) {{").unwrap();
+        src.push_str(&body);
+        writeln!(src, "    }}").unwrap();
+        writeln!(src, "}}").unwrap();
+        src.push_str(CPU_HELPERS);
+    }
+
+    if let Weights::Checkpoint = weights {
+        src.push_str(CHECKPOINT_READER);
+        src.push_str(match target {
+            Target::Gpu => GPU_TAKE_WEIGHT,
+            Target::Cpu => CPU_TAKE_VALUES,
+        });
+    }
+
+    Ok(src)
+}
+
+/// How deep statements are indented: in `build` for a `dl::Graph`, in `Model::run` for the CPU.
+fn indent(target: Target) -> &'static str {
+    match target {
+        Target::Gpu => "    ",
+        Target::Cpu => "        ",
+    }
+}
+
+/// The identifiers bound in generated code, kept distinct even where names sanitize alike.
+struct Idents {
+    vars: HashMap<VarIndex, String>,
+    used: HashSet<String>,
+}
+
+impl Idents {
+    fn new() -> Self {
+        Idents {
+            vars: HashMap::new(),
+            used: RESERVED.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    /// The identifier a variable is bound to.
+    fn var(&mut self, builder: &GraphBuilder, v: VarIndex) -> String {
+        if let Some(ident) = self.vars.get(&v) {
+            return ident.clone();
+        }
+        let ident = self.fresh(&builder.var_name(v));
+        self.vars.insert(v, ident.clone());
+        ident
+    }
+
+    /// An identifier for `name` that isn't used yet, numbered if it has to be.
+    fn fresh(&mut self, name: &str) -> String {
+        let base = sanitize(name);
+        let mut ident = base.clone();
+        let mut n = 2;
+        while self.used.contains(&ident) {
+            ident = format!("{}_{}", base, n);
+            n += 1;
+        }
+        self.used.insert(ident.clone());
+        ident
+    }
+}
+
+/// Turn a node name into something usable as a Rust identifier.
+fn sanitize(name: &str) -> String {
+    let mut ident: String = name.chars()
+                                .map(|c| if c.is_ascii() && c.is_alphanumeric() { c.to_ascii_lowercase() } else { '_' })
+                                .collect();
+    // `_` on its own can't be read back
+    if ident.chars().all(|c| c == '_') {
+        ident.push_str("var");
+    }
+    if ident.chars().next().unwrap().is_numeric() {
+        ident.insert(0, '_');
+    }
+    ident
+}
+
+/// `x` as an f32 literal in generated code.
+pub fn float_literal(x: f32) -> String {
+    let mut literal = format!("{:?}", x);
+    if !literal.contains('.') && !literal.contains('e') {
+        literal.push_str(".0");
+    }
+    literal
+}
+
+/// Reads the format written by `checkpoint::Checkpoint::write_to`.
+const CHECKPOINT_READER: &'static str = r#"
+fn read_checkpoint(path: &Path) -> io::Result<HashMap<String, (Vec<usize>, Vec<f32>)>> {
+    let mut r = BufReader::new(try!(File::open(path)));
+    let mut magic = [0; 4];
+    try!(r.read_exact(&mut magic));
+    if &magic != b"DLCK" || try!(r.read_u32::<LittleEndian>()) != 2 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a DeepLab checkpoint"));
+    }
+    let _iteration = try!(r.read_u64::<LittleEndian>());
+    let mut weights = HashMap::new();
+    for _ in 0..try!(r.read_u32::<LittleEndian>()) {
+        let mut name = vec![0; try!(r.read_u32::<LittleEndian>()) as usize];
+        try!(r.read_exact(&mut name));
+        let mut shape = vec![];
+        for _ in 0..try!(r.read_u32::<LittleEndian>()) {
+            shape.push(try!(r.read_u32::<LittleEndian>()) as usize);
+        }
+        let mut data = vec![];
+        for _ in 0..shape.iter().fold(1, |n, &d| n*d) {
+            data.push(try!(r.read_f32::<LittleEndian>()));
+        }
+        weights.insert(String::from_utf8_lossy(&name).into_owned(), (shape, data));
+    }
+    Ok(weights)
+}
+"#;
+
+const GPU_TAKE_WEIGHT: &'static str = r#"
+fn take_weight(weights: &mut HashMap<String, (Vec<usize>, Vec<f32>)>, name: &str, shape: (usize, usize))
+               -> io::Result<matrix::Matrix<f32>> {
+    match weights.remove(name) {
+        Some((_, ref data)) if data.len() == shape.0*shape.1 => {
+            Ok(matrix::Matrix::from_vec(shape.0, shape.1, data.clone()))
+        },
+        Some(_) => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("weight '{}' doesn't have shape {:?}", name, shape))),
+        None => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                   format!("checkpoint has no weight '{}'", name))),
+    }
+}
+"#;
+
+const CPU_TAKE_VALUES: &'static str = r#"
+fn take_values(weights: &mut HashMap<String, (Vec<usize>, Vec<f32>)>, name: &str, len: usize)
+               -> io::Result<Vec<f32>> {
+    match weights.remove(name) {
+        Some((_, data)) => {
+            if data.len() == len {
+                Ok(data)
+            } else {
+                Err(io::Error::new(io::ErrorKind::InvalidData,
+                                   format!("weight '{}' doesn't have {} values", name, len)))
+            }
+        },
+        None => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                   format!("checkpoint has no weight '{}'", name))),
+    }
+}
+"#;
+
+/// What the CPU kernels the generated code calls do; see `kernels`. Tensors are `Vec<f32>`s
+/// whose shapes are known when generating, so they're passed in where they're needed.
+const CPU_HELPERS: &'static str = r#"
+fn map<F: Fn(f32) -> f32>(x: &[f32], f: F) -> Vec<f32> {
+    x.iter().map(|&x| f(x)).collect()
+}
+
+/// `f` of the elements of `a` and `b` broadcast to `shape`, like numpy does.
+fn zip<F: Fn(f32, f32) -> f32>(a: &[f32], a_shape: &[usize], b: &[f32], b_shape: &[usize], shape: &[usize],
+                               f: F) -> Vec<f32> {
+    let len = shape.iter().fold(1, |n, &d| n*d);
+    (0..len).map(|i| f(a[broadcast_index(a_shape, shape, i)], b[broadcast_index(b_shape, shape, i)])).collect()
+}
+
+fn broadcast_index(dims: &[usize], out_dims: &[usize], mut index: usize) -> usize {
+    let offset = out_dims.len() - dims.len();
+    let mut result = 0;
+    let mut stride = 1;
+    for i in (0..out_dims.len()).rev() {
+        let coord = index % out_dims[i];
+        index /= out_dims[i];
+        if i >= offset {
+            let d = dims[i - offset];
+            if d != 1 {
+                result += coord * stride;
+            }
+            stride *= d;
+        }
+    }
+    result
+}
+
+/// The `n` by `m` product of the `n` by `k` matrix `a` and the `k` by `m` matrix `b`.
+fn matmul(a: &[f32], b: &[f32], n: usize, k: usize, m: usize) -> Vec<f32> {
+    let mut out = vec![0.0; n*m];
+    for i in 0..n {
+        for j in 0..m {
+            for l in 0..k {
+                out[i*m + j] += a[i*k + l] * b[l*m + j];
+            }
+        }
+    }
+    out
+}
+
+fn sigmoid(x: f32) -> f32 {
+    1.0 / (1.0 + (-x).exp())
+}
+
+/// Softmax over each row of `x`.
+fn softmax(x: &[f32], columns: usize) -> Vec<f32> {
+    map(&log_softmax(x, columns), |x| x.exp())
+}
+
+fn log_softmax(x: &[f32], columns: usize) -> Vec<f32> {
+    let mut out = x.to_vec();
+    for row in out.chunks_mut(columns) {
+        let max = row.iter().fold(::std::f32::NEG_INFINITY, |m, &x| m.max(x));
+        let log_sum = row.iter().fold(0.0, |sum, &x| sum + (x - max).exp()).ln() + max;
+        for x in row.iter_mut() {
+            *x -= log_sum;
+        }
+    }
+    out
+}
+
+/// The mean of `f` of the elements of `a` and `b`, as a loss.
+fn mean<F: Fn(f32, f32) -> f32>(a: &[f32], b: &[f32], f: F) -> Vec<f32> {
+    vec![a.iter().zip(b).fold(0.0, |sum, (&a, &b)| sum + f(a, b)) / a.len() as f32]
+}
+
+/// Normalize each channel, dimension 1 of `shape`, of `x`.
+fn batch_norm(x: &[f32], shape: &[usize], gamma: &[f32], beta: &[f32], mean: &[f32], var: &[f32],
+              epsilon: f32) -> Vec<f32> {
+    let inner = shape[2..].iter().fold(1, |n, &d| n*d);
+    x.iter().enumerate().map(|(i, &v)| {
+        let c = (i / inner) % shape[1];
+        gamma[c] * (v - mean[c]) / (var[c] + epsilon).sqrt() + beta[c]
+    }).collect()
+}
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::{float_literal, generate_with_values, Target, Weights};
+    use super::super::catalog::OpCatalog;
+    use super::super::graph_builder::GraphBuilder;
+    use super::super::graph_text;
+    use super::super::tensor::Tensor;
+    use super::super::var_store::VarIndex;
+
+    fn graph(text: &str) -> GraphBuilder {
+        let mut graph = GraphBuilder::new();
+        graph_text::read(text, &mut graph, &OpCatalog::builtin()).unwrap();
+        graph.infer_dtypes().unwrap();
+        graph.infer_shapes().unwrap();
+        graph
+    }
+
+    fn generate(graph: &GraphBuilder, target: Target, weights: Weights) -> Result<String, String> {
+        generate_with_values(graph, &|_| None, target, weights)
+    }
+
+    const DENSE: &'static str = "x = Variable(shape=[2, 3])\n\
+                                 w = Variable(shape=[3, 4])\n\
+                                 b = Variable(shape=[4])\n\
+                                 h = Dense(x, w, b, activation=\"sigmoid\")\n\
+                                 y = Variable(shape=[2, 4])\n\
+                                 loss = SoftmaxCrossEntropy(h, y)\n";
+
+    #[test]
+    fn picks_the_gpu_only_when_dl_covers_every_node() {
+        assert_eq!(Target::for_graph(&graph("a = Variable(shape=[2, 2])\nr = ReLU(a)\n")), Target::Gpu);
+        assert_eq!(Target::for_graph(&graph(DENSE)), Target::Cpu);
+    }
+
+    #[test]
+    fn generates_dl_graphs_with_loss_gradients() {
+        let graph = graph("a = Variable(shape=[2, 3])\nw = Variable(shape=[3, 2])\nm = MatMul(a, w)\n\
+                           t = Variable(shape=[2, 2])\nloss = MSE(m, t)\n");
+        let src = generate(&graph, Target::Gpu, Weights::Inline).unwrap();
+        assert!(src.contains("pub graph: dl::Graph,"));
+        assert!(src.contains("pub loss_grad: dl::VarIndex,"));
+        assert!(src.contains("let loss_grad = graph.add_gradient(ctx, node, 0);"));
+        assert!(src.contains("pub fn build(ctx: &matrix::Context) -> Model {"));
+    }
+
+    #[test]
+    fn points_graphs_dl_cant_build_to_the_cpu() {
+        let e = generate(&graph(DENSE), Target::Gpu, Weights::Inline).err().unwrap();
+        assert!(e.contains("'Dense' of node 'h'"));
+        assert!(e.contains("generate for the CPU instead"));
+    }
+
+    #[test]
+    fn generates_plain_rust_for_the_cpu() {
+        let graph = graph(DENSE);
+        let w = graph.var_by_name("w").unwrap();
+        let values = |v: VarIndex| if v == w { Some(Tensor::new(vec![3, 4], vec![0.5; 12])) } else { None };
+        let src = generate_with_values(&graph, &values, Target::Cpu, Weights::Inline).unwrap();
+        assert!(src.contains("pub h: Vec<f32>,"));
+        assert!(!src.contains("dl::"));
+        assert!(src.contains("const W_VALUE: [f32; 12] = [0.5, "));
+        assert!(src.contains("        w: W_VALUE.to_vec(),\n"));
+        assert!(src.contains("        b: vec![0.0; 4],\n"));
+        assert!(src.contains("    pub fn run(&mut self) {"));
+        assert!(src.contains("        self.h = zip(&matmul(&self.x, &self.w, 2, 3, 4), &[2, 4], &self.b, &[4], &[2, 4], \
+                              |a, b| sigmoid(a + b));"));
+        assert!(src.contains("self.loss = vec![-log_softmax(&self.h, 4)"));
+        assert!(src.contains("fn log_softmax("));
+    }
+
+    #[test]
+    fn reads_cpu_weights_from_checkpoints() {
+        let src = generate(&graph(DENSE), Target::Cpu, Weights::Checkpoint).unwrap();
+        assert!(src.contains("pub fn build(checkpoint: &Path) -> io::Result<Model> {"));
+        assert!(src.contains("        w: try!(take_values(&mut weights, \"w\", 12)),\n"));
+        assert!(src.contains("fn take_values("));
+        assert!(!src.contains("fn take_weight("));
+    }
+
+    #[test]
+    fn rejects_what_the_cpu_cant_generate() {
+        let graph = graph("x = Variable(shape=[2, 1, 3])\nh0 = Variable(shape=[1, 4])\n\
+                           w = Variable(shape=[3, 4])\nu = Variable(shape=[4, 4])\nb = Variable(shape=[4])\n\
+                           h = RNNCell(x, h0, w, u, b)\n");
+        let e = generate(&graph, Target::Cpu, Weights::Inline).err().unwrap();
+        assert!(e.contains("'RNNCell' of node 'h' can't be generated for the CPU"));
+    }
+
+    #[test]
+    fn keeps_identifiers_distinct() {
+        let graph = graph("`a b` = Variable(shape=[2])\na_b = Variable(shape=[2])\nfn = Add(`a b`, a_b)\n");
+        let src = generate(&graph, Target::Cpu, Weights::Inline).unwrap();
+        assert!(src.contains("pub a_b: Vec<f32>,"));
+        assert!(src.contains("pub a_b_2: Vec<f32>,"));
+        assert!(src.contains("self.fn_2 = zip(&self.a_b, &[2], &self.a_b_2, &[2], &[2], |a, b| a + b);"));
+    }
+
+    #[test]
+    fn writes_float_literals() {
+        assert_eq!(float_literal(2.0), "2.0");
+        assert_eq!(float_literal(-0.25), "-0.25");
+        assert_eq!(float_literal(1e-7), "1e-7");
+    }
+}
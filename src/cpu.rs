@@ -0,0 +1,246 @@
+//! A backend running graphs on the CPU with the operations' `forward` and `backward` kernels.
+//! Slow, but it supports every built-in operation, unlike `dl`.
+
+use std::collections::HashMap;
+
+use rand;
+use rand::distributions::{IndependentSample, Normal};
+
+use super::graph_builder::{Mode, NodeId};
+use super::node::Node;
+use super::op::ForwardFn;
+use super::tensor::Tensor;
+use super::var_store::{VarIndex, VarStore};
+
+/// Penalties on the size of a variable's values, `l1*|w|` and `l2*w^2/2` summed over the values.
+/// Only their gradients are used, added to the variable's when applying gradients; the losses
+/// the graph computes don't include them.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct WeightDecay {
+    pub l1: f32,
+    pub l2: f32,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Pass {
+    Forward,
+    Backward,
+}
+
+/// A value, or gradient in the backward pass, that isn't finite, and the node that gave it.
+#[derive(Copy, Clone, PartialEq)]
+pub struct Anomaly {
+    pub node: NodeId,
+    pub var: VarIndex,
+    pub pass: Pass,
+}
+
+pub struct CpuGraph {
+    order: Vec<NodeId>,
+    values: HashMap<VarIndex, Tensor>,
+    grads: HashMap<VarIndex, Tensor>,
+    /// Whether passes check what each node gives, stopping at the first value that isn't finite.
+    pub check_finite: bool,
+}
+
+impl CpuGraph {
+    /// Set up a graph running `nodes` in `order`. Variables start with their initial value if they
+    /// have one, otherwise with random values.
+    pub fn new(order: Vec<NodeId>, nodes: &[Node], vars: &VarStore) -> Self {
+        let mut rng = rand::thread_rng();
+        let normal = Normal::new(0.5, 0.2);
+
+        let mut values = HashMap::new();
+        for node in nodes.iter().filter(|node| node.inputs.is_empty() && node.op.subgraph.is_none()) {
+            for &v in &node.outputs {
+                let value = match v.get(vars).value {
+                    Some(ref value) => value.clone(),
+                    None => {
+                        let mut value = zeros(vars, v);
+                        for x in &mut value.data {
+                            *x = normal.ind_sample(&mut rng) as f32;
+                        }
+                        value
+                    },
+                };
+                values.insert(v, value);
+            }
+        }
+
+        CpuGraph {
+            order: order,
+            values: values,
+            grads: HashMap::new(),
+            check_finite: false,
+        }
+    }
+
+    pub fn value(&self, v: VarIndex) -> Option<&Tensor> {
+        self.values.get(&v)
+    }
+
+    pub fn set_value(&mut self, v: VarIndex, value: Tensor) {
+        self.values.insert(v, value);
+    }
+
+    /// The gradient of `v` from the last backward pass.
+    pub fn grad(&self, v: VarIndex) -> Option<&Tensor> {
+        self.grads.get(&v)
+    }
+
+    /// The nodes in the order they run forward. They run backward in reverse.
+    pub fn order(&self) -> &[NodeId] {
+        &self.order
+    }
+
+    /// Run every node. Training also stores the outputs of operations with updates in the
+    /// variables they update, once all nodes have run.
+    pub fn forward(&mut self, nodes: &[Node], vars: &VarStore, mode: Mode) -> Result<(), Anomaly> {
+        for i in 0..self.order.len() {
+            let id = self.order[i];
+            let node = &nodes[id.index()];
+            self.forward_node(node, vars, mode);
+            if self.check_finite {
+                if let Some(v) = self.first_non_finite(&node.outputs, Pass::Forward) {
+                    return Err(Anomaly { node: id, var: v, pass: Pass::Forward });
+                }
+            }
+        }
+        if mode == Mode::Train {
+            self.apply_updates(nodes);
+        }
+        Ok(())
+    }
+
+    /// Whether the node has a kernel to run forward for `mode`.
+    pub fn runs_forward(node: &Node, mode: Mode) -> bool {
+        forward_kernel(node, mode).is_some()
+    }
+
+    /// Run one node forward, storing its outputs.
+    pub fn forward_node(&mut self, node: &Node, vars: &VarStore, mode: Mode) {
+        let forward = match forward_kernel(node, mode) {
+            Some(forward) => forward,
+            None => return,
+        };
+        let mut outputs: Vec<Tensor> = node.outputs.iter().map(|&v| zeros(vars, v)).collect();
+        {
+            let inputs: Vec<&Tensor> = node.inputs.iter().map(|v| &self.values[&v.unwrap()]).collect();
+            forward(&node.attrs, &inputs, &mut outputs);
+        }
+        for (&v, value) in node.outputs.iter().zip(outputs) {
+            self.values.insert(v, value);
+        }
+    }
+
+    /// Store the outputs of operations with updates in the variables they update.
+    pub fn apply_updates(&mut self, nodes: &[Node]) {
+        for &id in &self.order {
+            let node = &nodes[id.index()];
+            if node.op.forward.is_none() {
+                continue;
+            }
+            for &(output, input) in &node.op.updates {
+                let value = self.values[&node.outputs[output]].clone();
+                self.values.insert(node.inputs[input].unwrap(), value);
+            }
+        }
+    }
+
+    /// Backpropagate from `losses`, seeding their gradients with ones.
+    pub fn backward(&mut self, nodes: &[Node], vars: &VarStore, losses: &[VarIndex]) -> Result<(), Anomaly> {
+        self.seed_gradients(vars, losses);
+        for i in (0..self.order.len()).rev() {
+            let id = self.order[i];
+            let node = &nodes[id.index()];
+            self.backward_node(node, vars);
+            if self.check_finite {
+                let inputs: Vec<VarIndex> = node.inputs.iter().map(|v| v.unwrap()).collect();
+                if let Some(v) = self.first_non_finite(&inputs, Pass::Backward) {
+                    return Err(Anomaly { node: id, var: v, pass: Pass::Backward });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The first of `vars` whose value, or gradient in the backward pass, isn't finite.
+    pub fn first_non_finite(&self, vars: &[VarIndex], pass: Pass) -> Option<VarIndex> {
+        vars.iter().cloned().find(|v| {
+            let tensor = match pass {
+                Pass::Forward => self.values.get(v),
+                Pass::Backward => self.grads.get(v),
+            };
+            tensor.map_or(false, |tensor| !tensor.is_finite())
+        })
+    }
+
+    /// Forget the gradients from the last backward pass and start a new one from `losses`, with
+    /// their gradients ones.
+    pub fn seed_gradients(&mut self, vars: &VarStore, losses: &[VarIndex]) {
+        self.grads.clear();
+        for &v in losses {
+            let mut seed = zeros(vars, v);
+            for x in &mut seed.data {
+                *x = 1.0;
+            }
+            self.grads.insert(v, seed);
+        }
+    }
+
+    /// Whether the node has a kernel to run backward and a gradient reached it.
+    pub fn runs_backward(&self, node: &Node) -> bool {
+        node.op.backward.is_some() && node.outputs.iter().any(|v| self.grads.contains_key(v))
+    }
+
+    /// Run one node backward, adding the gradients of its inputs to theirs so far.
+    pub fn backward_node(&mut self, node: &Node, vars: &VarStore) {
+        if !self.runs_backward(node) {
+            return;
+        }
+        let backward = node.op.backward.as_ref().unwrap();
+
+        let mut in_grads: Vec<Tensor> = node.inputs.iter().map(|v| zeros(vars, v.unwrap())).collect();
+        {
+            let inputs: Vec<&Tensor> = node.inputs.iter().map(|v| &self.values[&v.unwrap()]).collect();
+            let outputs: Vec<&Tensor> = node.outputs.iter().map(|v| &self.values[v]).collect();
+            let out_zeros: Vec<Tensor> = node.outputs.iter().map(|&v| zeros(vars, v)).collect();
+            let out_grads: Vec<&Tensor> =
+                node.outputs.iter().zip(&out_zeros)
+                            .map(|(v, zero)| self.grads.get(v).unwrap_or(zero))
+                            .collect();
+            backward(&node.attrs, &inputs, &outputs, &out_grads, &mut in_grads);
+        }
+        for (v, grad) in node.inputs.iter().map(|v| v.unwrap()).zip(in_grads) {
+            let total = self.grads.entry(v).or_insert_with(|| zeros(vars, v));
+            for (t, g) in total.data.iter_mut().zip(grad.data) {
+                *t += g;
+            }
+        }
+    }
+
+    /// Take a gradient descent step on each of `variables`, including their weight decay.
+    pub fn apply_gradients(&mut self, variables: &[(VarIndex, WeightDecay)], learning_rate: f32) {
+        for &(v, decay) in variables {
+            if let (Some(value), Some(grad)) = (self.values.get_mut(&v), self.grads.get(&v)) {
+                for (x, g) in value.data.iter_mut().zip(&grad.data) {
+                    let sign = if *x == 0.0 { 0.0 } else { x.signum() };
+                    let penalty = decay.l1 * sign + decay.l2 * *x;
+                    *x -= learning_rate * (g + penalty);
+                }
+            }
+        }
+    }
+}
+
+fn forward_kernel(node: &Node, mode: Mode) -> Option<&ForwardFn> {
+    match (mode, &node.op.eval_forward, &node.op.forward) {
+        (Mode::Eval, &Some(ref forward), _) => Some(&**forward),
+        (_, _, &Some(ref forward)) => Some(&**forward),
+        _ => None,
+    }
+}
+
+fn zeros(vars: &VarStore, v: VarIndex) -> Tensor {
+    Tensor::zeros(v.get(vars).dims.clone())
+}
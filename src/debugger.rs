@@ -0,0 +1,268 @@
+//! Stepping through a graph built for the CPU one node at a time: forward in the build order, then
+//! backward from the losses in reverse. Execution can stop at breakpoints on nodes, and as soon as
+//! a node produces a value or gradient that isn't finite.
+
+use super::cpu::{CpuGraph, Pass};
+use super::graph_builder::{Mode, NodeId};
+use super::node::Node;
+use super::var_store::{VarIndex, VarStore};
+
+/// Why stepping stopped.
+#[derive(Copy, Clone, PartialEq)]
+pub enum Stop {
+    /// A single step was taken.
+    Stepped,
+    /// The next node to run has a breakpoint.
+    Breakpoint(NodeId),
+    /// The node that just ran gave the variable a value or gradient that isn't finite.
+    NonFinite(NodeId, VarIndex),
+    /// Every node has run.
+    Finished,
+}
+
+pub struct Debugger {
+    pass: Pass,
+    /// How many nodes of the build order the pass has been through.
+    done: usize,
+    mode: Mode,
+    losses: Vec<VarIndex>,
+    /// The node that ran last and the pass it ran in, whose inputs and outputs are shown.
+    pub last: Option<(NodeId, Pass)>,
+    pub break_on_non_finite: bool,
+    pub finished: bool,
+}
+
+impl Debugger {
+    /// Start before the first node that runs, for `mode`. The backward pass starts from `losses`,
+    /// and is left out if there are none.
+    pub fn new(cpu: &mut CpuGraph, nodes: &[Node], vars: &VarStore, mode: Mode, losses: Vec<VarIndex>) -> Self {
+        let mut debugger = Debugger {
+            pass: Pass::Forward,
+            done: 0,
+            mode: mode,
+            losses: losses,
+            last: None,
+            break_on_non_finite: true,
+            finished: false,
+        };
+        debugger.skip_idle(cpu, nodes, vars);
+        debugger
+    }
+
+    pub fn pass(&self) -> Pass {
+        self.pass
+    }
+
+    /// The node that runs next.
+    pub fn next(&self, cpu: &CpuGraph) -> Option<NodeId> {
+        let order = cpu.order();
+        if self.finished || self.done >= order.len() {
+            return None;
+        }
+        Some(match self.pass {
+            Pass::Forward => order[self.done],
+            Pass::Backward => order[order.len() - 1 - self.done],
+        })
+    }
+
+    /// Run the next node.
+    pub fn step(&mut self, cpu: &mut CpuGraph, nodes: &[Node], vars: &VarStore) -> Stop {
+        let id = match self.next(cpu) {
+            Some(id) => id,
+            None => return Stop::Finished,
+        };
+        let node = &nodes[id.index()];
+        let pass = self.pass;
+        let changed = match pass {
+            Pass::Forward => {
+                cpu.forward_node(node, vars, self.mode);
+                node.outputs.clone()
+            },
+            Pass::Backward => {
+                cpu.backward_node(node, vars);
+                node.inputs.iter().map(|v| v.unwrap()).collect()
+            },
+        };
+        self.last = Some((id, pass));
+        self.done += 1;
+        self.skip_idle(cpu, nodes, vars);
+
+        if self.break_on_non_finite {
+            if let Some(v) = cpu.first_non_finite(&changed, pass) {
+                return Stop::NonFinite(id, v);
+            }
+        }
+        if self.finished { Stop::Finished } else { Stop::Stepped }
+    }
+
+    /// Run nodes until the next one has a breakpoint, a value isn't finite or every node has run.
+    /// The first node runs even if it has a breakpoint, so execution can go on from one.
+    pub fn resume(&mut self, cpu: &mut CpuGraph, nodes: &[Node], vars: &VarStore, breakpoints: &[NodeId]) -> Stop {
+        loop {
+            let stop = self.step(cpu, nodes, vars);
+            if stop != Stop::Stepped {
+                return stop;
+            }
+            if let Some(next) = self.next(cpu) {
+                if breakpoints.contains(&next) {
+                    return Stop::Breakpoint(next);
+                }
+            }
+        }
+    }
+
+    /// Move past nodes that wouldn't do anything, going from the forward to the backward pass
+    /// when the forward pass is done.
+    fn skip_idle(&mut self, cpu: &mut CpuGraph, nodes: &[Node], vars: &VarStore) {
+        let len = cpu.order().len();
+        loop {
+            match self.next(cpu) {
+                Some(id) => {
+                    let node = &nodes[id.index()];
+                    let runs = match self.pass {
+                        Pass::Forward => CpuGraph::runs_forward(node, self.mode),
+                        Pass::Backward => cpu.runs_backward(node),
+                    };
+                    if runs {
+                        return;
+                    }
+                    self.done += 1;
+                },
+                None if self.finished => return,
+                None => {
+                    if self.pass == Pass::Forward && self.done >= len {
+                        if self.mode == Mode::Train {
+                            cpu.apply_updates(nodes);
+                        }
+                        if !self.losses.is_empty() {
+                            cpu.seed_gradients(vars, &self.losses);
+                            self.pass = Pass::Backward;
+                            self.done = 0;
+                            continue;
+                        }
+                    }
+                    self.finished = true;
+                    return;
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32;
+
+    use super::{Debugger, Stop};
+    use super::super::catalog::OpCatalog;
+    use super::super::cpu::{CpuGraph, Pass};
+    use super::super::graph_builder::{GraphBuilder, Mode, NodeId};
+    use super::super::graph_text;
+    use super::super::tensor::Tensor;
+
+    /// `u` is left out of the loss, so it doesn't run backward.
+    const GRAPH: &'static str = "a = Variable(shape=[2])\n\
+                                 t = Variable(shape=[2])\n\
+                                 r = ReLU(a)\n\
+                                 s = Sigmoid(r)\n\
+                                 u = Tanh(a)\n\
+                                 loss = MSE(s, t)\n";
+
+    fn build(text: &str, a: Vec<f32>) -> (GraphBuilder, CpuGraph) {
+        let mut graph = GraphBuilder::new();
+        graph_text::read(text, &mut graph, &OpCatalog::builtin()).unwrap();
+        graph.infer_dtypes().unwrap();
+        graph.infer_shapes().unwrap();
+        let mut cpu = CpuGraph::new(graph.build_order().unwrap(), graph.nodes(), &graph.vars);
+        cpu.set_value(graph.var_by_name("a").unwrap(), Tensor::new(vec![2], a));
+        if let Some(t) = graph.var_by_name("t") {
+            cpu.set_value(t, Tensor::new(vec![2], vec![0.0, 1.0]));
+        }
+        (graph, cpu)
+    }
+
+    fn node(graph: &GraphBuilder, name: &str) -> NodeId {
+        graph.node_by_name(name).unwrap()
+    }
+
+    /// The nodes that ran in each pass, stepping until every node has run.
+    fn run_all(graph: &GraphBuilder, cpu: &mut CpuGraph, debugger: &mut Debugger) -> (Vec<NodeId>, Vec<NodeId>) {
+        let (mut forward, mut backward) = (vec![], vec![]);
+        loop {
+            let stop = debugger.step(cpu, graph.nodes(), &graph.vars);
+            match debugger.last {
+                Some((id, Pass::Forward)) => forward.push(id),
+                Some((id, Pass::Backward)) => backward.push(id),
+                None => panic!("nothing ran"),
+            }
+            if stop == Stop::Finished {
+                return (forward, backward);
+            }
+            assert!(stop == Stop::Stepped);
+        }
+    }
+
+    #[test]
+    fn steps_forward_then_back_from_the_losses() {
+        let (graph, mut cpu) = build(GRAPH, vec![1.0, -1.0]);
+        let losses = graph.losses();
+        let mut debugger = Debugger::new(&mut cpu, graph.nodes(), &graph.vars, Mode::Train, losses);
+        assert!(debugger.next(&cpu) == Some(node(&graph, "r")) || debugger.next(&cpu) == Some(node(&graph, "u")));
+
+        let (forward, backward) = run_all(&graph, &mut cpu, &mut debugger);
+        assert_eq!(forward.len(), 4);
+        assert!(forward.iter().position(|&id| id == node(&graph, "r")) < forward.iter().position(|&id| id == node(&graph, "s")));
+        assert!(backward == vec![node(&graph, "loss"), node(&graph, "s"), node(&graph, "r")]);
+        assert!(debugger.finished);
+        assert!(debugger.next(&cpu).is_none());
+        assert!(debugger.step(&mut cpu, graph.nodes(), &graph.vars) == Stop::Finished);
+        assert!(cpu.grad(graph.var_by_name("a").unwrap()).is_some());
+    }
+
+    #[test]
+    fn stops_after_the_forward_pass_without_losses() {
+        let (graph, mut cpu) = build("a = Variable(shape=[2])\nr = ReLU(a)\n", vec![1.0, -1.0]);
+        let mut debugger = Debugger::new(&mut cpu, graph.nodes(), &graph.vars, Mode::Eval, vec![]);
+        assert!(debugger.step(&mut cpu, graph.nodes(), &graph.vars) == Stop::Finished);
+        assert!(debugger.pass() == Pass::Forward);
+        assert_eq!(cpu.value(graph.var_by_name("r").unwrap()), Some(&Tensor::new(vec![2], vec![1.0, 0.0])));
+    }
+
+    #[test]
+    fn resumes_to_breakpoints() {
+        let (graph, mut cpu) = build(GRAPH, vec![1.0, -1.0]);
+        let losses = graph.losses();
+        let mut debugger = Debugger::new(&mut cpu, graph.nodes(), &graph.vars, Mode::Train, losses);
+        let s = node(&graph, "s");
+
+        let stop = debugger.resume(&mut cpu, graph.nodes(), &graph.vars, &[s]);
+        assert!(stop == Stop::Breakpoint(s));
+        assert!(debugger.pass() == Pass::Forward);
+        assert!(debugger.next(&cpu) == Some(s));
+
+        // The node stopped at runs, and the breakpoint stops the backward pass too
+        let stop = debugger.resume(&mut cpu, graph.nodes(), &graph.vars, &[s]);
+        assert!(stop == Stop::Breakpoint(s));
+        assert!(debugger.pass() == Pass::Backward);
+        assert!(debugger.last == Some((node(&graph, "loss"), Pass::Backward)));
+
+        let stop = debugger.resume(&mut cpu, graph.nodes(), &graph.vars, &[s]);
+        assert!(stop == Stop::Finished);
+    }
+
+    #[test]
+    fn stops_at_values_that_arent_finite() {
+        let (graph, mut cpu) = build(GRAPH, vec![f32::INFINITY, 1.0]);
+        let losses = graph.losses();
+        let mut debugger = Debugger::new(&mut cpu, graph.nodes(), &graph.vars, Mode::Train, losses);
+        // The tanh of infinity is finite, so only the ReLU stops execution
+        let r = node(&graph, "r");
+        let stop = debugger.resume(&mut cpu, graph.nodes(), &graph.vars, &[]);
+        assert!(stop == Stop::NonFinite(r, graph.var_by_name("r").unwrap()));
+        assert!(debugger.last == Some((r, Pass::Forward)));
+
+        debugger.break_on_non_finite = false;
+        let stop = debugger.resume(&mut cpu, graph.nodes(), &graph.vars, &[]);
+        assert!(stop == Stop::Finished);
+    }
+}
@@ -1,3 +1,7 @@
+use std::fs::{self, File};
+use std::io::Write;
+use std::mem;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 
 use conrod::Ui;
@@ -6,16 +10,51 @@ use matrix;
 use opengl_graphics::GlGraphics;
 use opengl_graphics::glyph_cache::GlyphCache;
 use piston::input;
-use dl;
+use time;
 
-use super::graph_builder::{GraphAction, GraphBuilder};
+use super::catalog::OpCatalog;
+use super::checkpoint::Checkpoint;
+use super::clipboard;
+use super::codegen::{self, Target, Weights};
+use super::cpu::Pass;
+use super::debugger::Stop;
+use super::graph_builder::{Backend, EdgeColors, GraphAction, GraphBuilder, Mode, NodeId, Port};
+use super::graph_text;
+use super::history::History;
+use super::keymap::{self, Action, Keymap, KEYMAP_PATH};
+use super::onnx;
 use super::op::Operation;
-use super::var_store::{VarStore, VarIndex};
+use super::plugin::{self, PLUGIN_DIR};
+use super::quick_add::{QuickAdd, QuickAddAction};
+use super::script::{self, Script, Value};
+use super::subgraph;
+use super::trainer::Trainer;
+use super::var_store::VarIndex;
+
+const TRAIN_ITERATIONS: u64 = 1000;
+const STEPS_PER_UPDATE: usize = 10;
+const CHECKPOINT_PATH: &'static str = "deeplab.ckpt";
+const CHECKPOINT_EVERY: u64 = 100;
+const GRAPH_PATH: &'static str = "model.graph";
+const DOUBLE_CLICK_TIME: f64 = 0.4; // Seconds
+const LIBRARY_DIR: &'static str = "library"; // Saved subgraphs
+const CONSOLE_LINES: usize = 8; // Lines of script output shown in the console
+const PALETTE_ROW_HEIGHT: f64 = 32.0;
+const ZOOM_STEP: f64 = 1.25;
+const HELP_TEXT_SIZE: u32 = 12;
+const DEBUG_VALUES: usize = 6; // Values of each tensor the debugger lists
 
 pub struct Mouse {
     pub pos: [f64; 2],
     pub lmb: bool,
     pub rmb: bool,
+    /// Whether the last left click came soon enough after the one before to be a double-click.
+    pub double_click: bool,
+    /// Whether shift is held, making clicks select.
+    pub shift: bool,
+    /// Whether ctrl is held, for keyboard shortcuts.
+    pub ctrl: bool,
+    last_click: f64,
 }
 
 impl Mouse {
@@ -24,100 +63,319 @@ impl Mouse {
             pos: [0.0; 2],
             lmb: false,
             rmb: false,
+            double_click: false,
+            shift: false,
+            ctrl: false,
+            last_click: 0.0,
         }
     }
+
+    fn click(&mut self) {
+        let now = time::precise_time_s();
+        self.double_click = now - self.last_click < DOUBLE_CLICK_TIME;
+        self.last_click = now;
+    }
 }
 
 pub struct DeepLabUi {
-    activation_blocks: [[Rc<Operation>; 2]; 2],
+    palette: Vec<(String, Vec<Rc<Operation>>)>, // Operations to place, by category
+    palette_filter: String, // Only operations matching it are in the palette
+    searching_palette: bool, // Whether typing goes to the palette filter
+    catalog: OpCatalog,
     graph: GraphBuilder,
     ctx: matrix::Context,
 
+    trainer: Option<Trainer>,
+    iteration: u64, // Training iterations run so far
+
     place_op: Option<Rc<Operation>>,
     sel_var: Option<(VarIndex, Vec<usize>)>, // Selected variable
     mouse: Mouse,
+
+    script: Script,
+    console: Option<String>, // The line being typed into the console, if it's open
+    quick_add: Option<QuickAdd>,
+
+    keymap: Keymap,
+    history: History,
+    zoom: f64, // Scale the graph is drawn at
+    show_help: bool, // Whether the shortcuts are listed over the graph
+    debug_status: String, // Why the debugger last stopped
+    anomaly_report: String, // Where the last value that wasn't finite came from
+    gpu_before_gradients: bool, // Whether taking gradients built the graph for the CPU instead of the GPU
 }
 
 impl DeepLabUi {
     pub fn new() -> DeepLabUi {
-        let mat_mul = Rc::new(Operation::new("MatMul".to_string(), 2, 1,
-            |ctx: &matrix::Context,
-             graph: &mut dl::Graph,
-             vars: &mut VarStore,
-             _in: &[Option<VarIndex>],
-             _out: &[VarIndex]| {
-                let a = *_in[0].unwrap().get(&vars);
-                let b = *_in[1].unwrap().get(&vars);
-                let op = dl::op::MatMul::new(&ctx, a.shape, b.shape);
-                let node = graph.add_node(ctx, op,
-                                          vec![a.gpu.unwrap(), b.gpu.unwrap()],
-                                          &[_out[0].get(vars).shape]);
-                _out[0].get_mut(vars).gpu = Some(node.get(&graph).outputs[0]);
-            }));
-        let relu = Rc::new(Operation::new("ReLU".to_string(), 1, 1,
-            |ctx: &matrix::Context,
-             graph: &mut dl::Graph,
-             vars: &mut VarStore,
-             _in: &[Option<VarIndex>],
-             _out: &[VarIndex]| {
-                let a = *_in[0].unwrap().get(&vars);
-                let op = dl::op::Relu::new();
-                let node = graph.add_node(ctx, op,
-                                          vec![a.gpu.unwrap()],
-                                          &[_out[0].get(vars).shape]);
-                _out[0].get_mut(vars).gpu = Some(node.get(&graph).outputs[0]);
-            }));
-        let mse = Rc::new(Operation::new("MSE".to_string(), 2, 1,
-            |ctx: &matrix::Context,
-             graph: &mut dl::Graph,
-             vars: &mut VarStore,
-             _in: &[Option<VarIndex>],
-             _out: &[VarIndex]| {
-                let a = *_in[0].unwrap().get(&vars);
-                let b = *_in[1].unwrap().get(&vars);
-                let out_shape = _out[0].get(vars).shape;
-                let op = dl::op::Mse::new();
-                let node = graph.add_node(ctx, op,
-                                          vec![a.gpu.unwrap(), b.gpu.unwrap()],
-                                          &[out_shape]);
-                _out[0].get_mut(vars).gpu = Some(node.get(&graph).outputs[0]);
-                let gradient = graph.add_gradient(ctx, node, 0);
-                gradient.get(graph)
-                        .set(ctx, &matrix::Matrix::from_vec(out_shape.0, out_shape.1, vec![-0.1]));
-            }));
-        let variable = Rc::new(Operation::new("Variable".to_string(), 0, 1,
-            |ctx: &matrix::Context,
-             graph: &mut dl::Graph,
-             vars: &mut VarStore,
-             _in: &[Option<VarIndex>],
-             _out: &[VarIndex]| {
-                 _out[0].get_mut(vars).gpu = Some(graph.add_variable(ctx, (1, 1), dl::init::Normal(0.5, 0.2)));
-            }));
+        let mut catalog = OpCatalog::builtin();
+        plugin::add_plugins(&mut catalog, Path::new(PLUGIN_DIR));
+        for (path, op) in subgraph::load_library(Path::new(LIBRARY_DIR), &mut catalog) {
+            if let Err(e) = op {
+                println!("Failed to load '{}' from the library: {}", path.display(), e);
+            }
+        }
+        let keymap = Keymap::load(Path::new(KEYMAP_PATH)).unwrap_or_else(|e| {
+            println!("Failed to load the keymap, using the default one: {}", e);
+            Keymap::default()
+        });
         DeepLabUi {
-            activation_blocks: [[mat_mul.clone(), relu.clone()],
-                                [variable.clone(), mse.clone()]],
+            palette: catalog.categories(),
+            palette_filter: String::new(),
+            searching_palette: false,
+            catalog: catalog,
             graph: GraphBuilder::new(),
             ctx: matrix::Context::new(),
 
+            trainer: None,
+            iteration: 0,
+
             place_op: None,
             sel_var: None,
             mouse: Mouse::new(),
+
+            script: Script::new(),
+            console: None,
+            quick_add: None,
+
+            keymap: keymap,
+            history: History::new(),
+            zoom: 1.0,
+            show_help: false,
+            debug_status: String::new(),
+            anomaly_report: String::new(),
+            gpu_before_gradients: false,
+        }
+    }
+
+    /// Add the contents of an ONNX model to the graph.
+    pub fn import_onnx(&mut self, path: &Path) -> Result<onnx::ImportReport, String> {
+        let model = try!(onnx::read_model(path));
+        onnx::import(&model, &mut self.graph, &self.catalog)
+    }
+
+    /// Replace the graph with the one written as text in `path`.
+    pub fn open_graph(&mut self, path: &Path) -> Result<(), String> {
+        let mut graph = GraphBuilder::new();
+        try!(graph_text::load(path, &mut graph, &self.catalog));
+        self.graph = graph;
+        self.sel_var = None;
+        Ok(())
+    }
+
+    pub fn build(&mut self) {
+        if let Err(e) = self.graph.build(&self.ctx) {
+            println!("Build failed: {}", e);
+        }
+    }
+
+    pub fn run(&mut self) {
+        if let Err(e) = self.graph.run(&self.ctx) {
+            println!("Run failed: {}", e);
+            self.keep_anomaly_report(&e);
+        }
+    }
+
+    /// Keep the error as the report shown over the graph, if it's about a value that isn't
+    /// finite.
+    fn keep_anomaly_report(&mut self, e: &str) {
+        if self.graph.anomaly().is_some() {
+            self.anomaly_report = e.to_string();
+        }
+    }
+
+    /// Take the gradients of the losses, to look at in the gradient view. Only the CPU keeps
+    /// gradients, so a graph built for the GPU is built for the CPU until the gradient view is
+    /// left.
+    pub fn backward(&mut self) -> Result<(), String> {
+        if let Some(&Backend::Gpu) = self.graph.backend() {
+            self.gpu_before_gradients = true;
+        }
+        try!(self.build_for_cpu("to keep the gradients until leaving the gradient view"));
+        self.graph.backward()
+    }
+
+    /// Build the graph for the GPU again if taking gradients built it for the CPU, keeping what
+    /// the variables were trained to.
+    fn restore_gpu_build(&mut self) -> Result<(), String> {
+        if !self.gpu_before_gradients {
+            return Ok(());
+        }
+        self.gpu_before_gradients = false;
+        let on_cpu = match self.graph.backend() {
+            Some(&Backend::Cpu(_)) => true,
+            _ => false,
+        };
+        // Stepping through the graph and checking its values keep it on the CPU
+        if on_cpu && self.graph.debugger().is_none() && !self.graph.checks_finite() {
+            println!("Building for the GPU again");
+            try!(self.graph.build(&self.ctx));
+        }
+        Ok(())
+    }
+
+    /// Build the graph again for the CPU if it's built for the GPU, saying `why`.
+    fn build_for_cpu(&mut self, why: &str) -> Result<(), String> {
+        if let Some(&Backend::Gpu) = self.graph.backend() {
+            println!("Building for the CPU {}", why);
+            try!(self.graph.build_for_cpu(&self.ctx));
+        }
+        Ok(())
+    }
+
+    /// Start stepping through the graph, or stop if already stepping through it.
+    pub fn toggle_debugging(&mut self) -> Result<(), String> {
+        if self.graph.debugger().is_some() {
+            self.graph.stop_debugging();
+            return Ok(());
+        }
+        try!(self.build_for_cpu("to step through it"));
+        try!(self.graph.start_debugging());
+        self.debug_status = "Started".to_string();
+        Ok(())
+    }
+
+    /// Run the next node, or with `resume` every node up to the next breakpoint, and say why
+    /// execution stopped.
+    pub fn debug_step(&mut self, resume: bool) -> Result<(), String> {
+        let stop = try!(self.graph.debug_step(&self.ctx, resume));
+        self.debug_status = match stop {
+            Stop::Stepped => "Stepped".to_string(),
+            Stop::Breakpoint(node) => format!("Breakpoint at '{}'", node.get(&self.graph).name()),
+            Stop::NonFinite(node, v) => {
+                let what = match self.graph.debugger().and_then(|debugger| debugger.last) {
+                    Some((_, Pass::Backward)) => "The gradient of",
+                    _ => "The value of",
+                };
+                format!("{} '{}' isn't finite after '{}'", what, self.graph.var_name(v), node.get(&self.graph).name())
+            },
+            Stop::Finished => "Finished".to_string(),
+        };
+        Ok(())
+    }
+
+    /// Start training for another `TRAIN_ITERATIONS` iterations, or stop training.
+    pub fn toggle_training(&mut self) {
+        if self.trainer.is_some() {
+            self.trainer = None;
+        } else {
+            let mut trainer = Trainer::new(self.iteration + TRAIN_ITERATIONS);
+            trainer.iteration = self.iteration;
+            trainer.checkpoint_path = Some(PathBuf::from(CHECKPOINT_PATH));
+            trainer.checkpoint_every = CHECKPOINT_EVERY;
+            self.trainer = Some(trainer);
+        }
+    }
+
+    pub fn save_graph(&self) {
+        if let Err(e) = graph_text::save(&self.graph, Path::new(GRAPH_PATH)) {
+            println!("Failed to save the graph: {}", e);
+        }
+    }
+
+    /// Put the selected nodes on the clipboard, with the values of their variables if
+    /// `with_values`.
+    pub fn copy_selection(&self, with_values: bool) -> Result<(), String> {
+        if self.graph.selection().is_empty() {
+            return Err("Nothing is selected".to_string());
+        }
+        let values = if with_values { Some(&self.ctx) } else { None };
+        clipboard::store(&try!(clipboard::copy(&self.graph, self.graph.selection(), values)))
+    }
+
+    /// Put the selected nodes on the clipboard and remove them from the graph.
+    pub fn cut_selection(&mut self) -> Result<(), String> {
+        try!(self.copy_selection(false));
+        self.delete_selection();
+        Ok(())
+    }
+
+    pub fn delete_selection(&mut self) {
+        let selection = self.graph.selection().to_vec();
+        self.graph.remove_nodes(&selection);
+        self.sel_var = None;
+    }
+
+    /// Add the nodes on the clipboard to the graph at the mouse, selecting them.
+    pub fn paste(&mut self) -> Result<(), String> {
+        let text = try!(clipboard::retrieve());
+        let nodes = try!(clipboard::paste(&text, &mut self.graph, &self.catalog, self.mouse.pos));
+        self.graph.set_selection(nodes);
+        self.graph.infer_dtypes().and_then(|_| self.graph.infer_shapes())
+    }
+
+    /// Copy the selected nodes and their values next to them, selecting the copies.
+    pub fn duplicate_selection(&mut self) -> Result<(), String> {
+        let selection = self.graph.selection().to_vec();
+        let nodes = try!(clipboard::duplicate(&mut self.graph, &selection, &self.catalog, &self.ctx));
+        self.graph.set_selection(nodes);
+        self.graph.infer_dtypes().and_then(|_| self.graph.infer_shapes())
+    }
+
+    /// Write a Rust module building the current graph, with the variables' current values inlined.
+    /// Graphs `dl` can't build are written as plain Rust computing them on the CPU; see `codegen`.
+    pub fn export_rust(&self, path: &Path) -> Result<(), String> {
+        let src = try!(codegen::generate(&self.graph, &self.ctx, Target::for_graph(&self.graph), Weights::Inline));
+        File::create(path).and_then(|mut f| f.write_all(src.as_bytes())).map_err(|e| e.to_string())
+    }
+
+    /// Save the subgraph of a composite node to the library and add it to the palette.
+    pub fn save_to_library(&mut self, node: NodeId) -> Result<(), String> {
+        let subgraph = try!(self.graph.subgraph(node).ok_or("Only subgraphs can be saved to the library".to_string()));
+        // Saving replaces a subgraph already in the library, but not other operations
+        if let Some(op) = self.catalog.get(&subgraph.name) {
+            if op.category != subgraph::CATEGORY {
+                return Err(format!("There's already an operation called '{}'; rename the subgraph to save it",
+                                   subgraph.name));
+            }
         }
+        try!(fs::create_dir_all(LIBRARY_DIR).map_err(|e| e.to_string()));
+        let path = Path::new(LIBRARY_DIR).join(subgraph.name.replace(' ', "_")).with_extension(subgraph::EXTENSION);
+        try!(subgraph.save(&path).map_err(|e| e.to_string()));
+        self.catalog.replace(Operation::composite(Rc::new(subgraph), subgraph::CATEGORY));
+        self.palette = self.catalog.search_categories(&self.palette_filter);
+        Ok(())
+    }
+
+    /// Restore the variables from a checkpoint, continuing training from where it was saved.
+    pub fn resume(&mut self, path: &Path) -> Result<(), String> {
+        let checkpoint = try!(Checkpoint::load(path).map_err(|e| e.to_string()));
+        try!(self.graph.restore_checkpoint(&self.ctx, &checkpoint));
+        self.iteration = checkpoint.iteration;
+        self.trainer = None;
+        Ok(())
     }
 
     pub fn event(&mut self, event: &input::Event) {
         use piston::input::*;
+        event.update(|_| {
+            self.train_step();
+        });
         event.mouse_cursor(|x, y| {
-            self.mouse.pos = [x, y];
+            self.mouse.pos = [x / self.zoom, y / self.zoom];
         });
         event.press(|button| {
             //use piston::input::Button;
             match button {
-                Button::Keyboard(key) => println!("Pressed keyboard key '{:?}'", key),
+                Button::Keyboard(key) => {
+                    if key == Key::LShift || key == Key::RShift {
+                        self.mouse.shift = true;
+                    }
+                    if key == Key::LCtrl || key == Key::RCtrl {
+                        self.mouse.ctrl = true;
+                    }
+                    if key == Key::Backquote {
+                        self.console = match self.console { Some(_) => None, None => Some(String::new()) };
+                    } else if self.searching_palette {
+                        self.palette_search_key(key);
+                    } else if !self.graph.is_renaming() && self.console.is_none() && self.quick_add.is_none() {
+                        self.on_key_pressed(key);
+                    }
+                },
                 Button::Mouse(button) => {
                     match button {
                         mouse::MouseButton::Left => {
                             self.mouse.lmb = true;
+                            self.mouse.click();
                         },
                         mouse::MouseButton::Right => {
                             self.mouse.rmb = true;
@@ -129,16 +387,30 @@ impl DeepLabUi {
                 _ => { },
             }
         });
+        event.text(|text| {
+            // The slash starting the search is typed too
+            if self.searching_palette && !(self.palette_filter.is_empty() && text == "/") {
+                self.palette_filter.push_str(text);
+                self.palette = self.catalog.search_categories(&self.palette_filter);
+            }
+        });
         event.release(|button| {
             //use piston::input::Button;
             match button {
-                Button::Keyboard(key) => println!("Released keyboard key '{:?}'", key),
+                Button::Keyboard(key) => {
+                    if key == Key::LShift || key == Key::RShift {
+                        self.mouse.shift = false;
+                    }
+                    if key == Key::LCtrl || key == Key::RCtrl {
+                        self.mouse.ctrl = false;
+                    }
+                },
                 Button::Mouse(button) => {
                     match button {
                         mouse::MouseButton::Left => {
                             self.mouse.lmb = false;
                             if let Some(ref place_op) = self.place_op {
-                                self.graph.add_node("asdf".to_string(), self.mouse.pos, place_op.clone());
+                                self.graph.add_op_node(self.mouse.pos, place_op.clone());
                             }
                         },
                         mouse::MouseButton::Right => {
@@ -150,20 +422,228 @@ impl DeepLabUi {
                 _ => { },
             }
         });
-        if let Some(graph_action) = self.graph.event(event, &self.mouse) {
+        if self.quick_add.is_some() {
+            self.quick_add_event(event);
+        } else if let Some(graph_action) = self.graph.event(event, &self.mouse) {
             match graph_action {
                 GraphAction::SelectNode(n) => { },
                 GraphAction::SelectVariable(v) => { self.sel_var = Some((v, vec![0, 0])); },
+                GraphAction::DropConnection(port) => { self.quick_add = Some(QuickAdd::new(self.mouse.pos, Some(port))); },
             }
         }
+
+        // Changes are made by pressing and releasing keys and buttons. Only graphs that changed
+        // are noted, as noting one writes all of it out.
+        let mut changing = false;
+        event.press(|_| changing = true);
+        event.release(|_| changing = true);
+        if changing && self.graph.take_changed() {
+            self.history.record(&self.graph);
+        }
+    }
+
+    /// Typing into the palette filter: Backspace deletes, Enter keeps the filter and Escape
+    /// clears it.
+    fn palette_search_key(&mut self, key: input::Key) {
+        match key {
+            input::Key::Backspace => { self.palette_filter.pop(); },
+            input::Key::Return => { self.searching_palette = false; },
+            input::Key::Escape => {
+                self.palette_filter.clear();
+                self.searching_palette = false;
+            },
+            _ => { },
+        }
+        self.palette = self.catalog.search_categories(&self.palette_filter);
     }
 
-    pub fn draw(&self, c: Context, gl: &mut GlGraphics) {
-        self.graph.draw(&c, gl);
+    /// While the quick-add popup is open it gets the events instead of the graph.
+    fn quick_add_event(&mut self, event: &input::Event) {
+        let action = match self.quick_add {
+            Some(ref mut quick_add) => quick_add.event(event, &self.mouse, &self.catalog),
+            None => None,
+        };
+        match action {
+            Some(QuickAddAction::Place(op)) => {
+                if let Some(quick_add) = self.quick_add.take() {
+                    self.add_connected_node(op, quick_add.pos, quick_add.from);
+                }
+            },
+            Some(QuickAddAction::Close) => { self.quick_add = None; },
+            None => { },
+        }
+    }
+
+    /// Add a node for `op` at `pos`. If a connection was dragged out of a port to place it, its
+    /// first input or output is connected to that port, and it's put to the left of `pos` when
+    /// it feeds the port.
+    fn add_connected_node(&mut self, op: Rc<Operation>, pos: [f64; 2], from: Option<Port>) {
+        let node = self.graph.add_op_node(pos, op);
+        let (num_inputs, num_outputs) = (node.get(&self.graph).inputs.len(), node.get(&self.graph).outputs.len());
+        match from {
+            Some(Port::Output(send_node, send_index)) if num_inputs > 0 => {
+                self.graph.connect(send_node, send_index, node, 0);
+            },
+            Some(Port::Input(recv_node, recv_index)) if num_outputs > 0 => {
+                let width = node.get(&self.graph).size()[0];
+                node.get_mut(&mut self.graph).set_pos([pos[0] - width, pos[1]]);
+                self.graph.connect(node, 0, recv_node, recv_index);
+            },
+            _ => { },
+        }
+        if let Err(e) = self.graph.infer_dtypes().and_then(|_| self.graph.infer_shapes()) {
+            println!("{}", e);
+        }
+    }
+
+    /// Run a line typed into the console, showing it along with its result.
+    fn run_script(&mut self, src: &str) {
+        self.script.output.push(format!("> {}", src));
+        match self.script.run(src, &mut self.graph, &self.catalog, &self.ctx) {
+            Ok(Value::Nil) => { },
+            Ok(value) => {
+                let shown = script::show(&value, &self.graph);
+                self.script.output.push(shown);
+            },
+            Err(e) => { self.script.output.push(e); },
+        }
+        // Scripts can set attributes on nodes directly
+        self.graph.mark_changed();
+    }
+
+    /// Run a few iterations of training if we're training, without holding up the UI for long.
+    fn train_step(&mut self) {
+        let mut finished = false;
+        let mut failure = None;
+        if let Some(ref mut trainer) = self.trainer {
+            for _ in 0..STEPS_PER_UPDATE {
+                if let Err(e) = trainer.step(&self.ctx, &mut self.graph) {
+                    println!("Training stopped: {}", e);
+                    failure = Some(e);
+                    finished = true;
+                    break;
+                }
+                self.iteration = trainer.iteration;
+                if trainer.is_done() {
+                    finished = true;
+                    break;
+                }
+            }
+        }
+        if let Some(e) = failure {
+            self.keep_anomaly_report(&e);
+        }
+        if finished {
+            self.trainer = None;
+        }
+    }
+
+    pub fn draw(&self, c: Context, gl: &mut GlGraphics, glyphs: &mut GlyphCache) {
+        use graphics::Transformed;
+
+        let zoomed = c.zoom(self.zoom);
+        self.graph.draw(&zoomed, gl, glyphs);
+        if let Some(ref quick_add) = self.quick_add {
+            quick_add.draw(&zoomed, gl, glyphs, &self.catalog);
+        }
+        self.draw_debugger(&c, gl, glyphs);
+        if self.graph.anomaly().is_some() {
+            self.draw_anomaly(&c, gl, glyphs);
+        }
+        if self.show_help {
+            self.draw_help(&c, gl, glyphs);
+        }
+    }
+
+    /// Where the debugger is and why it stopped, and the tensors of the node it ran last.
+    fn draw_debugger(&self, c: &Context, gl: &mut GlGraphics, glyphs: &mut GlyphCache) {
+        use graphics::{Rectangle, Text, Transformed};
+
+        let debugger = match self.graph.debugger() {
+            Some(debugger) => debugger,
+            None => return,
+        };
+        let mut lines = vec![];
+        lines.push(match (debugger.finished, debugger.pass()) {
+            (true, _) => "Debugging: finished".to_string(),
+            (false, Pass::Forward) => "Debugging: forward pass".to_string(),
+            (false, Pass::Backward) => "Debugging: backward pass".to_string(),
+        });
+        if let Some(next) = self.graph.debug_next() {
+            lines.push(format!("Next: {}", next.get(&self.graph).name()));
+        }
+        lines.push(self.debug_status.clone());
+        lines.push(format!("Break on values that aren't finite: {}", if debugger.break_on_non_finite { "on" } else { "off" }));
+
+        if let Some((last, pass)) = debugger.last {
+            let node = last.get(&self.graph);
+            let what = match pass {
+                Pass::Forward => "value",
+                Pass::Backward => "gradient",
+            };
+            lines.push(format!("Ran '{}', {}s:", node.name(), what));
+            let vars = node.inputs.iter().filter_map(|&v| v).map(|v| ("in", v))
+                                  .chain(node.outputs.iter().map(|&v| ("out", v)));
+            for (direction, v) in vars {
+                let tensor = match pass {
+                    Pass::Forward => self.graph.var_value(&self.ctx, v),
+                    Pass::Backward => self.graph.grad(v).cloned(),
+                };
+                lines.push(match tensor {
+                    Some(tensor) => format!("  {} {} {:?}: {}", direction, self.graph.var_name(v), tensor.shape,
+                                            summarize(&tensor.data)),
+                    None => format!("  {} {}: none", direction, self.graph.var_name(v)),
+                });
+            }
+        }
+
+        let line_height = (HELP_TEXT_SIZE + 6) as f64;
+        let origin = [8.0, 56.0];
+        Rectangle::new([1.0, 1.0, 1.0, 0.9]).draw([origin[0], origin[1], 420.0, (lines.len() as f64 + 0.5)*line_height],
+                                                  &c.draw_state, c.transform, gl);
+        for (i, line) in lines.iter().enumerate() {
+            Text::new_color([0.0, 0.0, 0.0, 1.0], HELP_TEXT_SIZE)
+                .draw(line, glyphs, &c.draw_state,
+                      c.transform.trans(origin[0] + 8.0, origin[1] + (i + 1) as f64*line_height), gl);
+        }
+    }
+
+    /// The report of the last value that wasn't finite, in red.
+    fn draw_anomaly(&self, c: &Context, gl: &mut GlGraphics, glyphs: &mut GlyphCache) {
+        use graphics::{Rectangle, Text, Transformed};
+
+        let lines: Vec<&str> = self.anomaly_report.lines().collect();
+        let line_height = (HELP_TEXT_SIZE + 6) as f64;
+        let origin = [8.0, 56.0];
+        Rectangle::new([1.0, 0.9, 0.9, 0.9]).draw([origin[0], origin[1], 640.0, (lines.len() as f64 + 0.5)*line_height],
+                                                  &c.draw_state, c.transform, gl);
+        for (i, line) in lines.iter().enumerate() {
+            Text::new_color([0.7, 0.0, 0.0, 1.0], HELP_TEXT_SIZE)
+                .draw(line, glyphs, &c.draw_state,
+                      c.transform.trans(origin[0] + 8.0, origin[1] + (i + 1) as f64*line_height), gl);
+        }
+    }
+
+    /// List every action with its shortcuts.
+    fn draw_help(&self, c: &Context, gl: &mut GlGraphics, glyphs: &mut GlyphCache) {
+        use graphics::{Rectangle, Text, Transformed};
+
+        let line_height = (HELP_TEXT_SIZE + 6) as f64;
+        let origin = [40.0, 80.0];
+        Rectangle::new([1.0, 1.0, 1.0, 0.9]).draw([origin[0], origin[1], 460.0, (keymap::ACTIONS.len() + 1) as f64*line_height],
+                                                  &c.draw_state, c.transform, gl);
+        for (i, &(action, _, description)) in keymap::ACTIONS.iter().enumerate() {
+            let shortcuts: Vec<String> = self.keymap.shortcuts(action).iter().map(|shortcut| shortcut.label()).collect();
+            let y = origin[1] + (i + 1) as f64*line_height;
+            Text::new_color([0.0, 0.0, 0.0, 1.0], HELP_TEXT_SIZE)
+                .draw(&shortcuts.join(", "), glyphs, &c.draw_state, c.transform.trans(origin[0] + 8.0, y), gl);
+            Text::new_color([0.3, 0.3, 0.3, 1.0], HELP_TEXT_SIZE)
+                .draw(description, glyphs, &c.draw_state, c.transform.trans(origin[0] + 160.0, y), gl);
+        }
     }
 
     pub fn set_widgets<'a>(&mut self, ui: &mut Ui<GlyphCache<'a>>) {
-        use conrod::{color, Button, Canvas, Colorable, Frameable, Labelable, Positionable, Sizeable, Slider, Tabs, Text, Widget, WidgetMatrix};
+        use conrod::{color, Button, Canvas, Colorable, Frameable, Labelable, Positionable, Sizeable, Slider, Tabs, Text, TextBox, Widget, WidgetMatrix};
 
         // Construct our main `Canvas` tree.
         Canvas::new().flow_down(&[
@@ -171,13 +651,21 @@ impl DeepLabUi {
                 (TOOL_BAR, Canvas::new().color(color::rgb(0.7, 0.7, 0.7)).length(48.0).flow_right(&[
                     (BUILD_BTN_AREA, Canvas::new().color(color::rgb(0.7, 0.7, 0.7))),
                     (RUN_BTN_AREA, Canvas::new().color(color::rgb(0.7, 0.7, 0.7))),
+                    (TRAIN_BTN_AREA, Canvas::new().color(color::rgb(0.7, 0.7, 0.7))),
+                    (MODE_BTN_AREA, Canvas::new().color(color::rgb(0.7, 0.7, 0.7))),
+                    (SAVE_BTN_AREA, Canvas::new().color(color::rgb(0.7, 0.7, 0.7))),
+                    (RESUME_BTN_AREA, Canvas::new().color(color::rgb(0.7, 0.7, 0.7))),
+                    (EXPORT_BTN_AREA, Canvas::new().color(color::rgb(0.7, 0.7, 0.7))),
+                    (SAVE_GRAPH_BTN_AREA, Canvas::new().color(color::rgb(0.7, 0.7, 0.7))),
+                    (OPEN_GRAPH_BTN_AREA, Canvas::new().color(color::rgb(0.7, 0.7, 0.7))),
                 ])),
                 (GRAPH_AREA, Canvas::new().color(color::rgb(1.0, 1.0, 0.8))),
             ])),
             (LOWER, Canvas::new().color(color::rgb(1.0, 0.8, 1.0)).scroll_kids_vertically().flow_right(&[
-                (BLOCKS, Canvas::new().color(color::rgb(0.8, 1.0, 1.0)).pad_bottom(10.0)),
+                (BLOCKS, Canvas::new().color(color::rgb(0.8, 1.0, 1.0)).pad_bottom(10.0).scroll_kids_vertically()),
                 (RELU_B, Canvas::new().color(color::rgb(0.8, 1.0, 0.8)).pad_bottom(10.0)),
                 (VAR_MANIP, Canvas::new().color(color::rgb(0.8, 0.2, 0.8)).pad_bottom(10.0).pad_left(10.0)),
+                (CONSOLE, Canvas::new().color(color::rgb(0.9, 0.9, 0.9)).pad(10.0)),
             ])),
         ]).set(MASTER, ui);
 
@@ -189,41 +677,143 @@ impl DeepLabUi {
         Button::new().rgb(0.3, 0.3, 0.8)
                      .label("Build")
                      .middle_of(BUILD_BTN_AREA)
-                     .react(|| {
-                         self.graph.gpu_build(&self.ctx);
-                     }).set(BUILD_BTN, ui);
+                     .react(|| self.build()).set(BUILD_BTN, ui);
 
         Button::new().rgb(0.3, 0.3, 0.8)
                      .label("Run")
                      .middle_of(RUN_BTN_AREA)
+                     .react(|| self.run()).set(RUN_BTN, ui);
+
+        let train_label = if self.trainer.is_some() { "Stop" } else { "Train" };
+        Button::new().rgb(0.3, 0.3, 0.8)
+                     .label(train_label)
+                     .middle_of(TRAIN_BTN_AREA)
+                     .react(|| self.toggle_training()).set(TRAIN_BTN, ui);
+
+        // Which mode Run runs the graph in, e.g. whether dropout drops anything
+        let mode_label = match self.graph.mode() { Mode::Train => "Run: train", Mode::Eval => "Run: eval" };
+        Button::new().rgb(0.3, 0.3, 0.8)
+                     .label(mode_label)
+                     .middle_of(MODE_BTN_AREA)
+                     .react(|| {
+                         let mode = match self.graph.mode() { Mode::Train => Mode::Eval, Mode::Eval => Mode::Train };
+                         self.graph.set_mode(mode);
+                     }).set(MODE_BTN, ui);
+
+        Button::new().rgb(0.3, 0.3, 0.8)
+                     .label("Save")
+                     .middle_of(SAVE_BTN_AREA)
+                     .react(|| {
+                         let mut checkpoint = self.graph.checkpoint(&self.ctx);
+                         checkpoint.iteration = self.iteration;
+                         if let Err(e) = checkpoint.save(Path::new(CHECKPOINT_PATH)) {
+                             println!("Failed to save checkpoint: {}", e);
+                         }
+                     }).set(SAVE_BTN, ui);
+
+        Button::new().rgb(0.3, 0.3, 0.8)
+                     .label("Resume")
+                     .middle_of(RESUME_BTN_AREA)
+                     .react(|| {
+                         if let Err(e) = self.resume(Path::new(CHECKPOINT_PATH)) {
+                             println!("Failed to resume: {}", e);
+                         }
+                     }).set(RESUME_BTN, ui);
+
+        Button::new().rgb(0.3, 0.3, 0.8)
+                     .label("Export")
+                     .middle_of(EXPORT_BTN_AREA)
                      .react(|| {
-                         self.graph.graph.run(&self.ctx);
-                     }).set(RUN_BTN, ui);
+                         if let Err(e) = self.export_rust(Path::new("model.rs")) {
+                             println!("Export failed: {}", e);
+                         }
+                     }).set(EXPORT_BTN, ui);
+
+        Button::new().rgb(0.3, 0.3, 0.8)
+                     .label("Save graph")
+                     .middle_of(SAVE_GRAPH_BTN_AREA)
+                     .react(|| self.save_graph()).set(SAVE_GRAPH_BTN, ui);
+
+        Button::new().rgb(0.3, 0.3, 0.8)
+                     .label("Open graph")
+                     .middle_of(OPEN_GRAPH_BTN_AREA)
+                     .react(|| {
+                         if let Err(e) = self.open_graph(Path::new(GRAPH_PATH)) {
+                             println!("Failed to open the graph: {}", e);
+                         }
+                     }).set(OPEN_GRAPH_BTN, ui);
+
+        // The script console, opened and closed with the backquote key
+        if self.console.is_some() {
+            let console_wh = ui.wh_of(CONSOLE).unwrap();
+            let first_line = self.script.output.len().saturating_sub(CONSOLE_LINES);
+            Text::new(&self.script.output[first_line..].join("\n"))
+                .color(color::rgb(0.0, 0.0, 0.0))
+                .font_size(12)
+                .top_left_of(CONSOLE)
+                .set(CONSOLE_OUTPUT, ui);
+
+            let mut submitted = None;
+            if let Some(ref mut line) = self.console {
+                TextBox::new(line)
+                    .font_size(14)
+                    .w_h(console_wh[0], 24.0)
+                    .mid_bottom_of(CONSOLE)
+                    .react(|line: &mut String| submitted = Some(mem::replace(line, String::new())))
+                    .set(CONSOLE_INPUT, ui);
+            }
+            if let Some(src) = submitted {
+                self.run_script(&src);
+            }
+        }
 
+        // The palette has a search box, and below it a column per category headed by the
+        // category's name, listing the operations matching the search
         let footer_wh = ui.wh_of(BLOCKS).unwrap();
-        WidgetMatrix::new(2, 2)
-            .w_h(footer_wh[0], footer_wh[1])
-            .mid_top_of(BLOCKS)
+        let search = if self.searching_palette {
+            format!("Search: {}|", self.palette_filter)
+        } else if self.palette_filter.is_empty() {
+            "Press / to search".to_string()
+        } else {
+            format!("Search: {} (/ to change)", self.palette_filter)
+        };
+        Text::new(&search)
+            .color(color::rgb(0.0, 0.0, 0.0))
+            .font_size(14)
+            .h(24.0)
+            .top_left_of(BLOCKS)
+            .set(PALETTE_SEARCH, ui);
+        let palette_rows = 1 + self.palette.iter().map(|&(_, ref ops)| ops.len()).max().unwrap_or(0);
+        WidgetMatrix::new(self.palette.len(), palette_rows)
+            .w_h(footer_wh[0], (palette_rows as f64)*PALETTE_ROW_HEIGHT)
+            .down_from(PALETTE_SEARCH, 4.0)
             .each_widget(|n, col, row| {
-                let op: Rc<Operation> = {
-                    let _row: &[Rc<Operation>; 2] = &self.activation_blocks[row];
-                    let op: &Rc<Operation> = &_row[col];
-                    op.clone()
+                let (label, op, color): (String, Option<Rc<Operation>>, [f32; 3]) = {
+                    let (ref category, ref ops) = self.palette[col];
+                    if row == 0 {
+                        (category.clone(), None, [0.7, 0.7, 0.7])
+                    } else {
+                        match ops.get(row - 1) {
+                            Some(op) => (op.name.clone(), Some(op.clone()), [0.3, 0.8, 0.3]),
+                            None => (String::new(), None, [0.8, 1.0, 1.0]),
+                        }
+                    }
                 };
                 Button::new()
-                    .rgb(0.3, 0.8, 0.3)
-                    .label(op.name.clone().as_ref())
+                    .rgb(color[0], color[1], color[2])
+                    .label(label.as_ref())
                     .react(|| {
-                        self.place_op = Some(op);
+                        if let Some(op) = op {
+                            self.place_op = Some(op);
+                        }
                     })
             }).set(ACTIVATION_BLOCK_MATRIX, ui);
 
         // Build the variable manipulator
-        if let Some((sel_var, ref coords)) = self.sel_var {
-            let gpu_var = sel_var.get(&self.graph.vars).gpu.unwrap();
-            let var_val = *gpu_var.get(&self.graph.graph).get(&self.ctx).get(coords[0], coords[1]);
-            let var_rows = gpu_var.get(&self.graph.graph).get(&self.ctx).rows();
-            let var_cols = gpu_var.get(&self.graph.graph).get(&self.ctx).columns();
+        let sel_value = self.sel_var.as_ref().and_then(|&(v, _)| self.graph.var_value(&self.ctx, v));
+        if let (Some((_, ref coords)), Some(value)) = (self.sel_var.clone(), sel_value) {
+            let (var_rows, var_cols) = value.shape_2d();
+            let var_val = value.data[coords[0]*var_cols + coords[1]];
 
             Slider::new(var_val, 0.0, 1.0)
                 .w_h(30.0, 150.0)
@@ -245,14 +835,216 @@ impl DeepLabUi {
                         })
                 }).set(VAR_MATRIX, ui);
         }
+
+        // In the gradient view, the inspector lists the mean magnitude of each trainable
+        // variable's value and gradient, and the selected element of the selected variable
+        if self.graph.edge_colors() == EdgeColors::Gradient {
+            let mut lines = vec!["Variable: mean |value|, mean |gradient|".to_string()];
+            for (v, _) in self.graph.trainable_variables() {
+                let value = self.graph.var_value(&self.ctx, v).map(|value| format!("{:.3e}", value.mean_abs()));
+                let grad = self.graph.grad_magnitude(v).map(|magnitude| format!("{:.3e}", magnitude));
+                lines.push(format!("{}: {}, {}", self.graph.var_name(v), value.unwrap_or("-".to_string()),
+                                   grad.unwrap_or("-".to_string())));
+            }
+            if let Some((v, ref coords)) = self.sel_var {
+                let value = self.graph.var_value(&self.ctx, v);
+                let grad = self.graph.grad(v);
+                if let (Some(value), Some(grad)) = (value, grad) {
+                    let i = coords[0]*value.shape_2d().1 + coords[1];
+                    if i < value.len() && i < grad.len() {
+                        lines.push(format!("{}[{}, {}] = {}, gradient {}", self.graph.var_name(v), coords[0], coords[1],
+                                           value.data[i], grad.data[i]));
+                    }
+                }
+            }
+            Text::new(&lines.join("\n"))
+                .color(color::rgb(0.0, 0.0, 0.0))
+                .font_size(11)
+                .top_left_of(VAR_MANIP)
+                .set(INSPECTOR, ui);
+        }
     }
 
     pub fn on_key_pressed(&mut self, key: input::Key) {
-        match key {
-            _ => { },
+        if let Some(action) = self.keymap.action(key, self.mouse.ctrl, self.mouse.shift) {
+            self.perform(action);
         }
     }
 
+    /// Do what a keyboard shortcut is bound to.
+    pub fn perform(&mut self, action: Action) {
+        let done = match action {
+            Action::Help => {
+                self.show_help = !self.show_help;
+                Ok(())
+            },
+            Action::Build => {
+                self.build();
+                Ok(())
+            },
+            Action::Run => {
+                self.run();
+                Ok(())
+            },
+            Action::Train => {
+                self.toggle_training();
+                Ok(())
+            },
+            Action::Save => {
+                self.save_graph();
+                Ok(())
+            },
+            Action::Open => self.open_graph(Path::new(GRAPH_PATH)),
+            Action::Undo => {
+                self.sel_var = None;
+                let values = self.graph.checkpoint(&self.ctx);
+                self.history.undo(&mut self.graph, &self.catalog, &values).map(|_| ())
+            },
+            Action::Redo => {
+                self.sel_var = None;
+                let values = self.graph.checkpoint(&self.ctx);
+                self.history.redo(&mut self.graph, &self.catalog, &values).map(|_| ())
+            },
+            // Remove the selected nodes, or disconnect the selected edge
+            Action::Delete => {
+                if self.graph.selection().is_empty() {
+                    self.graph.delete_selected_edge();
+                } else {
+                    self.delete_selection();
+                }
+                Ok(())
+            },
+            Action::SelectAll => {
+                let nodes = self.graph.node_ids().into_iter().filter(|&node| !self.graph.is_hidden(node)).collect();
+                self.graph.set_selection(nodes);
+                Ok(())
+            },
+            Action::Copy => self.copy_selection(false),
+            Action::CopyWithValues => self.copy_selection(true),
+            Action::Cut => self.cut_selection(),
+            Action::Paste => self.paste(),
+            Action::Duplicate => self.duplicate_selection(),
+            Action::Search => {
+                self.searching_palette = true;
+                Ok(())
+            },
+            Action::QuickAdd => {
+                self.quick_add = Some(QuickAdd::new(self.mouse.pos, None));
+                Ok(())
+            },
+            // Lay out the selected nodes, or the whole graph if nothing's selected
+            Action::Layout => {
+                if self.graph.selection().is_empty() {
+                    self.graph.auto_layout();
+                } else {
+                    self.graph.layout_selection();
+                }
+                Ok(())
+            },
+            Action::Group => {
+                let selection = self.graph.selection().to_vec();
+                self.graph.group_nodes(&selection, "subgraph").map(|_| self.graph.clear_selection())
+                                                             .map_err(|e| format!("Can't group: {}", e))
+            },
+            Action::ToggleGroup => {
+                if let Some(node) = self.graph.node_at(self.mouse.pos) {
+                    self.graph.toggle_group(node);
+                }
+                Ok(())
+            },
+            // Unroll the recurrent cell under the mouse over every timestep of its sequence
+            Action::Unroll => {
+                match self.graph.node_at(self.mouse.pos) {
+                    Some(node) => {
+                        self.graph.sequence_length(node)
+                                  .ok_or("The length of the sequence isn't known".to_string())
+                                  .and_then(|steps| self.graph.unroll(node, steps, &self.catalog))
+                                  .map(|_| ())
+                                  .map_err(|e| format!("Can't unroll: {}", e))
+                    },
+                    None => Ok(()),
+                }
+            },
+            Action::SaveToLibrary => {
+                match self.graph.node_at(self.mouse.pos) {
+                    Some(node) => self.save_to_library(node).map_err(|e| format!("Can't save to the library: {}", e)),
+                    None => Ok(()),
+                }
+            },
+            // Switching to the gradient view takes the gradients if the graph is built, and
+            // leaving it puts back a GPU build that taking them replaced
+            Action::EdgeColors => {
+                match self.graph.edge_colors() {
+                    EdgeColors::DType => {
+                        self.graph.set_edge_colors(EdgeColors::Gradient);
+                        if self.graph.backend().is_some() { self.backward() } else { Ok(()) }
+                    },
+                    EdgeColors::Gradient => {
+                        self.graph.set_edge_colors(EdgeColors::DType);
+                        self.restore_gpu_build()
+                    },
+                }
+            },
+            // Gradients are shown in the gradient view, which is what undoes their CPU build
+            Action::Backward => {
+                self.graph.set_edge_colors(EdgeColors::Gradient);
+                self.backward()
+            },
+            Action::CheckFinite => {
+                let check = !self.graph.checks_finite();
+                if check {
+                    try!(self.build_for_cpu("to check each node as it runs"));
+                }
+                self.graph.set_check_finite(check);
+                println!("Checking for values that aren't finite is {}", if check { "on" } else { "off" });
+                Ok(())
+            },
+            Action::Debug => self.toggle_debugging(),
+            Action::Step => self.debug_step(false),
+            Action::Resume => self.debug_step(true),
+            Action::Breakpoint => {
+                if let Some(node) = self.graph.node_at(self.mouse.pos) {
+                    self.graph.toggle_breakpoint(node);
+                }
+                Ok(())
+            },
+            Action::BreakOnNonFinite => {
+                match self.graph.debugger_mut() {
+                    Some(debugger) => {
+                        debugger.break_on_non_finite = !debugger.break_on_non_finite;
+                        Ok(())
+                    },
+                    None => Err("The debugger isn't running".to_string()),
+                }
+            },
+            Action::Previews => {
+                let show = !self.graph.shows_previews();
+                self.graph.set_show_previews(&self.ctx, show);
+                Ok(())
+            },
+            Action::ZoomIn | Action::ZoomOut | Action::ZoomReset => {
+                let zoom = match action {
+                    Action::ZoomIn => self.zoom * ZOOM_STEP,
+                    Action::ZoomOut => self.zoom / ZOOM_STEP,
+                    _ => 1.0,
+                };
+                self.set_zoom(zoom);
+                Ok(())
+            },
+        };
+        if let Err(e) = done {
+            println!("{}", e);
+            self.keep_anomaly_report(&e);
+        }
+    }
+
+    /// Scale the graph, keeping the mouse over the same point of it.
+    fn set_zoom(&mut self, zoom: f64) {
+        let window_pos = [self.mouse.pos[0] * self.zoom, self.mouse.pos[1] * self.zoom];
+        self.zoom = zoom.max(0.25).min(4.0);
+        self.mouse.pos = [window_pos[0] / self.zoom, window_pos[1] / self.zoom];
+    }
+
     pub fn on_key_released(&mut self, key: input::Key) {
         match key {
             _ => { },
@@ -260,12 +1052,29 @@ impl DeepLabUi {
     }
 }
 
+/// The first few of `data`, and how many more there are.
+fn summarize(data: &[f32]) -> String {
+    let shown: Vec<String> = data.iter().take(DEBUG_VALUES).map(|x| format!("{:.4}", x)).collect();
+    if data.len() > DEBUG_VALUES {
+        format!("{} ... ({} more)", shown.join(" "), data.len() - DEBUG_VALUES)
+    } else {
+        shown.join(" ")
+    }
+}
+
 widget_ids! {
     // Canvas IDs
     MASTER,
     TOOL_BAR,
     BUILD_BTN_AREA,
     RUN_BTN_AREA,
+    TRAIN_BTN_AREA,
+    MODE_BTN_AREA,
+    SAVE_BTN_AREA,
+    RESUME_BTN_AREA,
+    EXPORT_BTN_AREA,
+    SAVE_GRAPH_BTN_AREA,
+    OPEN_GRAPH_BTN_AREA,
     GRAPH_AREA,
     UPPER,
     LOWER,
@@ -273,8 +1082,16 @@ widget_ids! {
     // Widget IDs
     BUILD_BTN,
     RUN_BTN,
+    TRAIN_BTN,
+    MODE_BTN,
+    SAVE_BTN,
+    RESUME_BTN,
+    EXPORT_BTN,
+    SAVE_GRAPH_BTN,
+    OPEN_GRAPH_BTN,
     NODE,
     BLOCKS,
+    PALETTE_SEARCH,
     ACTIVATION_BLOCK_MATRIX,
     RELU_B,
     TITLE,
@@ -287,4 +1104,10 @@ widget_ids! {
     VAR_MATRIX,
     VAR_TRAINABLE,
     VAR_DATASET,
+    INSPECTOR,
+
+    // Script console
+    CONSOLE,
+    CONSOLE_OUTPUT,
+    CONSOLE_INPUT,
 }
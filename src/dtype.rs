@@ -0,0 +1,102 @@
+//! Element types of variables.
+//!
+//! Values are held as f32 by both backends whatever their type; the type decides which
+//! operations accept a value and how `Cast` converts it. An f32 holds every integer up to 2^24
+//! exactly, so i32 values are limited to that range: values outside it are rejected where they
+//! come into the graph, and `Cast` saturates to it. f64 values are f32s too, so they only have an
+//! f32's precision, and the GPU backend doesn't take them at all.
+
+use std::fmt;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum DType {
+    F32,
+    F64,
+    I32,
+    Bool,
+}
+
+pub const ALL: &'static [DType] = &[DType::F32, DType::F64, DType::I32, DType::Bool];
+pub const FLOATS: &'static [DType] = &[DType::F32, DType::F64];
+/// The largest magnitude an i32 value can have and still be held exactly as an f32.
+pub const MAX_EXACT_INT: f32 = 16777216.0;
+
+impl DType {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            DType::F32 => "f32",
+            DType::F64 => "f64",
+            DType::I32 => "i32",
+            DType::Bool => "bool",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<DType> {
+        ALL.iter().find(|dtype| dtype.name() == name).cloned()
+    }
+
+    pub fn is_float(&self) -> bool {
+        FLOATS.contains(self)
+    }
+
+    /// Convert a value to this type, still held as an f32.
+    pub fn convert(&self, x: f32) -> f32 {
+        match *self {
+            DType::F32 | DType::F64 => x,
+            DType::I32 => x.trunc().max(-MAX_EXACT_INT).min(MAX_EXACT_INT),
+            DType::Bool => if x != 0.0 { 1.0 } else { 0.0 },
+        }
+    }
+
+    /// Check that values are ones this type can hold: whole numbers f32 holds exactly for i32, 0
+    /// and 1 for bool.
+    pub fn check_values(&self, data: &[f32]) -> Result<(), String> {
+        let bad = match *self {
+            DType::F32 | DType::F64 => None,
+            DType::I32 => data.iter().find(|x| x.fract() != 0.0 || x.abs() > MAX_EXACT_INT),
+            DType::Bool => data.iter().find(|&&x| x != 0.0 && x != 1.0),
+        };
+        match bad {
+            Some(x) => Err(format!("{} can't hold the value {}", self, x)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for DType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DType, MAX_EXACT_INT};
+
+    #[test]
+    fn converts_values() {
+        assert_eq!(DType::I32.convert(-2.7), -2.0);
+        assert_eq!(DType::I32.convert(1e30), MAX_EXACT_INT);
+        assert_eq!(DType::Bool.convert(-0.5), 1.0);
+        assert_eq!(DType::F32.convert(0.25), 0.25);
+        assert_eq!(DType::F64.convert(0.25), 0.25);
+    }
+
+    #[test]
+    fn checks_values_fit() {
+        assert!(DType::I32.check_values(&[-3.0, 0.0, MAX_EXACT_INT]).is_ok());
+        assert!(DType::I32.check_values(&[0.5]).is_err());
+        assert!(DType::I32.check_values(&[MAX_EXACT_INT * 2.0]).is_err());
+        assert!(DType::Bool.check_values(&[0.0, 1.0]).is_ok());
+        assert!(DType::Bool.check_values(&[2.0]).is_err());
+        assert!(DType::F32.check_values(&[0.5, 1e30]).is_ok());
+    }
+
+    #[test]
+    fn names_round_trip() {
+        for dtype in &[DType::F32, DType::F64, DType::I32, DType::Bool] {
+            assert_eq!(DType::from_name(dtype.name()), Some(*dtype));
+        }
+        assert_eq!(DType::from_name("f16"), None);
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::rc::Rc;
 
 use dl;
@@ -5,24 +6,136 @@ use graphics;
 use matrix;
 use piston::input;
 use opengl_graphics::GlGraphics;
+use opengl_graphics::glyph_cache::GlyphCache;
 
+use super::catalog::{self, OpCatalog};
+use super::checkpoint::Checkpoint;
+use super::cpu::{Anomaly, CpuGraph, Pass, WeightDecay};
+use super::debugger::{Debugger, Stop};
 use super::dl_ui::Mouse;
+use super::dtype::DType;
+use super::layout;
 use super::node::{Node, NodeAction, NodeResponse};
-use super::op::Operation;
+use super::op::{AttrValue, Operation};
+use super::preview;
+use super::shape::Shape;
+use super::subgraph::{Subgraph, SubgraphNode};
+use super::tensor::Tensor;
 use super::var_store::{VarIndex, VarStore};
 
 pub enum GraphAction {
     SelectNode(NodeId),
     SelectVariable(VarIndex),
+    /// A connection was dragged out of a port and dropped where there's no port to connect it to.
+    DropConnection(Port),
+}
+
+/// An input or output of a node.
+#[derive(Copy, Clone, PartialEq)]
+pub enum Port {
+    Input(NodeId, usize),
+    Output(NodeId, usize),
+}
+
+/// Nodes drawn as a single node while collapsed, e.g. the timesteps of an unrolled cell or the
+/// nodes of a subgraph.
+struct Group {
+    /// The node standing in for the group while it's collapsed. For subgraphs it's a composite
+    /// node, which is only shown while the group is collapsed.
+    node: NodeId,
+    /// The other nodes of the group, hidden while it's collapsed.
+    members: Vec<NodeId>,
+    /// Shown after the name of `node` while collapsed.
+    label: String,
+    collapsed: bool,
+    /// For subgraphs, the (node, input) ports each input of the composite node feeds and the
+    /// (node, output) port each of its outputs comes from.
+    inputs: Vec<Vec<(NodeId, usize)>>,
+    outputs: Vec<(NodeId, usize)>,
+}
+
+/// A group with its nodes given by name, to make it again in a graph read back from text, which
+/// doesn't keep groups.
+#[derive(Clone)]
+pub struct NamedGroup {
+    node: String,
+    /// The operation and position of the composite node, for subgraphs.
+    composite: Option<(Rc<Operation>, [f64; 2])>,
+    members: Vec<String>,
+    label: String,
+    collapsed: bool,
+}
+
+/// Groups are the same if they group the same nodes the same way. Where the composite node is and
+/// whether the group is collapsed don't count.
+impl PartialEq for NamedGroup {
+    fn eq(&self, other: &NamedGroup) -> bool {
+        let op = |group: &NamedGroup| group.composite.as_ref().map(|&(ref op, _)| op.name.clone());
+        self.node == other.node && op(self) == op(other) && self.members == other.members && self.label == other.label
+    }
+}
+
+/// Whether the graph is run to train it or to evaluate it. Operations like dropout behave
+/// differently in each.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Mode {
+    Train,
+    Eval,
+}
+
+/// What the colours of edges show.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum EdgeColors {
+    /// The type of the tensor flowing through.
+    DType,
+    /// How large the gradient flowing back was in the last backward pass, from blue for tiny to
+    /// red for large. Nodes are framed in the colour of their outputs' gradients. Edges and nodes
+    /// without a gradient are grey. Only the CPU keeps gradients.
+    Gradient,
+}
+
+/// Segments each edge's curve is drawn with.
+const EDGE_SEGMENTS: usize = 24;
+/// How close the mouse has to be to an edge to be over it.
+const EDGE_HIT_DISTANCE: f64 = 5.0;
+
+/// What a graph has been built for.
+pub enum Backend {
+    Gpu,
+    Cpu(CpuGraph),
 }
 
 pub struct GraphBuilder {
     pub graph: dl::Graph,
     pub vars: VarStore,
-    dim_vars: Vec<usize>,
+    backend: Option<Backend>,
+    /// Whether the variables were trained on the GPU by a build that's been dropped since, so the
+    /// next build reads their values back from `graph`.
+    unread_gpu_values: bool,
+    mode: Mode, // What `run` runs the graph for; training always runs in `Mode::Train`
+    loss_grads: Vec<(dl::VarIndex, (usize, usize))>, // Gradients of the losses in `graph`
+    dim_vars: Vec<(String, usize)>, // Sizes of symbolic dimensions
     nodes: Vec<Node>,
     edges: Vec<(NodeId, usize, NodeId, usize)>,
+    groups: Vec<Group>,
+    selection: Vec<NodeId>,
+    band: Option<([f64; 2], [f64; 2])>, // Corners of the box being dragged out to select nodes
+    edge_colors: EdgeColors,
+    // Edges are identified by the (node, input) they feed
+    hovered_edge: Option<(NodeId, usize)>,
+    selected_edge: Option<(NodeId, usize)>,
+    /// Values of the nodes' outputs from the last run, drawn next to their ports, if shown.
+    previews: Option<HashMap<VarIndex, Tensor>>,
+    /// Nodes the debugger stops before running.
+    breakpoints: Vec<NodeId>,
+    debugger: Option<Debugger>,
+    check_finite: bool,
+    /// The first value that wasn't finite in the last run, if checking found one.
+    anomaly: Option<Anomaly>,
     node_action: Option<(NodeId, NodeAction)>,
+    renaming: Option<(NodeId, String)>, // Node being renamed and the name typed so far
+    /// Whether nodes, edges, attributes, shapes or positions changed since `take_changed`.
+    changed: bool,
 }
 
 impl GraphBuilder {
@@ -30,27 +143,689 @@ impl GraphBuilder {
         GraphBuilder {
             graph: dl::Graph::new(),
             vars: VarStore::new(),
+            backend: None,
+            unread_gpu_values: false,
+            mode: Mode::Eval,
+            loss_grads: vec![],
             dim_vars: vec![],
             nodes: vec![],
             edges: vec![],
+            groups: vec![],
+            selection: vec![],
+            band: None,
+            edge_colors: EdgeColors::DType,
+            hovered_edge: None,
+            selected_edge: None,
+            previews: None,
+            breakpoints: vec![],
+            debugger: None,
+            check_finite: false,
+            anomaly: None,
             node_action: None,
+            renaming: None,
+            // Nothing has seen the graph yet
+            changed: true,
         }
     }
 
-    pub fn add_node(&mut self, name: String, pos: [f64; 2], op: Rc<Operation>) {
-        let num_in = op.num_inputs;
-        let mut outs = Vec::with_capacity(op.num_outputs as usize);
-        for _ in 0..op.num_outputs {
-            outs.push(self.vars.add((1, 1)));
+    /// Add a node. If `name` is already taken a number is appended to it to make it unique.
+    pub fn add_node(&mut self, name: String, pos: [f64; 2], op: Rc<Operation>) -> NodeId {
+        let name = if self.is_name_taken(&name) { self.unique_name(&name) } else { name };
+        let num_in = op.num_inputs();
+        let mut outs = Vec::with_capacity(op.num_outputs());
+        for port in &op.outputs {
+            outs.push(self.vars.add(output_name(&name, port, op.num_outputs()), Shape::matrix(1, 1)));
         }
         self.nodes.push(Node::new(name, pos, op, num_in, outs));
+        self.changed = true;
+        NodeId(self.nodes.len()-1)
+    }
+
+    /// Add a node named after its operation, e.g. `matmul_1`. Subgraph operations add their
+    /// nodes along with a composite node standing for them.
+    pub fn add_op_node(&mut self, pos: [f64; 2], op: Rc<Operation>) -> NodeId {
+        let name = self.unique_name(&op.name.to_lowercase().replace(' ', "_"));
+        match op.subgraph.clone() {
+            Some(subgraph) => {
+                let members: Vec<NodeId> = subgraph.nodes.iter().map(|node| {
+                    let id = self.add_node(format!("{}/{}", name, node.name),
+                                           [pos[0] + node.pos[0], pos[1] + node.pos[1]],
+                                           node.op.clone());
+                    self.nodes[id.0].attrs = node.attrs.clone();
+                    id
+                }).collect();
+                for &(send_node, send_index, recv_node, recv_index) in &subgraph.edges {
+                    self.connect(members[send_node], send_index, members[recv_node], recv_index);
+                }
+                self.add_composite(name, pos, op, members)
+            },
+            None => self.add_node(name, pos, op),
+        }
+    }
+
+    /// Rename a node along with its output variables. Composite nodes' outputs belong to the
+    /// nodes inside them, so they keep their names.
+    pub fn rename_node(&mut self, node: NodeId, name: String) -> Result<(), String> {
+        if name.is_empty() {
+            return Err("Names can't be empty".to_string());
+        }
+        let op = self.nodes[node.0].op.clone();
+        let taken_by_node = self.node_by_name(&name).map_or(false, |n| n != node);
+        let taken_by_var = op.subgraph.is_none() && op.outputs.iter().any(|port| {
+            self.vars.find(&output_name(&name, port, op.num_outputs()))
+                     .map_or(false, |v| !self.nodes[node.0].outputs.contains(&v))
+        });
+        if taken_by_node || taken_by_var {
+            return Err(format!("The name '{}' is already taken", name));
+        }
+
+        if op.subgraph.is_none() {
+            for (port, &v) in op.outputs.iter().zip(&self.nodes[node.0].outputs) {
+                v.get_mut(&mut self.vars).name = output_name(&name, port, op.num_outputs());
+            }
+        }
+        self.nodes[node.0].set_name(name);
+        self.changed = true;
+        Ok(())
+    }
+
+    pub fn node_by_name(&self, name: &str) -> Option<NodeId> {
+        self.nodes.iter().position(|node| node.name() == name).map(NodeId)
+    }
+
+    pub fn var_by_name(&self, name: &str) -> Option<VarIndex> {
+        self.vars.find(name)
+    }
+
+    /// `base` followed by the lowest number that makes a name no node or variable has yet.
+    pub fn unique_name(&self, base: &str) -> String {
+        (1..).map(|i| format!("{}_{}", base, i))
+             .find(|name| !self.is_name_taken(name))
+             .unwrap()
+    }
+
+    fn is_name_taken(&self, name: &str) -> bool {
+        self.node_by_name(name).is_some() || self.vars.find(name).is_some()
+    }
+
+    /// Feed output `send_index` of `send_node` into input `recv_index` of `recv_node`, replacing
+    /// whatever was connected to that input before. The ports of composite nodes stand for the
+    /// ports of the nodes inside them. The graph has to be built again afterwards.
+    pub fn connect(&mut self, send_node: NodeId, send_index: usize, recv_node: NodeId, recv_index: usize) {
+        if let Some(g) = self.composite_group(send_node) {
+            let (node, index) = self.groups[g].outputs[send_index];
+            return self.connect(node, index, recv_node, recv_index);
+        }
+        if let Some(g) = self.composite_group(recv_node) {
+            for (node, index) in self.groups[g].inputs[recv_index].clone() {
+                self.connect(send_node, send_index, node, index);
+            }
+            return;
+        }
+
+        let v = self.nodes[send_node.0].outputs[send_index];
+        self.nodes[recv_node.0].inputs[recv_index] = Some(v);
+        self.edges.retain(|&(_, _, n, i)| !(n == recv_node && i == recv_index));
+        self.edges.push((send_node, send_index, recv_node, recv_index));
+        self.changed = true;
+        self.unbuild();
+
+        // Show composite nodes' inputs as connected once what they stand for is
+        for group in &self.groups {
+            if let Some(k) = group.inputs.iter().position(|ports| ports.contains(&(recv_node, recv_index))) {
+                self.nodes[group.node.0].inputs[k] = Some(v);
+            }
+        }
+    }
+
+    /// Disconnect input `recv_index` of `recv_node`. For composite nodes this disconnects every
+    /// port their input feeds. The graph has to be built again afterwards.
+    pub fn disconnect(&mut self, recv_node: NodeId, recv_index: usize) {
+        if let Some(g) = self.composite_group(recv_node) {
+            for (node, index) in self.groups[g].inputs[recv_index].clone() {
+                self.disconnect(node, index);
+            }
+            return;
+        }
+
+        self.nodes[recv_node.0].inputs[recv_index] = None;
+        self.edges.retain(|&(_, _, n, i)| !(n == recv_node && i == recv_index));
+        self.changed = true;
+        self.unbuild();
+        for group in &self.groups {
+            if let Some(k) = group.inputs.iter().position(|ports| ports.contains(&(recv_node, recv_index))) {
+                self.nodes[group.node.0].inputs[k] = None;
+            }
+        }
+        if self.selected_edge == Some((recv_node, recv_index)) {
+            self.selected_edge = None;
+        }
+    }
+
+    /// Add copies of the nodes of `other` and the edges between them, moved by `offset`. Nodes
+    /// whose names are taken get a number appended, and dimensions this graph doesn't have yet
+    /// are taken from `other`. Composite nodes aren't copied, only the nodes inside them. Gives
+    /// the new nodes.
+    pub fn insert(&mut self, other: &GraphBuilder, offset: [f64; 2]) -> Vec<NodeId> {
+        for &(ref name, size) in other.dims() {
+            if self.dim_by_name(name).is_none() {
+                self.set_dim(name, size);
+            }
+        }
+
+        let mut copies = vec![None; other.nodes.len()];
+        for (i, node) in other.nodes.iter().enumerate() {
+            if node.op.subgraph.is_some() {
+                continue;
+            }
+            let pos = [node.pos()[0] + offset[0], node.pos()[1] + offset[1]];
+            let id = self.add_node(node.name().to_string(), pos, node.op.clone());
+            self.nodes[id.0].attrs = node.attrs.clone();
+            for (&v, &from) in self.nodes[id.0].outputs.iter().zip(&node.outputs) {
+                let from = from.get(&other.vars);
+                let var = v.get_mut(&mut self.vars);
+                var.shape = from.shape.clone();
+                var.dims = from.dims.clone();
+                var.dtype = from.dtype;
+                var.value = from.value.clone();
+            }
+            copies[i] = Some(id);
+        }
+        for &(send_node, send_index, recv_node, recv_index) in &other.edges {
+            if let (Some(send_node), Some(recv_node)) = (copies[send_node.0], copies[recv_node.0]) {
+                self.connect(send_node, send_index, recv_node, recv_index);
+            }
+        }
+        copies.into_iter().filter_map(|id| id).collect()
+    }
+
+    /// Remove nodes along with their edges. Groups losing any of their nodes are broken up, and
+    /// the composite nodes standing for subgraphs go with them. The removed nodes' variables stay
+    /// in the store without names, so the names can be used again. The graph has to be built
+    /// again afterwards, starting from what the variables left were trained to.
+    pub fn remove_nodes(&mut self, nodes: &[NodeId]) {
+        self.unbuild();
+        let mut removed = vec![false; self.nodes.len()];
+        for node in nodes {
+            removed[node.0] = true;
+        }
+        for group in &self.groups {
+            let broken = removed[group.node.0] || group.members.iter().any(|member| removed[member.0]);
+            if broken && self.nodes[group.node.0].op.subgraph.is_some() {
+                removed[group.node.0] = true;
+            }
+        }
+
+        let fed: Vec<(NodeId, usize)> = self.edges.iter()
+                                                  .filter(|&&(send, _, recv, _)| removed[send.0] && !removed[recv.0])
+                                                  .map(|&(_, _, recv, index)| (recv, index))
+                                                  .collect();
+        for (node, index) in fed {
+            self.disconnect(node, index);
+        }
+
+        let mut new_ids = vec![None; self.nodes.len()];
+        let mut kept = vec![];
+        for (i, node) in self.nodes.drain(..).enumerate() {
+            if !removed[i] {
+                new_ids[i] = Some(NodeId(kept.len()));
+                kept.push(node);
+            } else if node.op.subgraph.is_none() {
+                // Composite nodes' outputs belong to the nodes inside them
+                for &v in &node.outputs {
+                    v.get_mut(&mut self.vars).name = String::new();
+                }
+            }
+        }
+        self.nodes = kept;
+
+        let new_id = |node: NodeId| new_ids[node.0];
+        self.edges = self.edges.iter().filter_map(|&(send_node, send_index, recv_node, recv_index)| {
+            match (new_id(send_node), new_id(recv_node)) {
+                (Some(send_node), Some(recv_node)) => Some((send_node, send_index, recv_node, recv_index)),
+                _ => None,
+            }
+        }).collect();
+        self.groups.retain(|group| new_id(group.node).is_some() && group.members.iter().all(|&m| new_id(m).is_some()));
+        for group in &mut self.groups {
+            group.node = new_id(group.node).unwrap();
+            group.members = group.members.iter().map(|&m| new_id(m).unwrap()).collect();
+            for ports in &mut group.inputs {
+                *ports = ports.iter().map(|&(node, index)| (new_id(node).unwrap(), index)).collect();
+            }
+            group.outputs = group.outputs.iter().map(|&(node, index)| (new_id(node).unwrap(), index)).collect();
+        }
+
+        self.selection.clear();
+        self.hovered_edge = None;
+        self.selected_edge = None;
+        self.node_action = None;
+        self.renaming = None;
+        self.breakpoints = self.breakpoints.iter().filter_map(|&node| new_id(node)).collect();
+        self.changed = true;
+    }
+
+    /// (sending node, output, receiving node, input) for every edge.
+    pub fn edges(&self) -> &[(NodeId, usize, NodeId, usize)] {
+        &self.edges
+    }
+
+    /// The node and output feeding input `recv_index` of `recv_node`, if it's connected.
+    pub fn input_edge(&self, recv_node: NodeId, recv_index: usize) -> Option<(NodeId, usize)> {
+        self.edges.iter()
+                  .find(|&&(_, _, n, i)| n == recv_node && i == recv_index)
+                  .map(|&(send_node, send_index, _, _)| (send_node, send_index))
+    }
+
+    /// The number of timesteps in the sequence fed to a recurrent cell, if it's known.
+    pub fn sequence_length(&self, cell: NodeId) -> Option<usize> {
+        let node = &self.nodes[cell.0];
+        node.op.recurrence.as_ref()
+            .and_then(|recurrence| node.inputs[recurrence.input])
+            .and_then(|v| v.get(&self.vars).shape.dims().first().and_then(|d| d.known()))
+    }
+
+    /// Unroll a recurrent cell over `steps` timesteps of the sequence connected to its input. The
+    /// cell becomes the first timestep and a copy of it is added for each of the others, sharing
+    /// its weights and taking the state of the timestep before. What the cell's outputs fed now
+    /// gets the last timestep's outputs instead. Returns the node stacking the outputs of every
+    /// timestep into a sequence.
+    ///
+    /// The added nodes are grouped with the cell and shown collapsed into it; see `toggle_group`.
+    /// If the unrolled graph's shapes don't work out, e.g. for more timesteps than the sequence
+    /// has, the graph is put back the way it was.
+    pub fn unroll(&mut self, cell: NodeId, steps: usize, catalog: &OpCatalog) -> Result<NodeId, String> {
+        let op = self.nodes[cell.0].op.clone();
+        let name = self.nodes[cell.0].name().to_string();
+        let recurrence = try!(op.recurrence.as_ref().ok_or(format!("'{}' isn't a recurrent cell", name)));
+        if steps == 0 {
+            return Err("Can't unroll over 0 timesteps".to_string());
+        }
+        if self.group_of(cell).is_some() {
+            return Err(format!("'{}' is already unrolled", name));
+        }
+        let (seq_node, seq_index) = try!(self.input_edge(cell, recurrence.input).ok_or(
+            format!("Connect a sequence to input '{}' of '{}' first", op.inputs[recurrence.input], name)));
+        let time_step = try!(catalog.get("TimeStep").ok_or("catalog has no TimeStep operation".to_string()));
+
+        let consumers: Vec<(NodeId, usize, NodeId, usize)> =
+            self.edges.iter().filter(|&&(send, _, _, _)| send == cell).cloned().collect();
+        let shared: Vec<(usize, (NodeId, usize))> =
+            (0..op.num_inputs()).filter(|&i| i != recurrence.input && !recurrence.is_state(i))
+                                .filter_map(|i| self.input_edge(cell, i).map(|producer| (i, producer)))
+                                .collect();
+
+        let pos = self.nodes[cell.0].pos();
+        let mut members = vec![];
+        let mut cells = vec![];
+        for t in 0..steps {
+            let step_pos = [pos[0] + (t as f64)*layout::LAYER_SPACING, pos[1]];
+            let step = self.add_node(format!("{}_t{}", name, t),
+                                     [step_pos[0], step_pos[1] - layout::ROW_SPACING],
+                                     time_step.clone());
+            self.nodes[step.0].attrs.set("t", AttrValue::Int(t as i64));
+            self.connect(seq_node, seq_index, step, 0);
+            members.push(step);
+
+            let node = if t == 0 {
+                cell
+            } else {
+                let node = self.add_node(format!("{}_{}", name, t), step_pos, op.clone());
+                self.nodes[node.0].attrs = self.nodes[cell.0].attrs.clone();
+                for &(i, (send_node, send_index)) in &shared {
+                    self.connect(send_node, send_index, node, i);
+                }
+                let prev = *cells.last().unwrap();
+                for &(output, input) in &recurrence.state {
+                    self.connect(prev, output, node, input);
+                }
+                members.push(node);
+                node
+            };
+            self.connect(step, 0, node, recurrence.input);
+            cells.push(node);
+        }
+
+        let last = *cells.last().unwrap();
+        let stack = self.add_node(format!("{}_seq", name),
+                                  [pos[0] + (steps as f64)*layout::LAYER_SPACING, pos[1]],
+                                  Rc::new(catalog::stack_op(steps)));
+        for (t, &node) in cells.iter().enumerate() {
+            self.connect(node, 0, stack, t);
+        }
+        members.push(stack);
+        for &(_, send_index, recv_node, recv_index) in &consumers {
+            self.connect(last, send_index, recv_node, recv_index);
+        }
+
+        self.groups.push(Group {
+            node: cell,
+            members: members.clone(),
+            label: format!("x{}", steps),
+            collapsed: true,
+            inputs: vec![],
+            outputs: vec![],
+        });
+        let inferred = match self.infer_dtypes() {
+            Ok(()) => self.infer_shapes(),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = inferred {
+            let selection = self.selection.clone();
+            self.remove_nodes(&members);
+            self.connect(seq_node, seq_index, cell, recurrence.input);
+            for (_, send_index, recv_node, recv_index) in consumers {
+                self.connect(cell, send_index, recv_node, recv_index);
+            }
+            self.selection = selection;
+            let _ = self.infer_dtypes().and_then(|_| self.infer_shapes());
+            return Err(e);
+        }
+        Ok(stack)
+    }
+
+    /// Group `nodes` into a subgraph shown collapsed into a single composite node named `name`.
+    /// Inputs fed from outside the subgraph or not at all and outputs used outside the subgraph or
+    /// not at all become the composite node's ports. Returns the composite node.
+    pub fn group_nodes(&mut self, nodes: &[NodeId], name: &str) -> Result<NodeId, String> {
+        if nodes.is_empty() {
+            return Err("Select the nodes to group first".to_string());
+        }
+        if let Some(&node) = nodes.iter().find(|&&node| self.group_of(node).is_some()) {
+            return Err(format!("'{}' is already in a group", self.nodes[node.0].name()));
+        }
+        let subgraph = self.extract_subgraph(name, nodes);
+        let pos = [nodes.iter().map(|&n| self.nodes[n.0].pos()[0]).fold(::std::f64::INFINITY, f64::min),
+                   nodes.iter().map(|&n| self.nodes[n.0].pos()[1]).fold(::std::f64::INFINITY, f64::min)];
+        let name = if self.is_name_taken(name) { self.unique_name(name) } else { name.to_string() };
+        let op = Rc::new(Operation::composite(Rc::new(subgraph), "Subgraphs"));
+        Ok(self.add_composite(name, pos, op, nodes.to_vec()))
+    }
+
+    /// The subgraph a composite node stands for as it is now, named after the node.
+    pub fn subgraph(&self, node: NodeId) -> Option<Subgraph> {
+        self.composite_group(node).map(|g| self.extract_subgraph(self.nodes[node.0].name(), &self.groups[g].members))
+    }
+
+    fn extract_subgraph(&self, name: &str, members: &[NodeId]) -> Subgraph {
+        let index = |node: NodeId| members.iter().position(|&m| m == node);
+        let origin = [members.iter().map(|&n| self.nodes[n.0].pos()[0]).fold(::std::f64::INFINITY, f64::min),
+                      members.iter().map(|&n| self.nodes[n.0].pos()[1]).fold(::std::f64::INFINITY, f64::min)];
+        let mut subgraph = Subgraph {
+            name: name.to_string(),
+            nodes: vec![],
+            edges: vec![],
+            inputs: vec![],
+            outputs: vec![],
+        };
+        // Where each exposed input is fed from, so inputs fed by the same output share one
+        let mut sources: Vec<Option<(NodeId, usize)>> = vec![];
+
+        for (i, &m) in members.iter().enumerate() {
+            let node = &self.nodes[m.0];
+            subgraph.nodes.push(SubgraphNode {
+                name: node.name().to_string(),
+                op: node.op.clone(),
+                attrs: node.attrs.clone(),
+                pos: [node.pos()[0] - origin[0], node.pos()[1] - origin[1]],
+            });
+            for port in 0..node.inputs.len() {
+                let source = self.input_edge(m, port);
+                match source.and_then(|(send_node, send_index)| index(send_node).map(|s| (s, send_index))) {
+                    Some((s, send_index)) => { subgraph.edges.push((s, send_index, i, port)); },
+                    None => {
+                        match sources.iter().position(|&s| s.is_some() && s == source) {
+                            Some(k) => { subgraph.inputs[k].1.push((i, port)); },
+                            None => {
+                                subgraph.inputs.push((node.op.inputs[port].clone(), vec![(i, port)]));
+                                sources.push(source);
+                            },
+                        }
+                    },
+                }
+            }
+        }
+
+        for (i, &m) in members.iter().enumerate() {
+            for port in 0..self.nodes[m.0].outputs.len() {
+                let mut consumers = self.edges.iter()
+                                              .filter(|&&(send, index, _, _)| send == m && index == port)
+                                              .map(|&(_, _, recv, _)| recv)
+                                              .peekable();
+                let unused = consumers.peek().is_none();
+                if unused || consumers.any(|recv| index(recv).is_none()) {
+                    subgraph.outputs.push((self.nodes[m.0].op.outputs[port].clone(), (i, port)));
+                }
+            }
+        }
+        subgraph
+    }
+
+    /// Add a composite node standing for `members`, the nodes of `op`'s subgraph, and group them.
+    fn add_composite(&mut self, name: String, pos: [f64; 2], op: Rc<Operation>, members: Vec<NodeId>) -> NodeId {
+        let subgraph = op.subgraph.clone().unwrap();
+        let inputs: Vec<Vec<(NodeId, usize)>> =
+            subgraph.inputs.iter()
+                           .map(|&(_, ref ports)| ports.iter().map(|&(n, port)| (members[n], port)).collect())
+                           .collect();
+        let outputs: Vec<(NodeId, usize)> =
+            subgraph.outputs.iter().map(|&(_, (n, port))| (members[n], port)).collect();
+
+        let outs = outputs.iter().map(|&(n, port)| self.nodes[n.0].outputs[port]).collect();
+        let mut node = Node::new(name, pos, op, inputs.len(), outs);
+        node.inputs = inputs.iter()
+                            .map(|ports| ports.first().and_then(|&(n, port)| self.nodes[n.0].inputs[port]))
+                            .collect();
+        self.nodes.push(node);
+        self.changed = true;
+        let id = NodeId(self.nodes.len() - 1);
+
+        self.groups.push(Group {
+            node: id,
+            label: format!("{} nodes", members.len()),
+            members: members,
+            collapsed: true,
+            inputs: inputs,
+            outputs: outputs,
+        });
+        id
+    }
+
+    /// Expand the group `node` belongs to if it's collapsed, or collapse it if it's expanded.
+    /// Returns false if the node isn't in a group.
+    pub fn toggle_group(&mut self, node: NodeId) -> bool {
+        match self.group_of(node) {
+            Some(g) => {
+                self.groups[g].collapsed = !self.groups[g].collapsed;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// The groups, with their nodes given by name.
+    pub fn named_groups(&self) -> Vec<NamedGroup> {
+        self.groups.iter().map(|group| {
+            let node = &self.nodes[group.node.0];
+            NamedGroup {
+                node: node.name().to_string(),
+                composite: node.op.subgraph.as_ref().map(|_| (node.op.clone(), node.pos())),
+                members: group.members.iter().map(|&m| self.nodes[m.0].name().to_string()).collect(),
+                label: group.label.clone(),
+                collapsed: group.collapsed,
+            }
+        }).collect()
+    }
+
+    /// Make groups again from their nodes' names, adding the composite nodes of subgraphs. Groups
+    /// with nodes this graph doesn't have are left out.
+    pub fn add_named_groups(&mut self, groups: &[NamedGroup]) {
+        for group in groups {
+            let members: Vec<NodeId> = group.members.iter().filter_map(|name| self.node_by_name(name)).collect();
+            if members.len() < group.members.len() || members.iter().any(|&m| self.group_of(m).is_some()) {
+                continue;
+            }
+            let node = match group.composite {
+                Some((ref op, pos)) => {
+                    let name = if self.is_name_taken(&group.node) {
+                        self.unique_name(&group.node)
+                    } else {
+                        group.node.clone()
+                    };
+                    self.add_composite(name, pos, op.clone(), members.clone())
+                },
+                None => {
+                    match self.node_by_name(&group.node) {
+                        Some(node) if self.group_of(node).is_none() => {
+                            self.groups.push(Group {
+                                node: node,
+                                members: members.clone(),
+                                label: String::new(),
+                                collapsed: true,
+                                inputs: vec![],
+                                outputs: vec![],
+                            });
+                            node
+                        },
+                        _ => continue,
+                    }
+                },
+            };
+            let g = self.groups.iter().position(|g| g.node == node).unwrap();
+            self.groups[g].label = group.label.clone();
+            self.groups[g].collapsed = group.collapsed;
+        }
+    }
+
+    fn group_of(&self, node: NodeId) -> Option<usize> {
+        self.groups.iter().position(|group| group.node == node || group.members.contains(&node))
+    }
+
+    /// The group of a composite node.
+    fn composite_group(&self, node: NodeId) -> Option<usize> {
+        if self.nodes[node.0].op.subgraph.is_none() {
+            return None;
+        }
+        self.groups.iter().position(|group| group.node == node)
+    }
+
+    /// Whether the node is hidden in a collapsed group, or is the composite node of an expanded one.
+    pub fn is_hidden(&self, node: NodeId) -> bool {
+        self.groups.iter().any(|group| {
+            if group.collapsed {
+                group.members.contains(&node)
+            } else {
+                group.node == node && self.nodes[node.0].op.subgraph.is_some()
+            }
+        })
+    }
+
+    /// Where to draw an edge into input `index` of `node`: there, or at the node standing in for
+    /// the collapsed group hiding it.
+    fn shown_input(&self, node: NodeId, index: usize) -> (NodeId, usize) {
+        match self.groups.iter().find(|group| group.collapsed && group.members.contains(&node)) {
+            Some(group) => {
+                let port = group.inputs.iter().position(|ports| ports.contains(&(node, index)));
+                (group.node, port.unwrap_or(shown_port(index, group.node.get(self).inputs.len())))
+            },
+            None => (node, index),
+        }
+    }
+
+    /// Where to draw an edge out of output `index` of `node`.
+    fn shown_output(&self, node: NodeId, index: usize) -> (NodeId, usize) {
+        match self.groups.iter().find(|group| group.collapsed && group.members.contains(&node)) {
+            Some(group) => {
+                let port = group.outputs.iter().position(|&port| port == (node, index));
+                (group.node, port.unwrap_or(shown_port(index, group.node.get(self).outputs.len())))
+            },
+            None => (node, index),
+        }
+    }
+
+    /// Add the node to the selection, or remove it if it's already selected.
+    pub fn toggle_selected(&mut self, node: NodeId) {
+        match self.selection.iter().position(|&n| n == node) {
+            Some(i) => { self.selection.remove(i); },
+            None => { self.selection.push(node); },
+        }
+    }
+
+    pub fn selection(&self) -> &[NodeId] {
+        &self.selection
+    }
+
+    pub fn clear_selection(&mut self) {
+        self.selection.clear();
+    }
+
+    pub fn set_selection(&mut self, nodes: Vec<NodeId>) {
+        self.selection = nodes;
+    }
+
+    /// Reposition every node left-to-right by data flow.
+    pub fn auto_layout(&mut self) {
+        let nodes = self.node_ids();
+        self.layout_nodes(&nodes, [32.0, 80.0]);
+    }
+
+    /// Reposition the selected nodes left-to-right by data flow between them, keeping them where
+    /// the selection was.
+    pub fn layout_selection(&mut self) {
+        let selection = self.selection.clone();
+        let origin = selection.iter().fold([::std::f64::INFINITY; 2], |origin, node| {
+            let pos = self.nodes[node.0].pos();
+            [origin[0].min(pos[0]), origin[1].min(pos[1])]
+        });
+        self.layout_nodes(&selection, origin);
+    }
+
+    /// Reposition `nodes` left-to-right by data flow below the rest of the graph, which stays
+    /// where it is.
+    pub fn layout_below(&mut self, nodes: &[NodeId]) {
+        let bottom = (0..self.nodes.len()).map(NodeId)
+                                          .filter(|node| !nodes.contains(node))
+                                          .map(|node| self.nodes[node.0].pos()[1] + self.nodes[node.0].size()[1])
+                                          .fold(None, |bottom: Option<f64>, y| Some(bottom.map_or(y, |b| b.max(y))));
+        let origin = match bottom {
+            Some(bottom) => [32.0, bottom + 64.0],
+            None => [32.0, 80.0],
+        };
+        self.layout_nodes(nodes, origin);
+    }
+
+    fn layout_nodes(&mut self, nodes: &[NodeId], origin: [f64; 2]) {
+        let index = |node: NodeId| nodes.iter().position(|&n| n == node);
+        let edges: Vec<(usize, usize)> = self.edges.iter().filter_map(|&(send, _, recv, _)| {
+            match (index(send), index(recv)) {
+                (Some(send), Some(recv)) => Some((send, recv)),
+                _ => None,
+            }
+        }).collect();
+        let heights: Vec<f64> = nodes.iter().map(|node| self.nodes[node.0].size()[1]).collect();
+        let positions = layout::layered(&heights, &edges, origin);
+        for (node, pos) in nodes.iter().zip(positions) {
+            self.nodes[node.0].set_pos(pos);
+        }
+        self.changed = true;
     }
 
     pub fn event(&mut self, event: &input::Event, mouse: &Mouse) -> Option<GraphAction> {
+        if self.rename_event(event, mouse) || self.select_event(event, mouse) {
+            return None;
+        }
+        if let Some(v) = self.edge_event(event, mouse) {
+            return Some(GraphAction::SelectVariable(v));
+        }
+
         let mut graph_action = None;
 
         let mut new_action: Option<(NodeId, NodeAction)> = None;
+        let hidden: Vec<bool> = (0..self.nodes.len()).map(|i| self.is_hidden(NodeId(i))).collect();
         for (i, node) in self.nodes.iter_mut().enumerate() {
+            if hidden[i] {
+                continue;
+            }
             node.event(event, mouse);
             if let Some(action) = node.action {
                 new_action = Some((NodeId(i), action));
@@ -58,6 +833,19 @@ impl GraphBuilder {
             }
         }
 
+        // A drag from a port ending anywhere but on a port
+        let mut released = false;
+        event.release(|button| {
+            released = button == input::Button::Mouse(input::mouse::MouseButton::Left);
+        });
+        if let (true, Some((node, action)), None) = (released, self.node_action, new_action) {
+            match action {
+                NodeAction::DragInput(i) => { graph_action = Some(GraphAction::DropConnection(Port::Input(node, i))); },
+                NodeAction::DragOutput(i) => { graph_action = Some(GraphAction::DropConnection(Port::Output(node, i))); },
+                _ => { },
+            }
+        }
+
         if let Some((old_node, old_action)) = self.node_action {
             if let Some((new_node, _new_action)) = new_action {
                 if let Some(response) = old_action.happened_before(&_new_action,
@@ -65,9 +853,10 @@ impl GraphBuilder {
                     match response {
                         NodeResponse::Connect(send_node, send_index, recv_node, recv_index) => {
                             // A connection was made
-                            let v = self.nodes[send_node.0].outputs[send_index];
-                            self.nodes[recv_node.0].inputs[recv_index] = Some(v);
-                            self.edges.push((send_node, send_index, recv_node, recv_index));
+                            self.connect(send_node, send_index, recv_node, recv_index);
+                            if let Err(e) = self.infer_dtypes().and_then(|_| self.infer_shapes()) {
+                                println!("{}", e);
+                            }
                             if recv_node == new_node {
                                 println!(" -> ");
                             } else {
@@ -108,27 +897,1134 @@ impl GraphBuilder {
         graph_action
     }
 
-    pub fn draw(&self, c: &graphics::Context, gl: &mut GlGraphics) {
-        use graphics::Line;
+    /// Shift-clicking a node adds it to the selection or removes it. Dragging a box out from
+    /// empty space selects the nodes it touches, adding them to the selection while shift is held
+    /// and replacing it otherwise. Returns whether the event was used for selecting.
+    fn select_event(&mut self, event: &input::Event, mouse: &Mouse) -> bool {
+        use piston::input::*;
+
+        let mut used = false;
+        event.press(|button| {
+            if button == Button::Mouse(mouse::MouseButton::Left) {
+                match self.node_at(mouse.pos) {
+                    Some(node) => {
+                        if mouse.shift {
+                            self.toggle_selected(node);
+                            used = true;
+                        }
+                    },
+                    None => {
+                        if self.hovered_edge.is_none() {
+                            self.band = Some((mouse.pos, mouse.pos));
+                        }
+                    },
+                }
+            }
+        });
+        event.mouse_cursor(|_, _| {
+            if let Some((start, _)) = self.band {
+                self.band = Some((start, mouse.pos));
+            }
+        });
+        event.release(|button| {
+            if button == Button::Mouse(mouse::MouseButton::Left) {
+                if let Some(band) = self.band.take() {
+                    if !mouse.shift {
+                        self.selection.clear();
+                    }
+                    let rect = band_rect(band);
+                    for i in 0..self.nodes.len() {
+                        let (pos, size) = (self.nodes[i].pos(), self.nodes[i].size());
+                        let touches = pos[0] < rect[0] + rect[2] && rect[0] < pos[0] + size[0] &&
+                                      pos[1] < rect[1] + rect[3] && rect[1] < pos[1] + size[1];
+                        if touches && !self.is_hidden(NodeId(i)) && !self.selection.contains(&NodeId(i)) {
+                            self.selection.push(NodeId(i));
+                        }
+                    }
+                }
+            }
+        });
+        used
+    }
+
+    /// Moving the mouse over an edge highlights it and clicking it selects it, or clicking
+    /// anywhere else deselects it. Gives the variable flowing through a newly selected edge.
+    fn edge_event(&mut self, event: &input::Event, mouse: &Mouse) -> Option<VarIndex> {
+        use piston::input::*;
+
+        event.mouse_cursor(|_, _| {
+            self.hovered_edge = if self.node_at(mouse.pos).is_some() { None } else { self.edge_at(mouse.pos) };
+        });
+        let mut selected = None;
+        event.press(|button| {
+            if button == Button::Mouse(mouse::MouseButton::Left) {
+                self.selected_edge = self.hovered_edge;
+                selected = self.selected_edge.and_then(|(node, index)| self.nodes[node.0].inputs[index]);
+            }
+        });
+        selected
+    }
+
+    /// The edge passing under `pos`, identified by the input it feeds.
+    pub fn edge_at(&self, pos: [f64; 2]) -> Option<(NodeId, usize)> {
+        self.edges.iter().rev().find(|&&edge| {
+            self.edge_curve(edge).map_or(false, |curve| {
+                curve.windows(2).any(|segment| distance_to_segment(pos, segment[0], segment[1]) < EDGE_HIT_DISTANCE)
+            })
+        }).map(|&(_, _, recv_node, recv_index)| (recv_node, recv_index))
+    }
+
+    pub fn selected_edge(&self) -> Option<(NodeId, usize)> {
+        self.selected_edge
+    }
+
+    /// Disconnect the selected edge, if any. Gives whether there was one.
+    pub fn delete_selected_edge(&mut self) -> bool {
+        match self.selected_edge {
+            Some((node, index)) => {
+                self.disconnect(node, index);
+                self.hovered_edge = None;
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn edge_colors(&self) -> EdgeColors {
+        self.edge_colors
+    }
+
+    pub fn set_edge_colors(&mut self, edge_colors: EdgeColors) {
+        self.edge_colors = edge_colors;
+    }
+
+    /// Points along the curve an edge is drawn as, from the output to the input, or None if it
+    /// isn't drawn. Edges inside a collapsed group aren't drawn, edges into or out of one are
+    /// drawn to the node standing in for it.
+    fn edge_curve(&self, (send_node, send_index, recv_node, recv_index): (NodeId, usize, NodeId, usize))
+                  -> Option<Vec<[f64; 2]>> {
+        let (shown_send, shown_send_index) = self.shown_output(send_node, send_index);
+        let (shown_recv, shown_recv_index) = self.shown_input(recv_node, recv_index);
+        if shown_send == shown_recv {
+            return None;
+        }
+        let start = shown_send.get(self).get_output_pos(shown_send_index);
+        let end = shown_recv.get(self).get_input_pos(shown_recv_index);
+
+        // A cubic Bezier curve leaving and entering its ports horizontally
+        let bend = ((end[0] - start[0]).abs() / 2.0).max(40.0);
+        let controls = [start, [start[0] + bend, start[1]], [end[0] - bend, end[1]], end];
+        Some((0..EDGE_SEGMENTS + 1).map(|i| {
+            let t = i as f64 / EDGE_SEGMENTS as f64;
+            let weights = [(1.0 - t).powi(3), 3.0*t*(1.0 - t).powi(2), 3.0*t*t*(1.0 - t), t.powi(3)];
+            let mut point = [0.0, 0.0];
+            for (w, control) in weights.iter().zip(&controls) {
+                point[0] += w * control[0];
+                point[1] += w * control[1];
+            }
+            point
+        }).collect())
+    }
+
+    /// The colour of an edge carrying `v`.
+    fn edge_color(&self, v: VarIndex) -> [f32; 4] {
+        match self.edge_colors {
+            EdgeColors::DType => match v.get(&self.vars).dtype {
+                DType::F32 => [1.0, 0.0, 0.0, 1.0],
+                DType::F64 => [0.1, 0.3, 0.9, 1.0],
+                DType::I32 => [0.1, 0.6, 0.2, 1.0],
+                DType::Bool => [0.6, 0.2, 0.7, 1.0],
+            },
+            EdgeColors::Gradient => gradient_color(self.grad_magnitude(v)),
+        }
+    }
+
+    /// The gradient of `v` from the last backward pass, if the graph is built for the CPU.
+    pub fn grad(&self, v: VarIndex) -> Option<&Tensor> {
+        match self.backend {
+            Some(Backend::Cpu(ref cpu)) => cpu.grad(v),
+            _ => None,
+        }
+    }
+
+    /// The mean magnitude of the gradient of `v` from the last backward pass, if it has one.
+    pub fn grad_magnitude(&self, v: VarIndex) -> Option<f32> {
+        self.grad(v).and_then(|grad| if grad.len() > 0 { Some(grad.mean_abs()) } else { None })
+    }
+
+    /// Double-clicking a node or pressing F2 over it starts renaming it. Typing edits the name,
+    /// Enter or clicking anywhere applies it. Double-clicking a composite node expands it instead.
+    /// Returns whether the event was used.
+    fn rename_event(&mut self, event: &input::Event, mouse: &Mouse) -> bool {
+        use piston::input::*;
+
+        let mut used = false;
+        event.press(|button| {
+            match button {
+                Button::Mouse(mouse::MouseButton::Left) => {
+                    if self.renaming.is_some() {
+                        self.finish_renaming();
+                        used = true;
+                    }
+                    if mouse.double_click {
+                        if let Some(node) = self.node_at(mouse.pos) {
+                            if self.composite_group(node).is_some() {
+                                self.toggle_group(node);
+                            } else {
+                                let name = node.get(self).name().to_string();
+                                self.renaming = Some((node, name));
+                            }
+                            used = true;
+                        }
+                    }
+                },
+                Button::Keyboard(Key::F2) => {
+                    if let Some(node) = self.node_at(mouse.pos) {
+                        let name = node.get(self).name().to_string();
+                        self.renaming = Some((node, name));
+                        used = true;
+                    }
+                },
+                Button::Keyboard(Key::Backspace) => {
+                    if let Some((_, ref mut name)) = self.renaming {
+                        name.pop();
+                        used = true;
+                    }
+                },
+                Button::Keyboard(Key::Return) => {
+                    if self.renaming.is_some() {
+                        self.finish_renaming();
+                        used = true;
+                    }
+                },
+                _ => { },
+            }
+        });
+        event.text(|text| {
+            if let Some((_, ref mut name)) = self.renaming {
+                name.push_str(text);
+                used = true;
+            }
+        });
+        used
+    }
+
+    fn finish_renaming(&mut self) {
+        if let Some((node, name)) = self.renaming.take() {
+            if let Err(e) = self.rename_node(node, name) {
+                println!("{}", e);
+            }
+        }
+    }
+
+    pub fn is_renaming(&self) -> bool {
+        self.renaming.is_some()
+    }
+
+    /// The topmost visible node under `pos`.
+    pub fn node_at(&self, pos: [f64; 2]) -> Option<NodeId> {
+        (0..self.nodes.len()).rev()
+                             .map(NodeId)
+                             .find(|&id| !self.is_hidden(id) && self.nodes[id.0].is_over(pos))
+    }
+
+    pub fn draw(&self, c: &graphics::Context, gl: &mut GlGraphics, glyphs: &mut GlyphCache) {
+        use graphics::{Ellipse, Line, Rectangle, Text, Transformed};
+
+        // A frame around the nodes of each expanded subgraph, named after its composite node
+        for group in self.groups.iter().filter(|group| !group.collapsed && group.node.get(self).op.subgraph.is_some()) {
+            let mut bounds = [::std::f64::INFINITY, ::std::f64::INFINITY, ::std::f64::NEG_INFINITY, ::std::f64::NEG_INFINITY];
+            for &member in &group.members {
+                let (pos, size) = (member.get(self).pos(), member.get(self).size());
+                bounds = [bounds[0].min(pos[0]), bounds[1].min(pos[1]),
+                          bounds[2].max(pos[0] + size[0]), bounds[3].max(pos[1] + size[1])];
+            }
+            Rectangle::new([0.0, 0.0, 0.0, 0.08]).draw([bounds[0] - 16.0, bounds[1] - 32.0,
+                                                        bounds[2] - bounds[0] + 32.0, bounds[3] - bounds[1] + 48.0],
+                                                       &c.draw_state, c.transform, gl);
+            Text::new_color([0.3, 0.3, 0.3, 1.0], 11)
+                .draw(group.node.get(self).name(), glyphs, &c.draw_state,
+                      c.transform.trans(bounds[0] - 12.0, bounds[1] - 20.0), gl);
+        }
+
+        // Nodes are framed by how large their outputs' gradients are
+        if self.edge_colors == EdgeColors::Gradient {
+            for node in self.nodes.iter().enumerate().filter(|&(i, _)| !self.is_hidden(NodeId(i))).map(|(_, node)| node) {
+                let grads: Vec<f32> = node.outputs.iter().filter_map(|&v| self.grad_magnitude(v)).collect();
+                let magnitude = if grads.is_empty() { None } else { Some(grads.iter().fold(0.0f32, |max, &g| max.max(g))) };
+                let (pos, size) = (node.pos(), node.size());
+                Rectangle::new(gradient_color(magnitude)).draw([pos[0] - 2.0, pos[1] - 2.0, size[0] + 4.0, size[1] + 4.0],
+                                                               &c.draw_state, c.transform, gl);
+            }
+        }
+
+        // Breakpoints are red dots in the top left corner, and the node the debugger runs next is
+        // framed in green
+        for &node in self.breakpoints.iter().filter(|&&node| !self.is_hidden(node)) {
+            let pos = node.get(self).pos();
+            Ellipse::new([0.9, 0.1, 0.1, 1.0]).draw([pos[0] - 5.0, pos[1] - 5.0, 10.0, 10.0],
+                                                   &c.draw_state, c.transform, gl);
+        }
+        if let Some(node) = self.debug_next() {
+            let (pos, size) = (node.get(self).pos(), node.get(self).size());
+            Rectangle::new([0.1, 0.7, 0.1, 1.0]).draw([pos[0] - 4.0, pos[1] - 4.0, size[0] + 8.0, size[1] + 8.0],
+                                                      &c.draw_state, c.transform, gl);
+        }
+
+        // The node that gave a value that isn't finite is framed in red
+        if let Some(anomaly) = self.anomaly {
+            if !self.is_hidden(anomaly.node) {
+                let (pos, size) = (anomaly.node.get(self).pos(), anomaly.node.get(self).size());
+                Rectangle::new([0.9, 0.1, 0.1, 1.0]).draw([pos[0] - 5.0, pos[1] - 5.0, size[0] + 10.0, size[1] + 10.0],
+                                                          &c.draw_state, c.transform, gl);
+            }
+        }
+
+        // Selected nodes are outlined
+        for &node in self.selection.iter().filter(|&&node| !self.is_hidden(node)) {
+            let (pos, size) = (node.get(self).pos(), node.get(self).size());
+            Rectangle::new([1.0, 0.8, 0.0, 1.0]).draw([pos[0] - 3.0, pos[1] - 3.0, size[0] + 6.0, size[1] + 6.0],
+                                                      &c.draw_state, c.transform, gl);
+        }
+        if let Some(band) = self.band {
+            Rectangle::new([1.0, 0.8, 0.0, 0.25]).draw(band_rect(band), &c.draw_state, c.transform, gl);
+        }
+
+        for &edge in &self.edges {
+            let (send_node, send_index, recv_node, recv_index) = edge;
+            let curve = match self.edge_curve(edge) {
+                Some(curve) => curve,
+                None => continue,
+            };
+            let v = send_node.get(self).outputs[send_index];
+
+            // The selected edge is outlined like selected nodes, the one under the mouse is thicker
+            let radius = if self.hovered_edge == Some((recv_node, recv_index)) { 2.0 } else { 1.0 };
+            if self.selected_edge == Some((recv_node, recv_index)) {
+                for segment in curve.windows(2) {
+                    Line::new_round([1.0, 0.8, 0.0, 1.0], radius + 2.0)
+                        .draw([segment[0][0], segment[0][1], segment[1][0], segment[1][1]],
+                              &c.draw_state, c.transform, gl);
+                }
+            }
+            let line = Line::new_round(self.edge_color(v), radius);
+            for segment in curve.windows(2) {
+                line.draw([segment[0][0], segment[0][1], segment[1][0], segment[1][1]],
+                          &c.draw_state, c.transform, gl);
+            }
+
+            // Label the edge with the shape of the tensor flowing through it
+            let var = v.get(&self.vars);
+            let label = match var.dtype {
+                DType::F32 => var.shape.to_string(),
+                dtype => format!("{} {}", dtype, var.shape),
+            };
+            let mid = curve[EDGE_SEGMENTS / 2];
+            Text::new_color([0.3, 0.3, 0.3, 1.0], 9)
+                .draw(&label, glyphs, &c.draw_state,
+                      c.transform.trans(mid[0] + 4.0, mid[1] - 4.0), gl);
+        }
+
+        for (i, node) in self.nodes.iter().enumerate() {
+            if self.is_hidden(NodeId(i)) {
+                continue;
+            }
+            match self.renaming {
+                Some((renamed, ref name)) if renamed.0 == i => {
+                    node.draw(c, gl, glyphs, &format!("{}|", name), true);
+                },
+                _ => {
+                    match self.groups.iter().find(|group| group.collapsed && group.node.0 == i) {
+                        Some(group) => {
+                            node.draw(c, gl, glyphs, &format!("{} ({})", node.name(), group.label), false);
+                        },
+                        None => {
+                            node.draw(c, gl, glyphs, node.name(), false);
+                        },
+                    }
+                },
+            }
+            if let Some(ref previews) = self.previews {
+                for (j, v) in node.outputs.iter().enumerate() {
+                    if let Some(value) = previews.get(v) {
+                        let pos = node.get_output_pos(j);
+                        preview::draw(value, [pos[0] + 8.0, pos[1]], c, gl, glyphs);
+                    }
+                }
+            }
+        }
+        self.draw_debugged_tensors(c, gl, glyphs);
+    }
+
+    /// Previews of the inputs and outputs of the node the debugger ran last, next to its ports:
+    /// their values in the forward pass, their gradients in the backward pass.
+    fn draw_debugged_tensors(&self, c: &graphics::Context, gl: &mut GlGraphics, glyphs: &mut GlyphCache) {
+        let (debugger, cpu) = match (&self.debugger, &self.backend) {
+            (&Some(ref debugger), &Some(Backend::Cpu(ref cpu))) => (debugger, cpu),
+            _ => return,
+        };
+        let (node, pass) = match debugger.last {
+            Some((node, pass)) if !self.is_hidden(node) => (node.get(self), pass),
+            _ => return,
+        };
+        let tensor = |v: VarIndex| match pass {
+            Pass::Forward => cpu.value(v),
+            Pass::Backward => cpu.grad(v),
+        };
+        for (i, &v) in node.inputs.iter().enumerate() {
+            if let Some(value) = v.and_then(|v| tensor(v)) {
+                let pos = node.get_input_pos(i);
+                preview::draw(value, [pos[0] - 8.0 - preview::WIDTH, pos[1]], c, gl, glyphs);
+            }
+        }
+        if self.previews.is_none() || pass == Pass::Backward {
+            for (j, &v) in node.outputs.iter().enumerate() {
+                if let Some(value) = tensor(v) {
+                    let pos = node.get_output_pos(j);
+                    preview::draw(value, [pos[0] + 8.0, pos[1]], c, gl, glyphs);
+                }
+            }
+        }
+    }
+
+    /// Build the graph for the CPU even if the GPU could run it, e.g. to look at gradients. The
+    /// variables keep the values they were trained to, on either backend.
+    pub fn build_for_cpu(&mut self, ctx: &matrix::Context) -> Result<(), String> {
+        self.unbuild();
+        self.read_gpu_values(ctx);
+        if self.previews.is_some() {
+            self.previews = Some(HashMap::new());
+        }
+        try!(self.infer_dtypes());
+        try!(self.infer_shapes());
+        try!(self.resolve_dims());
+        self.cpu_build()
+    }
+
+    /// Build the graph for the GPU if `dl` supports all of its operations, otherwise for the CPU.
+    /// Graphs checked for values that aren't finite are always built for the CPU. The variables
+    /// keep the values they were trained to, on either backend.
+    pub fn build(&mut self, ctx: &matrix::Context) -> Result<(), String> {
+        self.unbuild();
+        self.read_gpu_values(ctx);
+        if self.previews.is_some() {
+            self.previews = Some(HashMap::new());
+        }
+        try!(self.infer_dtypes());
+        try!(self.infer_shapes());
+        try!(self.resolve_dims());
+        if !self.check_finite && self.gpu_unsupported().is_none() {
+            self.gpu_build(ctx)
+        } else {
+            self.cpu_build()
+        }
+    }
 
-        for &(send_node, send_index, recv_node, recv_index) in &self.edges {
-            let start_pos = send_node.get(self).get_output_pos(send_index);
-            let end_pos = recv_node.get(self).get_input_pos(recv_index);
+    /// Drop the build after a change to what it was built from. What the variables were trained
+    /// to is kept as their initial values for the next build; values on the GPU need a context to
+    /// be read, so that's left to the next build.
+    fn unbuild(&mut self) {
+        match self.backend.take() {
+            Some(Backend::Cpu(cpu)) => {
+                for v in self.variables() {
+                    if let Some(value) = cpu.value(v) {
+                        v.get_mut(&mut self.vars).value = Some(value.clone());
+                    }
+                }
+            },
+            Some(Backend::Gpu) => { self.unread_gpu_values = true; },
+            None => { },
+        }
+        self.debugger = None;
+        self.anomaly = None;
+    }
 
-            Line::new([1.0, 0.0, 0.0, 1.0], 1.0).draw([start_pos[0], start_pos[1],
-                                                  end_pos[0], end_pos[1]],
-                                                 &c.draw_state, c.transform, gl);
+    /// Keep the values of the variables from a dropped GPU build as their initial values.
+    fn read_gpu_values(&mut self, ctx: &matrix::Context) {
+        if self.unread_gpu_values {
+            for v in self.variables() {
+                if let Some(value) = self.var_value(ctx, v) {
+                    v.get_mut(&mut self.vars).value = Some(value);
+                }
+            }
+            self.unread_gpu_values = false;
         }
+    }
 
+    /// Why the graph can't run on the GPU, if it can't.
+    fn gpu_unsupported(&self) -> Option<String> {
         for node in &self.nodes {
-            node.draw(c, gl);
+            if node.op.subgraph.is_some() {
+                continue;
+            }
+            if node.op.gpu_build.is_none() {
+                return Some(format!("Operation '{}' of node '{}' can't run on the GPU",
+                                    node.op.name, node.name()));
+            }
+            if let Some(&v) = node.outputs.iter().find(|v| v.get(&self.vars).dtype != DType::F32) {
+                return Some(format!("'{}' is {} but the GPU only supports f32",
+                                    self.var_name(v), v.get(&self.vars).dtype));
+            }
+            if weight_decay(node) != (WeightDecay { l1: 0.0, l2: 0.0 }) {
+                return Some(format!("Node '{}' has weight decay, which the GPU doesn't support", node.name()));
+            }
+            if node.attrs.get("trainable") == Some(&AttrValue::Int(0)) {
+                return Some(format!("Node '{}' is untrainable but the GPU trains every variable", node.name()));
+            }
+        }
+        None
+    }
+
+    fn gpu_build(&mut self, ctx: &matrix::Context) -> Result<(), String> {
+        let order = try!(self.build_order());
+        if let Some(e) = self.gpu_unsupported() {
+            return Err(e);
+        }
+        self.loss_grads.clear();
+        for node in order {
+            let node = &self.nodes[node.0];
+            let build = node.op.gpu_build.as_ref().unwrap();
+            let gpu_node = build(ctx, &mut self.graph, &mut self.vars, &node.inputs, &node.outputs);
+            if node.op.loss {
+                let gpu_node = try!(gpu_node.ok_or(format!("Loss '{}' didn't add a node to the graph",
+                                                           node.name())));
+                let gradient = self.graph.add_gradient(ctx, gpu_node, 0);
+                self.loss_grads.push((gradient, node.outputs[0].get(&self.vars).shape_2d()));
+            }
+        }
+        self.backend = Some(Backend::Gpu);
+        Ok(())
+    }
+
+    fn cpu_build(&mut self) -> Result<(), String> {
+        let order = try!(self.build_order());
+        for &node in &order {
+            let node = &self.nodes[node.0];
+            if !node.inputs.is_empty() && node.op.forward.is_none() {
+                return Err(format!("Operation '{}' of node '{}' can't run on the CPU",
+                                   node.op.name, node.name()));
+            }
+        }
+        let mut cpu = CpuGraph::new(order, &self.nodes, &self.vars);
+        cpu.check_finite = self.check_finite;
+        self.backend = Some(Backend::Cpu(cpu));
+        Ok(())
+    }
+
+    pub fn backend(&self) -> Option<&Backend> {
+        self.backend.as_ref()
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Set what `run` runs the graph for.
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+    }
+
+    /// Run the graph forward once.
+    pub fn run(&mut self, ctx: &matrix::Context) -> Result<(), String> {
+        self.debugger = None;
+        self.anomaly = None;
+        let checked = match self.backend {
+            Some(Backend::Gpu) => {
+                // `dl` always applies the gradients, so make them zero
+                self.seed_gpu_losses(ctx, 0.0);
+                self.graph.run(ctx);
+                Ok(())
+            },
+            Some(Backend::Cpu(ref mut cpu)) => cpu.forward(&self.nodes, &self.vars, self.mode),
+            None => { return Err("The graph isn't built".to_string()); },
+        };
+        if self.previews.is_some() {
+            self.update_previews(ctx);
+        }
+        self.note_anomaly(checked)
+    }
+
+    pub fn checks_finite(&self) -> bool {
+        self.check_finite
+    }
+
+    /// Check what every node gives when the graph runs, stopping at the first value or gradient
+    /// that isn't finite. The GPU runs the whole graph at once, so only graphs built for the CPU
+    /// can be checked: a GPU build is dropped, and has to be built again for the CPU.
+    pub fn set_check_finite(&mut self, check: bool) {
+        self.check_finite = check;
+        let on_gpu = match self.backend {
+            Some(Backend::Cpu(ref mut cpu)) => {
+                cpu.check_finite = check;
+                false
+            },
+            Some(Backend::Gpu) => true,
+            None => false,
+        };
+        if check && on_gpu {
+            self.unbuild();
+        }
+    }
+
+    pub fn anomaly(&self) -> Option<&Anomaly> {
+        self.anomaly.as_ref()
+    }
+
+    /// Keep the anomaly a check found, if any, and give a report of it as the error.
+    fn note_anomaly(&mut self, checked: Result<(), Anomaly>) -> Result<(), String> {
+        match checked {
+            Ok(()) => Ok(()),
+            Err(anomaly) => {
+                self.anomaly = Some(anomaly);
+                Err(self.anomaly_report(&anomaly))
+            },
+        }
+    }
+
+    /// Which node gave a value that isn't finite, with statistics of its inputs, and in the
+    /// backward pass of the gradients of its outputs. Only the CPU finds anomalies, so the values
+    /// are the CPU's.
+    pub fn anomaly_report(&self, anomaly: &Anomaly) -> String {
+        let node = &self.nodes[anomaly.node.0];
+        let what = match anomaly.pass {
+            Pass::Forward => "value",
+            Pass::Backward => "gradient",
+        };
+        let mut lines = vec![format!("The {} of '{}' from node '{}' ({}) isn't finite", what,
+                                     self.var_name(anomaly.var), node.name(), node.op.name)];
+        for &v in node.inputs.iter().filter_map(|v| v.as_ref()) {
+            let value = match self.backend {
+                Some(Backend::Cpu(ref cpu)) => cpu.value(v),
+                _ => None,
+            };
+            lines.push(match value {
+                Some(value) => format!("  input '{}' {:?}: {}", self.var_name(v), value.shape, value.statistics()),
+                None => format!("  input '{}' has no value", self.var_name(v)),
+            });
+        }
+        if anomaly.pass == Pass::Backward {
+            for &v in &node.outputs {
+                if let Some(grad) = self.grad(v) {
+                    lines.push(format!("  gradient of output '{}' {:?}: {}", self.var_name(v), grad.shape,
+                                       grad.statistics()));
+                }
+            }
+        }
+        lines.join("\n")
+    }
+
+    pub fn shows_previews(&self) -> bool {
+        self.previews.is_some()
+    }
+
+    /// Show or hide previews of the value of every output next to its port. They're updated after
+    /// every run.
+    pub fn set_show_previews(&mut self, ctx: &matrix::Context, show: bool) {
+        if show {
+            self.update_previews(ctx);
+        } else {
+            self.previews = None;
+        }
+    }
+
+    fn update_previews(&mut self, ctx: &matrix::Context) {
+        let mut previews = HashMap::new();
+        if self.backend.is_some() {
+            for v in self.nodes.iter().flat_map(|node| node.outputs.iter()) {
+                if let Some(value) = self.var_value(ctx, *v) {
+                    previews.insert(*v, value);
+                }
+            }
+        }
+        self.previews = Some(previews);
+    }
+
+    /// Run the graph forward and back from every loss without changing any variables, so the
+    /// gradients can be looked at. Only the CPU keeps gradients.
+    pub fn backward(&mut self) -> Result<(), String> {
+        self.debugger = None;
+        self.anomaly = None;
+        let losses = self.losses();
+        if losses.is_empty() {
+            return Err("The graph has no loss to take gradients of".to_string());
+        }
+        let checked = match self.backend {
+            Some(Backend::Cpu(ref mut cpu)) => {
+                let mut checked = cpu.forward(&self.nodes, &self.vars, self.mode);
+                if checked.is_ok() {
+                    checked = cpu.backward(&self.nodes, &self.vars, &losses);
+                }
+                checked
+            },
+            Some(Backend::Gpu) => { return Err("Gradients are only kept when the graph is built for the CPU".to_string()); },
+            None => { return Err("The graph isn't built".to_string()); },
+        };
+        self.note_anomaly(checked)
+    }
+
+    /// Run one iteration of training: forward, backward from every loss, and a gradient descent
+    /// step on the variables. On the GPU `dl` does all of this in `run`, adding the gradients to
+    /// the variables, so the losses' gradients are seeded with the negative learning rate.
+    pub fn train_step(&mut self, ctx: &matrix::Context, learning_rate: f32) -> Result<(), String> {
+        let losses = self.losses();
+        if losses.is_empty() {
+            return Err("The graph has no loss to train".to_string());
+        }
+        let variables = self.trainable_variables();
+        self.debugger = None;
+        self.anomaly = None;
+        let checked = match self.backend {
+            Some(Backend::Gpu) => {
+                self.seed_gpu_losses(ctx, -learning_rate);
+                self.graph.run(ctx);
+                Ok(())
+            },
+            Some(Backend::Cpu(ref mut cpu)) => {
+                // Gradients aren't applied after a pass that went wrong
+                let mut checked = cpu.forward(&self.nodes, &self.vars, Mode::Train);
+                if checked.is_ok() {
+                    checked = cpu.backward(&self.nodes, &self.vars, &losses);
+                }
+                if checked.is_ok() {
+                    cpu.apply_gradients(&variables, learning_rate);
+                }
+                checked
+            },
+            None => { return Err("The graph isn't built".to_string()); },
+        };
+        self.note_anomaly(checked)
+    }
+
+    /// Start stepping through the graph from its first node, in the mode it runs in. Only graphs
+    /// built for the CPU can be stepped through.
+    pub fn start_debugging(&mut self) -> Result<(), String> {
+        let losses = self.losses();
+        match self.backend {
+            Some(Backend::Cpu(ref mut cpu)) => {
+                self.debugger = Some(Debugger::new(cpu, &self.nodes, &self.vars, self.mode, losses));
+                Ok(())
+            },
+            Some(Backend::Gpu) => Err("Only graphs built for the CPU can be stepped through".to_string()),
+            None => Err("The graph isn't built".to_string()),
         }
     }
 
-    pub fn gpu_build(&mut self, ctx: &matrix::Context) {
-        for node in &mut self.nodes {
-            (node.op.build)(ctx, &mut self.graph, &mut self.vars, &node.inputs, &node.outputs);
+    pub fn stop_debugging(&mut self) {
+        self.debugger = None;
+    }
+
+    pub fn debugger(&self) -> Option<&Debugger> {
+        self.debugger.as_ref()
+    }
+
+    pub fn debugger_mut(&mut self) -> Option<&mut Debugger> {
+        self.debugger.as_mut()
+    }
+
+    /// The node the debugger runs next.
+    pub fn debug_next(&self) -> Option<NodeId> {
+        match (&self.debugger, &self.backend) {
+            (&Some(ref debugger), &Some(Backend::Cpu(ref cpu))) => debugger.next(cpu),
+            _ => None,
+        }
+    }
+
+    /// Run the next node, or with `resume` every node up to the next breakpoint.
+    pub fn debug_step(&mut self, ctx: &matrix::Context, resume: bool) -> Result<Stop, String> {
+        let stop = match (&mut self.debugger, &mut self.backend) {
+            (&mut Some(ref mut debugger), &mut Some(Backend::Cpu(ref mut cpu))) => {
+                if resume {
+                    debugger.resume(cpu, &self.nodes, &self.vars, &self.breakpoints)
+                } else {
+                    debugger.step(cpu, &self.nodes, &self.vars)
+                }
+            },
+            _ => return Err("The debugger isn't running".to_string()),
+        };
+        if self.previews.is_some() {
+            self.update_previews(ctx);
+        }
+        Ok(stop)
+    }
+
+    pub fn has_breakpoint(&self, node: NodeId) -> bool {
+        self.breakpoints.contains(&node)
+    }
+
+    pub fn toggle_breakpoint(&mut self, node: NodeId) {
+        match self.breakpoints.iter().position(|&n| n == node) {
+            Some(i) => { self.breakpoints.remove(i); },
+            None => { self.breakpoints.push(node); },
+        }
+    }
+
+    fn seed_gpu_losses(&self, ctx: &matrix::Context, seed: f32) {
+        for &(gradient, (rows, columns)) in &self.loss_grads {
+            gradient.get(&self.graph).set(ctx, &matrix::Matrix::from_vec(rows, columns, vec![seed; rows*columns]));
+        }
+    }
+
+    /// The outputs of loss nodes, which training minimizes.
+    pub fn losses(&self) -> Vec<VarIndex> {
+        self.nodes.iter()
+                  .filter(|node| node.op.loss)
+                  .map(|node| node.outputs[0])
+                  .collect()
+    }
+
+    /// Work out the types of the outputs of every node whose inputs are all connected, checking
+    /// that each node accepts the types of its inputs.
+    pub fn infer_dtypes(&mut self) -> Result<(), String> {
+        for id in try!(self.topological_order()) {
+            let node = &self.nodes[id.0];
+            let infer_dtypes = match node.op.infer_dtypes {
+                Some(ref infer_dtypes) => infer_dtypes,
+                None => continue,
+            };
+            if node.inputs.iter().any(|input| input.is_none()) {
+                continue;
+            }
+            let in_dtypes: Vec<DType> = node.inputs.iter()
+                                            .map(|input| input.unwrap().get(&self.vars).dtype)
+                                            .collect();
+            let out_dtypes = try!(infer_dtypes(&node.attrs, &in_dtypes)
+                                      .map_err(|e| format!("Node '{}': {}", node.name(), e)));
+            for (&v, dtype) in node.outputs.iter().zip(out_dtypes) {
+                v.get_mut(&mut self.vars).dtype = dtype;
+            }
+        }
+        Ok(())
+    }
+
+    /// Work out the shapes of the outputs of every node whose inputs are all connected, from the
+    /// shapes of the variables feeding the graph.
+    pub fn infer_shapes(&mut self) -> Result<(), String> {
+        for id in try!(self.topological_order()) {
+            let node = &self.nodes[id.0];
+            let infer_shapes = match node.op.infer_shapes {
+                Some(ref infer_shapes) => infer_shapes,
+                None => continue,
+            };
+            if node.inputs.iter().any(|input| input.is_none()) {
+                continue;
+            }
+            let in_shapes: Vec<Shape> = node.inputs.iter()
+                                               .map(|input| input.unwrap().get(&self.vars).shape.clone())
+                                               .collect();
+            let out_shapes = try!(infer_shapes(&node.attrs, &in_shapes)
+                                      .map_err(|e| format!("Node '{}': {}", node.name(), e)));
+            for (&v, shape) in node.outputs.iter().zip(out_shapes) {
+                let var = v.get_mut(&mut self.vars);
+                var.dims = shape.resolve(&self.dim_vars).unwrap_or(vec![]);
+                var.shape = shape;
+            }
         }
+        Ok(())
+    }
+
+    /// Give every variable the sizes of its dimensions, failing if a symbolic dimension has no
+    /// size yet or has one the operations using it can't take, e.g. a sequence shorter than the
+    /// timestep taken from it.
+    fn resolve_dims(&mut self) -> Result<(), String> {
+        for v in self.nodes.iter().flat_map(|node| node.outputs.iter()) {
+            let var = v.get_mut(&mut self.vars);
+            let dims = try!(var.shape.resolve(&self.dim_vars)
+                               .map_err(|e| format!("'{}' has shape {}: {}", var.name, var.shape, e)));
+            // A value kept from before a dimension changed size doesn't fit any more
+            if var.value.as_ref().map_or(false, |value| value.shape != dims) {
+                var.value = None;
+            }
+            var.dims = dims;
+        }
+
+        // Shapes were inferred knowing only the sizes that aren't symbolic, so check them again
+        for node in &self.nodes {
+            let infer_shapes = match node.op.infer_shapes {
+                Some(ref infer_shapes) => infer_shapes,
+                None => continue,
+            };
+            if node.inputs.iter().any(|input| input.is_none()) {
+                continue;
+            }
+            let in_shapes: Vec<Shape> = node.inputs.iter()
+                                               .map(|input| Shape::from_dims(&input.unwrap().get(&self.vars).dims))
+                                               .collect();
+            try!(infer_shapes(&node.attrs, &in_shapes).map_err(|e| format!("Node '{}': {}", node.name(), e)));
+        }
+        Ok(())
+    }
+
+    /// Declare the shape of a variable. Values it was given or trained to that don't fit the new
+    /// shape are dropped, along with the build.
+    pub fn set_var_shape(&mut self, v: VarIndex, shape: Shape) {
+        self.unbuild();
+        {
+            let var = v.get_mut(&mut self.vars);
+            if var.shape != shape {
+                var.gpu = None;
+            }
+            if var.value.as_ref().map_or(false, |value| !shape.matches(&value.shape)) {
+                var.value = None;
+            }
+            var.dims = shape.resolve(&self.dim_vars).unwrap_or(vec![]);
+            var.shape = shape;
+        }
+        self.changed = true;
+    }
+
+    /// Set the size of a symbolic dimension, e.g. the batch size.
+    pub fn set_dim(&mut self, name: &str, size: usize) -> DimVar {
+        self.changed = true;
+        match self.dim_by_name(name) {
+            Some(dim) => {
+                self.dim_vars[dim.0].1 = size;
+                dim
+            },
+            None => {
+                self.dim_vars.push((name.to_string(), size));
+                DimVar(self.dim_vars.len()-1)
+            },
+        }
+    }
+
+    /// The symbolic dimensions given a size, with their sizes.
+    pub fn dims(&self) -> &[(String, usize)] {
+        &self.dim_vars
+    }
+
+    pub fn dim_by_name(&self, name: &str) -> Option<DimVar> {
+        self.dim_vars.iter().position(|&(ref n, _)| n == name).map(DimVar)
+    }
+
+    /// The order to build nodes in, after checking that every node can be built.
+    pub fn build_order(&self) -> Result<Vec<NodeId>, String> {
+        for node in self.nodes.iter().filter(|node| node.op.subgraph.is_none()) {
+            if node.op.placeholder {
+                return Err(format!("Node '{}' uses unsupported operation '{}'", node.name(), node.op.name));
+            }
+            if let Some(i) = node.inputs.iter().position(|input| input.is_none()) {
+                return Err(format!("Input {} of node '{}' isn't connected", i, node.name()));
+            }
+        }
+        self.topological_order()
+    }
+
+    /// The nodes ordered so that every node comes after the nodes feeding it. Composite nodes
+    /// are left out, the nodes inside them are in it.
+    pub fn topological_order(&self) -> Result<Vec<NodeId>, String> {
+        let mut in_degree = vec![0; self.nodes.len()];
+        for &(_, _, recv, _) in &self.edges {
+            in_degree[recv.0] += 1;
+        }
+
+        let num_nodes = self.nodes.iter().filter(|node| node.op.subgraph.is_none()).count();
+        let mut ready: Vec<usize> = (0..self.nodes.len()).filter(|&n| in_degree[n] == 0 && self.nodes[n].op.subgraph.is_none())
+                                                         .rev()
+                                                         .collect();
+        let mut order = Vec::with_capacity(num_nodes);
+        while let Some(n) = ready.pop() {
+            order.push(NodeId(n));
+            for &(send, _, recv, _) in &self.edges {
+                if send.0 == n {
+                    in_degree[recv.0] -= 1;
+                    if in_degree[recv.0] == 0 {
+                        ready.push(recv.0);
+                    }
+                }
+            }
+        }
+
+        if order.len() < num_nodes {
+            return Err("The graph contains a cycle".to_string());
+        }
+        Ok(order)
+    }
+
+    /// The current value of a variable. Once the graph is built this is read back from the
+    /// backend, including a GPU build dropped after it trained the variable. Otherwise it's the
+    /// variable's initial value, if it has one.
+    pub fn var_value(&self, ctx: &matrix::Context, v: VarIndex) -> Option<Tensor> {
+        let var = v.get(&self.vars);
+        let on_gpu = match self.backend {
+            Some(Backend::Gpu) => true,
+            Some(Backend::Cpu(_)) => false,
+            None => self.unread_gpu_values,
+        };
+        match (&self.backend, var.gpu) {
+            (&Some(Backend::Cpu(ref cpu)), _) => cpu.value(v).cloned(),
+            (_, Some(gpu)) if on_gpu => {
+                let mut value = Tensor::from_matrix(&gpu.get(&self.graph).get(ctx));
+                value.shape = var.dims.clone();
+                Some(value)
+            },
+            _ => var.value.clone(),
+        }
+    }
+
+    pub fn nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+
+    pub fn node_ids(&self) -> Vec<NodeId> {
+        (0..self.nodes.len()).map(NodeId).collect()
+    }
+
+    /// Note a change made to the nodes directly, such as to their attributes.
+    pub fn mark_changed(&mut self) {
+        self.changed = true;
+    }
+
+    /// Whether the graph changed since this was last called. A new graph counts as changed.
+    pub fn take_changed(&mut self) -> bool {
+        let changed = self.changed;
+        self.changed = false;
+        changed
+    }
+
+    /// The float outputs of nodes without inputs that aren't marked untrainable, i.e. the values
+    /// that get trained, with their weight decay. Variables an operation updates itself, such as
+    /// BatchNorm's running statistics, are never trained.
+    pub fn trainable_variables(&self) -> Vec<(VarIndex, WeightDecay)> {
+        let updated = self.updated_variables();
+        self.nodes.iter()
+                  .filter(|node| node.inputs.is_empty() && node.op.subgraph.is_none())
+                  .filter(|node| node.attrs.get("trainable") != Some(&AttrValue::Int(0)))
+                  .flat_map(|node| node.outputs.iter().map(move |&v| (v, weight_decay(node))))
+                  .filter(|&(v, _)| v.get(&self.vars).dtype.is_float() && !updated.contains(&v))
+                  .collect()
+    }
+
+    /// The variables connected to inputs that operations replace while training.
+    fn updated_variables(&self) -> Vec<VarIndex> {
+        self.nodes.iter()
+                  .flat_map(|node| node.op.updates.iter().filter_map(move |&(_, input)| node.inputs[input]))
+                  .collect()
+    }
+
+    /// The outputs of nodes without inputs.
+    pub fn variables(&self) -> Vec<VarIndex> {
+        self.nodes.iter()
+                  .filter(|node| node.inputs.is_empty() && node.op.subgraph.is_none())
+                  .flat_map(|node| node.outputs.iter().cloned())
+                  .collect()
+    }
+
+    pub fn var_name(&self, v: VarIndex) -> String {
+        v.get(&self.vars).name.clone()
+    }
+
+    /// Snapshot the current values of every variable.
+    pub fn checkpoint(&self, ctx: &matrix::Context) -> Checkpoint {
+        let mut checkpoint = Checkpoint::new();
+        for v in self.variables() {
+            if let Some(value) = self.var_value(ctx, v) {
+                checkpoint.tensors.push((self.var_name(v), value));
+            }
+        }
+        checkpoint
+    }
+
+    /// Give every variable its value from `checkpoint`. If the graph is built the values are
+    /// given to the backend right away, and they're also kept as the variables' initial values for the next
+    /// build. Nothing is changed unless every variable is in the checkpoint with the right shape.
+    pub fn restore_checkpoint(&mut self, ctx: &matrix::Context, checkpoint: &Checkpoint) -> Result<(), String> {
+        try!(self.restore_values(checkpoint));
+        if let Some(Backend::Gpu) = self.backend {
+            for v in self.variables() {
+                let var = v.get(&self.vars);
+                if let (Some(gpu), Some(value)) = (var.gpu, var.value.as_ref()) {
+                    gpu.get(&self.graph).set(ctx, &value.to_matrix());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// What `restore_checkpoint` does, except for giving a GPU build the values.
+    fn restore_values(&mut self, checkpoint: &Checkpoint) -> Result<(), String> {
+        let mut values = vec![];
+        for v in self.variables() {
+            let name = self.var_name(v);
+            let value = match checkpoint.get(&name) {
+                Some(value) => value,
+                None => { return Err(format!("Checkpoint has no value for '{}'", name)); },
+            };
+            let shape = &v.get(&self.vars).shape;
+            if !shape.matches(&value.shape) {
+                return Err(format!("'{}' has shape {} but the checkpoint has {:?}",
+                                   name, shape, value.shape));
+            }
+            try!(v.get(&self.vars).dtype.check_values(&value.data).map_err(|e| format!("'{}': {}", name, e)));
+            values.push((v, value.clone()));
+        }
+
+        for (v, value) in values {
+            if let Some(Backend::Cpu(ref mut cpu)) = self.backend {
+                cpu.set_value(v, value.clone());
+            }
+            v.get_mut(&mut self.vars).value = Some(value);
+        }
+        Ok(())
+    }
+}
+
+/// The colour showing a mean gradient magnitude: magnitudes from 1e-6 to 1 go from blue to red,
+/// no gradient is grey.
+fn gradient_color(magnitude: Option<f32>) -> [f32; 4] {
+    match magnitude {
+        Some(magnitude) => {
+            let t = ((magnitude.log10() + 6.0) / 6.0).max(0.0).min(1.0);
+            [t, 0.0, 1.0 - t, 1.0]
+        },
+        None => [0.6, 0.6, 0.6, 1.0],
+    }
+}
+
+/// The rectangle spanned by the corners of a selection box.
+fn band_rect((start, end): ([f64; 2], [f64; 2])) -> [f64; 4] {
+    [start[0].min(end[0]), start[1].min(end[1]), (end[0] - start[0]).abs(), (end[1] - start[1]).abs()]
+}
+
+/// The distance from `p` to the line segment from `a` to `b`.
+fn distance_to_segment(p: [f64; 2], a: [f64; 2], b: [f64; 2]) -> f64 {
+    let ab = [b[0] - a[0], b[1] - a[1]];
+    let length_squared = ab[0]*ab[0] + ab[1]*ab[1];
+    let t = if length_squared == 0.0 {
+        0.0
+    } else {
+        (((p[0] - a[0])*ab[0] + (p[1] - a[1])*ab[1]) / length_squared).max(0.0).min(1.0)
+    };
+    let closest = [a[0] + t*ab[0], a[1] + t*ab[1]];
+    ((p[0] - closest[0]).powi(2) + (p[1] - closest[1]).powi(2)).sqrt()
+}
+
+/// The port of a group's stand-in node to draw an edge to or from port `index` of one of its
+/// hidden members at, when the group doesn't say. The stand-in may have fewer ports.
+fn shown_port(index: usize, num_ports: usize) -> usize {
+    if index < num_ports { index } else { 0 }
+}
+
+/// The weight decay set by a node's `l1` and `l2` attributes, if it has them.
+fn weight_decay(node: &Node) -> WeightDecay {
+    let strength = |name| {
+        match node.attrs.get(name) {
+            Some(&AttrValue::Float(f)) => f,
+            _ => 0.0,
+        }
+    };
+    WeightDecay {
+        l1: strength("l1"),
+        l2: strength("l2"),
+    }
+}
+
+/// Output variables are named after their node, with the port name appended if there's more than
+/// one output.
+fn output_name(node_name: &str, port: &str, num_outputs: usize) -> String {
+    if num_outputs == 1 {
+        node_name.to_string()
+    } else {
+        format!("{}:{}", node_name, port)
     }
 }
 
@@ -139,6 +2035,14 @@ impl NodeId {
     pub fn get<'a>(&self, graph: &'a GraphBuilder) -> &'a Node {
         &graph.nodes[self.0]
     }
+
+    pub fn get_mut<'a>(&self, graph: &'a mut GraphBuilder) -> &'a mut Node {
+        &mut graph.nodes[self.0]
+    }
+
+    pub fn index(&self) -> usize {
+        self.0
+    }
 }
 
 #[derive(Copy, Clone, PartialEq)]
@@ -146,6 +2050,205 @@ pub struct DimVar(usize);
 
 impl DimVar {
     pub fn get<'a>(&self, graph: &'a GraphBuilder) -> &'a usize {
-        &graph.dim_vars[self.0]
+        &graph.dim_vars[self.0].1
+    }
+
+    pub fn name<'a>(&self, graph: &'a GraphBuilder) -> &'a str {
+        &graph.dim_vars[self.0].0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Backend, GraphBuilder, Mode};
+    use super::super::catalog::OpCatalog;
+    use super::super::checkpoint::Checkpoint;
+    use super::super::cpu::{Anomaly, CpuGraph, Pass};
+    use super::super::graph_text;
+    use super::super::shape::Shape;
+    use super::super::tensor::Tensor;
+
+    fn graph(text: &str) -> GraphBuilder {
+        let mut graph = GraphBuilder::new();
+        graph_text::read(text, &mut graph, &OpCatalog::builtin()).unwrap();
+        graph
+    }
+
+    /// What `build_for_cpu` does, without the context it only needs for GPU builds.
+    fn build_for_cpu(graph: &mut GraphBuilder) {
+        graph.infer_dtypes().unwrap();
+        graph.infer_shapes().unwrap();
+        graph.resolve_dims().unwrap();
+        graph.cpu_build().unwrap();
+    }
+
+    fn cpu(graph: &mut GraphBuilder) -> &mut CpuGraph {
+        match graph.backend {
+            Some(Backend::Cpu(ref mut cpu)) => cpu,
+            _ => panic!("not built for the CPU"),
+        }
+    }
+
+    #[test]
+    fn changing_edges_drops_the_build_but_keeps_values() {
+        let mut graph = graph("a = Variable(shape=[2])\nr = ReLU(a)\n");
+        let (a, r) = (graph.var_by_name("a").unwrap(), graph.node_by_name("r").unwrap());
+        build_for_cpu(&mut graph);
+        cpu(&mut graph).set_value(a, Tensor::new(vec![2], vec![1.0, -1.0]));
+
+        graph.disconnect(r, 0);
+        assert!(graph.backend().is_none());
+        assert_eq!(a.get(&graph.vars).value, Some(Tensor::new(vec![2], vec![1.0, -1.0])));
+        assert!(graph.build_order().is_err());
+
+        let a_node = graph.node_by_name("a").unwrap();
+        graph.connect(a_node, 0, r, 0);
+        assert!(graph.backend().is_none());
+        build_for_cpu(&mut graph);
+        let out = r.get(&graph).outputs[0];
+        match graph.backend {
+            Some(Backend::Cpu(ref mut cpu)) => {
+                assert!(cpu.forward(&graph.nodes, &graph.vars, Mode::Eval).is_ok());
+                assert_eq!(cpu.value(out), Some(&Tensor::new(vec![2], vec![1.0, 0.0])));
+            },
+            _ => panic!("not built for the CPU"),
+        }
+    }
+
+    #[test]
+    fn removing_nodes_keeps_the_values_of_the_others() {
+        let mut graph = graph("a = Variable(shape=[2])\nr = ReLU(a)\nb = Variable(shape=[3])\n");
+        let a = graph.var_by_name("a").unwrap();
+        build_for_cpu(&mut graph);
+        cpu(&mut graph).set_value(a, Tensor::new(vec![2], vec![3.0, 4.0]));
+
+        let b = graph.node_by_name("b").unwrap();
+        graph.remove_nodes(&[b]);
+        assert!(graph.backend().is_none());
+        assert!(graph.var_by_name("b").is_none());
+        assert_eq!(a.get(&graph.vars).value, Some(Tensor::new(vec![2], vec![3.0, 4.0])));
+    }
+
+    const RNN: &'static str = "x = Variable(shape=[2, 1, 3])\n\
+                               h0 = Variable(shape=[1, 4])\n\
+                               w = Variable(shape=[3, 4])\n\
+                               u = Variable(shape=[4, 4])\n\
+                               b = Variable(shape=[4])\n\
+                               h = RNNCell(x, h0, w, u, b)\n\
+                               y = ReLU(h)\n";
+
+    #[test]
+    fn unrolls_cells() {
+        let mut graph = graph(RNN);
+        let h = graph.node_by_name("h").unwrap();
+        let stack = graph.unroll(h, 2, &OpCatalog::builtin()).unwrap();
+        let h1 = graph.node_by_name("h_1").unwrap();
+        let y = graph.node_by_name("y").unwrap();
+        assert!(graph.input_edge(y, 0) == Some((h1, 0)));
+        assert!(graph.input_edge(h1, 1) == Some((h, 0)));
+        assert!(graph.input_edge(stack, 1) == Some((h1, 0)));
+        assert!(graph.is_hidden(h1));
+    }
+
+    #[test]
+    fn puts_the_graph_back_when_unrolling_fails() {
+        let mut graph = graph(RNN);
+        let (x, h, y) = (graph.node_by_name("x").unwrap(), graph.node_by_name("h").unwrap(),
+                         graph.node_by_name("y").unwrap());
+        let nodes = graph.node_ids().len();
+        assert!(graph.unroll(h, 3, &OpCatalog::builtin()).unwrap_err().contains("outside a sequence of 2 steps"));
+        assert_eq!(graph.node_ids().len(), nodes);
+        assert!(graph.node_by_name("h_t0").is_none());
+        assert!(graph.input_edge(h, 0) == Some((x, 0)));
+        assert!(graph.input_edge(y, 0) == Some((h, 0)));
+        assert!(graph.unroll(h, 2, &OpCatalog::builtin()).is_ok());
+    }
+
+    #[test]
+    fn checks_symbolic_dimensions_once_they_have_sizes() {
+        let mut graph = graph("dim T = 2\nseq = Variable(shape=[T, 2])\ns = TimeStep(seq, t=3)\n");
+        graph.infer_dtypes().unwrap();
+        graph.infer_shapes().unwrap();
+        assert!(graph.resolve_dims().unwrap_err().contains("Timestep 3 is outside a sequence of 2 steps"));
+        graph.set_dim("T", 4);
+        assert!(graph.resolve_dims().is_ok());
+    }
+
+    #[test]
+    fn reports_values_that_arent_finite() {
+        let mut graph = graph("a = Variable(shape=[1, 2])\nt = Variable(shape=[1, 2])\nm = Mul(a, a)\nloss = MSE(m, t)\n");
+        let (a, m) = (graph.var_by_name("a").unwrap(), graph.node_by_name("m").unwrap());
+        graph.set_check_finite(true);
+        build_for_cpu(&mut graph);
+        cpu(&mut graph).set_value(a, Tensor::new(vec![1, 2], vec![::std::f32::INFINITY, 1.0]));
+        let checked = match graph.backend {
+            Some(Backend::Cpu(ref mut cpu)) => cpu.forward(&graph.nodes, &graph.vars, Mode::Eval),
+            _ => unreachable!(),
+        };
+        let report = graph.note_anomaly(checked).unwrap_err();
+        assert!(graph.anomaly().map(|anomaly| anomaly.node) == Some(m));
+        assert!(report.starts_with("The value of 'm' from node 'm' (Mul) isn't finite\n  input 'a' [1, 2]: "));
+
+        cpu(&mut graph).set_value(a, Tensor::new(vec![1, 2], vec![2.0, 1.0]));
+        let losses = graph.losses();
+        let checked = match graph.backend {
+            Some(Backend::Cpu(ref mut cpu)) => {
+                assert!(cpu.forward(&graph.nodes, &graph.vars, Mode::Eval).is_ok());
+                cpu.backward(&graph.nodes, &graph.vars, &losses)
+            },
+            _ => unreachable!(),
+        };
+        assert!(graph.note_anomaly(checked).is_ok());
+        let report = graph.anomaly_report(&Anomaly { node: m, var: a, pass: Pass::Backward });
+        assert!(report.starts_with("The gradient of 'a' from node 'm' (Mul) isn't finite"));
+        assert!(report.contains("\n  gradient of output 'm' [1, 2]: "));
+    }
+
+    #[test]
+    fn checking_values_drops_gpu_builds() {
+        let mut graph = graph("a = Variable(shape=[2])\n");
+        graph.backend = Some(Backend::Gpu);
+        graph.set_check_finite(false);
+        assert!(graph.backend().is_some());
+        graph.set_check_finite(true);
+        assert!(graph.backend().is_none());
+    }
+
+    #[test]
+    fn reshaping_drops_values_that_dont_fit() {
+        let mut graph = graph("a = Variable(shape=[2])\nb = Variable(shape=[2])\n");
+        let (a, b) = (graph.var_by_name("a").unwrap(), graph.var_by_name("b").unwrap());
+        build_for_cpu(&mut graph);
+
+        graph.set_var_shape(a, Shape::from_dims(&[2]));
+        assert!(graph.backend().is_none());
+        assert!(a.get(&graph.vars).value.is_some());
+        graph.set_var_shape(b, Shape::from_dims(&[3]));
+        assert!(b.get(&graph.vars).value.is_none());
+    }
+
+    #[test]
+    fn restoring_checks_shapes() {
+        let mut graph = graph("w = Variable(shape=[3, 2])\n");
+        let v = graph.var_by_name("w").unwrap();
+        let mut checkpoint = Checkpoint::new();
+        checkpoint.tensors.push(("w".to_string(), Tensor::new(vec![2, 3], vec![0.0; 6])));
+        assert!(graph.restore_values(&checkpoint).is_err());
+        assert!(v.get(&graph.vars).value.is_none());
+
+        checkpoint.tensors[0].1 = Tensor::new(vec![3, 2], vec![1.0; 6]);
+        graph.restore_values(&checkpoint).unwrap();
+        assert_eq!(v.get(&graph.vars).value, Some(Tensor::new(vec![3, 2], vec![1.0; 6])));
+    }
+
+    #[test]
+    fn restoring_gives_cpu_builds_the_values() {
+        let mut graph = graph("w = Variable(shape=[2])\n");
+        let v = graph.var_by_name("w").unwrap();
+        build_for_cpu(&mut graph);
+        let mut checkpoint = Checkpoint::new();
+        checkpoint.tensors.push(("w".to_string(), Tensor::new(vec![2], vec![4.0, 5.0])));
+        graph.restore_values(&checkpoint).unwrap();
+        assert_eq!(cpu(&mut graph).value(v), Some(&Tensor::new(vec![2], vec![4.0, 5.0])));
     }
 }
@@ -0,0 +1,572 @@
+//! Graphs written as text, for reading and diffing models in code review:
+//!
+//! ```text
+//! dim N = 32
+//! x = Variable(shape=[N, 784])
+//! w1 = Variable(shape=[784, 128], l2=0.001)
+//! h = ReLU(MatMul(x, w1))
+//! y = Variable(shape=[N, 128])
+//! d = Dropout(h, rate=0.3)
+//! loss = MSE(d, y)
+//! ```
+//!
+//! Each line names a node and says what it computes. Arguments are the node's inputs, in order
+//! or by port name, with `_` for inputs left unconnected, and `NAME=VALUE` for attributes that
+//! aren't the operation's default. A node stands for its first output; `node.port` picks another
+//! one. Nodes without inputs also give the shape of their output. Operation names are matched
+//! ignoring case.
+//!
+//! Nodes whose names were made up from their operation and which feed a single input are written
+//! inline, as `MatMul` is above, so reading text back and writing it again gives the same text.
+//! Where the nodes were placed is kept in a separate layout file, so moving nodes around doesn't
+//! change the graph's text. Subgraphs aren't kept: their nodes are written like any other.
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+use super::catalog::{self, OpCatalog};
+use super::graph_builder::{GraphBuilder, NodeId};
+use super::op::{AttrValue, Operation};
+use super::script::{self, Token};
+use super::shape::{Dim, Shape};
+
+pub const EXTENSION: &'static str = "graph";
+pub const LAYOUT_EXTENSION: &'static str = "layout";
+
+/// Write the graph to `path` and where its nodes are to the layout file next to it.
+pub fn save(graph: &GraphBuilder, path: &Path) -> Result<(), String> {
+    let text = try!(write(graph));
+    try!(File::create(path).and_then(|mut f| f.write_all(text.as_bytes())).map_err(|e| e.to_string()));
+    let layout = write_layout(graph);
+    File::create(path.with_extension(LAYOUT_EXTENSION)).and_then(|mut f| f.write_all(layout.as_bytes()))
+                                                       .map_err(|e| e.to_string())
+}
+
+/// Add the graph in `path` to `graph`, placing its nodes as the layout file next to it says.
+/// Nodes the layout file doesn't mention, or all of them if there's no layout file, are laid out
+/// automatically.
+pub fn load(path: &Path, graph: &mut GraphBuilder, catalog: &OpCatalog) -> Result<(), String> {
+    let mut text = String::new();
+    try!(File::open(path).and_then(|mut f| f.read_to_string(&mut text)).map_err(|e| e.to_string()));
+    try!(read(&text, graph, catalog).map_err(|e| format!("{}: {}", path.display(), e)));
+
+    graph.auto_layout();
+    let mut layout = String::new();
+    if File::open(path.with_extension(LAYOUT_EXTENSION)).and_then(|mut f| f.read_to_string(&mut layout)).is_ok() {
+        read_layout(&layout, graph);
+    }
+    Ok(())
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////
+// Writing
+
+/// The graph as text. Nodes are written in the order they're computed in.
+pub fn write(graph: &GraphBuilder) -> Result<String, String> {
+    write_nodes(graph, &graph.node_ids())
+}
+
+/// Some of the graph's nodes as text, with the edges between them. Inputs coming from nodes
+/// that aren't written are left unconnected.
+pub fn write_nodes(graph: &GraphBuilder, nodes: &[NodeId]) -> Result<String, String> {
+    let order = try!(graph.topological_order());
+    let mut written = vec![false; graph.nodes().len()];
+    for node in nodes {
+        written[node.index()] = true;
+    }
+    let inline = inlined_nodes(graph, &written);
+
+    let mut text = String::new();
+    for &(ref name, size) in graph.dims() {
+        text.push_str(&format!("dim {} = {}\n", ident(name), size));
+    }
+    for node in order.into_iter().filter(|node| written[node.index()] && !inline[node.index()]) {
+        text.push_str(&format!("{} = {}\n", ident(node.get(graph).name()), expr(graph, node, &written, &inline)));
+    }
+    Ok(text)
+}
+
+/// Which of the written nodes are written inline: those named after their operation, with a
+/// single output feeding a single written input.
+fn inlined_nodes(graph: &GraphBuilder, written: &[bool]) -> Vec<bool> {
+    let mut uses = vec![0; graph.nodes().len()];
+    for &(send, _, recv, _) in graph.edges() {
+        if written[recv.index()] {
+            uses[send.index()] += 1;
+        }
+    }
+    graph.nodes().iter().enumerate().map(|(i, node)| {
+        let base = node.op.name.to_lowercase().replace(' ', "_");
+        let generated = node.name().starts_with(&format!("{}_", base)) &&
+                        node.name()[base.len() + 1..].parse::<usize>().is_ok();
+        written[i] && generated && uses[i] == 1 && node.outputs.len() == 1 && node.op.subgraph.is_none()
+    }).collect()
+}
+
+fn expr(graph: &GraphBuilder, id: NodeId, written: &[bool], inline: &[bool]) -> String {
+    let node = id.get(graph);
+    let mut args = vec![];
+    for i in 0..node.inputs.len() {
+        args.push(match graph.input_edge(id, i) {
+            Some((send, _)) if !written[send.index()] => "_".to_string(),
+            Some((send, _)) if inline[send.index()] => expr(graph, send, written, inline),
+            Some((send, index)) => output(graph, send, index),
+            None => "_".to_string(),
+        });
+    }
+    if node.inputs.is_empty() {
+        if let Some(&v) = node.outputs.first() {
+            let dims: Vec<String> = v.get(&graph.vars).shape.dims().iter().map(|dim| match *dim {
+                Dim::Known(d) => d.to_string(),
+                Dim::Symbolic(ref name) => ident(name),
+            }).collect();
+            args.push(format!("shape=[{}]", dims.join(", ")));
+        }
+    }
+    for &(ref name, ref value) in node.attrs.iter() {
+        if node.op.attrs.get(name) != Some(value) {
+            args.push(format!("{}={}", name, attr_value(value)));
+        }
+    }
+    format!("{}({})", node.op.name, args.join(", "))
+}
+
+/// How output `index` of `node` is referred to.
+fn output(graph: &GraphBuilder, node: NodeId, index: usize) -> String {
+    let node = node.get(graph);
+    if index == 0 {
+        ident(node.name())
+    } else {
+        format!("{}.{}", ident(node.name()), ident(&node.op.outputs[index]))
+    }
+}
+
+fn attr_value(value: &AttrValue) -> String {
+    match *value {
+        AttrValue::Float(f) => f.to_string(),
+        AttrValue::Int(n) => n.to_string(),
+        AttrValue::Str(ref s) => format!("\"{}\"", s),
+        AttrValue::Ints(ref ints) => {
+            let ints: Vec<String> = ints.iter().map(|n| n.to_string()).collect();
+            format!("[{}]", ints.join(", "))
+        },
+    }
+}
+
+/// Names that aren't a single word are written in backquotes.
+fn ident(name: &str) -> String {
+    let word = name.chars().all(|c| c.is_alphanumeric() || c == '_') &&
+               name.chars().next().map_or(false, |c| !c.is_digit(10));
+    if word { name.to_string() } else { format!("`{}`", name) }
+}
+
+/// One line per node, `X Y NAME`.
+pub fn write_layout(graph: &GraphBuilder) -> String {
+    write_nodes_layout(graph, &graph.node_ids())
+}
+
+/// The layout of some of the graph's nodes.
+pub fn write_nodes_layout(graph: &GraphBuilder, nodes: &[NodeId]) -> String {
+    let mut layout = String::new();
+    for node in nodes.iter().map(|node| node.get(graph)) {
+        layout.push_str(&format!("{} {} {}\n", node.pos()[0], node.pos()[1], node.name()));
+    }
+    layout
+}
+
+/// Move the nodes named in `layout` to where it says, ignoring lines it can't read.
+pub fn read_layout(layout: &str, graph: &mut GraphBuilder) {
+    for line in layout.lines() {
+        let mut words = line.splitn(3, ' ');
+        let pos = (words.next().and_then(|x| x.parse().ok()), words.next().and_then(|y| y.parse().ok()));
+        if let ((Some(x), Some(y)), Some(name)) = (pos, words.next()) {
+            if let Some(node) = graph.node_by_name(name) {
+                node.get_mut(graph).set_pos([x, y]);
+            }
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////
+// Reading
+
+/// Add the nodes described by `text` to `graph`.
+pub fn read(text: &str, graph: &mut GraphBuilder, catalog: &OpCatalog) -> Result<(), String> {
+    let tokens = try!(script::tokenize(text));
+    let mut reader = Reader {
+        tokens: tokens,
+        pos: 0,
+        graph: graph,
+        catalog: catalog,
+    };
+    while reader.pos < reader.tokens.len() {
+        let line = reader.line();
+        try!(reader.statement().map_err(|e| format!("line {}: {}", line, e)));
+    }
+    reader.graph.infer_dtypes()
+}
+
+/// An argument of an operation.
+enum Arg {
+    Input(Option<(NodeId, usize)>),
+    Value(Literal),
+}
+
+enum Literal {
+    Num(f64),
+    Str(String),
+    List(Vec<Literal>),
+    Name(String),
+}
+
+struct Reader<'a> {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+    graph: &'a mut GraphBuilder,
+    catalog: &'a OpCatalog,
+}
+
+impl<'a> Reader<'a> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|&(ref token, _)| token)
+    }
+
+    fn line(&self) -> usize {
+        self.tokens.get(self.pos).or(self.tokens.last()).map_or(1, |&(_, line)| line)
+    }
+
+    fn is(&self, sym: &'static str) -> bool {
+        self.peek() == Some(&Token::Sym(sym))
+    }
+
+    fn expect(&mut self, sym: &'static str) -> Result<(), String> {
+        if !self.is(sym) {
+            return Err(format!("expected '{}'", sym));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        let token = self.peek().cloned();
+        match token {
+            Some(Token::Ident(name)) => {
+                self.pos += 1;
+                Ok(name)
+            },
+            _ => Err("expected a name".to_string()),
+        }
+    }
+
+    /// `dim NAME = SIZE` or `NAME = OPERATION(ARGS)`, or an empty line.
+    fn statement(&mut self) -> Result<(), String> {
+        if self.peek() == Some(&Token::Newline) {
+            self.pos += 1;
+            return Ok(());
+        }
+        let name = try!(self.name());
+        if name == "dim" && self.peek() != Some(&Token::Sym("=")) {
+            let dim = try!(self.name());
+            try!(self.expect("="));
+            match try!(self.literal()) {
+                Literal::Num(size) if size >= 0.0 && size.fract() == 0.0 => { self.graph.set_dim(&dim, size as usize); },
+                _ => { return Err(format!("the size of '{}' should be a count", dim)); },
+            }
+        } else {
+            if self.graph.node_by_name(&name).is_some() {
+                return Err(format!("there's already a node called '{}'", name));
+            }
+            try!(self.expect("="));
+            let (node, _) = try!(self.node());
+            try!(self.graph.rename_node(node, name));
+        }
+        match self.peek() {
+            Some(&Token::Newline) => {
+                self.pos += 1;
+                Ok(())
+            },
+            None => Ok(()),
+            _ => Err("expected the end of the line".to_string()),
+        }
+    }
+
+    /// A reference to an output of an existing node, or a new node.
+    fn input(&mut self) -> Result<Option<(NodeId, usize)>, String> {
+        let is_call = self.tokens.get(self.pos + 1).map(|&(ref token, _)| token) == Some(&Token::Sym("("));
+        if is_call {
+            return self.node().map(Some);
+        }
+        let name = try!(self.name());
+        if name == "_" {
+            return Ok(None);
+        }
+        let node = try!(self.graph.node_by_name(&name).ok_or(format!("there's no node called '{}'", name)));
+        if !self.is(".") {
+            return Ok(Some((node, 0)));
+        }
+        self.pos += 1;
+        let port = try!(self.name());
+        let index = try!(node.get(self.graph).op.outputs.iter().position(|p| *p == port)
+                             .ok_or(format!("'{}' has no output '{}'", name, port)));
+        Ok(Some((node, index)))
+    }
+
+    /// `OPERATION(ARGS)`, adding a node for it and giving its first output.
+    fn node(&mut self) -> Result<(NodeId, usize), String> {
+        let op_name = try!(self.name());
+        try!(self.expect("("));
+        let mut inputs = vec![];
+        let mut named = vec![];
+        while !self.is(")") {
+            let is_named = self.tokens.get(self.pos + 1).map(|&(ref token, _)| token) == Some(&Token::Sym("="));
+            if is_named {
+                let name = try!(self.name());
+                self.pos += 1;
+                named.push((name, try!(self.arg())));
+            } else {
+                inputs.push(try!(self.input()));
+            }
+            if !self.is(")") {
+                try!(self.expect(","));
+            }
+        }
+        self.pos += 1;
+
+        let op = try!(self.operation(&op_name, inputs.len()));
+        let node = self.graph.add_op_node([0.0, 0.0], op.clone());
+        let mut connections: Vec<(usize, Option<(NodeId, usize)>)> = inputs.into_iter().enumerate().collect();
+        for (name, arg) in named {
+            match (op.inputs.iter().position(|port| *port == name), arg) {
+                (Some(i), Arg::Input(input)) => { connections.push((i, input)); },
+                (Some(_), Arg::Value(_)) => { return Err(format!("input '{}' should be a node", name)); },
+                (None, Arg::Value(value)) => { try!(self.set_attr(node, &name, value)); },
+                (None, Arg::Input(_)) => { return Err(format!("'{}' has no input '{}'", op.name, name)); },
+            }
+        }
+        for (i, input) in connections {
+            if i >= op.num_inputs() {
+                return Err(format!("'{}' takes {} inputs", op.name, op.num_inputs()));
+            }
+            if let Some((send, index)) = input {
+                self.graph.connect(send, index, node, i);
+            }
+        }
+        Ok((node, 0))
+    }
+
+    /// The operation called `name`, ignoring case. Stacks aren't in the catalog since they're
+    /// made for a number of timesteps, which is how many inputs they have.
+    fn operation(&self, name: &str, num_inputs: usize) -> Result<Rc<Operation>, String> {
+        if name.to_lowercase() == "stack" {
+            return Ok(Rc::new(catalog::stack_op(num_inputs)));
+        }
+        self.catalog.ops().iter()
+            .find(|op| op.name.to_lowercase() == name.to_lowercase())
+            .cloned()
+            .ok_or(format!("there's no operation '{}'", name))
+    }
+
+    /// The value of a named argument: an input when it's a node, `_` or an operation, otherwise a
+    /// literal.
+    fn arg(&mut self) -> Result<Arg, String> {
+        let next = self.tokens.get(self.pos + 1).map(|&(ref token, _)| token.clone());
+        let is_input = match self.peek() {
+            Some(&Token::Ident(ref name)) => {
+                next == Some(Token::Sym("(")) || next == Some(Token::Sym(".")) ||
+                name == "_" || self.graph.node_by_name(name).is_some()
+            },
+            _ => false,
+        };
+        if is_input {
+            self.input().map(Arg::Input)
+        } else {
+            self.literal().map(Arg::Value)
+        }
+    }
+
+    fn literal(&mut self) -> Result<Literal, String> {
+        let token = self.peek().cloned();
+        self.pos += 1;
+        match token {
+            Some(Token::Num(n)) => Ok(Literal::Num(n)),
+            Some(Token::Sym("-")) => match try!(self.literal()) {
+                Literal::Num(n) => Ok(Literal::Num(-n)),
+                _ => Err("expected a number after '-'".to_string()),
+            },
+            Some(Token::Str(s)) => Ok(Literal::Str(s)),
+            Some(Token::Ident(name)) => Ok(Literal::Name(name)),
+            Some(Token::Sym("[")) => {
+                let mut items = vec![];
+                while !self.is("]") {
+                    items.push(try!(self.literal()));
+                    if !self.is("]") {
+                        try!(self.expect(","));
+                    }
+                }
+                self.pos += 1;
+                Ok(Literal::List(items))
+            },
+            _ => Err("expected a value".to_string()),
+        }
+    }
+
+    /// Set an attribute, or the shape of a node's output for `shape`. Values take the kind of the
+    /// attribute's default.
+    fn set_attr(&mut self, node: NodeId, name: &str, value: Literal) -> Result<(), String> {
+        if name == "shape" && node.get(self.graph).inputs.is_empty() {
+            let dims = match value {
+                Literal::List(dims) => dims,
+                _ => { return Err("the shape should be a list of dimensions".to_string()); },
+            };
+            let mut shape = vec![];
+            for dim in dims {
+                shape.push(match dim {
+                    Literal::Num(d) if d >= 0.0 && d.fract() == 0.0 => Dim::Known(d as usize),
+                    Literal::Name(name) => Dim::Symbolic(name),
+                    _ => { return Err("dimensions should be counts or names".to_string()); },
+                });
+            }
+            let v = try!(node.get(self.graph).outputs.first().cloned().ok_or("the node has no outputs".to_string()));
+            self.graph.set_var_shape(v, Shape::new(shape));
+            return Ok(());
+        }
+
+        let node = node.get_mut(self.graph);
+        let value = match (node.attrs.get(name), value) {
+            (None, _) => { return Err(format!("'{}' has no attribute '{}'", node.op.name, name)); },
+            (Some(&AttrValue::Float(_)), Literal::Num(n)) => AttrValue::Float(n as f32),
+            (Some(&AttrValue::Int(_)), Literal::Num(n)) if n.fract() == 0.0 => AttrValue::Int(n as i64),
+            (Some(&AttrValue::Str(_)), Literal::Str(s)) => AttrValue::Str(s),
+            (Some(&AttrValue::Ints(_)), Literal::List(items)) => {
+                let mut ints = vec![];
+                for item in items {
+                    match item {
+                        Literal::Num(n) if n.fract() == 0.0 => { ints.push(n as i64); },
+                        _ => { return Err(format!("'{}' should be a list of integers", name)); },
+                    }
+                }
+                AttrValue::Ints(ints)
+            },
+            (Some(_), _) => { return Err(format!("'{}' has the wrong kind of value", name)); },
+        };
+        node.attrs.set(name, value);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read, read_layout, write, write_layout, write_nodes};
+    use super::super::catalog::OpCatalog;
+    use super::super::graph_builder::GraphBuilder;
+
+    const MODEL: &'static str = "dim N = 32\n\
+                                 x = Variable(shape=[N, 784])\n\
+                                 w1 = Variable(shape=[784, 128], l2=0.001)\n\
+                                 h = ReLU(MatMul(x, w1))\n\
+                                 d = Dropout(h, rate=0.3)\n\
+                                 y = Variable(shape=[N, 128])\n\
+                                 loss = MSE(d, y)\n";
+
+    fn parse(text: &str) -> Result<GraphBuilder, String> {
+        let mut graph = GraphBuilder::new();
+        try!(read(text, &mut graph, &OpCatalog::builtin()));
+        Ok(graph)
+    }
+
+    fn error(text: &str) -> String {
+        match parse(text) {
+            Ok(_) => panic!("read {:?}", text),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn writes_what_it_read() {
+        let graph = parse(MODEL).unwrap();
+        assert_eq!(write(&graph), Ok(MODEL.to_string()));
+        let h = graph.node_by_name("h").unwrap();
+        let (matmul, _) = graph.input_edge(h, 0).unwrap();
+        assert_eq!(matmul.get(&graph).name(), "matmul_1");
+        assert!(graph.input_edge(matmul, 1).map(|(node, _)| node) == graph.node_by_name("w1"));
+    }
+
+    #[test]
+    fn reads_ports_by_name() {
+        let text = "a = Variable(shape=[2])\n\
+                    d = Dropout(a)\n\
+                    b = Variable(shape=[2])\n\
+                    loss = MSE(target=b, pred=d.mask)\n\
+                    `odd name` = Sigmoid(x=loss)\n\
+                    r = ReLU(_)\n";
+        let graph = parse(text).unwrap();
+        let loss = graph.node_by_name("loss").unwrap();
+        assert!(graph.input_edge(loss, 0) == Some((graph.node_by_name("d").unwrap(), 1)));
+        assert!(graph.input_edge(loss, 1) == Some((graph.node_by_name("b").unwrap(), 0)));
+        assert!(graph.input_edge(graph.node_by_name("r").unwrap(), 0).is_none());
+        assert!(graph.node_by_name("odd name").is_some());
+        assert_eq!(write(&graph), Ok("a = Variable(shape=[2])\n\
+                                      d = Dropout(a)\n\
+                                      b = Variable(shape=[2])\n\
+                                      loss = MSE(d.mask, b)\n\
+                                      `odd name` = Sigmoid(loss)\n\
+                                      r = ReLU(_)\n".to_string()));
+    }
+
+    #[test]
+    fn leaves_inputs_from_unwritten_nodes_unconnected() {
+        let graph = parse(MODEL).unwrap();
+        let nodes = vec![graph.node_by_name("h").unwrap(), graph.node_by_name("loss").unwrap()];
+        assert_eq!(write_nodes(&graph, &nodes), Ok("dim N = 32\n\
+                                                    h = ReLU(MatMul(_, _))\n\
+                                                    loss = MSE(_, _)\n".to_string()));
+    }
+
+    #[test]
+    fn gives_attributes_the_kind_of_their_default() {
+        let graph = parse("c = Conv2D(_, _, stride=2)\n\
+                           r = Reshape(_, shape=[-1, 4])\n\
+                           v = Variable(shape=[2], dtype=\"i32\", l1=1)\n").unwrap();
+        assert_eq!(write(&graph), Ok("c = Conv2D(_, _, stride=2)\n\
+                                      r = Reshape(_, shape=[-1, 4])\n\
+                                      v = Variable(shape=[2], dtype=\"i32\", l1=1)\n".to_string()));
+
+        assert_eq!(error("x = Variable(shape=[2], l2=\"a\")"), "line 1: 'l2' has the wrong kind of value");
+        assert_eq!(error("c = Conv2D(_, _, stride=1.5)"), "line 1: 'stride' has the wrong kind of value");
+        assert_eq!(error("d = Dense(_, _, _, activation=1)"), "line 1: 'activation' has the wrong kind of value");
+        assert_eq!(error("r = Reshape(_, shape=[2, 1.5])"), "line 1: 'shape' should be a list of integers");
+        assert_eq!(error("x = Variable(shape=[2], size=3)"), "line 1: 'Variable' has no attribute 'size'");
+        assert_eq!(error("x = Variable(shape=2)"), "line 1: the shape should be a list of dimensions");
+        assert_eq!(error("x = Variable(shape=[2, -1])"), "line 1: dimensions should be counts or names");
+    }
+
+    #[test]
+    fn reports_the_line_of_errors() {
+        assert_eq!(error("x = Variable(shape=[2])\nx = Variable(shape=[2])"),
+                   "line 2: there's already a node called 'x'");
+        assert_eq!(error("\ny = ReLU(x)"), "line 2: there's no node called 'x'");
+        assert_eq!(error("y = Frobnicate()"), "line 1: there's no operation 'Frobnicate'");
+        assert_eq!(error("x = Variable(shape=[2])\ny = ReLU(x.grad)"), "line 2: 'x' has no output 'grad'");
+        assert_eq!(error("y = ReLU(_, _)"), "line 1: 'ReLU' takes 1 inputs");
+        assert_eq!(error("x = Variable(shape=[2]) 3"), "line 1: expected the end of the line");
+        assert_eq!(error("dim N = 1.5"), "line 1: the size of 'N' should be a count");
+    }
+
+    #[test]
+    fn reads_layouts_it_wrote() {
+        let mut graph = parse(MODEL).unwrap();
+        for (i, node) in graph.node_ids().into_iter().enumerate() {
+            node.get_mut(&mut graph).set_pos([10.0 * i as f64, -2.5]);
+        }
+        let layout = write_layout(&graph);
+
+        let mut other = parse(MODEL).unwrap();
+        read_layout(&format!("nonsense\n1 2 missing\n{}", layout), &mut other);
+        for node in graph.node_ids() {
+            let name = node.get(&graph).name();
+            assert_eq!(other.node_by_name(name).unwrap().get(&other).pos(), node.get(&graph).pos());
+        }
+    }
+}
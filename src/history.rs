@@ -0,0 +1,265 @@
+//! Undo and redo. The graph is kept as text, with its layout and groups, from before every
+//! change. Changes that only moved nodes are undone by moving them back, keeping the build.
+//! Others are undone by reading the text back into a new graph, which has to be built again; its
+//! variables are given the values of the variables of the same name in the graph it replaces,
+//! so neither imported nor trained weights are lost.
+
+use super::catalog::OpCatalog;
+use super::checkpoint::Checkpoint;
+use super::graph_builder::{GraphBuilder, NamedGroup};
+use super::graph_text;
+
+/// Most changes kept to undo.
+const MAX_UNDO: usize = 100;
+
+/// The graph as it was at some point.
+#[derive(Clone, PartialEq)]
+struct State {
+    text: String,
+    layout: String,
+    groups: Vec<NamedGroup>,
+}
+
+pub struct History {
+    undo: Vec<State>,
+    redo: Vec<State>,
+    /// The graph as it was when last recorded.
+    current: Option<State>,
+}
+
+impl History {
+    pub fn new() -> Self {
+        History {
+            undo: vec![],
+            redo: vec![],
+            current: None,
+        }
+    }
+
+    /// Note the graph as it is now. If it changed since it was last noted, the way it was can be
+    /// undone to, and what was undone can't be redone any more. Graphs with cycles can't be
+    /// written as text, so they aren't noted.
+    pub fn record(&mut self, graph: &GraphBuilder) {
+        let state = match snapshot(graph) {
+            Some(state) => state,
+            None => return,
+        };
+        if self.current.as_ref() == Some(&state) {
+            return;
+        }
+        if let Some(previous) = self.current.take() {
+            self.undo.push(previous);
+            if self.undo.len() > MAX_UNDO {
+                self.undo.remove(0);
+            }
+            self.redo.clear();
+        }
+        self.current = Some(state);
+    }
+
+    /// Put the graph back the way it was before the last change. `values` are the current values
+    /// of the graph's variables, from `GraphBuilder::checkpoint`. Gives whether there was a
+    /// change to undo.
+    pub fn undo(&mut self, graph: &mut GraphBuilder, catalog: &OpCatalog, values: &Checkpoint)
+                -> Result<bool, String> {
+        let state = match self.undo.pop() {
+            Some(state) => state,
+            None => return Ok(false),
+        };
+        try!(restore(&state, self.current.as_ref(), graph, catalog, values));
+        if let Some(current) = self.current.take() {
+            self.redo.push(current);
+        }
+        self.current = Some(state);
+        Ok(true)
+    }
+
+    /// Make the last change undone again. Gives whether there was a change to redo.
+    pub fn redo(&mut self, graph: &mut GraphBuilder, catalog: &OpCatalog, values: &Checkpoint)
+                -> Result<bool, String> {
+        let state = match self.redo.pop() {
+            Some(state) => state,
+            None => return Ok(false),
+        };
+        try!(restore(&state, self.current.as_ref(), graph, catalog, values));
+        if let Some(current) = self.current.take() {
+            self.undo.push(current);
+        }
+        self.current = Some(state);
+        Ok(true)
+    }
+}
+
+fn snapshot(graph: &GraphBuilder) -> Option<State> {
+    graph_text::write(graph).ok().map(|text| {
+        State {
+            text: text,
+            layout: graph_text::write_layout(graph),
+            groups: graph.named_groups(),
+        }
+    })
+}
+
+/// Replace the graph, which is as `current` says, with the one in `state`.
+fn restore(state: &State, current: Option<&State>, graph: &mut GraphBuilder, catalog: &OpCatalog,
+           values: &Checkpoint) -> Result<(), String> {
+    let moved_only = current.map_or(false, |current| current.text == state.text && current.groups == state.groups);
+    if moved_only {
+        graph_text::read_layout(&state.layout, graph);
+        // This is the state already noted
+        graph.take_changed();
+        return Ok(());
+    }
+
+    let mut restored = GraphBuilder::new();
+    try!(graph_text::read(&state.text, &mut restored, catalog));
+    restored.add_named_groups(&state.groups);
+    restored.auto_layout();
+    graph_text::read_layout(&state.layout, &mut restored);
+    if let Err(e) = restored.infer_shapes() {
+        println!("{}", e);
+    }
+
+    for v in restored.variables() {
+        if let Some(value) = values.get(&restored.var_name(v)) {
+            v.get_mut(&mut restored.vars).value = Some(value.clone());
+        }
+    }
+    for node in graph.node_ids().into_iter().filter(|&node| graph.has_breakpoint(node)) {
+        if let Some(node) = restored.node_by_name(node.get(graph).name()) {
+            restored.toggle_breakpoint(node);
+        }
+    }
+    restored.set_check_finite(graph.checks_finite());
+    restored.set_mode(graph.mode());
+    restored.set_edge_colors(graph.edge_colors());
+    restored.take_changed();
+    *graph = restored;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::catalog::OpCatalog;
+    use super::super::checkpoint::Checkpoint;
+    use super::super::graph_builder::GraphBuilder;
+    use super::super::graph_text;
+    use super::super::tensor::Tensor;
+    use super::{History, MAX_UNDO};
+
+    fn text(graph: &GraphBuilder) -> String {
+        graph_text::write(graph).unwrap()
+    }
+
+    fn read(text: &str, graph: &mut GraphBuilder) {
+        graph_text::read(text, graph, &OpCatalog::builtin()).unwrap();
+    }
+
+    fn undo(history: &mut History, graph: &mut GraphBuilder) -> Result<bool, String> {
+        history.undo(graph, &OpCatalog::builtin(), &Checkpoint::new())
+    }
+
+    fn redo(history: &mut History, graph: &mut GraphBuilder) -> Result<bool, String> {
+        history.redo(graph, &OpCatalog::builtin(), &Checkpoint::new())
+    }
+
+    #[test]
+    fn undoes_and_redoes_changes() {
+        let mut graph = GraphBuilder::new();
+        let mut history = History::new();
+        read("a = Variable(shape=[2])\n", &mut graph);
+        history.record(&graph);
+        let before = text(&graph);
+        read("r = ReLU(a)\n", &mut graph);
+        history.record(&graph);
+        let after = text(&graph);
+
+        assert_eq!(undo(&mut history, &mut graph), Ok(true));
+        assert_eq!(text(&graph), before);
+        assert!(graph.node_by_name("r").is_none());
+        // The graph put back is what was recorded, so it doesn't count as a change
+        assert!(!graph.take_changed());
+        assert_eq!(undo(&mut history, &mut graph), Ok(false));
+
+        assert_eq!(redo(&mut history, &mut graph), Ok(true));
+        assert_eq!(text(&graph), after);
+        assert_eq!(redo(&mut history, &mut graph), Ok(false));
+
+        // A new change can't be followed by redoing what was undone before it
+        undo(&mut history, &mut graph).unwrap();
+        graph.set_dim("N", 4);
+        history.record(&graph);
+        assert_eq!(redo(&mut history, &mut graph), Ok(false));
+        assert_eq!(undo(&mut history, &mut graph), Ok(true));
+        assert_eq!(text(&graph), before);
+    }
+
+    #[test]
+    fn only_records_changes() {
+        let mut graph = GraphBuilder::new();
+        let mut history = History::new();
+        history.record(&graph);
+        history.record(&graph);
+        assert_eq!(undo(&mut history, &mut graph), Ok(false));
+
+        for i in 0..MAX_UNDO + 2 {
+            graph.set_dim("N", i);
+            history.record(&graph);
+        }
+        let mut undone = 0;
+        while undo(&mut history, &mut graph).unwrap() {
+            undone += 1;
+        }
+        assert_eq!(undone, MAX_UNDO);
+        assert_eq!(graph.dims(), &[("N".to_string(), 1)][..]);
+    }
+
+    #[test]
+    fn moves_nodes_back_in_place() {
+        let mut graph = GraphBuilder::new();
+        let mut history = History::new();
+        read("a = Variable(shape=[2])\n", &mut graph);
+        let a = graph.node_by_name("a").unwrap();
+        a.get_mut(&mut graph).set_pos([100.0, 50.0]);
+        history.record(&graph);
+        a.get_mut(&mut graph).set_pos([300.0, 10.0]);
+        graph.set_selection(vec![a]);
+        history.record(&graph);
+
+        assert_eq!(undo(&mut history, &mut graph), Ok(true));
+        assert_eq!(a.get(&graph).pos(), [100.0, 50.0]);
+        // The graph wasn't replaced, or the selection would have gone with it
+        assert!(graph.selection().len() == 1);
+        assert_eq!(redo(&mut history, &mut graph), Ok(true));
+        assert_eq!(a.get(&graph).pos(), [300.0, 10.0]);
+    }
+
+    #[test]
+    fn keeps_values_groups_and_breakpoints() {
+        let catalog = OpCatalog::builtin();
+        let mut graph = GraphBuilder::new();
+        let mut history = History::new();
+        read("a = Variable(shape=[2])\nr = ReLU(a)\ns = Sigmoid(r)\n", &mut graph);
+        let (r, s) = (graph.node_by_name("r").unwrap(), graph.node_by_name("s").unwrap());
+        graph.group_nodes(&[r, s], "block").unwrap();
+        graph.toggle_breakpoint(s);
+        graph.set_check_finite(true);
+        history.record(&graph);
+        read("t = Tanh(s)\n", &mut graph);
+        history.record(&graph);
+
+        let mut values = Checkpoint::new();
+        values.tensors.push(("a".to_string(), Tensor::new(vec![2], vec![1.0, 2.0])));
+        assert_eq!(history.undo(&mut graph, &catalog, &values), Ok(true));
+        assert!(graph.node_by_name("t").is_none());
+        let v = graph.var_by_name("a").unwrap();
+        assert_eq!(v.get(&graph.vars).value, Some(Tensor::new(vec![2], vec![1.0, 2.0])));
+
+        let block = graph.node_by_name("block").unwrap();
+        assert_eq!(graph.subgraph(block).map(|subgraph| subgraph.nodes.len()), Some(2));
+        let (r, s) = (graph.node_by_name("r").unwrap(), graph.node_by_name("s").unwrap());
+        assert!(graph.is_hidden(r) && graph.is_hidden(s));
+        assert!(graph.has_breakpoint(s) && !graph.has_breakpoint(r));
+        assert!(graph.checks_finite());
+    }
+}
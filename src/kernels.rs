@@ -0,0 +1,511 @@
+//! Math used by the CPU implementations of the built-in operations.
+//!
+//! Matrix operations treat tensors as (rows, columns) matrices, see `tensor::shape_2d`. Binary
+//! element-wise operations broadcast like numpy does, see `Shape::broadcast`.
+
+use rand;
+
+use super::shape;
+use super::tensor::Tensor;
+
+pub fn matmul(a: &Tensor, b: &Tensor, out: &mut Tensor) {
+    let (n, k) = a.shape_2d();
+    let m = b.shape_2d().1;
+    for i in 0..n {
+        for j in 0..m {
+            let mut sum = 0.0;
+            for l in 0..k {
+                sum += a.data[i*k + l] * b.data[l*m + j];
+            }
+            out.data[i*m + j] = sum;
+        }
+    }
+}
+
+/// Accumulate the gradients of `a` and `b` given the gradient `g` of `a * b`.
+pub fn matmul_backward(a: &Tensor, b: &Tensor, g: &Tensor, ga: &mut Tensor, gb: &mut Tensor) {
+    let (n, k) = a.shape_2d();
+    let m = b.shape_2d().1;
+    for i in 0..n {
+        for j in 0..m {
+            let g = g.data[i*m + j];
+            for l in 0..k {
+                ga.data[i*k + l] += g * b.data[l*m + j];
+                gb.data[l*m + j] += g * a.data[i*k + l];
+            }
+        }
+    }
+}
+
+pub fn binary<F>(a: &Tensor, b: &Tensor, out: &mut Tensor, f: F)
+    where F: Fn(f32, f32) -> f32 {
+    for i in 0..out.data.len() {
+        out.data[i] = f(a.data[shape::broadcast_index(&a.shape, &out.shape, i)],
+                        b.data[shape::broadcast_index(&b.shape, &out.shape, i)]);
+    }
+}
+
+/// Accumulate the gradients of the inputs of a binary element-wise operation. `df` gives the
+/// partial derivatives with respect to each input. Gradients of broadcast inputs are summed over
+/// the dimensions they were broadcast along.
+pub fn binary_backward<F>(a: &Tensor, b: &Tensor, g: &Tensor, ga: &mut Tensor, gb: &mut Tensor, df: F)
+    where F: Fn(f32, f32) -> (f32, f32) {
+    for i in 0..g.data.len() {
+        let ai = shape::broadcast_index(&a.shape, &g.shape, i);
+        let bi = shape::broadcast_index(&b.shape, &g.shape, i);
+        let (da, db) = df(a.data[ai], b.data[bi]);
+        ga.data[ai] += g.data[i] * da;
+        gb.data[bi] += g.data[i] * db;
+    }
+}
+
+pub fn unary<F>(x: &Tensor, out: &mut Tensor, f: F)
+    where F: Fn(f32) -> f32 {
+    for (y, &x) in out.data.iter_mut().zip(&x.data) {
+        *y = f(x);
+    }
+}
+
+/// Accumulate the gradient of the input of a unary element-wise operation. `df` gets the input
+/// and output of the operation and returns the derivative.
+pub fn unary_backward<F>(x: &Tensor, y: &Tensor, g: &Tensor, gx: &mut Tensor, df: F)
+    where F: Fn(f32, f32) -> f32 {
+    for i in 0..gx.data.len() {
+        gx.data[i] += g.data[i] * df(x.data[i], y.data[i]);
+    }
+}
+
+pub fn sigmoid(x: f32) -> f32 {
+    1.0 / (1.0 + (-x).exp())
+}
+
+/// Softmax over each row of `x`.
+pub fn softmax(x: &Tensor, out: &mut Tensor) {
+    let (rows, columns) = x.shape_2d();
+    for r in 0..rows {
+        let row = &x.data[r*columns..(r+1)*columns];
+        let max = row.iter().fold(::std::f32::NEG_INFINITY, |m, &x| m.max(x));
+        let mut sum = 0.0;
+        for c in 0..columns {
+            let e = (row[c] - max).exp();
+            out.data[r*columns + c] = e;
+            sum += e;
+        }
+        for c in 0..columns {
+            out.data[r*columns + c] /= sum;
+        }
+    }
+}
+
+/// Accumulate the gradient of the input of a row-wise softmax with output `y`.
+pub fn softmax_backward(y: &Tensor, g: &Tensor, gx: &mut Tensor) {
+    let (rows, columns) = y.shape_2d();
+    for r in 0..rows {
+        let row = r*columns..(r+1)*columns;
+        let dot = y.data[row.clone()].iter().zip(&g.data[row]).fold(0.0, |sum, (&y, &g)| sum + y*g);
+        for c in 0..columns {
+            let i = r*columns + c;
+            gx.data[i] += y.data[i] * (g.data[i] - dot);
+        }
+    }
+}
+
+/// Apply the activation function called `name`, as used by layers like Dense.
+pub fn activation(name: &str, x: f32) -> f32 {
+    match name {
+        "relu" => x.max(0.0),
+        "sigmoid" => sigmoid(x),
+        "tanh" => x.tanh(),
+        _ => x,
+    }
+}
+
+/// The derivative of the activation function called `name`, given its output `y`.
+pub fn activation_grad(name: &str, y: f32) -> f32 {
+    match name {
+        "relu" => if y > 0.0 { 1.0 } else { 0.0 },
+        "sigmoid" => y * (1.0 - y),
+        "tanh" => 1.0 - y*y,
+        _ => 1.0,
+    }
+}
+
+pub const ACTIVATIONS: &'static [&'static str] = &["none", "relu", "sigmoid", "tanh"];
+
+/// The log of the softmax over each row of `x`, computed without overflowing.
+pub fn log_softmax(x: &Tensor) -> Tensor {
+    let (rows, columns) = x.shape_2d();
+    let mut out = x.clone();
+    for r in 0..rows {
+        let row = &mut out.data[r*columns..(r+1)*columns];
+        let max = row.iter().fold(::std::f32::NEG_INFINITY, |m, &x| m.max(x));
+        let log_sum = row.iter().fold(0.0, |sum, &x| sum + (x - max).exp()).ln() + max;
+        for x in row.iter_mut() {
+            *x -= log_sum;
+        }
+    }
+    out
+}
+
+/// How a convolution or pooling window moves over its input.
+#[derive(Copy, Clone)]
+pub struct Window {
+    pub kernel: (usize, usize),
+    pub stride: usize,
+    pub padding: usize,
+    pub dilation: usize,
+}
+
+impl Window {
+    /// The output size along a dimension of size `input`, where `kernel` is the window's size
+    /// along it.
+    fn output_size(&self, input: usize, kernel: usize) -> Result<usize, String> {
+        let span = self.dilation * (kernel - 1) + 1;
+        if self.stride == 0 || input + 2*self.padding < span {
+            return Err(format!("A window of {} with stride {}, padding {} and dilation {} doesn't fit in {}",
+                               kernel, self.stride, self.padding, self.dilation, input));
+        }
+        Ok((input + 2*self.padding - span) / self.stride + 1)
+    }
+
+    /// The (height, width) of the output for an input of `input` (height, width).
+    pub fn output_shape(&self, input: (usize, usize)) -> Result<(usize, usize), String> {
+        Ok((try!(self.output_size(input.0, self.kernel.0)), try!(self.output_size(input.1, self.kernel.1))))
+    }
+
+    /// Where tap (`ky`, `kx`) of the window at output position (`oy`, `ox`) lands in an input of
+    /// `size`, or `None` if it's in the padding.
+    fn tap(&self, size: (usize, usize), oy: usize, ox: usize, ky: usize, kx: usize) -> Option<(usize, usize)> {
+        let y = (oy*self.stride + ky*self.dilation) as isize - self.padding as isize;
+        let x = (ox*self.stride + kx*self.dilation) as isize - self.padding as isize;
+        if y < 0 || x < 0 || y as usize >= size.0 || x as usize >= size.1 {
+            None
+        } else {
+            Some((y as usize, x as usize))
+        }
+    }
+}
+
+fn dims4(shape: &[usize]) -> (usize, usize, usize, usize) {
+    (shape[0], shape[1], shape[2], shape[3])
+}
+
+/// Call `f(out, x, w)` with the indices of every (output, input, weight) triple of a 2-D
+/// convolution of an NCHW input `x` with FCHW weights `w`.
+fn conv2d_taps<F>(x: &[usize], w: &[usize], out: &[usize], window: &Window, mut f: F)
+    where F: FnMut(usize, usize, usize) {
+    let (n, c, h, wd) = dims4(x);
+    let (filters, _, kh, kw) = dims4(w);
+    let (oh, ow) = (out[2], out[3]);
+    for b in 0..n {
+        for o in 0..filters {
+            for oy in 0..oh {
+                for ox in 0..ow {
+                    let out_i = ((b*filters + o)*oh + oy)*ow + ox;
+                    for ci in 0..c {
+                        for ky in 0..kh {
+                            for kx in 0..kw {
+                                if let Some((y, x)) = window.tap((h, wd), oy, ox, ky, kx) {
+                                    f(out_i, ((b*c + ci)*h + y)*wd + x, ((o*c + ci)*kh + ky)*kw + kx);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub fn conv2d(x: &Tensor, w: &Tensor, out: &mut Tensor, window: &Window) {
+    let out_shape = out.shape.clone();
+    conv2d_taps(&x.shape, &w.shape, &out_shape, window, |o, i, k| {
+        out.data[o] += x.data[i] * w.data[k];
+    });
+}
+
+pub fn conv2d_backward(x: &Tensor, w: &Tensor, g: &Tensor, gx: &mut Tensor, gw: &mut Tensor, window: &Window) {
+    conv2d_taps(&x.shape, &w.shape, &g.shape, window, |o, i, k| {
+        gx.data[i] += g.data[o] * w.data[k];
+        gw.data[k] += g.data[o] * x.data[i];
+    });
+}
+
+/// Call `f(out, taps)` for every output of pooling an NCHW input `x` into `out`, with the input
+/// indices the output's window covers.
+fn pool_windows<F>(x: &[usize], out: &[usize], window: &Window, mut f: F)
+    where F: FnMut(usize, &[usize]) {
+    let (n, c, h, w) = dims4(x);
+    let (oh, ow) = (out[2], out[3]);
+    let mut taps = Vec::with_capacity(window.kernel.0 * window.kernel.1);
+    for plane in 0..n*c {
+        for oy in 0..oh {
+            for ox in 0..ow {
+                taps.clear();
+                for ky in 0..window.kernel.0 {
+                    for kx in 0..window.kernel.1 {
+                        if let Some((y, x)) = window.tap((h, w), oy, ox, ky, kx) {
+                            taps.push((plane*h + y)*w + x);
+                        }
+                    }
+                }
+                f((plane*oh + oy)*ow + ox, &taps);
+            }
+        }
+    }
+}
+
+pub fn max_pool(x: &Tensor, out: &mut Tensor, window: &Window) {
+    let out_shape = out.shape.clone();
+    pool_windows(&x.shape, &out_shape, window, |o, taps| {
+        out.data[o] = taps.iter().fold(::std::f32::NEG_INFINITY, |m, &i| m.max(x.data[i]));
+    });
+}
+
+/// The gradient of each output goes to the input that was the maximum of its window.
+pub fn max_pool_backward(x: &Tensor, g: &Tensor, gx: &mut Tensor, window: &Window) {
+    pool_windows(&x.shape, &g.shape, window, |o, taps| {
+        let max = taps.iter().cloned().fold(None, |max: Option<usize>, i| {
+            match max {
+                Some(m) if x.data[m] >= x.data[i] => Some(m),
+                _ => Some(i),
+            }
+        });
+        if let Some(i) = max {
+            gx.data[i] += g.data[o];
+        }
+    });
+}
+
+/// Averages over the taps inside the input, so padding doesn't drag the edges towards zero.
+pub fn avg_pool(x: &Tensor, out: &mut Tensor, window: &Window) {
+    let out_shape = out.shape.clone();
+    pool_windows(&x.shape, &out_shape, window, |o, taps| {
+        out.data[o] = taps.iter().fold(0.0, |sum, &i| sum + x.data[i]) / taps.len().max(1) as f32;
+    });
+}
+
+pub fn avg_pool_backward(x: &Tensor, g: &Tensor, gx: &mut Tensor, window: &Window) {
+    pool_windows(&x.shape, &g.shape, window, |o, taps| {
+        for &i in taps {
+            gx.data[i] += g.data[o] / taps.len() as f32;
+        }
+    });
+}
+
+/// The pre-activations of the gates of a recurrent cell: `x*w + b` from the input and `h*u`
+/// from the state. They're kept apart because the GRU resets only the state's part.
+pub fn gate_inputs(x: &Tensor, h: &Tensor, w: &Tensor, u: &Tensor, b: &Tensor) -> (Tensor, Tensor) {
+    let rows = x.shape_2d().0;
+    let columns = w.shape_2d().1;
+    let mut xw = Tensor::zeros(vec![rows, columns]);
+    matmul(x, w, &mut xw);
+    for (j, v) in xw.data.iter_mut().enumerate() {
+        *v += b.data[j % columns];
+    }
+    let mut hu = Tensor::zeros(vec![rows, columns]);
+    matmul(h, u, &mut hu);
+    (xw, hu)
+}
+
+/// Accumulate the gradients of the inputs of `gate_inputs` given the gradients `g_xw` and `g_hu`
+/// of its outputs.
+pub fn gate_inputs_backward(x: &Tensor, h: &Tensor, w: &Tensor, u: &Tensor, g_xw: &Tensor, g_hu: &Tensor,
+                            gx: &mut Tensor, gh: &mut Tensor, gw: &mut Tensor, gu: &mut Tensor, gb: &mut Tensor) {
+    matmul_backward(x, w, g_xw, gx, gw);
+    matmul_backward(h, u, g_hu, gh, gu);
+    let columns = gb.len();
+    for (j, &g) in g_xw.data.iter().enumerate() {
+        gb.data[j % columns] += g;
+    }
+}
+
+/// An LSTM step from the pre-activations of its input, forget, cell and output gates, which are
+/// side by side in the columns of `pre`.
+pub fn lstm_cell(pre: &Tensor, c: &Tensor, h_out: &mut Tensor, c_out: &mut Tensor) {
+    let (rows, units) = c.shape_2d();
+    for r in 0..rows {
+        let p = &pre.data[r*4*units..(r + 1)*4*units];
+        for k in 0..units {
+            let (i, f, g, o) = (sigmoid(p[k]), sigmoid(p[units + k]), p[2*units + k].tanh(), sigmoid(p[3*units + k]));
+            let cell = f*c.data[r*units + k] + i*g;
+            c_out.data[r*units + k] = cell;
+            h_out.data[r*units + k] = o*cell.tanh();
+        }
+    }
+}
+
+/// Accumulate the gradients of the gate pre-activations and the previous cell state of an LSTM
+/// step, given the gradients of its new hidden and cell states.
+pub fn lstm_cell_backward(pre: &Tensor, c: &Tensor, c_out: &Tensor, g_h: &Tensor, g_c_out: &Tensor,
+                          g_pre: &mut Tensor, g_c: &mut Tensor) {
+    let (rows, units) = c.shape_2d();
+    for r in 0..rows {
+        let p = &pre.data[r*4*units..(r + 1)*4*units];
+        for k in 0..units {
+            let j = r*units + k;
+            let (i, f, g, o) = (sigmoid(p[k]), sigmoid(p[units + k]), p[2*units + k].tanh(), sigmoid(p[3*units + k]));
+            let tanh_c = c_out.data[j].tanh();
+            let d_c = g_c_out.data[j] + g_h.data[j]*o*(1.0 - tanh_c*tanh_c);
+            let gp = &mut g_pre.data[r*4*units..(r + 1)*4*units];
+            gp[k] += d_c*g*i*(1.0 - i);
+            gp[units + k] += d_c*c.data[j]*f*(1.0 - f);
+            gp[2*units + k] += d_c*i*(1.0 - g*g);
+            gp[3*units + k] += g_h.data[j]*tanh_c*o*(1.0 - o);
+            g_c.data[j] += d_c*f;
+        }
+    }
+}
+
+/// A GRU step from the pre-activations of its update, reset and candidate gates, side by side in
+/// the columns of `xw` and `hu`. The reset gate scales the state's part of the candidate.
+pub fn gru_cell(xw: &Tensor, hu: &Tensor, h: &Tensor, h_out: &mut Tensor) {
+    let (rows, units) = h.shape_2d();
+    for r in 0..rows {
+        let (x, s) = (&xw.data[r*3*units..(r + 1)*3*units], &hu.data[r*3*units..(r + 1)*3*units]);
+        for k in 0..units {
+            let z = sigmoid(x[k] + s[k]);
+            let reset = sigmoid(x[units + k] + s[units + k]);
+            let n = (x[2*units + k] + reset*s[2*units + k]).tanh();
+            h_out.data[r*units + k] = (1.0 - z)*n + z*h.data[r*units + k];
+        }
+    }
+}
+
+/// Accumulate the gradients of the gate pre-activations and the previous state of a GRU step,
+/// given the gradient of its new state.
+pub fn gru_cell_backward(xw: &Tensor, hu: &Tensor, h: &Tensor, g_h_out: &Tensor,
+                         g_xw: &mut Tensor, g_hu: &mut Tensor, g_h: &mut Tensor) {
+    let (rows, units) = h.shape_2d();
+    for r in 0..rows {
+        let (x, s) = (&xw.data[r*3*units..(r + 1)*3*units], &hu.data[r*3*units..(r + 1)*3*units]);
+        for k in 0..units {
+            let j = r*units + k;
+            let z = sigmoid(x[k] + s[k]);
+            let reset = sigmoid(x[units + k] + s[units + k]);
+            let n = (x[2*units + k] + reset*s[2*units + k]).tanh();
+            let g = g_h_out.data[j];
+            let d_n = g*(1.0 - z)*(1.0 - n*n);
+            let d_z = g*(h.data[j] - n)*z*(1.0 - z);
+            let d_reset = d_n*s[2*units + k]*reset*(1.0 - reset);
+            g_h.data[j] += g*z;
+
+            let (gx, gs) = (&mut g_xw.data[r*3*units..(r + 1)*3*units], &mut g_hu.data[r*3*units..(r + 1)*3*units]);
+            gx[k] += d_z;
+            gs[k] += d_z;
+            gx[units + k] += d_reset;
+            gs[units + k] += d_reset;
+            gx[2*units + k] += d_n;
+            gs[2*units + k] += d_n*reset;
+        }
+    }
+}
+
+/// Zero each element of `x` with probability `rate`, scaling up the rest so the expected value
+/// stays the same. `mask` gets the factor each element was multiplied by.
+pub fn dropout(x: &Tensor, rate: f32, out: &mut Tensor, mask: &mut Tensor) {
+    for i in 0..x.len() {
+        mask.data[i] = if rand::random::<f32>() < rate { 0.0 } else { 1.0 / (1.0 - rate) };
+        out.data[i] = x.data[i] * mask.data[i];
+    }
+}
+
+/// The channel of element `i` of a tensor whose channels are its second dimension, e.g. the
+/// columns of a matrix or the C of an NCHW image.
+fn channel(shape: &[usize], i: usize) -> usize {
+    let inner = shape[2..].iter().fold(1, |n, &d| n*d);
+    (i / inner) % shape[1]
+}
+
+/// The mean and variance of each channel of `x` over the batch and any spatial dimensions.
+pub fn channel_moments(x: &Tensor) -> (Vec<f32>, Vec<f32>) {
+    let channels = x.shape[1];
+    let count = (x.len() / channels) as f32;
+    let mut mean = vec![0.0; channels];
+    for (i, &v) in x.data.iter().enumerate() {
+        mean[channel(&x.shape, i)] += v / count;
+    }
+    let mut var = vec![0.0; channels];
+    for (i, &v) in x.data.iter().enumerate() {
+        let c = channel(&x.shape, i);
+        var[c] += (v - mean[c]) * (v - mean[c]) / count;
+    }
+    (mean, var)
+}
+
+/// Normalize each channel of `x` with the given mean and variance, then scale by `gamma` and
+/// shift by `beta`.
+pub fn batch_norm(x: &Tensor, gamma: &Tensor, beta: &Tensor, mean: &[f32], var: &[f32], epsilon: f32,
+                  out: &mut Tensor) {
+    for (i, &v) in x.data.iter().enumerate() {
+        let c = channel(&x.shape, i);
+        out.data[i] = gamma.data[c] * (v - mean[c]) / (var[c] + epsilon).sqrt() + beta.data[c];
+    }
+}
+
+/// Accumulate the gradients of `x`, `gamma` and `beta` of a batch normalization that used the
+/// moments of `x` itself, as it does while training.
+pub fn batch_norm_backward(x: &Tensor, gamma: &Tensor, g: &Tensor, epsilon: f32,
+                           gx: &mut Tensor, ggamma: &mut Tensor, gbeta: &mut Tensor) {
+    let channels = x.shape[1];
+    let count = (x.len() / channels) as f32;
+    let (mean, var) = channel_moments(x);
+    let inv_std: Vec<f32> = var.iter().map(|&v| 1.0 / (v + epsilon).sqrt()).collect();
+    let normalized = |i: usize, c: usize| (x.data[i] - mean[c]) * inv_std[c];
+
+    // Sums over each channel of the gradient of the normalized values, and of that times them
+    let mut sum_g = vec![0.0; channels];
+    let mut sum_g_norm = vec![0.0; channels];
+    for (i, &g) in g.data.iter().enumerate() {
+        let c = channel(&x.shape, i);
+        let n = normalized(i, c);
+        ggamma.data[c] += g * n;
+        gbeta.data[c] += g;
+        sum_g[c] += g * gamma.data[c];
+        sum_g_norm[c] += g * gamma.data[c] * n;
+    }
+    for (i, &g) in g.data.iter().enumerate() {
+        let c = channel(&x.shape, i);
+        let g_norm = g * gamma.data[c];
+        gx.data[i] += inv_std[c] * (g_norm - sum_g[c] / count - normalized(i, c) * sum_g_norm[c] / count);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{matmul, matmul_backward, softmax, Window};
+    use super::super::tensor::Tensor;
+
+    #[test]
+    fn multiplies_matrices() {
+        let a = Tensor::new(vec![2, 3], vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        let b = Tensor::new(vec![3, 2], vec![7.0, 8.0, 9.0, 10.0, 11.0, 12.0]);
+        let mut out = Tensor::zeros(vec![2, 2]);
+        matmul(&a, &b, &mut out);
+        assert_eq!(out.data, vec![58.0, 64.0, 139.0, 154.0]);
+
+        // Gradients accumulate into what's already there
+        let (mut ga, mut gb) = (Tensor::zeros(vec![2, 3]), Tensor::zeros(vec![3, 2]));
+        ga.data[0] = 1.0;
+        matmul_backward(&a, &b, &Tensor::new(vec![2, 2], vec![1.0, 0.0, 0.0, 0.0]), &mut ga, &mut gb);
+        assert_eq!(ga.data, vec![8.0, 9.0, 11.0, 0.0, 0.0, 0.0]);
+        assert_eq!(gb.data, vec![1.0, 0.0, 2.0, 0.0, 3.0, 0.0]);
+    }
+
+    #[test]
+    fn softmax_survives_large_inputs() {
+        let x = Tensor::new(vec![1, 3], vec![1000.0, 1000.0, -1000.0]);
+        let mut out = Tensor::zeros(vec![1, 3]);
+        softmax(&x, &mut out);
+        assert_eq!(out.data, vec![0.5, 0.5, 0.0]);
+    }
+
+    #[test]
+    fn windows_fit_their_input() {
+        let window = Window { kernel: (3, 3), stride: 2, padding: 1, dilation: 1 };
+        assert_eq!(window.output_shape((5, 6)), Ok((3, 3)));
+        let dilated = Window { kernel: (3, 3), stride: 1, padding: 0, dilation: 2 };
+        assert_eq!(dilated.output_shape((6, 5)), Ok((2, 1)));
+        assert!(dilated.output_shape((4, 5)).is_err());
+    }
+}
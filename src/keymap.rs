@@ -0,0 +1,332 @@
+//! Keyboard shortcuts. Every action is bound to keys, optionally with ctrl and shift held. The
+//! default bindings can be changed in a keymap file, one action per line:
+//!
+//! ```text
+//! # Comments start with a hash
+//! redo = ctrl+y, ctrl+shift+z
+//! build = f5
+//! ```
+//!
+//! A line replaces every default binding of its action, and takes its shortcuts away from any
+//! other action they were bound to. Key names are those of piston's `Key`, ignoring case.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use piston::input::Key;
+
+pub const KEYMAP_PATH: &'static str = "keymap.txt";
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Action {
+    Delete,
+    Undo,
+    Redo,
+    Build,
+    Run,
+    Train,
+    Save,
+    Open,
+    ZoomIn,
+    ZoomOut,
+    ZoomReset,
+    SelectAll,
+    Search,
+    QuickAdd,
+    Copy,
+    CopyWithValues,
+    Cut,
+    Paste,
+    Duplicate,
+    Layout,
+    Group,
+    ToggleGroup,
+    Unroll,
+    SaveToLibrary,
+    EdgeColors,
+    Backward,
+    Debug,
+    Step,
+    Resume,
+    Breakpoint,
+    BreakOnNonFinite,
+    CheckFinite,
+    Previews,
+    Help,
+}
+
+/// Every action with its name in keymap files and what it does, in the order the help lists them.
+pub const ACTIONS: &'static [(Action, &'static str, &'static str)] = &[
+    (Action::Help, "help", "Show or hide this help"),
+    (Action::Build, "build", "Build the graph"),
+    (Action::Run, "run", "Run the graph"),
+    (Action::Train, "train", "Start or stop training"),
+    (Action::Save, "save", "Save the graph as text"),
+    (Action::Open, "open", "Open the graph saved as text"),
+    (Action::Undo, "undo", "Undo the last change"),
+    (Action::Redo, "redo", "Redo the last change undone"),
+    (Action::Delete, "delete", "Remove the selected nodes or edge"),
+    (Action::SelectAll, "select_all", "Select every node"),
+    (Action::Copy, "copy", "Copy the selected nodes"),
+    (Action::CopyWithValues, "copy_with_values", "Copy the selected nodes with their values"),
+    (Action::Cut, "cut", "Cut the selected nodes"),
+    (Action::Paste, "paste", "Paste nodes at the mouse"),
+    (Action::Duplicate, "duplicate", "Duplicate the selected nodes"),
+    (Action::Search, "search", "Search the palette"),
+    (Action::QuickAdd, "quick_add", "Add an operation at the mouse"),
+    (Action::Layout, "layout", "Lay out the selection, or the whole graph"),
+    (Action::Group, "group", "Group the selected nodes into a subgraph"),
+    (Action::ToggleGroup, "toggle_group", "Expand or collapse the group under the mouse"),
+    (Action::Unroll, "unroll", "Unroll the recurrent cell under the mouse"),
+    (Action::SaveToLibrary, "save_to_library", "Save the subgraph under the mouse to the library"),
+    (Action::EdgeColors, "edge_colors", "Colour edges and nodes by type or by gradient"),
+    (Action::Backward, "backward", "Take the gradients of the losses without training"),
+    (Action::Debug, "debug", "Start or stop stepping through the graph"),
+    (Action::Step, "step", "Run the next node"),
+    (Action::Resume, "resume", "Run nodes up to the next breakpoint"),
+    (Action::Breakpoint, "breakpoint", "Set or clear a breakpoint on the node under the mouse"),
+    (Action::BreakOnNonFinite, "break_on_non_finite", "Stop on values that aren't finite, or don't"),
+    (Action::CheckFinite, "check_finite", "Check runs and training for values that aren't finite, or don't"),
+    (Action::Previews, "previews", "Show or hide previews of the values after each run"),
+    (Action::ZoomIn, "zoom_in", "Zoom in"),
+    (Action::ZoomOut, "zoom_out", "Zoom out"),
+    (Action::ZoomReset, "zoom_reset", "Reset the zoom"),
+];
+
+/// Keys that can be bound, and named in keymap files.
+const KEYS: &'static [Key] = &[
+    Key::A, Key::B, Key::C, Key::D, Key::E, Key::F, Key::G, Key::H, Key::I, Key::J, Key::K, Key::L, Key::M,
+    Key::N, Key::O, Key::P, Key::Q, Key::R, Key::S, Key::T, Key::U, Key::V, Key::W, Key::X, Key::Y, Key::Z,
+    Key::D0, Key::D1, Key::D2, Key::D3, Key::D4, Key::D5, Key::D6, Key::D7, Key::D8, Key::D9,
+    Key::F1, Key::F2, Key::F3, Key::F4, Key::F5, Key::F6, Key::F7, Key::F8, Key::F9, Key::F10, Key::F11, Key::F12,
+    Key::Space, Key::Tab, Key::Return, Key::Escape, Key::Backspace, Key::Delete, Key::Insert,
+    Key::Home, Key::End, Key::PageUp, Key::PageDown, Key::Up, Key::Down, Key::Left, Key::Right,
+    Key::Slash, Key::Backslash, Key::Comma, Key::Period, Key::Semicolon, Key::Quote,
+    Key::Minus, Key::Equals, Key::LeftBracket, Key::RightBracket,
+];
+
+#[derive(Copy, Clone, PartialEq)]
+pub struct Shortcut {
+    pub key: Key,
+    pub ctrl: bool,
+    pub shift: bool,
+}
+
+impl Shortcut {
+    fn new(key: Key) -> Self {
+        Shortcut { key: key, ctrl: false, shift: false }
+    }
+
+    fn ctrl(key: Key) -> Self {
+        Shortcut { key: key, ctrl: true, shift: false }
+    }
+
+    fn shift(key: Key) -> Self {
+        Shortcut { key: key, ctrl: false, shift: true }
+    }
+
+    fn ctrl_shift(key: Key) -> Self {
+        Shortcut { key: key, ctrl: true, shift: true }
+    }
+
+    /// `ctrl+shift+KEY`, leaving out the modifiers that aren't needed.
+    pub fn label(&self) -> String {
+        let mut s = String::new();
+        if self.ctrl {
+            s.push_str("ctrl+");
+        }
+        if self.shift {
+            s.push_str("shift+");
+        }
+        s.push_str(&format!("{:?}", self.key).to_lowercase());
+        s
+    }
+
+    fn parse(s: &str) -> Result<Shortcut, String> {
+        let mut shortcut = None;
+        let (mut ctrl, mut shift) = (false, false);
+        for part in s.split('+').map(|part| part.trim().to_lowercase()) {
+            match part.as_ref() {
+                "ctrl" => { ctrl = true; },
+                "shift" => { shift = true; },
+                name => {
+                    let key = try!(KEYS.iter().find(|key| format!("{:?}", key).to_lowercase() == name)
+                                       .ok_or(format!("there's no key '{}'", name)));
+                    shortcut = Some(*key);
+                },
+            }
+        }
+        let key = try!(shortcut.ok_or(format!("'{}' has no key", s)));
+        Ok(Shortcut { key: key, ctrl: ctrl, shift: shift })
+    }
+}
+
+pub struct Keymap {
+    bindings: Vec<(Shortcut, Action)>,
+}
+
+impl Keymap {
+    pub fn default() -> Self {
+        let bindings = vec![
+            (Shortcut::new(Key::F1), Action::Help),
+            (Shortcut::ctrl(Key::B), Action::Build),
+            (Shortcut::ctrl(Key::R), Action::Run),
+            (Shortcut::ctrl(Key::T), Action::Train),
+            (Shortcut::ctrl(Key::S), Action::Save),
+            (Shortcut::ctrl(Key::O), Action::Open),
+            (Shortcut::ctrl(Key::Z), Action::Undo),
+            (Shortcut::ctrl(Key::Y), Action::Redo),
+            (Shortcut::ctrl_shift(Key::Z), Action::Redo),
+            (Shortcut::new(Key::Delete), Action::Delete),
+            (Shortcut::new(Key::Backspace), Action::Delete),
+            (Shortcut::ctrl(Key::A), Action::SelectAll),
+            (Shortcut::ctrl(Key::C), Action::Copy),
+            (Shortcut::ctrl_shift(Key::C), Action::CopyWithValues),
+            (Shortcut::ctrl(Key::X), Action::Cut),
+            (Shortcut::ctrl(Key::V), Action::Paste),
+            (Shortcut::ctrl(Key::D), Action::Duplicate),
+            (Shortcut::new(Key::Slash), Action::Search),
+            (Shortcut::ctrl(Key::F), Action::Search),
+            (Shortcut::new(Key::Space), Action::QuickAdd),
+            (Shortcut::new(Key::Tab), Action::QuickAdd),
+            (Shortcut::new(Key::A), Action::Layout),
+            (Shortcut::new(Key::G), Action::Group),
+            (Shortcut::new(Key::E), Action::ToggleGroup),
+            (Shortcut::new(Key::U), Action::Unroll),
+            (Shortcut::new(Key::L), Action::SaveToLibrary),
+            (Shortcut::new(Key::C), Action::EdgeColors),
+            (Shortcut::ctrl_shift(Key::R), Action::Backward),
+            (Shortcut::new(Key::F5), Action::Debug),
+            (Shortcut::new(Key::F10), Action::Step),
+            (Shortcut::new(Key::F8), Action::Resume),
+            (Shortcut::new(Key::F9), Action::Breakpoint),
+            (Shortcut::shift(Key::F9), Action::BreakOnNonFinite),
+            (Shortcut::ctrl_shift(Key::N), Action::CheckFinite),
+            (Shortcut::new(Key::P), Action::Previews),
+            (Shortcut::new(Key::Equals), Action::ZoomIn),
+            (Shortcut::ctrl(Key::Equals), Action::ZoomIn),
+            (Shortcut::new(Key::Minus), Action::ZoomOut),
+            (Shortcut::ctrl(Key::Minus), Action::ZoomOut),
+            (Shortcut::ctrl(Key::D0), Action::ZoomReset),
+        ];
+        Keymap {
+            bindings: bindings,
+        }
+    }
+
+    /// The default keymap changed by the keymap file at `path`, if there is one.
+    pub fn load(path: &Path) -> Result<Keymap, String> {
+        let mut keymap = Keymap::default();
+        let mut text = String::new();
+        match File::open(path).and_then(|mut f| f.read_to_string(&mut text)) {
+            Ok(_) => { try!(keymap.read(&text).map_err(|e| format!("{}: {}", path.display(), e))); },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => { },
+            Err(e) => { return Err(format!("{}: {}", path.display(), e)); },
+        }
+        Ok(keymap)
+    }
+
+    /// Rebind the actions on each line of a keymap file.
+    pub fn read(&mut self, text: &str) -> Result<(), String> {
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut sides = line.splitn(2, '=');
+            let name = sides.next().unwrap().trim();
+            let shortcuts = try!(sides.next().ok_or(format!("line {}: expected 'ACTION = SHORTCUTS'", i + 1)));
+            let action = try!(ACTIONS.iter().find(|&&(_, n, _)| n == name).map(|&(action, _, _)| action)
+                                     .ok_or(format!("line {}: there's no action '{}'", i + 1, name)));
+
+            self.bindings.retain(|&(_, a)| a != action);
+            for shortcut in shortcuts.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+                let shortcut = try!(Shortcut::parse(shortcut).map_err(|e| format!("line {}: {}", i + 1, e)));
+                self.bindings.retain(|&(s, _)| s != shortcut);
+                self.bindings.push((shortcut, action));
+            }
+        }
+        Ok(())
+    }
+
+    /// The action bound to the key with the modifiers held, if any.
+    pub fn action(&self, key: Key, ctrl: bool, shift: bool) -> Option<Action> {
+        let pressed = Shortcut { key: key, ctrl: ctrl, shift: shift };
+        self.bindings.iter().find(|&&(shortcut, _)| shortcut == pressed).map(|&(_, action)| action)
+    }
+
+    /// The shortcuts bound to the action.
+    pub fn shortcuts(&self, action: Action) -> Vec<Shortcut> {
+        self.bindings.iter().filter(|&&(_, a)| a == action).map(|&(shortcut, _)| shortcut).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use piston::input::Key;
+
+    use super::{Action, Keymap, Shortcut, ACTIONS};
+
+    fn parse(s: &str) -> Result<String, String> {
+        Shortcut::parse(s).map(|shortcut| shortcut.label())
+    }
+
+    #[test]
+    fn parses_shortcuts() {
+        let shortcut = Shortcut::parse("ctrl+shift+z").unwrap();
+        assert!(shortcut.key == Key::Z && shortcut.ctrl && shortcut.shift);
+        assert_eq!(parse(" Ctrl + F5 "), Ok("ctrl+f5".to_string()));
+        assert_eq!(parse("shift+d0"), Ok("shift+d0".to_string()));
+        assert_eq!(parse("ctrl+hyper"), Err("there's no key 'hyper'".to_string()));
+        assert_eq!(parse("ctrl+shift"), Err("'ctrl+shift' has no key".to_string()));
+    }
+
+    #[test]
+    fn parses_the_labels_of_the_defaults() {
+        let keymap = Keymap::default();
+        for (i, &(shortcut, _)) in keymap.bindings.iter().enumerate() {
+            assert_eq!(parse(&shortcut.label()), Ok(shortcut.label()));
+            assert!(keymap.bindings[i+1..].iter().all(|&(other, _)| other != shortcut),
+                    "{} is bound twice", shortcut.label());
+        }
+        for &(action, name, _) in ACTIONS {
+            assert!(!keymap.shortcuts(action).is_empty(), "{} isn't bound", name);
+            assert_eq!(ACTIONS.iter().filter(|&&(_, n, _)| n == name).count(), 1);
+        }
+    }
+
+    #[test]
+    fn reads_keymaps() {
+        let mut keymap = Keymap::default();
+        assert_eq!(keymap.action(Key::B, true, false), Some(Action::Build));
+        keymap.read("# Comments start with a hash\n\
+                     \n\
+                     build = f6, ctrl+shift+b  # not ctrl+b\n\
+                     undo =\n").unwrap();
+        assert_eq!(keymap.action(Key::B, true, false), None);
+        assert_eq!(keymap.action(Key::F6, false, false), Some(Action::Build));
+        assert_eq!(keymap.action(Key::B, true, true), Some(Action::Build));
+        let labels: Vec<String> = keymap.shortcuts(Action::Build).iter().map(|s| s.label()).collect();
+        assert_eq!(labels, vec!["f6", "ctrl+shift+b"]);
+        assert!(keymap.shortcuts(Action::Undo).is_empty());
+        assert_eq!(keymap.action(Key::Y, true, false), Some(Action::Redo));
+    }
+
+    #[test]
+    fn takes_shortcuts_from_other_actions() {
+        let mut keymap = Keymap::default();
+        keymap.read("build = ctrl+s").unwrap();
+        assert_eq!(keymap.action(Key::S, true, false), Some(Action::Build));
+        assert!(keymap.shortcuts(Action::Save).is_empty());
+        assert_eq!(keymap.action(Key::O, true, false), Some(Action::Open));
+    }
+
+    #[test]
+    fn reports_the_line_of_errors() {
+        let mut keymap = Keymap::default();
+        assert_eq!(keymap.read("redo ctrl+y"), Err("line 1: expected 'ACTION = SHORTCUTS'".to_string()));
+        assert_eq!(keymap.read("\nfly = f1"), Err("line 2: there's no action 'fly'".to_string()));
+        assert_eq!(keymap.read("build = ctrl+hyper"), Err("line 1: there's no key 'hyper'".to_string()));
+    }
+}
@@ -0,0 +1,246 @@
+//! Automatic placement of nodes for graphs that don't come with positions, or whose positions
+//! have gotten messy.
+
+use std::collections::VecDeque;
+
+pub const LAYER_SPACING: f64 = 128.0;
+pub const ROW_SPACING: f64 = 72.0;
+/// Vertical space left between nodes in the same layer.
+const NODE_GAP: f64 = 24.0;
+/// Sweeps of crossing minimization, alternating between left-to-right and right-to-left.
+const SWEEPS: usize = 8;
+
+/// Lays out a graph left-to-right by data flow, Sugiyama-style:
+///
+/// 1. Every node is put in the layer given by the longest path leading to it.
+/// 2. Edges spanning several layers are broken up by dummy nodes, one per layer crossed, so that
+///    they take part in the ordering like any other.
+/// 3. Nodes are ordered within their layer by the barycenter heuristic, sweeping back and forth
+///    and keeping the ordering with the fewest edge crossings.
+/// 4. Nodes are placed level with the middle of their neighbours where the ordering and the
+///    space they need allow it.
+///
+/// `heights` are the nodes' heights and `edges` are (sending node, receiving node) pairs. Gives
+/// the top left corner of each node, with the graph's top left corner at `origin`. Nodes that
+/// are part of a cycle can't be ordered; they're placed just after whichever of their
+/// predecessors could be, and the edges closing the cycle are left out of the ordering.
+pub fn layered(heights: &[f64], edges: &[(usize, usize)], origin: [f64; 2]) -> Vec<[f64; 2]> {
+    let num_nodes = heights.len();
+    let layers = assign_layers(num_nodes, edges);
+
+    // Slots are the nodes followed by the dummy nodes, linked between adjacent layers
+    let mut slot_layers = layers.clone();
+    let mut slot_heights = heights.to_vec();
+    let mut links = vec![];
+    for &(from, to) in edges {
+        if layers[to] <= layers[from] {
+            continue;
+        }
+        let mut prev = from;
+        for layer in layers[from]+1..layers[to] {
+            slot_layers.push(layer);
+            slot_heights.push(0.0);
+            links.push((prev, slot_layers.len() - 1));
+            prev = slot_layers.len() - 1;
+        }
+        links.push((prev, to));
+    }
+
+    let num_layers = slot_layers.iter().cloned().max().map_or(0, |l| l + 1);
+    let mut order = vec![vec![]; num_layers];
+    for (slot, &layer) in slot_layers.iter().enumerate() {
+        order[layer].push(slot);
+    }
+    let mut preds = vec![vec![]; slot_layers.len()];
+    let mut succs = vec![vec![]; slot_layers.len()];
+    for &(from, to) in &links {
+        succs[from].push(to);
+        preds[to].push(from);
+    }
+
+    let order = minimize_crossings(order, &preds, &succs);
+    let ys = place(&order, &slot_heights, &preds, &succs);
+
+    let top = (0..num_nodes).map(|n| ys[n]).fold(::std::f64::INFINITY, f64::min);
+    (0..num_nodes).map(|n| [origin[0] + (layers[n] as f64)*LAYER_SPACING, origin[1] + ys[n] - top])
+                  .collect()
+}
+
+/// Longest-path layering, computed over a topological ordering of the nodes.
+pub fn assign_layers(num_nodes: usize, edges: &[(usize, usize)]) -> Vec<usize> {
+    let mut in_degree = vec![0; num_nodes];
+    let mut successors = vec![vec![]; num_nodes];
+    for &(from, to) in edges {
+        in_degree[to] += 1;
+        successors[from].push(to);
+    }
+
+    let mut layers = vec![0; num_nodes];
+    let mut ready: VecDeque<usize> = (0..num_nodes).filter(|&n| in_degree[n] == 0).collect();
+    while let Some(n) = ready.pop_front() {
+        for &m in &successors[n] {
+            if layers[m] < layers[n] + 1 {
+                layers[m] = layers[n] + 1;
+            }
+            in_degree[m] -= 1;
+            if in_degree[m] == 0 {
+                ready.push_back(m);
+            }
+        }
+    }
+    layers
+}
+
+/// Reorder each layer by the mean position of its slots' neighbours in the layer before it,
+/// sweeping left-to-right and then right-to-left, and give the ordering with the fewest
+/// crossings seen.
+fn minimize_crossings(mut order: Vec<Vec<usize>>, preds: &[Vec<usize>], succs: &[Vec<usize>])
+                      -> Vec<Vec<usize>> {
+    let mut best = order.clone();
+    let mut best_crossings = crossings(&order, succs);
+    for sweep in 0..SWEEPS {
+        if best_crossings == 0 {
+            break;
+        }
+        if sweep % 2 == 0 {
+            for layer in 1..order.len() {
+                let (before, after) = order.split_at_mut(layer);
+                reorder(&mut after[0], &before[layer - 1], preds);
+            }
+        } else {
+            for layer in (0..order.len().saturating_sub(1)).rev() {
+                let (before, after) = order.split_at_mut(layer + 1);
+                reorder(&mut before[layer], &after[0], succs);
+            }
+        }
+        let count = crossings(&order, succs);
+        if count < best_crossings {
+            best = order.clone();
+            best_crossings = count;
+        }
+    }
+    best
+}
+
+/// Sort `layer` by the mean index in `fixed` of each slot's `neighbours`. Slots without any keep
+/// their index, so they stay roughly where they were.
+fn reorder(layer: &mut Vec<usize>, fixed: &[usize], neighbours: &[Vec<usize>]) {
+    let mut index = vec![0; neighbours.len()];
+    for (i, &slot) in fixed.iter().enumerate() {
+        index[slot] = i;
+    }
+    let mut keyed: Vec<(f64, usize)> = layer.iter().enumerate().map(|(i, &slot)| {
+        let near = &neighbours[slot];
+        let barycenter = if near.is_empty() {
+            i as f64
+        } else {
+            near.iter().fold(0.0, |sum, &n| sum + index[n] as f64) / near.len() as f64
+        };
+        (barycenter, slot)
+    }).collect();
+    keyed.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+    *layer = keyed.into_iter().map(|(_, slot)| slot).collect();
+}
+
+/// How many pairs of links between adjacent layers cross.
+fn crossings(order: &[Vec<usize>], succs: &[Vec<usize>]) -> usize {
+    let mut index = vec![0; succs.len()];
+    for layer in order {
+        for (i, &slot) in layer.iter().enumerate() {
+            index[slot] = i;
+        }
+    }
+    let mut count = 0;
+    for layer in order {
+        let mut links = vec![];
+        for &from in layer {
+            for &to in &succs[from] {
+                links.push((index[from], index[to]));
+            }
+        }
+        for (i, &(a_from, a_to)) in links.iter().enumerate() {
+            for &(b_from, b_to) in &links[i+1..] {
+                if (a_from < b_from && a_to > b_to) || (a_from > b_from && a_to < b_to) {
+                    count += 1;
+                }
+            }
+        }
+    }
+    count
+}
+
+/// The top of each slot. Layers are placed left-to-right, then right-to-left, then left-to-right
+/// again, each slot as level with the middle of its neighbours on the side already placed as it
+/// can be without overlapping the slot above it.
+fn place(order: &[Vec<usize>], heights: &[f64], preds: &[Vec<usize>], succs: &[Vec<usize>]) -> Vec<f64> {
+    let mut ys = vec![0.0; heights.len()];
+    for layer in order {
+        let mut bottom = 0.0;
+        for &slot in layer {
+            ys[slot] = bottom;
+            bottom += heights[slot] + NODE_GAP;
+        }
+    }
+
+    let forward: Vec<usize> = (0..order.len()).collect();
+    let backward: Vec<usize> = (0..order.len()).rev().collect();
+    for &(layers, neighbours) in &[(&forward, preds), (&backward, succs), (&forward, preds)] {
+        for &layer in layers {
+            let mut bottom = ::std::f64::NEG_INFINITY;
+            for &slot in &order[layer] {
+                let near = &neighbours[slot];
+                let wanted = if near.is_empty() {
+                    ys[slot]
+                } else {
+                    let middle = near.iter().fold(0.0, |sum, &n| sum + ys[n] + heights[n]/2.0) / near.len() as f64;
+                    middle - heights[slot]/2.0
+                };
+                ys[slot] = wanted.max(bottom);
+                bottom = ys[slot] + heights[slot] + NODE_GAP;
+            }
+        }
+    }
+    ys
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{assign_layers, layered, LAYER_SPACING, NODE_GAP};
+
+    #[test]
+    fn layers_by_longest_path() {
+        assert_eq!(assign_layers(4, &[(0, 1), (1, 2), (0, 2), (3, 2)]), vec![0, 1, 2, 0]);
+        // 1 and 2 form a cycle, so 1 is placed after 0 and 2 isn't ordered
+        assert_eq!(assign_layers(3, &[(0, 1), (1, 2), (2, 1)]), vec![0, 1, 0]);
+    }
+
+    #[test]
+    fn places_chains_level_from_the_origin() {
+        assert_eq!(layered(&[], &[], [5.0, 7.0]), Vec::<[f64; 2]>::new());
+        assert_eq!(layered(&[40.0], &[], [5.0, 7.0]), vec![[5.0, 7.0]]);
+        assert_eq!(layered(&[40.0, 40.0, 40.0], &[(0, 1), (1, 2)], [10.0, 20.0]),
+                   vec![[10.0, 20.0], [10.0 + LAYER_SPACING, 20.0], [10.0 + 2.0*LAYER_SPACING, 20.0]]);
+    }
+
+    #[test]
+    fn keeps_nodes_in_a_layer_apart() {
+        let heights = [40.0, 30.0, 50.0, 20.0, 60.0];
+        let pos = layered(&heights, &[(0, 1), (0, 2), (0, 3), (2, 4), (0, 4)], [0.0, 0.0]);
+        assert_eq!(pos.iter().map(|p| p[1]).fold(::std::f64::INFINITY, f64::min), 0.0);
+        assert_eq!(pos[4][0], 2.0*LAYER_SPACING);
+
+        let mut layer: Vec<usize> = vec![1, 2, 3];
+        assert!(layer.iter().all(|&n| pos[n][0] == LAYER_SPACING));
+        layer.sort_by(|&a, &b| pos[a][1].partial_cmp(&pos[b][1]).unwrap());
+        for pair in layer.windows(2) {
+            assert!(pos[pair[1]][1] >= pos[pair[0]][1] + heights[pair[0]] + NODE_GAP);
+        }
+    }
+
+    #[test]
+    fn uncrosses_edges() {
+        let pos = layered(&[40.0; 4], &[(0, 3), (1, 2)], [0.0, 0.0]);
+        assert!(pos[0][1] < pos[1][1]);
+        assert!(pos[3][1] < pos[2][1]);
+    }
+}
@@ -1,11 +1,17 @@
 use std::cell::RefCell;
+use std::env;
+use std::io::{self, Write};
 use std::path::Path;
+use std::process;
 use std::rc::Rc;
 
+extern crate byteorder;
 #[macro_use] extern crate conrod;
 extern crate deeplearn as dl;
 extern crate time;
+extern crate libloading;
 extern crate matrix;
+extern crate rand;
 extern crate piston;
 extern crate graphics;
 extern crate opengl_graphics;
@@ -24,22 +30,60 @@ use piston::event_loop::*;
 use piston::window::{WindowSettings, Size};
 use glutin_window::GlutinWindow;
 
+use cli::Options;
 use dl_ui::DeepLabUi;
 
+mod catalog;
+mod checkpoint;
+mod cli;
+mod clipboard;
+mod codegen;
+mod cpu;
+mod debugger;
 mod dl_ui;
+mod dtype;
 mod graph_builder;
+mod graph_text;
+mod history;
+mod kernels;
+mod keymap;
+mod layout;
 mod node;
+mod onnx;
 mod op;
+mod plugin;
+mod preview;
+mod protobuf;
+mod quick_add;
+mod script;
+mod shape;
+mod subgraph;
+mod tensor;
+mod trainer;
 mod var_store;
 
 fn main() {
+    let options = match Options::parse(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            let _ = writeln!(io::stderr(), "{}\n\n{}", e, cli::USAGE);
+            process::exit(cli::EXIT_FAILED);
+        },
+    };
+    if options.is_headless() {
+        if let Err(failure) = cli::run_headless(&options) {
+            let _ = writeln!(io::stderr(), "{}", failure.message);
+            process::exit(failure.status);
+        }
+        return;
+    }
+
     let opengl = OpenGL::V3_2;
     let window = GlutinWindow::new(
         WindowSettings::new(
             "Deep Lab".to_string(),
             Size { width: 1280, height: 700 }
         )
-        .exit_on_esc(true)
         .samples(4)
     ).unwrap();
     let window = Rc::new(RefCell::new(window));
@@ -49,11 +93,32 @@ fn main() {
     let theme = Theme::default();
     let glyph_cache = GlyphCache::new(&font_path).unwrap();
     let mut ui = Ui::new(glyph_cache, theme);
+    // The UI owns its glyph cache, so the graph canvas needs its own
+    let mut canvas_glyphs = GlyphCache::new(&font_path).unwrap();
     
     ///////////////////////////////////////////////////////////////////////////////////////
 
     let mut deep_ui = DeepLabUi::new();
 
+    if let Some(ref model_path) = options.model {
+        if cli::is_graph_text(model_path) {
+            if let Err(e) = deep_ui.open_graph(model_path) {
+                println!("Failed to open '{}': {}", model_path.display(), e);
+            }
+        } else {
+            match deep_ui.import_onnx(model_path) {
+                Ok(report) => {
+                    println!("Imported {} nodes and {} variables from '{}'",
+                             report.nodes, report.variables, model_path.display());
+                    if !report.unsupported.is_empty() {
+                        println!("Unsupported operators: {}", report.unsupported.join(", "));
+                    }
+                },
+                Err(e) => println!("Failed to import '{}': {}", model_path.display(), e),
+            }
+        }
+    }
+
     for event in window.clone().events().ups(60) {
         ui.handle_event(&event);
         deep_ui.event(&event);
@@ -63,7 +128,7 @@ fn main() {
         event.render(|args| {
             gl.draw(args.viewport(), |c, gl| {
                 ui.draw(c, gl);
-                deep_ui.draw(c, gl);
+                deep_ui.draw(c, gl, &mut canvas_glyphs);
             });
         });
     }
@@ -1,18 +1,28 @@
+use std::cmp;
 use std::rc::Rc;
 
 use graphics;
 use piston::input;
 use opengl_graphics::GlGraphics;
+use opengl_graphics::glyph_cache::GlyphCache;
 use vecmath;
 
 use super::dl_ui::Mouse;
 use super::graph_builder::{GraphBuilder, NodeId};
-use super::op::Operation;
+use super::op::{Attrs, Operation};
 use super::var_store::VarIndex;
 
+const NODE_WIDTH: f64 = 96.0;
+const HEADER_HEIGHT: f64 = 16.0;
+const PORT_SPACING: f64 = 16.0;
+const LABEL_SIZE: u32 = 11;
+const PORT_LABEL_SIZE: u32 = 9;
+
 pub struct Node {
     name: String,
     pub op: Rc<Operation>,
+    /// This node's settings for the operation, starting out as the operation's defaults.
+    pub attrs: Attrs,
     pub inputs: Vec<Option<VarIndex>>,
     pub outputs: Vec<VarIndex>,
     pos: [f64; 2],
@@ -23,6 +33,7 @@ impl Node {
     pub fn new(name: String, pos: [f64; 2], op: Rc<Operation>, num_in: usize, outs: Vec<VarIndex>) -> Self {
         Node {
             name: name,
+            attrs: op.attrs.clone(),
             op: op,
             inputs: vec![None; num_in as usize],
             outputs: outs,
@@ -43,7 +54,7 @@ impl Node {
             }
         }
 
-        let mouse_over = is_over_rect([self.pos[0], self.pos[1], 64.0, 32.0], mouse.pos);
+        let mouse_over = self.is_over(mouse.pos);
 
         event.press(|button| {
             match button {
@@ -74,22 +85,16 @@ impl Node {
     }
 
     pub fn on_lmb_clicked(&mut self, mouse: &Mouse, mouse_over: bool) {
-        let input_spacing = 32.0 / (self.inputs.len() as f64);
         for (i, input) in self.inputs.iter().enumerate() {
-            let mut pos = [0.0, input_spacing*(i as f64) + input_spacing/2.0];
-            pos[0] += self.pos[0];
-            pos[1] += self.pos[1];
+            let pos = self.get_input_pos(i);
             if is_over_circle(pos, 5.0, mouse.pos) {
                 self.action = Some(NodeAction::DragInput(i));
                 println!("Drag input");
             }
         }
 
-        let output_spacing = 32.0 / (self.outputs.len() as f64);
         for (i, output) in self.outputs.iter().enumerate() {
-            let mut pos = [64.0, output_spacing*(i as f64) + output_spacing/2.0];
-            pos[0] += self.pos[0];
-            pos[1] += self.pos[1];
+            let pos = self.get_output_pos(i);
             if is_over_circle(pos, 5.0, mouse.pos) {
                 self.action = Some(NodeAction::DragOutput(i));
                 println!("Drag output");
@@ -117,13 +122,33 @@ impl Node {
         }
     }
 
-    pub fn draw(&self, c: &graphics::Context, gl: &mut GlGraphics) {
-        use graphics::{Ellipse, Rectangle, Transformed};
+    /// Draw the node with `label` as its name; the graph passes the text being typed in while
+    /// the node is renamed.
+    pub fn draw(&self, c: &graphics::Context, gl: &mut GlGraphics, glyphs: &mut GlyphCache,
+                label: &str, renaming: bool) {
+        use graphics::{Ellipse, Rectangle, Text, Transformed};
+        use graphics::character::CharacterCache;
+
+        let size = self.size();
+        let color = if self.op.placeholder { [0.5, 0.5, 0.5, 1.0] } else { [0.1, 0.3, 0.8, 1.0] };
+        Rectangle::new(color).draw([self.pos[0], self.pos[1], size[0], size[1]], &c.draw_state, c.transform, gl);
+        Rectangle::new([0.0, 0.0, 0.0, 0.25]).draw([self.pos[0], self.pos[1], size[0], HEADER_HEIGHT],
+                                                   &c.draw_state, c.transform, gl);
 
-        Rectangle::new([0.1, 0.3, 0.8, 1.0]).draw([self.pos[0], self.pos[1], 64.0, 32.0], &c.draw_state, c.transform, gl);
+        // Name above the node, operation in the header
+        let label_color = if renaming { [0.8, 0.0, 0.0, 1.0] } else { [0.0, 0.0, 0.0, 1.0] };
+        Text::new_color(label_color, LABEL_SIZE).draw(label, glyphs, &c.draw_state,
+                                                      c.transform.trans(self.pos[0], self.pos[1] - 4.0), gl);
+        let op_width = glyphs.width(LABEL_SIZE, &self.op.name);
+        Text::new_color([1.0, 1.0, 1.0, 1.0], LABEL_SIZE)
+            .draw(&self.op.name, glyphs, &c.draw_state,
+                  c.transform.trans(self.pos[0] + (size[0] - op_width)/2.0, self.pos[1] + HEADER_HEIGHT - 4.0), gl);
 
         for (i, input) in self.inputs.iter().enumerate() {
             let mut pos = self.get_input_pos(i);
+            Text::new_color([1.0, 1.0, 1.0, 1.0], PORT_LABEL_SIZE)
+                .draw(&self.op.inputs[i], glyphs, &c.draw_state,
+                      c.transform.trans(pos[0] + 8.0, pos[1] + 4.0), gl);
             pos[0] -= 4.0;
             pos[1] -= 4.0;
             match *input {
@@ -138,6 +163,10 @@ impl Node {
 
         for (i, output) in self.outputs.iter().enumerate() {
             let mut pos = self.get_output_pos(i);
+            let port_width = glyphs.width(PORT_LABEL_SIZE, &self.op.outputs[i]);
+            Text::new_color([1.0, 1.0, 1.0, 1.0], PORT_LABEL_SIZE)
+                .draw(&self.op.outputs[i], glyphs, &c.draw_state,
+                      c.transform.trans(pos[0] - 8.0 - port_width, pos[1] + 4.0), gl);
             pos[0] -= 4.0;
             pos[1] -= 4.0;
             Ellipse::new([1.0, 0.0, 0.0, 1.0]).draw([pos[0], pos[1], 8.0, 8.0], &c.draw_state, c.transform, gl);
@@ -148,17 +177,39 @@ impl Node {
         self.name.as_ref()
     }
 
+    /// Only `GraphBuilder::rename_node` should call this, so output variables get renamed too.
+    pub fn set_name(&mut self, name: String) {
+        self.name = name;
+    }
+
+    pub fn is_over(&self, pos: [f64; 2]) -> bool {
+        let size = self.size();
+        is_over_rect([self.pos[0], self.pos[1], size[0], size[1]], pos)
+    }
+
+    /// A header row for the operation name, then a row for each pair of input and output ports.
+    pub fn size(&self) -> [f64; 2] {
+        let rows = cmp::max(1, cmp::max(self.inputs.len(), self.outputs.len()));
+        [NODE_WIDTH, HEADER_HEIGHT + (rows as f64)*PORT_SPACING]
+    }
+
+    pub fn pos(&self) -> [f64; 2] {
+        self.pos
+    }
+
+    pub fn set_pos(&mut self, pos: [f64; 2]) {
+        self.pos = pos;
+    }
+
     pub fn get_input_pos(&self, i: usize) -> [f64; 2] {
-        let input_spacing = 32.0 / (self.inputs.len() as f64);
-        let mut pos = [0.0, input_spacing*(i as f64) + input_spacing/2.0];
+        let mut pos = [0.0, HEADER_HEIGHT + PORT_SPACING*(i as f64) + PORT_SPACING/2.0];
         pos[0] += self.pos[0];
         pos[1] += self.pos[1];
         pos
     }
 
     pub fn get_output_pos(&self, i: usize) -> [f64; 2] {
-        let output_spacing = 32.0 / (self.outputs.len() as f64);
-        let mut pos = [64.0, output_spacing*(i as f64) + output_spacing/2.0];
+        let mut pos = [NODE_WIDTH, HEADER_HEIGHT + PORT_SPACING*(i as f64) + PORT_SPACING/2.0];
         pos[0] += self.pos[0];
         pos[1] += self.pos[1];
         pos
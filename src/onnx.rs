@@ -0,0 +1,761 @@
+//! Importing ONNX models into a `GraphBuilder`.
+//!
+//! Only the parts of the ONNX schema needed to rebuild the graph topology are read: nodes, their
+//! attributes, initializers and the shapes of graph inputs, outputs and intermediate values.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::rc::Rc;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::catalog::OpCatalog;
+use super::dtype::{self, DType};
+use super::graph_builder::{GraphBuilder, NodeId};
+use super::op::{AttrValue, Operation};
+use super::protobuf::{Reader, Value};
+use super::shape::{Dim, Shape};
+use super::tensor::Tensor;
+
+/// ONNX operator types we can map onto operations in the catalog.
+const OP_MAP: &'static [(&'static str, &'static str)] = &[
+    ("MatMul", "MatMul"),
+    ("Add", "Add"),
+    ("Sub", "Sub"),
+    ("Mul", "Mul"),
+    ("Relu", "ReLU"),
+    ("Sigmoid", "Sigmoid"),
+    ("Tanh", "Tanh"),
+    ("LeakyRelu", "LeakyReLU"),
+    ("Elu", "ELU"),
+    ("Softmax", "Softmax"),
+    ("Flatten", "Flatten"),
+];
+
+// TensorProto.DataType
+const FLOAT: i64 = 1;
+const INT32: i64 = 6;
+const INT64: i64 = 7;
+const BOOL: i64 = 9;
+const DOUBLE: i64 = 11;
+
+pub struct OnnxGraph {
+    pub name: String,
+    pub nodes: Vec<OnnxNode>,
+    pub initializers: Vec<(String, Tensor)>,
+    /// The TensorProto.DataType of each initializer, whose values are read as f32.
+    pub initializer_types: Vec<(String, i64)>,
+    pub inputs: Vec<ValueInfo>,
+    pub outputs: Vec<ValueInfo>,
+    pub value_info: Vec<ValueInfo>,
+}
+
+impl OnnxGraph {
+    fn new() -> Self {
+        OnnxGraph {
+            name: String::new(),
+            nodes: vec![],
+            initializers: vec![],
+            initializer_types: vec![],
+            inputs: vec![],
+            outputs: vec![],
+            value_info: vec![],
+        }
+    }
+
+    /// The known shape of a named tensor, if any.
+    pub fn shape_of(&self, name: &str) -> Option<Shape> {
+        if let Some(&(_, ref t)) = self.initializers.iter().find(|&&(ref n, _)| n == name) {
+            return Some(Shape::from_dims(&t.shape));
+        }
+        self.inputs.iter().chain(self.outputs.iter()).chain(self.value_info.iter())
+            .find(|info| info.name == name)
+            .and_then(|info| info.shape.clone())
+    }
+
+    /// The element type of a named tensor, if it's known and one we support.
+    pub fn dtype_of(&self, name: &str) -> Option<DType> {
+        if let Some(&(_, data_type)) = self.initializer_types.iter().find(|&&(ref n, _)| n == name) {
+            return dtype_from_onnx(data_type);
+        }
+        self.inputs.iter().chain(self.outputs.iter()).chain(self.value_info.iter())
+            .find(|info| info.name == name)
+            .and_then(|info| info.dtype)
+    }
+}
+
+pub struct OnnxNode {
+    pub name: String,
+    pub op_type: String,
+    pub inputs: Vec<String>,
+    pub outputs: Vec<String>,
+    pub attributes: Vec<(String, Attribute)>,
+}
+
+pub enum Attribute {
+    Float(f32),
+    Int(i64),
+    String(String),
+    Tensor(Tensor),
+    Floats(Vec<f32>),
+    Ints(Vec<i64>),
+}
+
+pub struct ValueInfo {
+    pub name: String,
+    /// `None` if the shape is unknown. Dimensions with neither a size nor a name are taken to
+    /// be 1.
+    pub shape: Option<Shape>,
+    /// `None` if the element type is unknown or one we don't support.
+    pub dtype: Option<DType>,
+}
+
+/// What happened during an import.
+pub struct ImportReport {
+    pub nodes: usize,
+    pub variables: usize,
+    /// ONNX operator types that were imported as placeholder nodes.
+    pub unsupported: Vec<String>,
+}
+
+pub fn read_model(path: &Path) -> Result<OnnxGraph, String> {
+    let mut buf = vec![];
+    try!(File::open(path).and_then(|mut f| f.read_to_end(&mut buf)).map_err(|e| e.to_string()));
+    parse_model(&buf)
+}
+
+/// Parse a serialized `ModelProto` and return its graph.
+pub fn parse_model(buf: &[u8]) -> Result<OnnxGraph, String> {
+    let mut reader = Reader::new(buf);
+    let mut graph = None;
+    while let Some((field, value)) = try!(reader.next_field()) {
+        match field {
+            7 => { graph = Some(try!(parse_graph(try!(value.as_bytes())))); },
+            _ => { },
+        }
+    }
+    graph.ok_or("model has no graph".to_string())
+}
+
+/// Add the nodes of an ONNX graph to `builder`. Initializers become `Variable` nodes holding
+/// their values, graph inputs become empty `Variable` nodes, and operators we have no operation
+/// for become placeholder nodes so the rest of the model can still be inspected. The nodes are
+/// laid out below the ones already in `builder`, which don't move. Nothing is added if the
+/// import fails.
+pub fn import(onnx: &OnnxGraph, builder: &mut GraphBuilder, catalog: &OpCatalog)
+              -> Result<ImportReport, String> {
+    // Build the model in a graph of its own first, so a failed import leaves `builder` alone
+    let mut imported = GraphBuilder::new();
+    let report = try!(import_into(onnx, &mut imported, catalog));
+
+    // Lay out only the imported nodes, below anything already on the canvas
+    let nodes = builder.insert(&imported, [0.0, 0.0]);
+    builder.layout_below(&nodes);
+    Ok(report)
+}
+
+fn import_into(onnx: &OnnxGraph, builder: &mut GraphBuilder, catalog: &OpCatalog) -> Result<ImportReport, String> {
+    let variable = try!(catalog.get("Variable").ok_or("catalog has no Variable operation".to_string()));
+    let mut report = ImportReport { nodes: 0, variables: 0, unsupported: vec![] };
+
+    // Maps tensor names to the node output producing them
+    let mut producers: HashMap<String, (NodeId, usize)> = HashMap::new();
+
+    for &(ref name, ref value) in &onnx.initializers {
+        let node = builder.add_node(name.clone(), [0.0, 0.0], variable.clone());
+        let out = node.get(builder).outputs[0];
+        let var = out.get_mut(&mut builder.vars);
+        var.shape = Shape::from_dims(&value.shape);
+        var.dims = value.shape.clone();
+        var.value = Some(value.clone());
+        set_variable_dtype(builder, onnx, node, name);
+        producers.insert(name.clone(), (node, 0));
+        report.variables += 1;
+    }
+
+    for input in &onnx.inputs {
+        if producers.contains_key(&input.name) {
+            // Older exporters list initializers as graph inputs too
+            continue;
+        }
+        let node = builder.add_node(input.name.clone(), [0.0, 0.0], variable.clone());
+        set_output_shape(builder, onnx, node, 0, &input.name);
+        set_variable_dtype(builder, onnx, node, &input.name);
+        producers.insert(input.name.clone(), (node, 0));
+        report.variables += 1;
+    }
+
+    for onnx_node in &onnx.nodes {
+        let op = match find_op(catalog, onnx_node) {
+            Some(op) => op,
+            None => {
+                if !report.unsupported.contains(&onnx_node.op_type) {
+                    report.unsupported.push(onnx_node.op_type.clone());
+                }
+                Rc::new(Operation::placeholder(onnx_node.op_type.clone(),
+                                               onnx_node.inputs.len(),
+                                               onnx_node.outputs.len()))
+            },
+        };
+        let name = if onnx_node.name.is_empty() { onnx_node.op_type.clone() } else { onnx_node.name.clone() };
+        let node = builder.add_node(name, [0.0, 0.0], op);
+        copy_attributes(builder, node, onnx_node);
+
+        for (i, input) in onnx_node.inputs.iter().enumerate() {
+            if input.is_empty() {
+                // Omitted optional input
+                continue;
+            }
+            match producers.get(input) {
+                Some(&(send_node, send_index)) => builder.connect(send_node, send_index, node, i),
+                None => {
+                    return Err(format!("Tensor '{}' consumed by '{}' is never produced",
+                                       input, onnx_node.op_type));
+                },
+            }
+        }
+        for (i, output) in onnx_node.outputs.iter().enumerate() {
+            set_output_shape(builder, onnx, node, i, output);
+            producers.insert(output.clone(), (node, i));
+        }
+        report.nodes += 1;
+    }
+    Ok(report)
+}
+
+/// Give the node the values of any ONNX attributes its operation has an attribute of the same
+/// name and kind for, e.g. the `alpha` of a LeakyRelu.
+fn copy_attributes(builder: &mut GraphBuilder, node: NodeId, onnx_node: &OnnxNode) {
+    let attrs = &mut node.get_mut(builder).attrs;
+    for &(ref name, ref value) in &onnx_node.attributes {
+        let value = match (attrs.get(name), value) {
+            (Some(&AttrValue::Float(_)), &Attribute::Float(f)) => AttrValue::Float(f),
+            (Some(&AttrValue::Int(_)), &Attribute::Int(i)) => AttrValue::Int(i),
+            (Some(&AttrValue::Str(_)), &Attribute::String(ref s)) => AttrValue::Str(s.clone()),
+            (Some(&AttrValue::Ints(_)), &Attribute::Ints(ref ints)) => AttrValue::Ints(ints.clone()),
+            _ => continue,
+        };
+        attrs.set(name, value);
+    }
+}
+
+fn find_op(catalog: &OpCatalog, node: &OnnxNode) -> Option<Rc<Operation>> {
+    OP_MAP.iter()
+          .find(|&&(onnx_name, _)| onnx_name == node.op_type)
+          .and_then(|&(_, name)| catalog.get(name))
+          .and_then(|op| {
+              if op.num_inputs() == node.inputs.len() && op.num_outputs() == node.outputs.len() {
+                  Some(op)
+              } else {
+                  None
+              }
+          })
+}
+
+fn set_output_shape(builder: &mut GraphBuilder, onnx: &OnnxGraph, node: NodeId, index: usize, tensor: &str) {
+    if let Some(shape) = onnx.shape_of(tensor) {
+        // Symbolic dimensions such as the batch size default to 1 until they're given a size
+        for dim in shape.dims() {
+            if let Dim::Symbolic(ref name) = *dim {
+                if builder.dim_by_name(name).is_none() {
+                    builder.set_dim(name, 1);
+                }
+            }
+        }
+        let out = node.get(builder).outputs[index];
+        builder.set_var_shape(out, shape);
+    }
+}
+
+/// Make a `Variable` node produce the type of the tensor it stands for.
+fn set_variable_dtype(builder: &mut GraphBuilder, onnx: &OnnxGraph, node: NodeId, tensor: &str) {
+    if let Some(dtype) = onnx.dtype_of(tensor) {
+        node.get_mut(builder).attrs.set("dtype", AttrValue::Str(dtype.name().to_string()));
+    }
+}
+
+fn dtype_from_onnx(data_type: i64) -> Option<DType> {
+    match data_type {
+        FLOAT => Some(DType::F32),
+        // Doubles keep their type, though their values are read as f32 like every other type
+        DOUBLE => Some(DType::F64),
+        INT32 | INT64 => Some(DType::I32),
+        BOOL => Some(DType::Bool),
+        _ => None,
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+// Message parsing
+
+fn parse_graph(buf: &[u8]) -> Result<OnnxGraph, String> {
+    let mut graph = OnnxGraph::new();
+    let mut reader = Reader::new(buf);
+    while let Some((field, value)) = try!(reader.next_field()) {
+        match field {
+            1 => { graph.nodes.push(try!(parse_node(try!(value.as_bytes())))); },
+            2 => { graph.name = try!(value.as_string()); },
+            5 => {
+                let (name, data_type, tensor) = try!(parse_tensor(try!(value.as_bytes())));
+                graph.initializer_types.push((name.clone(), data_type));
+                graph.initializers.push((name, tensor));
+            },
+            11 => { graph.inputs.push(try!(parse_value_info(try!(value.as_bytes())))); },
+            12 => { graph.outputs.push(try!(parse_value_info(try!(value.as_bytes())))); },
+            13 => { graph.value_info.push(try!(parse_value_info(try!(value.as_bytes())))); },
+            _ => { },
+        }
+    }
+    Ok(graph)
+}
+
+fn parse_node(buf: &[u8]) -> Result<OnnxNode, String> {
+    let mut node = OnnxNode {
+        name: String::new(),
+        op_type: String::new(),
+        inputs: vec![],
+        outputs: vec![],
+        attributes: vec![],
+    };
+    let mut reader = Reader::new(buf);
+    while let Some((field, value)) = try!(reader.next_field()) {
+        match field {
+            1 => { node.inputs.push(try!(value.as_string())); },
+            2 => { node.outputs.push(try!(value.as_string())); },
+            3 => { node.name = try!(value.as_string()); },
+            4 => { node.op_type = try!(value.as_string()); },
+            5 => {
+                if let Some(attr) = try!(parse_attribute(try!(value.as_bytes()))) {
+                    node.attributes.push(attr);
+                }
+            },
+            _ => { },
+        }
+    }
+    Ok(node)
+}
+
+/// Returns `None` for attribute kinds we don't use, such as subgraphs.
+fn parse_attribute(buf: &[u8]) -> Result<Option<(String, Attribute)>, String> {
+    let mut name = String::new();
+    let mut attr = None;
+    let mut floats = vec![];
+    let mut ints = vec![];
+    let mut reader = Reader::new(buf);
+    while let Some((field, value)) = try!(reader.next_field()) {
+        match field {
+            1 => { name = try!(value.as_string()); },
+            2 => { attr = Some(Attribute::Float(try!(value.as_f32()))); },
+            3 => { attr = Some(Attribute::Int(try!(value.as_i64()))); },
+            4 => { attr = Some(Attribute::String(try!(value.as_string()))); },
+            5 => { attr = Some(Attribute::Tensor(try!(parse_tensor(try!(value.as_bytes()))).2)); },
+            7 => { floats.extend(try!(value.as_f32s())); },
+            8 => { ints.extend(try!(value.as_varints()).into_iter().map(|i| i as i64)); },
+            _ => { },
+        }
+    }
+    if attr.is_none() {
+        if !floats.is_empty() {
+            attr = Some(Attribute::Floats(floats));
+        } else if !ints.is_empty() {
+            attr = Some(Attribute::Ints(ints));
+        }
+    }
+    Ok(attr.map(|attr| (name, attr)))
+}
+
+/// Parse a `TensorProto` into its name, TensorProto.DataType and values.
+fn parse_tensor(buf: &[u8]) -> Result<(String, i64, Tensor), String> {
+    let mut name = String::new();
+    let mut dims = vec![];
+    let mut data_type = FLOAT;
+    let mut raw_data: &[u8] = &[];
+    let mut data = vec![];
+    let mut reader = Reader::new(buf);
+    while let Some((field, value)) = try!(reader.next_field()) {
+        match field {
+            1 => {
+                for d in try!(value.as_varints()) {
+                    dims.push(try!(dim_size(d)));
+                }
+            },
+            2 => { data_type = try!(value.as_i64()); },
+            4 => { data.extend(try!(value.as_f32s())); },
+            // int32_data also holds bools
+            5 => {
+                for i in try!(value.as_varints()) {
+                    data.push(try!(exact_int(i as i32 as i64)));
+                }
+            },
+            7 => {
+                for i in try!(value.as_varints()) {
+                    data.push(try!(exact_int(i as i64)));
+                }
+            },
+            8 => { name = try!(value.as_string()); },
+            9 => { raw_data = try!(value.as_bytes()); },
+            10 => { data.extend(try!(f64s(&value)).into_iter().map(|d| d as f32)); },
+            _ => { },
+        }
+    }
+    if !raw_data.is_empty() {
+        data = try!(decode_raw(raw_data, data_type));
+    }
+    let len = try!(dims.iter().fold(Some(1usize), |n, &d| n.and_then(|n| n.checked_mul(d)))
+                       .ok_or(format!("Initializer '{}' has too many values: {:?}", name, dims)));
+    if data.len() != len {
+        return Err(format!("Initializer '{}' has {} values but shape {:?}", name, data.len(), dims));
+    }
+    Ok((name, data_type, Tensor::new(dims, data)))
+}
+
+fn parse_value_info(buf: &[u8]) -> Result<ValueInfo, String> {
+    let mut info = ValueInfo { name: String::new(), shape: None, dtype: None };
+    let mut reader = Reader::new(buf);
+    while let Some((field, value)) = try!(reader.next_field()) {
+        match field {
+            1 => { info.name = try!(value.as_string()); },
+            2 => {
+                // TypeProto.tensor_type
+                let mut type_reader = Reader::new(try!(value.as_bytes()));
+                while let Some((field, value)) = try!(type_reader.next_field()) {
+                    if field == 1 {
+                        let (dtype, shape) = try!(parse_tensor_type(try!(value.as_bytes())));
+                        info.dtype = dtype;
+                        info.shape = shape;
+                    }
+                }
+            },
+            _ => { },
+        }
+    }
+    Ok(info)
+}
+
+/// Parse a `TypeProto.Tensor` into its element type and shape.
+fn parse_tensor_type(buf: &[u8]) -> Result<(Option<DType>, Option<Shape>), String> {
+    let mut dtype = None;
+    let mut shape = None;
+    let mut reader = Reader::new(buf);
+    while let Some((field, value)) = try!(reader.next_field()) {
+        match field {
+            1 => { dtype = dtype_from_onnx(try!(value.as_i64())); },
+            2 => { shape = Some(try!(parse_tensor_shape(try!(value.as_bytes())))); },
+            _ => { },
+        }
+    }
+    Ok((dtype, shape))
+}
+
+fn parse_tensor_shape(buf: &[u8]) -> Result<Shape, String> {
+    let mut shape = vec![];
+    let mut shape_reader = Reader::new(buf);
+    while let Some((field, value)) = try!(shape_reader.next_field()) {
+        if field != 1 {
+            continue;
+        }
+        // TensorShapeProto.Dimension: either dim_value or a symbolic dim_param
+        let mut dim = Dim::Known(1);
+        let mut dim_reader = Reader::new(try!(value.as_bytes()));
+        while let Some((field, value)) = try!(dim_reader.next_field()) {
+            match field {
+                1 => { dim = Dim::Known(try!(dim_size(try!(value.as_u64())))); },
+                2 => { dim = Dim::Symbolic(try!(value.as_string())); },
+                _ => { },
+            }
+        }
+        shape.push(dim);
+    }
+    Ok(Shape::new(shape))
+}
+
+/// A dimension read as an int64 varint, which has to be a size we can hold.
+fn dim_size(d: u64) -> Result<usize, String> {
+    if d as i64 >= 0 && d <= ::std::usize::MAX as u64 {
+        Ok(d as usize)
+    } else {
+        Err(format!("Bad dimension {}", d as i64))
+    }
+}
+
+/// An integer as an f32, if the f32 holds it exactly.
+fn exact_int(i: i64) -> Result<f32, String> {
+    let max = dtype::MAX_EXACT_INT as i64;
+    if i < -max || i > max {
+        return Err(format!("Integer {} is too large to hold exactly", i));
+    }
+    Ok(i as f32)
+}
+
+/// Split `raw` into elements of `size` bytes, rejecting data that stops partway through one.
+fn elements(raw: &[u8], size: usize) -> Result<::std::slice::Chunks<u8>, String> {
+    if raw.len() % size != 0 {
+        return Err(format!("Tensor data of {} bytes doesn't split into {}-byte elements", raw.len(), size));
+    }
+    Ok(raw.chunks(size))
+}
+
+fn f64s(value: &Value) -> Result<Vec<f64>, String> {
+    match *value {
+        Value::Bytes(b) => Ok(try!(elements(b, 8)).map(|c| LittleEndian::read_f64(c)).collect()),
+        Value::Fixed64(v) => {
+            let mut buf = [0; 8];
+            LittleEndian::write_u64(&mut buf, v);
+            Ok(vec![LittleEndian::read_f64(&buf)])
+        },
+        _ => Err("expected a 64-bit float".to_string()),
+    }
+}
+
+fn decode_raw(raw: &[u8], data_type: i64) -> Result<Vec<f32>, String> {
+    let data = match data_type {
+        FLOAT => try!(elements(raw, 4)).map(|c| LittleEndian::read_f32(c)).collect(),
+        DOUBLE => try!(elements(raw, 8)).map(|c| LittleEndian::read_f64(c) as f32).collect(),
+        INT32 => {
+            let mut data = vec![];
+            for c in try!(elements(raw, 4)) {
+                data.push(try!(exact_int(LittleEndian::read_i32(c) as i64)));
+            }
+            data
+        },
+        INT64 => {
+            let mut data = vec![];
+            for c in try!(elements(raw, 8)) {
+                data.push(try!(exact_int(LittleEndian::read_i64(c))));
+            }
+            data
+        },
+        BOOL => raw.iter().map(|&b| if b != 0 { 1.0 } else { 0.0 }).collect(),
+        _ => { return Err(format!("Unsupported tensor data type {}", data_type)); },
+    };
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use byteorder::{ByteOrder, LittleEndian};
+
+    use super::{decode_raw, dtype_from_onnx, import, parse_model, Attribute, DOUBLE, FLOAT, INT32, INT64};
+    use super::super::catalog::OpCatalog;
+    use super::super::dtype::DType;
+    use super::super::graph_builder::GraphBuilder;
+    use super::super::op::AttrValue;
+    use super::super::shape::{Dim, Shape};
+
+    fn varint(mut v: u64, out: &mut Vec<u8>) {
+        while v >= 0x80 {
+            out.push((v as u8) | 0x80);
+            v >>= 7;
+        }
+        out.push(v as u8);
+    }
+
+    fn int_field(field: u32, v: u64) -> Vec<u8> {
+        let mut out = vec![];
+        varint((field as u64) << 3, &mut out);
+        varint(v, &mut out);
+        out
+    }
+
+    fn bytes_field(field: u32, bytes: &[u8]) -> Vec<u8> {
+        let mut out = vec![];
+        varint((field as u64) << 3 | 2, &mut out);
+        varint(bytes.len() as u64, &mut out);
+        out.extend_from_slice(bytes);
+        out
+    }
+
+    fn float_field(field: u32, f: f32) -> Vec<u8> {
+        let mut out = vec![];
+        varint((field as u64) << 3 | 5, &mut out);
+        let mut buf = [0; 4];
+        LittleEndian::write_f32(&mut buf, f);
+        out.extend_from_slice(&buf);
+        out
+    }
+
+    fn message(fields: Vec<Vec<u8>>) -> Vec<u8> {
+        fields.concat()
+    }
+
+    fn node(name: &str, op_type: &str, inputs: &[&str], outputs: &[&str], attrs: Vec<Vec<u8>>) -> Vec<u8> {
+        let mut fields: Vec<Vec<u8>> = inputs.iter().map(|i| bytes_field(1, i.as_bytes())).collect();
+        fields.extend(outputs.iter().map(|o| bytes_field(2, o.as_bytes())));
+        if !name.is_empty() {
+            fields.push(bytes_field(3, name.as_bytes()));
+        }
+        fields.push(bytes_field(4, op_type.as_bytes()));
+        fields.extend(attrs.iter().map(|a| bytes_field(5, a)));
+        message(fields)
+    }
+
+    fn tensor(name: &str, dims: &[u64], data_type: i64, raw: &[u8]) -> Vec<u8> {
+        let mut fields: Vec<Vec<u8>> = dims.iter().map(|&d| int_field(1, d)).collect();
+        fields.push(int_field(2, data_type as u64));
+        fields.push(bytes_field(8, name.as_bytes()));
+        fields.push(bytes_field(9, raw));
+        message(fields)
+    }
+
+    fn floats(values: &[f32]) -> Vec<u8> {
+        let mut raw = vec![0; values.len() * 4];
+        for (c, &v) in raw.chunks_mut(4).zip(values) {
+            LittleEndian::write_f32(c, v);
+        }
+        raw
+    }
+
+    /// A float input whose dimensions are either sizes or symbolic names.
+    fn input(name: &str, dims: &[Result<u64, &str>]) -> Vec<u8> {
+        let dims: Vec<Vec<u8>> = dims.iter().map(|dim| {
+            let dim = match *dim {
+                Ok(size) => int_field(1, size),
+                Err(param) => bytes_field(2, param.as_bytes()),
+            };
+            bytes_field(1, &dim)
+        }).collect();
+        let tensor_type = message(vec![int_field(1, FLOAT as u64), bytes_field(2, &message(dims))]);
+        let type_proto = bytes_field(1, &tensor_type);
+        message(vec![bytes_field(1, name.as_bytes()), bytes_field(2, &type_proto)])
+    }
+
+    /// x[N, 2] · w[2, 2] through a LeakyRelu into an operator we don't have.
+    fn model() -> Vec<u8> {
+        let alpha = message(vec![bytes_field(1, b"alpha"), float_field(2, 0.2)]);
+        let graph = message(vec![
+            bytes_field(1, &node("mm", "MatMul", &["x", "w"], &["y"], vec![])),
+            bytes_field(1, &node("", "LeakyRelu", &["y"], &["z"], vec![alpha])),
+            bytes_field(1, &node("custom", "Custom", &["z"], &["out"], vec![])),
+            bytes_field(2, b"test"),
+            bytes_field(5, &tensor("w", &[2, 2], FLOAT, &floats(&[1.0, 2.0, 3.0, 4.0]))),
+            bytes_field(11, &input("x", &[Err("N"), Ok(2)])),
+            // Older exporters list initializers as inputs too
+            bytes_field(11, &input("w", &[Ok(2), Ok(2)])),
+        ]);
+        bytes_field(7, &graph)
+    }
+
+    #[test]
+    fn parses_a_model() {
+        let graph = parse_model(&model()).unwrap();
+        assert_eq!(graph.name, "test");
+        assert_eq!(graph.nodes.len(), 3);
+        assert_eq!(graph.nodes[0].op_type, "MatMul");
+        assert_eq!(graph.nodes[0].inputs, vec!["x".to_string(), "w".to_string()]);
+        match graph.nodes[1].attributes[0] {
+            (ref name, Attribute::Float(alpha)) => {
+                assert_eq!(name, "alpha");
+                assert_eq!(alpha, 0.2);
+            },
+            _ => panic!("alpha should be a float"),
+        }
+
+        let (ref name, ref w) = graph.initializers[0];
+        assert_eq!(name, "w");
+        assert_eq!(w.shape, vec![2, 2]);
+        assert_eq!(w.data, vec![1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(graph.shape_of("x"), Some(Shape::new(vec![Dim::Symbolic("N".to_string()), Dim::Known(2)])));
+        assert_eq!(graph.dtype_of("x"), Some(DType::F32));
+    }
+
+    #[test]
+    fn imports_below_the_existing_graph() {
+        let catalog = OpCatalog::builtin();
+        let mut builder = GraphBuilder::new();
+        let existing = builder.add_node("existing".to_string(), [300.0, 200.0], catalog.get("Variable").unwrap());
+
+        let graph = parse_model(&model()).unwrap();
+        let report = import(&graph, &mut builder, &catalog).unwrap();
+        assert_eq!(report.nodes, 3);
+        assert_eq!(report.variables, 2);
+        assert_eq!(report.unsupported, vec!["Custom".to_string()]);
+
+        let x = builder.node_by_name("x").unwrap();
+        let w = builder.node_by_name("w").unwrap();
+        let mm = builder.node_by_name("mm").unwrap();
+        let relu = builder.node_by_name("LeakyRelu").unwrap();
+        let custom = builder.node_by_name("custom").unwrap();
+        assert!(builder.input_edge(mm, 0) == Some((x, 0)));
+        assert!(builder.input_edge(mm, 1) == Some((w, 0)));
+        assert!(builder.input_edge(relu, 0) == Some((mm, 0)));
+        assert!(builder.input_edge(custom, 0) == Some((relu, 0)));
+        assert!(custom.get(&builder).op.placeholder);
+        assert_eq!(relu.get(&builder).attrs.get("alpha"), Some(&AttrValue::Float(0.2)));
+        assert_eq!(w.get(&builder).outputs[0].get(&builder.vars).value.as_ref().unwrap().data,
+                   vec![1.0, 2.0, 3.0, 4.0]);
+        assert!(builder.dim_by_name("N").is_some());
+
+        let bottom = existing.get(&builder).pos()[1] + existing.get(&builder).size()[1];
+        assert_eq!(existing.get(&builder).pos(), [300.0, 200.0]);
+        for node in &[x, w, mm, relu, custom] {
+            assert!(node.get(&builder).pos()[1] > bottom);
+        }
+    }
+
+    #[test]
+    fn rejects_inputs_nothing_produces() {
+        let graph = message(vec![bytes_field(1, &node("mm", "MatMul", &["x", "w"], &["y"], vec![]))]);
+        let graph = parse_model(&bytes_field(7, &graph)).unwrap();
+        assert!(import(&graph, &mut GraphBuilder::new(), &OpCatalog::builtin()).is_err());
+    }
+
+    #[test]
+    fn leaves_the_graph_alone_when_an_import_fails() {
+        let catalog = OpCatalog::builtin();
+        let mut builder = GraphBuilder::new();
+        builder.add_node("existing".to_string(), [0.0, 0.0], catalog.get("Variable").unwrap());
+        let graph = message(vec![
+            bytes_field(5, &tensor("w", &[2], FLOAT, &floats(&[1.0, 2.0]))),
+            bytes_field(1, &node("r", "Relu", &["w"], &["y"], vec![])),
+            bytes_field(1, &node("mm", "MatMul", &["y", "x"], &["z"], vec![])),
+        ]);
+        let graph = parse_model(&bytes_field(7, &graph)).unwrap();
+        assert!(import(&graph, &mut builder, &catalog).is_err());
+        assert_eq!(builder.node_ids().len(), 1);
+        assert!(builder.var_by_name("w").is_none());
+    }
+
+    #[test]
+    fn rejects_dimensions_too_large_to_hold() {
+        let huge = 1 << 62;
+        let graph = message(vec![bytes_field(5, &tensor("w", &[huge, huge, 0], FLOAT, &[]))]);
+        assert!(parse_model(&bytes_field(7, &graph)).is_err());
+        let graph = message(vec![bytes_field(5, &tensor("w", &[-1i64 as u64], FLOAT, &[]))]);
+        assert!(parse_model(&bytes_field(7, &graph)).is_err());
+    }
+
+    #[test]
+    fn rejects_values_not_matching_the_shape() {
+        let graph = message(vec![bytes_field(5, &tensor("w", &[2, 2], FLOAT, &floats(&[1.0, 2.0, 3.0])))]);
+        assert!(parse_model(&bytes_field(7, &graph)).is_err());
+    }
+
+    #[test]
+    fn reads_doubles_as_f64() {
+        assert_eq!(dtype_from_onnx(DOUBLE), Some(DType::F64));
+        let mut raw = [0; 16];
+        LittleEndian::write_f64(&mut raw[..8], 0.5);
+        LittleEndian::write_f64(&mut raw[8..], -2.0);
+        assert_eq!(decode_raw(&raw, DOUBLE).unwrap(), vec![0.5, -2.0]);
+    }
+
+    #[test]
+    fn rejects_data_ending_partway_through_an_element() {
+        assert!(decode_raw(&[0, 0, 0x80, 0x3F, 0], FLOAT).is_err());
+        assert!(decode_raw(&[1, 0, 0, 0, 0, 0, 0], INT64).is_err());
+    }
+
+    #[test]
+    fn rejects_integers_an_f32_cant_hold() {
+        let mut raw = [0; 8];
+        LittleEndian::write_i64(&mut raw, 1 << 24);
+        assert_eq!(decode_raw(&raw, INT64).unwrap(), vec![16777216.0]);
+        LittleEndian::write_i64(&mut raw, (1 << 24) + 1);
+        assert!(decode_raw(&raw, INT64).is_err());
+        let mut raw = [0; 4];
+        LittleEndian::write_i32(&mut raw, -7);
+        assert_eq!(decode_raw(&raw, INT32).unwrap(), vec![-7.0]);
+    }
+}
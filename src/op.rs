@@ -1,26 +1,305 @@
+use std::rc::Rc;
+
 use matrix;
 use dl;
 
+use super::codegen::EmitNode;
+use super::dtype::{self, DType};
+use super::shape::Shape;
+use super::subgraph::Subgraph;
+use super::tensor::Tensor;
 use super::var_store::{VarIndex, VarStore};
 
-//pub trait BuildFn : Fn(&matrix::Context, &mut dl::Graph, &VarStore, &[Option<VarIndex>], &[VarIndex]) -> dl::NodeIndex { }
+/// Adds the operation to a `dl::Graph`, returning the `dl` node it added, if any.
+pub type GpuBuildFn = Fn(&matrix::Context, &mut dl::Graph, &mut VarStore, &[Option<VarIndex>], &[VarIndex])
+                         -> Option<dl::NodeIndex>;
+/// Works out the output shapes from the input shapes, or says why the inputs don't fit.
+pub type ShapeFn = Fn(&Attrs, &[Shape]) -> Result<Vec<Shape>, String>;
+/// Works out the output types from the input types, or says which input has the wrong type.
+pub type DTypeFn = Fn(&Attrs, &[DType]) -> Result<Vec<DType>, String>;
+/// Computes the outputs from the inputs. The outputs come zeroed and already shaped.
+pub type ForwardFn = Fn(&Attrs, &[&Tensor], &mut [Tensor]);
+/// Adds the gradients of the inputs to `in_grads`, given the inputs, outputs and the gradients
+/// of the outputs.
+pub type BackwardFn = Fn(&Attrs, &[&Tensor], &[&Tensor], &[&Tensor], &mut [Tensor]);
 
 pub struct Operation {
     pub name: String,
-    pub num_inputs: usize,
-    pub num_outputs: usize,
-    pub build: Box<Fn(&matrix::Context, &mut dl::Graph, &mut VarStore, &[Option<VarIndex>], &[VarIndex])>,
+    /// Which group of the palette the operation is listed in.
+    pub category: String,
+    /// Names of the input and output ports, e.g. `pred` and `target` for a loss.
+    pub inputs: Vec<String>,
+    pub outputs: Vec<String>,
+    /// Attributes every node of this operation has, with their default values.
+    pub attrs: Attrs,
+    pub infer_shapes: Option<Box<ShapeFn>>,
+    pub infer_dtypes: Option<Box<DTypeFn>>,
+    pub gpu_build: Option<Box<GpuBuildFn>>,
+    pub forward: Option<Box<ForwardFn>>,
+    /// Used instead of `forward` when evaluating rather than training, e.g. by dropout.
+    pub eval_forward: Option<Box<ForwardFn>>,
+    pub backward: Option<Box<BackwardFn>>,
+    /// `(output, input)` pairs: after a training forward pass the value of the output is stored in
+    /// the variable feeding the input, e.g. the running mean of a batch normalization.
+    pub updates: Vec<(usize, usize)>,
+    /// Emits the Rust statements that do what `gpu_build` does; see `codegen`.
+    pub codegen: Option<Box<Fn(&EmitNode) -> String>>,
+    /// Emits the Rust statements that do what the kernel does when evaluating, for code generated
+    /// for the CPU.
+    pub cpu_codegen: Option<Box<Fn(&Attrs, &EmitNode) -> String>>,
+    /// Whether the first output is a loss to minimize. Training seeds the gradients of losses.
+    pub loss: bool,
+    /// How a recurrent cell is chained from one timestep to the next; see `GraphBuilder::unroll`.
+    pub recurrence: Option<Recurrence>,
+    /// For composite nodes, the subgraph they stand for. They don't compute anything themselves;
+    /// see `GraphBuilder::group_nodes`.
+    pub subgraph: Option<Rc<Subgraph>>,
+    pub placeholder: bool,
 }
 
 impl Operation {
-    pub fn new<F>(name: String, num_inputs: usize, num_outputs: usize, build: F) -> Self
-        where F: Fn(&matrix::Context, &mut dl::Graph, &mut VarStore,
-                    &[Option<VarIndex>], &[VarIndex]) + 'static {
+    /// A new operation taking and producing floats; see `with_dtypes` to accept other types.
+    pub fn new(name: &str, category: &str, inputs: &[&str], outputs: &[&str]) -> Self {
+        let num_outputs = outputs.len();
         Operation {
-            name: name,
-            num_inputs: num_inputs,
-            num_outputs: num_outputs,
-            build: Box::new(build),
+            name: name.to_string(),
+            category: category.to_string(),
+            inputs: inputs.iter().map(|s| s.to_string()).collect(),
+            outputs: outputs.iter().map(|s| s.to_string()).collect(),
+            attrs: Attrs::new(),
+            infer_shapes: None,
+            infer_dtypes: Some(Box::new(move |_, dtypes| same_dtype(dtype::FLOATS, dtypes, num_outputs))),
+            gpu_build: None,
+            forward: None,
+            eval_forward: None,
+            backward: None,
+            updates: vec![],
+            codegen: None,
+            cpu_codegen: None,
+            loss: false,
+            recurrence: None,
+            subgraph: None,
+            placeholder: false,
+        }
+    }
+
+    pub fn with_attr(mut self, name: &str, default: AttrValue) -> Self {
+        self.attrs.set(name, default);
+        self
+    }
+
+    pub fn with_shape_fn<F>(mut self, infer_shapes: F) -> Self
+        where F: Fn(&Attrs, &[Shape]) -> Result<Vec<Shape>, String> + 'static {
+        self.infer_shapes = Some(Box::new(infer_shapes));
+        self
+    }
+
+    /// Accept inputs of any of the types in `accepted`, as long as they're all the same type, and
+    /// produce outputs of that type.
+    pub fn with_dtypes(mut self, accepted: &'static [DType]) -> Self {
+        let num_outputs = self.num_outputs();
+        self.infer_dtypes = Some(Box::new(move |_, dtypes| same_dtype(accepted, dtypes, num_outputs)));
+        self
+    }
+
+    pub fn with_dtype_fn<F>(mut self, infer_dtypes: F) -> Self
+        where F: Fn(&Attrs, &[DType]) -> Result<Vec<DType>, String> + 'static {
+        self.infer_dtypes = Some(Box::new(infer_dtypes));
+        self
+    }
+
+    /// Support building this operation into a `dl::Graph`.
+    pub fn with_gpu_build<F>(mut self, build: F) -> Self
+        where F: Fn(&matrix::Context, &mut dl::Graph, &mut VarStore,
+                    &[Option<VarIndex>], &[VarIndex]) -> Option<dl::NodeIndex> + 'static {
+        self.gpu_build = Some(Box::new(build));
+        self
+    }
+
+    /// Support running this operation on the CPU backend.
+    pub fn with_kernel<F, B>(mut self, forward: F, backward: B) -> Self
+        where F: Fn(&Attrs, &[&Tensor], &mut [Tensor]) + 'static,
+              B: Fn(&Attrs, &[&Tensor], &[&Tensor], &[&Tensor], &mut [Tensor]) + 'static {
+        self.forward = Some(Box::new(forward));
+        self.backward = Some(Box::new(backward));
+        self
+    }
+
+    /// Compute the outputs with `forward` instead of the kernel's when evaluating.
+    pub fn with_eval_kernel<F>(mut self, forward: F) -> Self
+        where F: Fn(&Attrs, &[&Tensor], &mut [Tensor]) + 'static {
+        self.eval_forward = Some(Box::new(forward));
+        self
+    }
+
+    /// After each training forward pass, store the value of output `output` in the variable
+    /// feeding input `input`.
+    pub fn with_update(mut self, output: usize, input: usize) -> Self {
+        self.updates.push((output, input));
+        self
+    }
+
+    /// Support generating code for this operation. `codegen` must bind each of the node's output
+    /// identifiers to the `dl::VarIndex` holding that output, and for losses bind `node` to the
+    /// `dl::NodeIndex` it added.
+    pub fn with_codegen<F>(mut self, codegen: F) -> Self
+        where F: Fn(&EmitNode) -> String + 'static {
+        self.codegen = Some(Box::new(codegen));
+        self
+    }
+
+    /// Support generating code running this operation on the CPU. `codegen` must assign each of
+    /// the node's outputs the values the kernel gives when evaluating, using the helpers in the
+    /// generated module.
+    pub fn with_cpu_codegen<F>(mut self, codegen: F) -> Self
+        where F: Fn(&Attrs, &EmitNode) -> String + 'static {
+        self.cpu_codegen = Some(Box::new(codegen));
+        self
+    }
+
+    /// Mark the operation's first output as a loss.
+    pub fn as_loss(mut self) -> Self {
+        self.loss = true;
+        self
+    }
+
+    /// Mark the operation as a recurrent cell taking each timestep's value on input `input`. Each
+    /// `(output, input)` pair in `state` feeds that output into that input of the next timestep.
+    pub fn as_cell(mut self, input: usize, state: &[(usize, usize)]) -> Self {
+        self.recurrence = Some(Recurrence {
+            input: input,
+            state: state.to_vec(),
+        });
+        self
+    }
+
+    pub fn num_inputs(&self) -> usize {
+        self.inputs.len()
+    }
+
+    pub fn num_outputs(&self) -> usize {
+        self.outputs.len()
+    }
+
+    /// An operation we know the name and arity of but can't build, e.g. an unsupported operator
+    /// in an imported model. It can be placed and connected but the graph won't build with it.
+    pub fn placeholder(name: String, num_inputs: usize, num_outputs: usize) -> Self {
+        let mut op = Operation::new(&name, "Unsupported", &[], &[]);
+        op.inputs = (0..num_inputs).map(|i| format!("in{}", i)).collect();
+        op.outputs = (0..num_outputs).map(|i| format!("out{}", i)).collect();
+        op.infer_dtypes = None;
+        op.placeholder = true;
+        op
+    }
+
+    /// The operation of composite nodes standing for `subgraph`, with a port for each of its
+    /// exposed inputs and outputs.
+    pub fn composite(subgraph: Rc<Subgraph>, category: &str) -> Self {
+        let mut op = Operation::new(&subgraph.name, category, &[], &[]);
+        op.inputs = subgraph.inputs.iter().map(|&(ref name, _)| name.clone()).collect();
+        op.outputs = subgraph.outputs.iter().map(|&(ref name, _)| name.clone()).collect();
+        op.infer_dtypes = None;
+        op.subgraph = Some(subgraph);
+        op
+    }
+}
+
+pub struct Recurrence {
+    /// The input taking one timestep of the sequence.
+    pub input: usize,
+    /// Outputs carried over to inputs of the next timestep, e.g. the hidden state.
+    pub state: Vec<(usize, usize)>,
+}
+
+impl Recurrence {
+    /// Whether input `i` takes state from the previous timestep.
+    pub fn is_state(&self, i: usize) -> bool {
+        self.state.iter().any(|&(_, input)| input == i)
+    }
+}
+
+/// The type rule of most operations: every input has the same type, one of `accepted`, and the
+/// outputs have that type too. Operations without inputs produce f32.
+fn same_dtype(accepted: &[DType], dtypes: &[DType], num_outputs: usize) -> Result<Vec<DType>, String> {
+    let dtype = dtypes.first().cloned().unwrap_or(DType::F32);
+    for (i, &d) in dtypes.iter().enumerate() {
+        if !accepted.contains(&d) {
+            let accepted: Vec<&str> = accepted.iter().map(|d| d.name()).collect();
+            return Err(format!("Input {} is {} but should be {}", i, d, accepted.join(" or ")));
+        }
+        if d != dtype {
+            return Err(format!("Input {} is {} but input 0 is {}", i, d, dtype));
+        }
+    }
+    Ok(vec![dtype; num_outputs])
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AttrValue {
+    Float(f32),
+    Int(i64),
+    Str(String),
+    Ints(Vec<i64>),
+}
+
+/// Per-node settings of an operation, such as the slope of a leaky ReLU.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Attrs {
+    attrs: Vec<(String, AttrValue)>,
+}
+
+impl Attrs {
+    pub fn new() -> Self {
+        Attrs {
+            attrs: vec![],
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&AttrValue> {
+        self.attrs.iter().find(|&&(ref n, _)| n == name).map(|&(_, ref v)| v)
+    }
+
+    pub fn set(&mut self, name: &str, value: AttrValue) {
+        match self.attrs.iter().position(|&(ref n, _)| n == name) {
+            Some(i) => { self.attrs[i].1 = value; },
+            None => { self.attrs.push((name.to_string(), value)); },
+        }
+    }
+
+    pub fn iter(&self) -> ::std::slice::Iter<(String, AttrValue)> {
+        self.attrs.iter()
+    }
+
+    /// Panics if the attribute is missing or isn't a number; operations declare the attributes
+    /// their kernels use, so that'd be a bug in the operation.
+    pub fn float(&self, name: &str) -> f32 {
+        match self.get(name) {
+            Some(&AttrValue::Float(f)) => f,
+            Some(&AttrValue::Int(i)) => i as f32,
+            _ => panic!("Attribute '{}' should be a float", name),
+        }
+    }
+
+    pub fn int(&self, name: &str) -> i64 {
+        match self.get(name) {
+            Some(&AttrValue::Int(i)) => i,
+            _ => panic!("Attribute '{}' should be an integer", name),
+        }
+    }
+
+    pub fn ints(&self, name: &str) -> &[i64] {
+        match self.get(name) {
+            Some(&AttrValue::Ints(ref ints)) => ints,
+            _ => panic!("Attribute '{}' should be a list of integers", name),
+        }
+    }
+
+    pub fn str(&self, name: &str) -> &str {
+        match self.get(name) {
+            Some(&AttrValue::Str(ref s)) => s,
+            _ => panic!("Attribute '{}' should be a string", name),
         }
     }
 }
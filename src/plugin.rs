@@ -0,0 +1,690 @@
+//! Operations loaded from plugins: dynamic libraries in the plugins directory that register
+//! operations through a C ABI, so they can be built separately from Deep Lab and with any
+//! compiler version.
+//!
+//! A plugin exports two functions:
+//!
+//! ```text
+//! u32 deeplab_plugin_abi_version();
+//! i32 deeplab_plugin_register(PluginRegistrar *registrar);
+//! ```
+//!
+//! The first returns the `PLUGIN_ABI_VERSION` the plugin was written against and is checked
+//! before anything else is called. The second calls `registrar->register_op` once for each
+//! operation, returning nonzero if registration failed. Plugins declare the `#[repr(C)]` types
+//! below themselves; changing any of them means bumping `PLUGIN_ABI_VERSION`.
+//!
+//! Plugin operations run on the CPU backend through their kernels. `dl` has no stable ABI, so a
+//! plugin can't add GPU nodes itself; an operation with a build function instead describes how
+//! to build it out of built-in operations that run on the GPU, which is checked when the plugin
+//! is loaded and against the operation's shapes whenever they're inferred.
+
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_void};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::{env, slice};
+
+use libloading::Library;
+
+use dl;
+use matrix;
+
+use super::catalog::OpCatalog;
+use super::op::{AttrValue, Attrs, Operation};
+use super::shape::{Dim, Shape};
+use super::tensor::Tensor;
+use super::var_store::{VarIndex, VarStore};
+
+pub const PLUGIN_ABI_VERSION: u32 = 2;
+/// Where plugins are loaded from at startup.
+pub const PLUGIN_DIR: &'static str = "plugins";
+/// The most dimensions a shape passed to or from a plugin can have.
+pub const MAX_RANK: usize = 8;
+const ERROR_LEN: usize = 256;
+
+/// A shape, with -1 for dimensions that are symbolic. An output dimension of -1 takes the
+/// dimension at the same position of the first input.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct PluginShape {
+    pub rank: u32,
+    pub dims: [i64; MAX_RANK],
+}
+
+/// A view of a tensor's values, row-major. Plugins must only write to the outputs of `forward`
+/// and the input gradients of `backward`.
+#[repr(C)]
+pub struct PluginTensor {
+    pub rank: usize,
+    pub dims: *const usize,
+    pub len: usize,
+    pub data: *mut f32,
+}
+
+/// Fills in the output shapes, or writes a NUL-terminated message of at most `error_len` bytes to
+/// `error` and returns nonzero. `attrs` holds the values of the attributes in the order they
+/// were declared.
+pub type PluginShapeFn = extern "C" fn(attrs: *const f32,
+                                       in_shapes: *const PluginShape, num_inputs: usize,
+                                       out_shapes: *mut PluginShape, num_outputs: usize,
+                                       error: *mut c_char, error_len: usize) -> i32;
+/// Computes the outputs, which come zeroed and already shaped.
+pub type PluginForwardFn = extern "C" fn(attrs: *const f32,
+                                         inputs: *const PluginTensor, num_inputs: usize,
+                                         outputs: *mut PluginTensor, num_outputs: usize);
+/// Adds the gradients of the inputs to `in_grads`, which has `num_inputs` tensors, given the
+/// gradients of the outputs in `out_grads`, which has `num_outputs`.
+pub type PluginBackwardFn = extern "C" fn(attrs: *const f32,
+                                          inputs: *const PluginTensor, num_inputs: usize,
+                                          outputs: *const PluginTensor, num_outputs: usize,
+                                          out_grads: *const PluginTensor,
+                                          in_grads: *mut PluginTensor);
+
+/// Describes how to build the operation on the GPU, by calling `builder->add_node` for each
+/// built-in operation it's made of, in order. It's called once, while the operation is
+/// registered, so the build can't depend on attributes. Returns nonzero if it failed.
+pub type PluginBuildFn = extern "C" fn(builder: *mut PluginGraphBuilder) -> i32;
+
+/// What a build function adds nodes through. Values are numbered: the operation's inputs first,
+/// then its outputs, then the values added by `add_node` in the order they were added.
+#[repr(C)]
+pub struct PluginGraphBuilder {
+    pub context: *mut c_void,
+    /// Adds a node of the built-in operation called `op`, with its default attributes, fed by
+    /// the values `inputs`. Each of `outputs` is either an output of the plugin operation for the
+    /// node to compute, or -1 for a new value, whose number is written back. Returns nonzero if
+    /// the node was rejected.
+    pub add_node: extern "C" fn(context: *mut c_void, op: *const c_char,
+                                inputs: *const i64, num_inputs: usize,
+                                outputs: *mut i64, num_outputs: usize) -> i32,
+}
+
+/// Everything Deep Lab needs to know about a plugin operation. The strings and arrays only need
+/// to live until `register_op` returns. Operations without `backward` pass no gradient back, and
+/// those without `build` only run on the CPU.
+#[repr(C)]
+pub struct PluginOpDescriptor {
+    pub name: *const c_char,
+    pub category: *const c_char,
+    pub num_inputs: usize,
+    pub input_names: *const *const c_char,
+    pub num_outputs: usize,
+    pub output_names: *const *const c_char,
+    /// Attributes are floats, with their defaults in `attr_defaults`.
+    pub num_attrs: usize,
+    pub attr_names: *const *const c_char,
+    pub attr_defaults: *const f32,
+    pub infer_shapes: Option<PluginShapeFn>,
+    pub forward: Option<PluginForwardFn>,
+    pub backward: Option<PluginBackwardFn>,
+    pub build: Option<PluginBuildFn>,
+}
+
+#[repr(C)]
+pub struct PluginRegistrar {
+    pub abi_version: u32,
+    pub context: *mut c_void,
+    /// Returns nonzero if the operation was rejected.
+    pub register_op: extern "C" fn(context: *mut c_void, op: *const PluginOpDescriptor) -> i32,
+}
+
+/// Add the operations of every plugin in `dir` to `catalog`, reporting plugins that fail to load.
+/// Operations can't take the name of one already in the catalog.
+pub fn add_plugins(catalog: &mut OpCatalog, dir: &Path) {
+    for (path, ops) in load_plugins(dir, catalog) {
+        match ops {
+            Ok(ops) => {
+                for op in ops {
+                    if let Err(e) = catalog.try_add(op) {
+                        println!("Failed to load an operation of the plugin '{}': {}", path.display(), e);
+                    }
+                }
+            },
+            Err(e) => { println!("Failed to load the plugin '{}': {}", path.display(), e); },
+        }
+    }
+}
+
+/// The operations of every plugin in `dir`, or why each plugin that failed couldn't be loaded.
+/// Build functions can use the operations in `catalog`.
+pub fn load_plugins(dir: &Path, catalog: &OpCatalog) -> Vec<(PathBuf, Result<Vec<Operation>, String>)> {
+    let mut plugins = vec![];
+    let entries = match dir.read_dir() {
+        Ok(entries) => entries,
+        Err(_) => { return plugins; },
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        if path.extension().and_then(|ext| ext.to_str()) == Some(env::consts::DLL_EXTENSION) {
+            let ops = load_plugin(&path, catalog);
+            plugins.push((path, ops));
+        }
+    }
+    plugins
+}
+
+pub fn load_plugin(path: &Path, catalog: &OpCatalog) -> Result<Vec<Operation>, String> {
+    let library = Rc::new(try!(Library::new(path).map_err(|e| e.to_string())));
+
+    let version = unsafe {
+        let abi_version = try!(library.get::<extern "C" fn() -> u32>(b"deeplab_plugin_abi_version\0")
+                                      .map_err(|e| e.to_string()));
+        (*abi_version)()
+    };
+    try!(check_abi_version(version));
+
+    let mut registration = Registration {
+        library: library.clone(),
+        builtin: catalog.ops().to_vec(),
+        ops: vec![],
+        errors: vec![],
+    };
+    let status = unsafe {
+        let register = try!(library.get::<extern "C" fn(*mut PluginRegistrar) -> i32>(b"deeplab_plugin_register\0")
+                                   .map_err(|e| e.to_string()));
+        let mut registrar = PluginRegistrar {
+            abi_version: PLUGIN_ABI_VERSION,
+            context: &mut registration as *mut Registration as *mut c_void,
+            register_op: register_op,
+        };
+        (*register)(&mut registrar)
+    };
+    if !registration.errors.is_empty() {
+        return Err(registration.errors.join(", "));
+    }
+    if status != 0 {
+        return Err(format!("registration failed with status {}", status));
+    }
+    Ok(registration.ops)
+}
+
+fn check_abi_version(version: u32) -> Result<(), String> {
+    if version != PLUGIN_ABI_VERSION {
+        return Err(format!("the plugin was written for ABI version {}, but this is version {}",
+                           version, PLUGIN_ABI_VERSION));
+    }
+    Ok(())
+}
+
+/// What `register_op` gets as its context while a plugin registers its operations.
+struct Registration {
+    library: Rc<Library>,
+    /// The operations build functions can use.
+    builtin: Vec<Rc<Operation>>,
+    ops: Vec<Operation>,
+    errors: Vec<String>,
+}
+
+extern "C" fn register_op(context: *mut c_void, op: *const PluginOpDescriptor) -> i32 {
+    let registration = unsafe { &mut *(context as *mut Registration) };
+    match unsafe { read_descriptor(&*op, &registration.builtin) } {
+        Ok(desc) => {
+            registration.ops.push(operation(desc, &registration.library));
+            0
+        },
+        Err(e) => {
+            registration.errors.push(e);
+            1
+        },
+    }
+}
+
+/// A plugin's operation, with everything copied out of its descriptor.
+struct PluginOp {
+    name: String,
+    category: String,
+    inputs: Vec<String>,
+    outputs: Vec<String>,
+    attrs: Vec<(String, f32)>,
+    infer_shapes: PluginShapeFn,
+    forward: PluginForwardFn,
+    backward: Option<PluginBackwardFn>,
+    build: Option<GpuBuild>,
+}
+
+unsafe fn read_descriptor(desc: &PluginOpDescriptor, builtin: &[Rc<Operation>]) -> Result<PluginOp, String> {
+    let name = try!(string(desc.name));
+    let (infer_shapes, forward) = match (desc.infer_shapes, desc.forward) {
+        (Some(infer_shapes), Some(forward)) => (infer_shapes, forward),
+        _ => { return Err(format!("'{}' needs shape inference and a forward kernel", name)); },
+    };
+    let category = try!(string(desc.category));
+    let inputs = try!(strings(desc.input_names, desc.num_inputs));
+    let outputs = try!(strings(desc.output_names, desc.num_outputs));
+    let attr_names = try!(strings(desc.attr_names, desc.num_attrs));
+    let attr_defaults = if desc.num_attrs == 0 { &[][..] } else { slice::from_raw_parts(desc.attr_defaults, desc.num_attrs) };
+    let build = match desc.build {
+        Some(build) => Some(try!(GpuBuild::record(build, builtin, inputs.len(), outputs.len())
+                                          .map_err(|e| format!("the build of '{}' failed: {}", name, e)))),
+        None => None,
+    };
+    Ok(PluginOp {
+        name: name,
+        category: category,
+        inputs: inputs,
+        outputs: outputs,
+        attrs: attr_names.into_iter().zip(attr_defaults.iter().cloned()).collect(),
+        infer_shapes: infer_shapes,
+        forward: forward,
+        backward: desc.backward,
+        build: build,
+    })
+}
+
+/// Wrap a plugin's operation, keeping `library` loaded for as long as the operation is around.
+fn operation(desc: PluginOp, library: &Rc<Library>) -> Operation {
+    let kernels = Rc::new(PluginKernels {
+        _library: library.clone(),
+        attrs: desc.attrs.iter().map(|&(ref name, _)| name.clone()).collect(),
+        num_outputs: desc.outputs.len(),
+        infer_shapes: desc.infer_shapes,
+        forward: desc.forward,
+        backward: desc.backward,
+    });
+    let inputs: Vec<&str> = desc.inputs.iter().map(|s| &s[..]).collect();
+    let outputs: Vec<&str> = desc.outputs.iter().map(|s| &s[..]).collect();
+    let mut op = Operation::new(&desc.name, &desc.category, &inputs, &outputs);
+    for &(ref attr, default) in &desc.attrs {
+        op = op.with_attr(attr, AttrValue::Float(default));
+    }
+    let build = desc.build.map(Rc::new);
+    let (shape_kernels, shape_build) = (kernels.clone(), build.clone());
+    let (forward_kernels, backward_kernels) = (kernels.clone(), kernels);
+    op = op.with_shape_fn(move |attrs, shapes| {
+               let out_shapes = try!(shape_kernels.infer_shapes(attrs, shapes));
+               if let Some(ref build) = shape_build {
+                   try!(build.check_shapes(shapes, &out_shapes));
+               }
+               Ok(out_shapes)
+           })
+           .with_kernel(move |attrs, i, o| forward_kernels.forward(attrs, i, o),
+                        move |attrs, i, o, g, ig| backward_kernels.backward(attrs, i, o, g, ig));
+    match build {
+        Some(build) => {
+            let name = desc.name.clone();
+            op.with_gpu_build(move |ctx, graph, vars, inputs, outputs| build.build(&name, ctx, graph, vars, inputs, outputs))
+        },
+        None => op,
+    }
+}
+
+unsafe fn string(s: *const c_char) -> Result<String, String> {
+    if s.is_null() {
+        return Err("a plugin operation has a null string".to_string());
+    }
+    Ok(CStr::from_ptr(s).to_string_lossy().into_owned())
+}
+
+unsafe fn strings(s: *const *const c_char, len: usize) -> Result<Vec<String>, String> {
+    if len == 0 {
+        return Ok(vec![]);
+    }
+    slice::from_raw_parts(s, len).iter().map(|&s| string(s)).collect()
+}
+
+/// A plugin operation's functions. The plugin stays loaded as long as this does.
+struct PluginKernels {
+    _library: Rc<Library>,
+    attrs: Vec<String>,
+    num_outputs: usize,
+    infer_shapes: PluginShapeFn,
+    forward: PluginForwardFn,
+    backward: Option<PluginBackwardFn>,
+}
+
+impl PluginKernels {
+    fn attr_values(&self, attrs: &Attrs) -> Vec<f32> {
+        self.attrs.iter().map(|name| attrs.float(name)).collect()
+    }
+
+    fn infer_shapes(&self, attrs: &Attrs, shapes: &[Shape]) -> Result<Vec<Shape>, String> {
+        let mut in_shapes = vec![];
+        for shape in shapes {
+            if shape.rank() > MAX_RANK {
+                return Err(format!("plugin operations take at most {} dimensions", MAX_RANK));
+            }
+            let mut dims = [0; MAX_RANK];
+            for (d, dim) in dims.iter_mut().zip(shape.dims()) {
+                *d = dim.known().map(|d| d as i64).unwrap_or(-1);
+            }
+            in_shapes.push(PluginShape { rank: shape.rank() as u32, dims: dims });
+        }
+        let mut out_shapes = vec![PluginShape { rank: 0, dims: [0; MAX_RANK] }; self.num_outputs];
+        let mut error = [0 as c_char; ERROR_LEN];
+        let attrs = self.attr_values(attrs);
+        let status = (self.infer_shapes)(attrs.as_ptr(), in_shapes.as_ptr(), in_shapes.len(),
+                                         out_shapes.as_mut_ptr(), out_shapes.len(),
+                                         error.as_mut_ptr(), ERROR_LEN);
+        if status != 0 {
+            error[ERROR_LEN - 1] = 0;
+            return Err(unsafe { CStr::from_ptr(error.as_ptr()) }.to_string_lossy().into_owned());
+        }
+
+        let mut result = vec![];
+        for out_shape in &out_shapes {
+            let rank = out_shape.rank as usize;
+            if rank > MAX_RANK {
+                return Err(format!("plugin operations give at most {} dimensions", MAX_RANK));
+            }
+            let mut dims = vec![];
+            for (i, &d) in out_shape.dims[..rank].iter().enumerate() {
+                if d >= 0 {
+                    dims.push(Dim::Known(d as usize));
+                } else if let Some(dim) = shapes.first().and_then(|shape| shape.dims().get(i)) {
+                    dims.push(dim.clone());
+                } else {
+                    return Err(format!("output dimension {} can't be taken from the first input", i));
+                }
+            }
+            result.push(Shape::new(dims));
+        }
+        Ok(result)
+    }
+
+    fn forward(&self, attrs: &Attrs, inputs: &[&Tensor], outputs: &mut [Tensor]) {
+        let attrs = self.attr_values(attrs);
+        let inputs: Vec<PluginTensor> = inputs.iter().map(|t| view(t)).collect();
+        let mut outputs: Vec<PluginTensor> = outputs.iter_mut().map(view_mut).collect();
+        (self.forward)(attrs.as_ptr(), inputs.as_ptr(), inputs.len(), outputs.as_mut_ptr(), outputs.len());
+    }
+
+    fn backward(&self, attrs: &Attrs, inputs: &[&Tensor], outputs: &[&Tensor], out_grads: &[&Tensor],
+                in_grads: &mut [Tensor]) {
+        let backward = match self.backward {
+            Some(backward) => backward,
+            None => { return; },
+        };
+        let attrs = self.attr_values(attrs);
+        let inputs: Vec<PluginTensor> = inputs.iter().map(|t| view(t)).collect();
+        let outputs: Vec<PluginTensor> = outputs.iter().map(|t| view(t)).collect();
+        let out_grads: Vec<PluginTensor> = out_grads.iter().map(|t| view(t)).collect();
+        let mut in_grads: Vec<PluginTensor> = in_grads.iter_mut().map(view_mut).collect();
+        backward(attrs.as_ptr(), inputs.as_ptr(), inputs.len(), outputs.as_ptr(), outputs.len(),
+                 out_grads.as_ptr(), in_grads.as_mut_ptr());
+    }
+}
+
+/// How a plugin operation is built on the GPU, recorded from its build function.
+struct GpuBuild {
+    num_inputs: usize,
+    num_outputs: usize,
+    steps: Vec<BuildStep>,
+}
+
+/// A node of a GPU build, with values numbered as for `PluginGraphBuilder`.
+struct BuildStep {
+    op: Rc<Operation>,
+    inputs: Vec<usize>,
+    outputs: Vec<usize>,
+}
+
+impl GpuBuild {
+    fn record(build: PluginBuildFn, builtin: &[Rc<Operation>], num_inputs: usize, num_outputs: usize)
+              -> Result<GpuBuild, String> {
+        let mut recording = Recording {
+            builtin: builtin,
+            build: GpuBuild { num_inputs: num_inputs, num_outputs: num_outputs, steps: vec![] },
+            computed: (0..num_inputs + num_outputs).map(|i| i < num_inputs).collect(),
+            error: None,
+        };
+        let status = {
+            let mut builder = PluginGraphBuilder {
+                context: &mut recording as *mut Recording as *mut c_void,
+                add_node: add_node,
+            };
+            build(&mut builder)
+        };
+        if let Some(e) = recording.error {
+            return Err(e);
+        }
+        if status != 0 {
+            return Err(format!("it returned status {}", status));
+        }
+        if let Some(i) = recording.computed[num_inputs..num_inputs + num_outputs].iter().position(|&c| !c) {
+            return Err(format!("output {} isn't computed", i));
+        }
+        Ok(recording.build)
+    }
+
+    /// Check that the build gives outputs of the shapes the plugin says the operation has.
+    fn check_shapes(&self, in_shapes: &[Shape], out_shapes: &[Shape]) -> Result<(), String> {
+        let mut values: Vec<Option<Shape>> = in_shapes.iter().cloned().map(Some).collect();
+        values.extend((0..self.num_outputs).map(|_| None));
+        for step in &self.steps {
+            // Recording made sure the inputs are computed before they're used
+            let shapes: Vec<Shape> = step.inputs.iter().map(|&i| values[i].clone().unwrap()).collect();
+            let infer_shapes = step.op.infer_shapes.as_ref().unwrap();
+            let shapes = try!(infer_shapes(&step.op.attrs, &shapes)
+                                  .map_err(|e| format!("'{}' in the GPU build: {}", step.op.name, e)));
+            for (&o, shape) in step.outputs.iter().zip(shapes) {
+                if o < values.len() {
+                    values[o] = Some(shape);
+                } else {
+                    values.push(Some(shape));
+                }
+            }
+        }
+        for (i, expected) in out_shapes.iter().enumerate() {
+            let built = values[self.num_inputs + i].as_ref().unwrap();
+            if built != expected {
+                return Err(format!("the GPU build gives output {} shape {} instead of {}", i, built, expected));
+            }
+        }
+        Ok(())
+    }
+
+    /// Add the nodes of the build to `graph`, with a variable for each value in between named
+    /// after the operation.
+    fn build(&self, name: &str, ctx: &matrix::Context, graph: &mut dl::Graph, vars: &mut VarStore,
+             inputs: &[Option<VarIndex>], outputs: &[VarIndex]) -> Option<dl::NodeIndex> {
+        let mut values: Vec<VarIndex> = inputs.iter().map(|v| v.unwrap()).chain(outputs.iter().cloned()).collect();
+        let mut last = None;
+        for step in &self.steps {
+            let step_inputs: Vec<Option<VarIndex>> = step.inputs.iter().map(|&i| Some(values[i])).collect();
+            let shapes: Vec<Shape> = step.inputs.iter().map(|&i| Shape::from_dims(&values[i].get(vars).dims)).collect();
+            // Checked against the operation's shapes when they were inferred
+            let infer_shapes = step.op.infer_shapes.as_ref().unwrap();
+            let out_shapes = infer_shapes(&step.op.attrs, &shapes).unwrap();
+            let mut step_outputs = vec![];
+            for (&o, shape) in step.outputs.iter().zip(out_shapes) {
+                if o == values.len() {
+                    values.push(vars.add(format!("{}/{}", name, o), shape));
+                }
+                step_outputs.push(values[o]);
+            }
+            let build = step.op.gpu_build.as_ref().unwrap();
+            last = build(ctx, graph, vars, &step_inputs, &step_outputs);
+        }
+        last
+    }
+}
+
+/// What `add_node` gets as its context while a build function runs.
+struct Recording<'a> {
+    builtin: &'a [Rc<Operation>],
+    build: GpuBuild,
+    /// Whether each value has been computed yet.
+    computed: Vec<bool>,
+    error: Option<String>,
+}
+
+impl<'a> Recording<'a> {
+    unsafe fn add_node(&mut self, op: *const c_char, inputs: *const i64, num_inputs: usize,
+                       outputs: *mut i64, num_outputs: usize) -> Result<(), String> {
+        let name = try!(string(op));
+        let op = try!(self.builtin.iter().find(|op| op.name == name).cloned()
+                          .ok_or(format!("there's no operation called '{}'", name)));
+        if op.gpu_build.is_none() || op.infer_shapes.is_none() || op.num_inputs() == 0 {
+            return Err(format!("'{}' can't be part of a GPU build", name));
+        }
+        if num_inputs != op.num_inputs() || num_outputs != op.num_outputs() {
+            return Err(format!("'{}' takes {} inputs and gives {} outputs", name, op.num_inputs(), op.num_outputs()));
+        }
+        let inputs = slice::from_raw_parts(inputs, num_inputs);
+        let outputs = slice::from_raw_parts_mut(outputs, num_outputs);
+
+        let mut step = BuildStep { op: op, inputs: vec![], outputs: vec![] };
+        for &input in inputs {
+            if input < 0 || !self.computed.get(input as usize).cloned().unwrap_or(false) {
+                return Err(format!("'{}' is fed value {}, which isn't computed yet", name, input));
+            }
+            step.inputs.push(input as usize);
+        }
+        let plugin_outputs = self.build.num_inputs as i64..(self.build.num_inputs + self.build.num_outputs) as i64;
+        for output in outputs.iter_mut() {
+            if *output == -1 {
+                *output = self.computed.len() as i64;
+                self.computed.push(true);
+            } else if *output >= plugin_outputs.start && *output < plugin_outputs.end && !self.computed[*output as usize] {
+                self.computed[*output as usize] = true;
+            } else {
+                return Err(format!("'{}' can't compute value {}", name, output));
+            }
+            step.outputs.push(*output as usize);
+        }
+        self.build.steps.push(step);
+        Ok(())
+    }
+}
+
+extern "C" fn add_node(context: *mut c_void, op: *const c_char, inputs: *const i64, num_inputs: usize,
+                       outputs: *mut i64, num_outputs: usize) -> i32 {
+    let recording = unsafe { &mut *(context as *mut Recording) };
+    match unsafe { recording.add_node(op, inputs, num_inputs, outputs, num_outputs) } {
+        Ok(()) => 0,
+        Err(e) => {
+            // Later nodes likely only fail because of the first
+            if recording.error.is_none() {
+                recording.error = Some(e);
+            }
+            1
+        },
+    }
+}
+
+fn view(t: &Tensor) -> PluginTensor {
+    PluginTensor {
+        rank: t.shape.len(),
+        dims: t.shape.as_ptr(),
+        len: t.data.len(),
+        data: t.data.as_ptr() as *mut f32,
+    }
+}
+
+fn view_mut(t: &mut Tensor) -> PluginTensor {
+    PluginTensor {
+        rank: t.shape.len(),
+        dims: t.shape.as_ptr(),
+        len: t.data.len(),
+        data: t.data.as_mut_ptr(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+    use std::os::raw::c_char;
+
+    use super::{check_abi_version, read_descriptor, PluginBuildFn, PluginForwardFn, PluginGraphBuilder,
+                PluginOp, PluginOpDescriptor, PluginShape, PluginShapeFn, PluginTensor, PLUGIN_ABI_VERSION};
+    use super::super::catalog::OpCatalog;
+    use super::super::shape::Shape;
+
+    extern "C" fn same_shapes(_: *const f32, in_shapes: *const PluginShape, _: usize,
+                              out_shapes: *mut PluginShape, _: usize, _: *mut c_char, _: usize) -> i32 {
+        unsafe { *out_shapes = *in_shapes; }
+        0
+    }
+
+    extern "C" fn forward(_: *const f32, _: *const PluginTensor, _: usize, _: *mut PluginTensor, _: usize) {}
+
+    fn relu() -> *const c_char {
+        b"ReLU\0".as_ptr() as *const c_char
+    }
+
+    /// Two ReLUs in a row: value 0 is the input and value 1 the output.
+    extern "C" fn relu_twice(builder: *mut PluginGraphBuilder) -> i32 {
+        let builder = unsafe { &mut *builder };
+        let (mut hidden, mut out) = (-1, 1);
+        let first = (builder.add_node)(builder.context, relu(), &0, 1, &mut hidden, 1);
+        first | (builder.add_node)(builder.context, relu(), &hidden, 1, &mut out, 1)
+    }
+
+    extern "C" fn leaves_the_output(builder: *mut PluginGraphBuilder) -> i32 {
+        let builder = unsafe { &mut *builder };
+        (builder.add_node)(builder.context, relu(), &0, 1, &mut -1, 1)
+    }
+
+    extern "C" fn uses_dense(builder: *mut PluginGraphBuilder) -> i32 {
+        let builder = unsafe { &mut *builder };
+        (builder.add_node)(builder.context, b"Dense\0".as_ptr() as *const c_char, [0, 0, 0].as_ptr(), 3, &mut 1, 1)
+    }
+
+    fn read(forward: Option<PluginForwardFn>, build: Option<PluginBuildFn>) -> Result<PluginOp, String> {
+        let (name, category) = (CString::new("Scale").unwrap(), CString::new("Plugins").unwrap());
+        let (x, y, scale) = (CString::new("x").unwrap(), CString::new("y").unwrap(), CString::new("scale").unwrap());
+        let (inputs, outputs, attrs, defaults) = ([x.as_ptr()], [y.as_ptr()], [scale.as_ptr()], [2.0]);
+        let desc = PluginOpDescriptor {
+            name: name.as_ptr(),
+            category: category.as_ptr(),
+            num_inputs: 1,
+            input_names: inputs.as_ptr(),
+            num_outputs: 1,
+            output_names: outputs.as_ptr(),
+            num_attrs: 1,
+            attr_names: attrs.as_ptr(),
+            attr_defaults: defaults.as_ptr(),
+            infer_shapes: Some(same_shapes as PluginShapeFn),
+            forward: forward,
+            backward: None,
+            build: build,
+        };
+        unsafe { read_descriptor(&desc, OpCatalog::builtin().ops()) }
+    }
+
+    #[test]
+    fn checks_the_abi_version() {
+        assert!(check_abi_version(PLUGIN_ABI_VERSION).is_ok());
+        assert_eq!(check_abi_version(PLUGIN_ABI_VERSION + 1).err().unwrap(),
+                   format!("the plugin was written for ABI version {}, but this is version {}",
+                           PLUGIN_ABI_VERSION + 1, PLUGIN_ABI_VERSION));
+    }
+
+    #[test]
+    fn copies_descriptors() {
+        let op = read(Some(forward as PluginForwardFn), None).ok().unwrap();
+        assert_eq!(op.name, "Scale");
+        assert_eq!(op.category, "Plugins");
+        assert_eq!(op.inputs, vec!["x".to_string()]);
+        assert_eq!(op.outputs, vec!["y".to_string()]);
+        assert_eq!(op.attrs, vec![("scale".to_string(), 2.0)]);
+        assert!(op.build.is_none());
+    }
+
+    #[test]
+    fn needs_a_forward_kernel() {
+        assert_eq!(read(None, None).err().unwrap(), "'Scale' needs shape inference and a forward kernel");
+    }
+
+    #[test]
+    fn records_builds() {
+        let op = read(Some(forward as PluginForwardFn), Some(relu_twice as PluginBuildFn)).ok().unwrap();
+        let build = op.build.unwrap();
+        assert_eq!(build.steps.len(), 2);
+        assert_eq!((&build.steps[0].inputs, &build.steps[0].outputs), (&vec![0], &vec![2]));
+        assert_eq!((&build.steps[1].inputs, &build.steps[1].outputs), (&vec![2], &vec![1]));
+
+        let shape = Shape::from_dims(&[2, 3]);
+        assert!(build.check_shapes(&[shape.clone()], &[shape.clone()]).is_ok());
+        assert!(build.check_shapes(&[shape], &[Shape::from_dims(&[3, 2])]).is_err());
+    }
+
+    #[test]
+    fn rejects_builds_that_dont_fit() {
+        assert_eq!(read(Some(forward as PluginForwardFn), Some(leaves_the_output as PluginBuildFn)).err().unwrap(),
+                   "the build of 'Scale' failed: output 0 isn't computed");
+        assert_eq!(read(Some(forward as PluginForwardFn), Some(uses_dense as PluginBuildFn)).err().unwrap(),
+                   "the build of 'Scale' failed: 'Dense' can't be part of a GPU build");
+    }
+}
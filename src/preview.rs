@@ -0,0 +1,141 @@
+//! Small pictures of tensors, drawn next to the output ports they come out of: the value of
+//! scalars, a sparkline of vectors and a heatmap of everything else.
+
+use graphics;
+use opengl_graphics::GlGraphics;
+use opengl_graphics::glyph_cache::GlyphCache;
+
+use super::tensor::Tensor;
+
+pub const WIDTH: f64 = 48.0;
+const HEIGHT: f64 = 16.0;
+const TEXT_SIZE: u32 = 9;
+/// Heatmaps are drawn with at most this many cells each way, averaging the values in each cell.
+const MAX_CELLS: usize = 8;
+/// Sparklines are drawn with at most this many points, averaging the values in between.
+const MAX_POINTS: usize = 48;
+
+/// Draw a preview of `value` with its left edge at `pos[0]`, centred vertically on `pos[1]`.
+pub fn draw(value: &Tensor, pos: [f64; 2], c: &graphics::Context, gl: &mut GlGraphics, glyphs: &mut GlyphCache) {
+    use graphics::{Text, Transformed};
+
+    let top_left = [pos[0], pos[1] - HEIGHT/2.0];
+    let (rows, cols) = value.shape_2d();
+    if value.len() == 0 {
+        return;
+    } else if value.len() == 1 {
+        Text::new_color([0.0, 0.0, 0.0, 1.0], TEXT_SIZE)
+            .draw(&format_scalar(value.data[0]), glyphs, &c.draw_state,
+                  c.transform.trans(top_left[0], pos[1] + 3.0), gl);
+    } else if rows == 1 || cols == 1 {
+        sparkline(&value.data, top_left, c, gl);
+    } else {
+        heatmap(&value.data, rows, cols, top_left, c, gl);
+    }
+}
+
+/// Scalars with few enough digits to fit next to a port.
+fn format_scalar(x: f32) -> String {
+    if x != 0.0 && (x.abs() >= 1e4 || x.abs() < 1e-3) {
+        format!("{:.2e}", x)
+    } else {
+        format!("{:.4}", x)
+    }
+}
+
+/// The values as a line, scaled to fill the preview's height.
+fn sparkline(data: &[f32], top_left: [f64; 2], c: &graphics::Context, gl: &mut GlGraphics) {
+    use graphics::{Line, Rectangle};
+
+    let points = bucket_means(data, MAX_POINTS);
+    let (min, max) = range(&points);
+    let scale = if max > min { HEIGHT / (max - min) as f64 } else { 0.0 };
+    let y = |x: f32| if scale == 0.0 { top_left[1] + HEIGHT/2.0 } else { top_left[1] + HEIGHT - (x - min) as f64*scale };
+    let step = WIDTH / ((points.len() - 1).max(1)) as f64;
+
+    Rectangle::new([1.0, 1.0, 1.0, 0.8]).draw([top_left[0], top_left[1], WIDTH, HEIGHT], &c.draw_state, c.transform, gl);
+    let line = Line::new([0.1, 0.3, 0.8, 1.0], 0.5);
+    for i in 1..points.len() {
+        line.draw([top_left[0] + (i - 1) as f64*step, y(points[i - 1]), top_left[0] + i as f64*step, y(points[i])],
+                  &c.draw_state, c.transform, gl);
+    }
+}
+
+/// The values as cells going from blue for the smallest through white to red for the largest,
+/// with cells that aren't finite grey. Cells are square, so the preview has the matrix's shape.
+fn heatmap(data: &[f32], rows: usize, cols: usize, top_left: [f64; 2], c: &graphics::Context, gl: &mut GlGraphics) {
+    use graphics::Rectangle;
+
+    let (cell_rows, cell_cols) = (rows.min(MAX_CELLS), cols.min(MAX_CELLS));
+    let mut cells = vec![(0.0, 0); cell_rows*cell_cols];
+    for r in 0..rows {
+        for col in 0..cols {
+            let cell = &mut cells[(r*cell_rows/rows)*cell_cols + col*cell_cols/cols];
+            cell.0 += data[r*cols + col];
+            cell.1 += 1;
+        }
+    }
+    let means: Vec<f32> = cells.iter().map(|&(sum, n)| sum / n as f32).collect();
+    let (min, max) = range(&means);
+
+    let size = HEIGHT / cell_rows.max(cell_cols) as f64;
+    for (i, &mean) in means.iter().enumerate() {
+        let t = if max > min { (mean - min) / (max - min) } else { 0.5 };
+        let color = if !mean.is_finite() {
+            [0.5, 0.5, 0.5, 1.0]
+        } else if t < 0.5 {
+            [2.0*t, 2.0*t, 1.0, 1.0]
+        } else {
+            [1.0, 2.0 - 2.0*t, 2.0 - 2.0*t, 1.0]
+        };
+        let (r, col) = (i / cell_cols, i % cell_cols);
+        Rectangle::new(color).draw([top_left[0] + col as f64*size, top_left[1] + r as f64*size, size, size],
+                                   &c.draw_state, c.transform, gl);
+    }
+}
+
+/// The data split into at most `n` runs of about equal length, and the mean of each.
+fn bucket_means(data: &[f32], n: usize) -> Vec<f32> {
+    let buckets = data.len().min(n);
+    (0..buckets).map(|b| {
+        let run = &data[b*data.len()/buckets..(b + 1)*data.len()/buckets];
+        run.iter().fold(0.0, |sum, &x| sum + x) / run.len() as f32
+    }).collect()
+}
+
+/// The smallest and largest finite values.
+fn range(data: &[f32]) -> (f32, f32) {
+    data.iter().filter(|x| x.is_finite()).fold((::std::f32::INFINITY, ::std::f32::NEG_INFINITY),
+                                                |(min, max), &x| (min.min(x), max.max(x)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32;
+
+    use super::{bucket_means, format_scalar, range};
+
+    #[test]
+    fn averages_runs_of_values() {
+        assert_eq!(bucket_means(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0], 3), vec![1.5, 3.5, 5.5]);
+        assert_eq!(bucket_means(&[1.0, 2.0, 3.0, 4.0, 5.0], 2), vec![1.5, 4.0]);
+        // Fewer values than buckets are kept as they are
+        assert_eq!(bucket_means(&[1.0, -1.0], 8), vec![1.0, -1.0]);
+        assert_eq!(bucket_means(&[], 8), Vec::<f32>::new());
+    }
+
+    #[test]
+    fn ranges_over_finite_values() {
+        assert_eq!(range(&[2.0, -1.0, 0.5]), (-1.0, 2.0));
+        assert_eq!(range(&[f32::NAN, 3.0, f32::INFINITY, 1.0]), (1.0, 3.0));
+        assert_eq!(range(&[f32::NAN]), (f32::INFINITY, f32::NEG_INFINITY));
+    }
+
+    #[test]
+    fn formats_scalars_to_fit() {
+        assert_eq!(format_scalar(0.0), "0.0000");
+        assert_eq!(format_scalar(1.5), "1.5000");
+        assert_eq!(format_scalar(12345.0), "1.23e4");
+        assert_eq!(format_scalar(0.0001), "1.00e-4");
+    }
+}
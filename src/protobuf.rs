@@ -0,0 +1,206 @@
+//! Just enough of the protocol buffer wire format to read ONNX files without generated code.
+
+use byteorder::{ByteOrder, LittleEndian};
+
+pub enum Value<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    Bytes(&'a [u8]),
+    Fixed32(u32),
+}
+
+impl<'a> Value<'a> {
+    pub fn as_u64(&self) -> Result<u64, String> {
+        match *self {
+            Value::Varint(v) | Value::Fixed64(v) => Ok(v),
+            Value::Fixed32(v) => Ok(v as u64),
+            Value::Bytes(_) => Err("expected an integer, found a length-delimited field".to_string()),
+        }
+    }
+
+    pub fn as_i64(&self) -> Result<i64, String> {
+        self.as_u64().map(|v| v as i64)
+    }
+
+    pub fn as_f32(&self) -> Result<f32, String> {
+        match *self {
+            Value::Fixed32(v) => {
+                let mut buf = [0; 4];
+                LittleEndian::write_u32(&mut buf, v);
+                Ok(LittleEndian::read_f32(&buf))
+            },
+            _ => Err("expected a 32-bit float".to_string()),
+        }
+    }
+
+    pub fn as_bytes(&self) -> Result<&'a [u8], String> {
+        match *self {
+            Value::Bytes(b) => Ok(b),
+            _ => Err("expected a length-delimited field".to_string()),
+        }
+    }
+
+    pub fn as_string(&self) -> Result<String, String> {
+        let bytes = try!(self.as_bytes());
+        String::from_utf8(bytes.to_vec()).map_err(|e| e.to_string())
+    }
+
+    /// Reads a repeated integer field, which may be encoded either packed or one element at a
+    /// time.
+    pub fn as_varints(&self) -> Result<Vec<u64>, String> {
+        match *self {
+            Value::Bytes(b) => {
+                let mut reader = Reader::new(b);
+                let mut values = vec![];
+                while !reader.is_empty() {
+                    values.push(try!(reader.read_varint()));
+                }
+                Ok(values)
+            },
+            _ => Ok(vec![try!(self.as_u64())]),
+        }
+    }
+
+    /// Reads a repeated float field, which may be encoded either packed or one element at a time.
+    pub fn as_f32s(&self) -> Result<Vec<f32>, String> {
+        match *self {
+            Value::Bytes(b) => {
+                if b.len() % 4 != 0 {
+                    return Err("packed float field has a truncated element".to_string());
+                }
+                Ok(b.chunks(4).map(|c| LittleEndian::read_f32(c)).collect())
+            },
+            _ => Ok(vec![try!(self.as_f32())]),
+        }
+    }
+}
+
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Reader {
+            buf: buf,
+            pos: 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+
+    /// Returns the next field number and its value, or `None` at the end of the message.
+    pub fn next_field(&mut self) -> Result<Option<(u32, Value<'a>)>, String> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+        let key = try!(self.read_varint());
+        let field = (key >> 3) as u32;
+        let value = match key & 0x7 {
+            0 => Value::Varint(try!(self.read_varint())),
+            1 => Value::Fixed64(LittleEndian::read_u64(try!(self.read_bytes(8)))),
+            2 => {
+                let len = try!(self.read_varint()) as usize;
+                Value::Bytes(try!(self.read_bytes(len)))
+            },
+            5 => Value::Fixed32(LittleEndian::read_u32(try!(self.read_bytes(4)))),
+            wire_type => {
+                return Err(format!("unsupported wire type {} for field {}", wire_type, field));
+            },
+        };
+        Ok(Some((field, value)))
+    }
+
+    pub fn read_varint(&mut self) -> Result<u64, String> {
+        let mut value = 0u64;
+        let mut shift = 0;
+        loop {
+            if self.is_empty() {
+                return Err("unexpected end of message in varint".to_string());
+            }
+            let byte = self.buf[self.pos];
+            self.pos += 1;
+            if shift < 64 {
+                value |= ((byte & 0x7F) as u64) << shift;
+            }
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+            shift += 7;
+        }
+    }
+
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.buf.len() - self.pos < len {
+            return Err("unexpected end of message".to_string());
+        }
+        let bytes = &self.buf[self.pos..self.pos+len];
+        self.pos += len;
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Reader, Value};
+
+    #[test]
+    fn reads_each_wire_type() {
+        // field 1 varint 300, field 2 "hi", field 3 fixed32 1.5, field 4 fixed64 7
+        let buf = [0x08, 0xAC, 0x02,
+                   0x12, 0x02, b'h', b'i',
+                   0x1D, 0x00, 0x00, 0xC0, 0x3F,
+                   0x21, 7, 0, 0, 0, 0, 0, 0, 0];
+        let mut reader = Reader::new(&buf);
+
+        let (field, value) = reader.next_field().unwrap().unwrap();
+        assert_eq!(field, 1);
+        assert_eq!(value.as_u64().unwrap(), 300);
+        let (field, value) = reader.next_field().unwrap().unwrap();
+        assert_eq!(field, 2);
+        assert_eq!(value.as_string().unwrap(), "hi");
+        let (field, value) = reader.next_field().unwrap().unwrap();
+        assert_eq!(field, 3);
+        assert_eq!(value.as_f32().unwrap(), 1.5);
+        let (field, value) = reader.next_field().unwrap().unwrap();
+        assert_eq!(field, 4);
+        assert_eq!(value.as_u64().unwrap(), 7);
+        assert!(reader.next_field().unwrap().is_none());
+    }
+
+    #[test]
+    fn reads_packed_and_unpacked_repeated_fields() {
+        assert_eq!(Value::Bytes(&[1, 0x96, 0x01]).as_varints().unwrap(), vec![1, 150]);
+        assert_eq!(Value::Varint(5).as_varints().unwrap(), vec![5]);
+        assert_eq!(Value::Bytes(&[0, 0, 0x80, 0x3F, 0, 0, 0, 0x40]).as_f32s().unwrap(), vec![1.0, 2.0]);
+        assert_eq!(Value::Fixed32(0x3F800000).as_f32s().unwrap(), vec![1.0]);
+    }
+
+    #[test]
+    fn rejects_truncated_messages() {
+        // A varint that never ends
+        assert!(Reader::new(&[0x08, 0x80]).next_field().is_err());
+        // A string longer than what's left
+        assert!(Reader::new(&[0x12, 0x05, b'h']).next_field().is_err());
+        // A fixed32 cut short
+        assert!(Reader::new(&[0x1D, 0x00, 0x00]).next_field().is_err());
+        // Packed floats ending partway through one
+        assert!(Value::Bytes(&[0, 0, 0x80]).as_f32s().is_err());
+    }
+
+    #[test]
+    fn rejects_unsupported_wire_types() {
+        // Wire type 3 starts a group
+        assert!(Reader::new(&[0x0B]).next_field().is_err());
+    }
+
+    #[test]
+    fn rejects_values_of_the_wrong_kind() {
+        assert!(Value::Bytes(b"x").as_u64().is_err());
+        assert!(Value::Varint(1).as_bytes().is_err());
+        assert!(Value::Varint(1).as_f32().is_err());
+    }
+}
@@ -0,0 +1,139 @@
+//! The quick-add popup, for placing operations without going through the palette. It opens at
+//! the mouse when Space or Tab is pressed over the graph, or when a connection is dropped where
+//! there's no port. Typing narrows down the operations, Up and Down pick one and Enter or
+//! clicking it places it where the popup opened.
+
+use std::rc::Rc;
+
+use graphics;
+use opengl_graphics::GlGraphics;
+use opengl_graphics::glyph_cache::GlyphCache;
+use piston::input;
+
+use super::catalog::OpCatalog;
+use super::dl_ui::Mouse;
+use super::graph_builder::Port;
+use super::node::is_over_rect;
+use super::op::Operation;
+
+const WIDTH: f64 = 180.0;
+const ROW_HEIGHT: f64 = 16.0;
+const TEXT_SIZE: u32 = 11;
+/// Most operations listed at once.
+const MAX_MATCHES: usize = 10;
+
+pub struct QuickAdd {
+    /// Where the popup opened, and where the operation is placed.
+    pub pos: [f64; 2],
+    pub query: String,
+    /// The port a connection was dragged out of to open the popup. The new node gets connected
+    /// to it.
+    pub from: Option<Port>,
+    selected: usize, // Index into the matches
+}
+
+pub enum QuickAddAction {
+    Place(Rc<Operation>),
+    Close,
+}
+
+impl QuickAdd {
+    pub fn new(pos: [f64; 2], from: Option<Port>) -> Self {
+        QuickAdd {
+            pos: pos,
+            query: String::new(),
+            from: from,
+            selected: 0,
+        }
+    }
+
+    /// The operations matching what's been typed, best first.
+    pub fn matches(&self, catalog: &OpCatalog) -> Vec<Rc<Operation>> {
+        catalog.search(&self.query).into_iter().take(MAX_MATCHES).collect()
+    }
+
+    pub fn event(&mut self, event: &input::Event, mouse: &Mouse, catalog: &OpCatalog) -> Option<QuickAddAction> {
+        use piston::input::*;
+
+        let matches = self.matches(catalog);
+        let mut action = None;
+        event.press(|button| {
+            match button {
+                Button::Keyboard(Key::Backspace) => {
+                    self.query.pop();
+                    self.selected = 0;
+                },
+                Button::Keyboard(Key::Up) => {
+                    self.selected = self.selected.saturating_sub(1);
+                },
+                Button::Keyboard(Key::Down) => {
+                    if self.selected + 1 < matches.len() {
+                        self.selected += 1;
+                    }
+                },
+                Button::Keyboard(Key::Return) => {
+                    action = matches.get(self.selected).map(|op| QuickAddAction::Place(op.clone()));
+                },
+                Button::Keyboard(Key::Escape) => {
+                    action = Some(QuickAddAction::Close);
+                },
+                Button::Mouse(mouse::MouseButton::Left) => {
+                    // Clicking an operation places it, clicking anywhere else closes the popup
+                    let clicked = (0..matches.len()).find(|&i| is_over_rect(self.row_rect(i + 1), mouse.pos));
+                    action = Some(match clicked {
+                        Some(i) => QuickAddAction::Place(matches[i].clone()),
+                        None => QuickAddAction::Close,
+                    });
+                },
+                _ => { },
+            }
+        });
+        event.text(|text| {
+            // The key that opened the popup types a space or tab too
+            if !(self.query.is_empty() && text.trim().is_empty()) {
+                self.query.push_str(text);
+                self.selected = 0;
+            }
+        });
+        action
+    }
+
+    /// The query, with the matching operations below it and the one Enter places highlighted.
+    pub fn draw(&self, c: &graphics::Context, gl: &mut GlGraphics, glyphs: &mut GlyphCache, catalog: &OpCatalog) {
+        use graphics::{Rectangle, Text, Transformed};
+        use graphics::character::CharacterCache;
+
+        let matches = self.matches(catalog);
+        let rows = 1 + ::std::cmp::max(1, matches.len());
+        Rectangle::new([0.95, 0.95, 0.95, 1.0]).draw([self.pos[0], self.pos[1], WIDTH, (rows as f64)*ROW_HEIGHT],
+                                                     &c.draw_state, c.transform, gl);
+        Rectangle::new([0.0, 0.0, 0.0, 0.25]).draw(self.row_rect(0), &c.draw_state, c.transform, gl);
+        Text::new_color([0.0, 0.0, 0.0, 1.0], TEXT_SIZE)
+            .draw(&format!("{}|", self.query), glyphs, &c.draw_state,
+                  c.transform.trans(self.pos[0] + 4.0, self.pos[1] + ROW_HEIGHT - 4.0), gl);
+
+        if matches.is_empty() {
+            Text::new_color([0.5, 0.5, 0.5, 1.0], TEXT_SIZE)
+                .draw("No matching operations", glyphs, &c.draw_state,
+                      c.transform.trans(self.pos[0] + 4.0, self.pos[1] + 2.0*ROW_HEIGHT - 4.0), gl);
+        }
+        for (i, op) in matches.iter().enumerate() {
+            let rect = self.row_rect(i + 1);
+            if i == self.selected {
+                Rectangle::new([1.0, 0.8, 0.0, 1.0]).draw(rect, &c.draw_state, c.transform, gl);
+            }
+            Text::new_color([0.0, 0.0, 0.0, 1.0], TEXT_SIZE)
+                .draw(&op.name, glyphs, &c.draw_state,
+                      c.transform.trans(rect[0] + 4.0, rect[1] + ROW_HEIGHT - 4.0), gl);
+            let category_width = glyphs.width(TEXT_SIZE, &op.category);
+            Text::new_color([0.5, 0.5, 0.5, 1.0], TEXT_SIZE)
+                .draw(&op.category, glyphs, &c.draw_state,
+                      c.transform.trans(rect[0] + WIDTH - 4.0 - category_width, rect[1] + ROW_HEIGHT - 4.0), gl);
+        }
+    }
+
+    /// Row 0 is the query, the matches follow.
+    fn row_rect(&self, row: usize) -> [f64; 4] {
+        [self.pos[0], self.pos[1] + (row as f64)*ROW_HEIGHT, WIDTH, ROW_HEIGHT]
+    }
+}
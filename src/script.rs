@@ -0,0 +1,912 @@
+//! A small scripting language for building and manipulating graphs, for structures too
+//! repetitive to click together. Scripts are run from the console or with `--script`:
+//!
+//! ```text
+//! set_dim("N", 32)
+//! let x = node("Variable", "x")
+//! set_shape(x, ["N", 784])
+//! for i in 0..20 {
+//!     let w = node("Variable", "w" + i)
+//!     set_shape(w, [784, 784])
+//!     let b = node("Variable", "b" + i)
+//!     set_shape(b, [1, 784])
+//!     let layer = node("Dense", "dense" + i)
+//!     set_attr(layer, "activation", "relu")
+//!     connect(x, 0, layer, "x")
+//!     connect(w, 0, layer, "w")
+//!     connect(b, 0, layer, "b")
+//!     x = layer
+//! }
+//! ```
+//!
+//! Values are numbers, strings, lists and nodes. Statements are `let NAME = EXPR`,
+//! `NAME = EXPR`, `for NAME in EXPR..EXPR { ... }`, `if EXPR { ... } else { ... }` and
+//! expressions, separated by newlines or `;`, and `#` starts a comment. `+` also joins strings
+//! and lists, and comparisons give 1 or 0. The functions are listed in `Script::call`.
+
+use std::collections::HashMap;
+
+use matrix;
+
+use super::catalog::OpCatalog;
+use super::graph_builder::{Backend, GraphBuilder, Mode, NodeId};
+use super::op::AttrValue;
+use super::shape::{Dim, Shape};
+use super::var_store::VarIndex;
+
+/// Learning rate of `train` when the script doesn't give one.
+const LEARNING_RATE: f64 = 0.1;
+
+#[derive(Clone, PartialEq)]
+pub enum Value {
+    Nil,
+    Num(f64),
+    Str(String),
+    List(Vec<Value>),
+    Node(NodeId),
+}
+
+impl Value {
+    fn is_true(&self) -> bool {
+        match *self {
+            Value::Nil => false,
+            Value::Num(n) => n != 0.0,
+            Value::Str(ref s) => !s.is_empty(),
+            Value::List(ref list) => !list.is_empty(),
+            Value::Node(_) => true,
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match *self {
+            Value::Nil => "nil",
+            Value::Num(_) => "a number",
+            Value::Str(_) => "a string",
+            Value::List(_) => "a list",
+            Value::Node(_) => "a node",
+        }
+    }
+}
+
+/// How a value is printed, with nodes shown by name.
+pub fn show(value: &Value, graph: &GraphBuilder) -> String {
+    match *value {
+        Value::Nil => "nil".to_string(),
+        Value::Num(n) => n.to_string(),
+        Value::Str(ref s) => s.clone(),
+        Value::List(ref list) => {
+            let items: Vec<String> = list.iter().map(|item| show(item, graph)).collect();
+            format!("[{}]", items.join(", "))
+        },
+        Value::Node(node) => node.get(graph).name().to_string(),
+    }
+}
+
+/// Runs scripts, keeping their variables from one run to the next so the console can build on
+/// earlier lines.
+pub struct Script {
+    vars: HashMap<String, Value>,
+    /// What `print` printed, for the caller to show.
+    pub output: Vec<String>,
+}
+
+/// What the functions of a script work on.
+struct Env<'a> {
+    graph: &'a mut GraphBuilder,
+    catalog: &'a OpCatalog,
+    /// Only missing when testing, so scripts that don't run the graph need no OpenCL device.
+    ctx: Option<&'a matrix::Context>,
+}
+
+impl<'a> Env<'a> {
+    fn ctx(&self) -> Result<&'a matrix::Context, String> {
+        self.ctx.ok_or("there's no context to run the graph with".to_string())
+    }
+}
+
+impl Script {
+    pub fn new() -> Self {
+        Script {
+            vars: HashMap::new(),
+            output: vec![],
+        }
+    }
+
+    /// Run `src`, returning the value of its last statement if that's an expression. The nodes a
+    /// script adds are laid out below the rest of the graph when it finishes.
+    pub fn run(&mut self, src: &str, graph: &mut GraphBuilder, catalog: &OpCatalog, ctx: &matrix::Context)
+               -> Result<Value, String> {
+        self.run_in(src, graph, catalog, Some(ctx))
+    }
+
+    fn run_in(&mut self, src: &str, graph: &mut GraphBuilder, catalog: &OpCatalog, ctx: Option<&matrix::Context>)
+              -> Result<Value, String> {
+        let tokens = try!(tokenize(src));
+        let mut parser = Parser { tokens: tokens, pos: 0 };
+        let block = try!(parser.block());
+        if let Some(&(_, line)) = parser.tokens.get(parser.pos) {
+            return Err(format!("line {}: unexpected '}}'", line));
+        }
+
+        let existing = graph.node_ids().len();
+        let mut env = Env { graph: graph, catalog: catalog, ctx: ctx };
+        let result = self.exec_block(&mut env, &block);
+        let added: Vec<NodeId> = env.graph.node_ids().into_iter().skip(existing).collect();
+        if !added.is_empty() {
+            env.graph.layout_below(&added);
+        }
+        result
+    }
+
+    fn exec_block(&mut self, env: &mut Env, block: &[(Stmt, usize)]) -> Result<Value, String> {
+        let mut last = Value::Nil;
+        for &(ref stmt, line) in block {
+            last = try!(self.exec(env, stmt, line));
+        }
+        Ok(last)
+    }
+
+    /// Run a statement, giving the value of expression statements and nil for the others.
+    fn exec(&mut self, env: &mut Env, stmt: &Stmt, line: usize) -> Result<Value, String> {
+        let at = &|e: String| format!("line {}: {}", line, e);
+        match *stmt {
+            Stmt::Let(ref name, ref expr) => {
+                let value = try!(self.eval(env, expr).map_err(at));
+                self.vars.insert(name.clone(), value);
+            },
+            Stmt::Assign(ref name, ref expr) => {
+                if !self.vars.contains_key(name) {
+                    return Err(at(format!("'{}' isn't defined; use 'let {} = ...'", name, name)));
+                }
+                let value = try!(self.eval(env, expr).map_err(at));
+                self.vars.insert(name.clone(), value);
+            },
+            Stmt::For(ref name, ref start, ref end, ref body) => {
+                let start = try!(self.eval(env, start).and_then(|x| number(&x)).map_err(at));
+                let end = try!(self.eval(env, end).and_then(|x| number(&x)).map_err(at));
+                let mut i = start.floor();
+                while i < end {
+                    self.vars.insert(name.clone(), Value::Num(i));
+                    try!(self.exec_block(env, body));
+                    i += 1.0;
+                }
+            },
+            Stmt::If(ref cond, ref then, ref otherwise) => {
+                let branch = if try!(self.eval(env, cond).map_err(at)).is_true() { then } else { otherwise };
+                try!(self.exec_block(env, branch));
+            },
+            Stmt::Expr(ref expr) => { return self.eval(env, expr).map_err(at); },
+        }
+        Ok(Value::Nil)
+    }
+
+    fn eval(&mut self, env: &mut Env, expr: &Expr) -> Result<Value, String> {
+        match *expr {
+            Expr::Num(n) => Ok(Value::Num(n)),
+            Expr::Str(ref s) => Ok(Value::Str(s.clone())),
+            Expr::Var(ref name) => self.vars.get(name).cloned().ok_or(format!("'{}' isn't defined", name)),
+            Expr::List(ref items) => {
+                let mut list = vec![];
+                for item in items {
+                    list.push(try!(self.eval(env, item)));
+                }
+                Ok(Value::List(list))
+            },
+            Expr::Call(ref name, ref args) => {
+                let mut values = vec![];
+                for arg in args {
+                    values.push(try!(self.eval(env, arg)));
+                }
+                self.call(env, name, values).map_err(|e| format!("{}: {}", name, e))
+            },
+            Expr::Index(ref list, ref index) => {
+                let list = try!(self.eval(env, list));
+                let index = try!(self.eval(env, index).and_then(|x| count(&x)));
+                match list {
+                    Value::List(list) => {
+                        list.get(index).cloned().ok_or(format!("index {} is past the end", index))
+                    },
+                    other => Err(format!("can't index {}", other.kind())),
+                }
+            },
+            Expr::Neg(ref x) => Ok(Value::Num(-try!(self.eval(env, x).and_then(|x| number(&x))))),
+            Expr::Binary(op, ref a, ref b) => {
+                let a = try!(self.eval(env, a));
+                let b = try!(self.eval(env, b));
+                binary(op, a, b, env.graph)
+            },
+        }
+    }
+
+    /// The functions scripts can call:
+    ///
+    /// - `node(OPERATION[, NAME])` adds a node and gives it.
+    /// - `find(NAME)` gives the node called `NAME`.
+    /// - `connect(FROM, OUTPUT, TO, INPUT)` connects two nodes. Ports are numbers or names.
+    /// - `set_shape(NODE, DIMS)` sets the shape of a node's first output, e.g. a variable.
+    ///   Dimensions are numbers, or strings for symbolic ones.
+    /// - `set_attr(NODE, NAME, VALUE)` sets one of a node's attributes.
+    /// - `set_dim(NAME, SIZE)` sets the size of a symbolic dimension.
+    /// - `set_mode("train" | "eval")` sets what `run` runs the graph for.
+    /// - `check_finite(1 | 0)` turns stopping at values that aren't finite on or off. Checking
+    ///   builds a graph built for the GPU again for the CPU.
+    /// - `build()`, `run()` and `train(ITERATIONS[, LEARNING_RATE])` do what the buttons do.
+    /// - `value(NODE | NAME)` gives the values of a variable as a flat list, and `shape` its
+    ///   dimensions. Nodes stand for their first output.
+    /// - `len(LIST | STRING)` and `print(VALUE, ...)`.
+    fn call(&mut self, env: &mut Env, name: &str, args: Vec<Value>) -> Result<Value, String> {
+        match (name, args.len()) {
+            ("node", 1) => self.add_node(env, try!(string(&args[0])), None),
+            ("node", 2) => self.add_node(env, try!(string(&args[0])), Some(try!(string(&args[1])))),
+            ("find", 1) => {
+                let name = try!(string(&args[0]));
+                env.graph.node_by_name(name).map(Value::Node).ok_or(format!("there's no node '{}'", name))
+            },
+            ("connect", 4) => {
+                let (from, to) = (try!(node(&args[0])), try!(node(&args[2])));
+                let output = try!(port(&from.get(env.graph).op.outputs, &args[1]));
+                let input = try!(port(&to.get(env.graph).op.inputs, &args[3]));
+                env.graph.connect(from, output, to, input);
+                try!(env.graph.infer_dtypes());
+                Ok(Value::Nil)
+            },
+            ("set_shape", 2) => {
+                let mut shape = vec![];
+                for dim in try!(list(&args[1])) {
+                    shape.push(match *dim {
+                        Value::Num(d) if d >= 0.0 && d.fract() == 0.0 => Dim::Known(d as usize),
+                        Value::Str(ref name) => Dim::Symbolic(name.clone()),
+                        ref other => { return Err(format!("a dimension can't be {}", other.kind())); },
+                    });
+                }
+                let v = try!(variable(env.graph, &args[0]));
+                env.graph.set_var_shape(v, Shape::new(shape));
+                Ok(Value::Nil)
+            },
+            ("set_attr", 3) => {
+                let (target, attr) = (try!(node(&args[0])).get_mut(env.graph), try!(string(&args[1])));
+                let value = match (target.attrs.get(attr), &args[2]) {
+                    (None, _) => { return Err(format!("'{}' has no attribute '{}'", target.op.name, attr)); },
+                    (Some(&AttrValue::Float(_)), &Value::Num(n)) => AttrValue::Float(n as f32),
+                    (Some(&AttrValue::Int(_)), &Value::Num(n)) if n.fract() == 0.0 => AttrValue::Int(n as i64),
+                    (Some(&AttrValue::Str(_)), &Value::Str(ref s)) => AttrValue::Str(s.clone()),
+                    (Some(&AttrValue::Ints(_)), &Value::List(ref list)) => {
+                        let mut ints = vec![];
+                        for n in list {
+                            ints.push(try!(number(n)) as i64);
+                        }
+                        AttrValue::Ints(ints)
+                    },
+                    (Some(_), value) => { return Err(format!("'{}' can't be {}", attr, value.kind())); },
+                };
+                target.attrs.set(attr, value);
+                Ok(Value::Nil)
+            },
+            ("set_dim", 2) => {
+                env.graph.set_dim(try!(string(&args[0])), try!(count(&args[1])));
+                Ok(Value::Nil)
+            },
+            ("set_mode", 1) => {
+                let mode = match try!(string(&args[0])) {
+                    "train" => Mode::Train,
+                    "eval" => Mode::Eval,
+                    mode => { return Err(format!("the mode should be \"train\" or \"eval\", not \"{}\"", mode)); },
+                };
+                env.graph.set_mode(mode);
+                Ok(Value::Nil)
+            },
+            ("check_finite", 1) => {
+                let check = try!(number(&args[0])) != 0.0;
+                let on_gpu = match env.graph.backend() {
+                    Some(&Backend::Gpu) => true,
+                    _ => false,
+                };
+                if check && on_gpu {
+                    let ctx = try!(env.ctx());
+                    try!(env.graph.build_for_cpu(ctx));
+                }
+                env.graph.set_check_finite(check);
+                Ok(Value::Nil)
+            },
+            ("build", 0) => {
+                let ctx = try!(env.ctx());
+                env.graph.build(ctx).map(|_| Value::Nil)
+            },
+            ("run", 0) => {
+                let ctx = try!(env.ctx());
+                env.graph.run(ctx).map(|_| Value::Nil)
+            },
+            ("train", 1) | ("train", 2) => {
+                let learning_rate = match args.get(1) {
+                    Some(learning_rate) => try!(number(learning_rate)),
+                    None => LEARNING_RATE,
+                };
+                let ctx = try!(env.ctx());
+                for i in 0..try!(count(&args[0])) {
+                    try!(env.graph.train_step(ctx, learning_rate as f32)
+                                  .map_err(|e| format!("iteration {}: {}", i + 1, e)));
+                }
+                Ok(Value::Nil)
+            },
+            ("value", 1) => {
+                let v = try!(variable(env.graph, &args[0]));
+                let ctx = try!(env.ctx());
+                let value = try!(env.graph.var_value(ctx, v).ok_or("the variable has no value yet".to_string()));
+                Ok(Value::List(value.data.iter().map(|&x| Value::Num(x as f64)).collect()))
+            },
+            ("shape", 1) => {
+                let v = try!(variable(env.graph, &args[0]));
+                let dims = v.get(&env.graph.vars).shape.dims().iter().map(|dim| match *dim {
+                    Dim::Known(d) => Value::Num(d as f64),
+                    Dim::Symbolic(ref name) => Value::Str(name.clone()),
+                }).collect();
+                Ok(Value::List(dims))
+            },
+            ("len", 1) => match args[0] {
+                Value::List(ref list) => Ok(Value::Num(list.len() as f64)),
+                Value::Str(ref s) => Ok(Value::Num(s.chars().count() as f64)),
+                ref other => Err(format!("{} has no length", other.kind())),
+            },
+            ("print", _) => {
+                let line: Vec<String> = args.iter().map(|arg| show(arg, env.graph)).collect();
+                self.output.push(line.join(" "));
+                Ok(Value::Nil)
+            },
+            _ => Err(format!("there's no function '{}' taking {} arguments", name, args.len())),
+        }
+    }
+
+    fn add_node(&mut self, env: &mut Env, op: &str, name: Option<&str>) -> Result<Value, String> {
+        let op = try!(env.catalog.get(op).ok_or(format!("there's no operation '{}'", op)));
+        let node = env.graph.add_op_node([0.0, 0.0], op);
+        if let Some(name) = name {
+            try!(env.graph.rename_node(node, name.to_string()));
+        }
+        Ok(Value::Node(node))
+    }
+}
+
+/// The index of a port given by number or by name.
+fn port(ports: &[String], port: &Value) -> Result<usize, String> {
+    let index = match *port {
+        Value::Num(n) if n >= 0.0 => n as usize,
+        Value::Str(ref name) => try!(ports.iter().position(|p| p == name).ok_or(format!("there's no port '{}'", name))),
+        ref other => { return Err(format!("a port can't be {}", other.kind())); },
+    };
+    if index >= ports.len() {
+        return Err(format!("there's no port {}", index));
+    }
+    Ok(index)
+}
+
+fn variable(graph: &GraphBuilder, var: &Value) -> Result<VarIndex, String> {
+    match *var {
+        Value::Node(node) => node.get(graph).outputs.first().cloned().ok_or("the node has no outputs".to_string()),
+        Value::Str(ref name) => graph.var_by_name(name).ok_or(format!("there's no variable '{}'", name)),
+        ref other => Err(format!("a variable can't be {}", other.kind())),
+    }
+}
+
+fn number(value: &Value) -> Result<f64, String> {
+    match *value {
+        Value::Num(n) => Ok(n),
+        ref other => Err(format!("expected a number, not {}", other.kind())),
+    }
+}
+
+fn count(value: &Value) -> Result<usize, String> {
+    match *value {
+        Value::Num(n) if n >= 0.0 && n.fract() == 0.0 => Ok(n as usize),
+        Value::Num(n) => Err(format!("expected a count, not {}", n)),
+        ref other => Err(format!("expected a count, not {}", other.kind())),
+    }
+}
+
+fn string(value: &Value) -> Result<&str, String> {
+    match *value {
+        Value::Str(ref s) => Ok(s),
+        ref other => Err(format!("expected a string, not {}", other.kind())),
+    }
+}
+
+fn list(value: &Value) -> Result<&[Value], String> {
+    match *value {
+        Value::List(ref list) => Ok(list),
+        ref other => Err(format!("expected a list, not {}", other.kind())),
+    }
+}
+
+fn node(value: &Value) -> Result<NodeId, String> {
+    match *value {
+        Value::Node(node) => Ok(node),
+        ref other => Err(format!("expected a node, not {}", other.kind())),
+    }
+}
+
+fn binary(op: &str, a: Value, b: Value, graph: &GraphBuilder) -> Result<Value, String> {
+    let truth = |b: bool| Value::Num(if b { 1.0 } else { 0.0 });
+    match (op, a, b) {
+        ("==", a, b) => Ok(truth(a == b)),
+        ("!=", a, b) => Ok(truth(a != b)),
+        ("+", Value::List(mut a), Value::List(b)) => {
+            a.extend(b);
+            Ok(Value::List(a))
+        },
+        ("+", a @ Value::Str(_), b) | ("+", a, b @ Value::Str(_)) => {
+            Ok(Value::Str(show(&a, graph) + &show(&b, graph)))
+        },
+        (op, Value::Num(a), Value::Num(b)) => match op {
+            "+" => Ok(Value::Num(a + b)),
+            "-" => Ok(Value::Num(a - b)),
+            "*" => Ok(Value::Num(a * b)),
+            "/" => Ok(Value::Num(a / b)),
+            "%" => Ok(Value::Num(a % b)),
+            "<" => Ok(truth(a < b)),
+            ">" => Ok(truth(a > b)),
+            "<=" => Ok(truth(a <= b)),
+            ">=" => Ok(truth(a >= b)),
+            _ => unreachable!(),
+        },
+        (op, a, b) => Err(format!("can't use '{}' on {} and {}", op, a.kind(), b.kind())),
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////
+// Parsing
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Token {
+    Num(f64),
+    Str(String),
+    Ident(String),
+    Sym(&'static str),
+    Newline,
+}
+
+/// Longer symbols come first so that e.g. `<=` isn't read as `<`.
+const SYMBOLS: &'static [&'static str] = &["..", ".", "==", "!=", "<=", ">=", "<", ">", "+", "-", "*", "/",
+                                            "%", "(", ")", "[", "]", "{", "}", ",", "=", ";"];
+
+/// Split a script into tokens with their line numbers. Newlines inside brackets don't end
+/// statements, so long calls and lists can be split over lines. Names that aren't single words
+/// can be written in backquotes. Also used to read graphs written as text; see `graph_text`.
+pub fn tokenize(src: &str) -> Result<Vec<(Token, usize)>, String> {
+    let mut tokens = vec![];
+    let mut depth = 0;
+    for (i, text) in src.lines().enumerate() {
+        let line = i + 1;
+        let chars: Vec<char> = text.chars().collect();
+        let mut pos = 0;
+        while pos < chars.len() {
+            let c = chars[pos];
+            let rest: String = chars[pos..].iter().cloned().collect();
+            if c == '#' {
+                break;
+            } else if c.is_whitespace() {
+                pos += 1;
+            } else if c.is_digit(10) {
+                // Digits with at most one point between them
+                let start = pos;
+                let mut point = false;
+                while pos < chars.len() &&
+                      (chars[pos].is_digit(10) ||
+                       !point && chars[pos] == '.' && chars.get(pos + 1).map_or(false, |c| c.is_digit(10))) {
+                    point = point || chars[pos] == '.';
+                    pos += 1;
+                }
+                let number: String = chars[start..pos].iter().cloned().collect();
+                let number = try!(number.parse().map_err(|_| format!("line {}: bad number", line)));
+                tokens.push((Token::Num(number), line));
+            } else if c.is_alphabetic() || c == '_' {
+                let start = pos;
+                while pos < chars.len() && (chars[pos].is_alphanumeric() || chars[pos] == '_') {
+                    pos += 1;
+                }
+                tokens.push((Token::Ident(chars[start..pos].iter().cloned().collect()), line));
+            } else if c == '`' {
+                let end = try!(chars[pos+1..].iter().position(|&c| c == '`')
+                                             .ok_or(format!("line {}: the name isn't closed", line)));
+                tokens.push((Token::Ident(chars[pos+1..pos+1+end].iter().cloned().collect()), line));
+                pos += end + 2;
+            } else if c == '"' {
+                let end = try!(chars[pos+1..].iter().position(|&c| c == '"')
+                                             .ok_or(format!("line {}: the string isn't closed", line)));
+                tokens.push((Token::Str(chars[pos+1..pos+1+end].iter().cloned().collect()), line));
+                pos += end + 2;
+            } else if let Some(&sym) = SYMBOLS.iter().find(|sym| rest.starts_with(**sym)) {
+                match sym {
+                    "(" | "[" => { depth += 1; },
+                    ")" | "]" if depth > 0 => { depth -= 1; },
+                    _ => { },
+                }
+                tokens.push((Token::Sym(sym), line));
+                pos += sym.len();
+            } else {
+                return Err(format!("line {}: unexpected '{}'", line, c));
+            }
+        }
+        if depth == 0 {
+            tokens.push((Token::Newline, line));
+        }
+    }
+    Ok(tokens)
+}
+
+enum Stmt {
+    Let(String, Expr),
+    Assign(String, Expr),
+    For(String, Expr, Expr, Vec<(Stmt, usize)>),
+    If(Expr, Vec<(Stmt, usize)>, Vec<(Stmt, usize)>),
+    Expr(Expr),
+}
+
+enum Expr {
+    Num(f64),
+    Str(String),
+    Var(String),
+    List(Vec<Expr>),
+    Call(String, Vec<Expr>),
+    Index(Box<Expr>, Box<Expr>),
+    Neg(Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+}
+
+/// Binary operators from the loosest binding to the tightest.
+const PRECEDENCE: &'static [&'static [&'static str]] = &[&["==", "!=", "<", ">", "<=", ">="],
+                                                          &["+", "-"],
+                                                          &["*", "/", "%"]];
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|&(ref token, _)| token)
+    }
+
+    fn line(&self) -> usize {
+        self.tokens.get(self.pos).or(self.tokens.last()).map_or(1, |&(_, line)| line)
+    }
+
+    fn is(&self, sym: &'static str) -> bool {
+        self.peek() == Some(&Token::Sym(sym))
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        match self.peek() {
+            Some(&Token::Ident(ref word)) => word == keyword,
+            _ => false,
+        }
+    }
+
+    fn error<T>(&self, expected: &str) -> Result<T, String> {
+        let found = match self.peek() {
+            Some(&Token::Num(n)) => n.to_string(),
+            Some(&Token::Str(ref s)) => format!("\"{}\"", s),
+            Some(&Token::Ident(ref word)) => word.clone(),
+            Some(&Token::Sym(sym)) => sym.to_string(),
+            Some(&Token::Newline) => "the end of the line".to_string(),
+            None => "the end of the script".to_string(),
+        };
+        Err(format!("line {}: expected {} but found {}", self.line(), expected, found))
+    }
+
+    fn expect(&mut self, sym: &'static str) -> Result<(), String> {
+        if !self.is(sym) {
+            return self.error(&format!("'{}'", sym));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        let token = self.peek().cloned();
+        match token {
+            Some(Token::Ident(name)) => {
+                self.pos += 1;
+                Ok(name)
+            },
+            _ => self.error("a name"),
+        }
+    }
+
+    /// Statements up to a closing `}` or the end of the script.
+    fn block(&mut self) -> Result<Vec<(Stmt, usize)>, String> {
+        let mut block = vec![];
+        loop {
+            while self.peek() == Some(&Token::Newline) || self.is(";") {
+                self.pos += 1;
+            }
+            if self.peek().is_none() || self.is("}") {
+                return Ok(block);
+            }
+            let line = self.line();
+            block.push((try!(self.statement()), line));
+            if !(self.peek() == Some(&Token::Newline) || self.is(";") || self.is("}") || self.peek().is_none()) {
+                return self.error("the end of the statement");
+            }
+        }
+    }
+
+    fn braced_block(&mut self) -> Result<Vec<(Stmt, usize)>, String> {
+        try!(self.expect("{"));
+        let block = try!(self.block());
+        try!(self.expect("}"));
+        Ok(block)
+    }
+
+    fn statement(&mut self) -> Result<Stmt, String> {
+        if self.is_keyword("let") {
+            self.pos += 1;
+            let name = try!(self.name());
+            try!(self.expect("="));
+            Ok(Stmt::Let(name, try!(self.expr())))
+        } else if self.is_keyword("for") {
+            self.pos += 1;
+            let name = try!(self.name());
+            if !self.is_keyword("in") {
+                return self.error("'in'");
+            }
+            self.pos += 1;
+            let start = try!(self.expr());
+            try!(self.expect(".."));
+            let end = try!(self.expr());
+            Ok(Stmt::For(name, start, end, try!(self.braced_block())))
+        } else if self.is_keyword("if") {
+            self.pos += 1;
+            let cond = try!(self.expr());
+            let then = try!(self.braced_block());
+            let mut otherwise = vec![];
+            if self.is_keyword("else") {
+                self.pos += 1;
+                if self.is_keyword("if") {
+                    let line = self.line();
+                    otherwise.push((try!(self.statement()), line));
+                } else {
+                    otherwise = try!(self.braced_block());
+                }
+            }
+            Ok(Stmt::If(cond, then, otherwise))
+        } else if let Some(name) = self.assignment() {
+            self.pos += 2;
+            Ok(Stmt::Assign(name, try!(self.expr())))
+        } else {
+            Ok(Stmt::Expr(try!(self.expr())))
+        }
+    }
+
+    /// The variable assigned to if the next statement is an assignment.
+    fn assignment(&self) -> Option<String> {
+        match (self.tokens.get(self.pos), self.tokens.get(self.pos + 1)) {
+            (Some(&(Token::Ident(ref name), _)), Some(&(Token::Sym("="), _))) => Some(name.clone()),
+            _ => None,
+        }
+    }
+
+    fn expr(&mut self) -> Result<Expr, String> {
+        self.binary(0)
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr, String> {
+        if level == PRECEDENCE.len() {
+            return self.unary();
+        }
+        let mut lhs = try!(self.binary(level + 1));
+        loop {
+            let op = match self.peek() {
+                Some(&Token::Sym(sym)) if PRECEDENCE[level].contains(&sym) => sym,
+                _ => { return Ok(lhs); },
+            };
+            self.pos += 1;
+            let rhs = try!(self.binary(level + 1));
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.is("-") {
+            self.pos += 1;
+            return Ok(Expr::Neg(Box::new(try!(self.unary()))));
+        }
+        let mut expr = try!(self.primary());
+        while self.is("[") {
+            self.pos += 1;
+            let index = try!(self.expr());
+            try!(self.expect("]"));
+            expr = Expr::Index(Box::new(expr), Box::new(index));
+        }
+        Ok(expr)
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        let token = self.peek().cloned();
+        match token {
+            Some(Token::Num(n)) => {
+                self.pos += 1;
+                Ok(Expr::Num(n))
+            },
+            Some(Token::Str(s)) => {
+                self.pos += 1;
+                Ok(Expr::Str(s))
+            },
+            Some(Token::Ident(name)) => {
+                self.pos += 1;
+                if self.is("(") {
+                    self.pos += 1;
+                    Ok(Expr::Call(name, try!(self.list(")"))))
+                } else {
+                    Ok(Expr::Var(name))
+                }
+            },
+            Some(Token::Sym("(")) => {
+                self.pos += 1;
+                let expr = try!(self.expr());
+                try!(self.expect(")"));
+                Ok(expr)
+            },
+            Some(Token::Sym("[")) => {
+                self.pos += 1;
+                Ok(Expr::List(try!(self.list("]"))))
+            },
+            _ => self.error("a value"),
+        }
+    }
+
+    /// Comma-separated expressions up to and including `close`.
+    fn list(&mut self, close: &'static str) -> Result<Vec<Expr>, String> {
+        let mut items = vec![];
+        while !self.is(close) {
+            items.push(try!(self.expr()));
+            if !self.is(close) {
+                try!(self.expect(","));
+            }
+        }
+        self.pos += 1;
+        Ok(items)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{show, tokenize, Script, Token, Value};
+    use super::super::catalog::OpCatalog;
+    use super::super::graph_builder::GraphBuilder;
+    use super::super::op::AttrValue;
+
+    fn run(script: &mut Script, graph: &mut GraphBuilder, src: &str) -> Result<Value, String> {
+        script.run_in(src, graph, &OpCatalog::builtin(), None)
+    }
+
+    /// What `src` gives when run on its own, as `print` would show it.
+    fn eval(src: &str) -> Result<String, String> {
+        let mut graph = GraphBuilder::new();
+        let value = try!(run(&mut Script::new(), &mut graph, src));
+        Ok(show(&value, &graph))
+    }
+
+    fn error(src: &str) -> String {
+        match eval(src) {
+            Ok(value) => panic!("{:?} gave {}", src, value),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn evaluates_expressions() {
+        assert_eq!(eval("1 + 2 * 3"), Ok("7".to_string()));
+        assert_eq!(eval("(1 + 2) * 3"), Ok("9".to_string()));
+        assert_eq!(eval("-2 * 3 % 4"), Ok("-2".to_string()));
+        assert_eq!(eval("\"w\" + 1"), Ok("w1".to_string()));
+        assert_eq!(eval("[1, 2] + [3]"), Ok("[1, 2, 3]".to_string()));
+        assert_eq!(eval("[4, 5, 6][1]"), Ok("5".to_string()));
+        assert_eq!(eval("2 <= 2"), Ok("1".to_string()));
+        assert_eq!(eval("\"a\" == \"b\""), Ok("0".to_string()));
+        assert_eq!(eval("len(\"héllo\") # comment"), Ok("5".to_string()));
+    }
+
+    #[test]
+    fn runs_statements() {
+        let mut graph = GraphBuilder::new();
+        let mut script = Script::new();
+        let src = "let total = 0\n\
+                   for i in 0..4 {\n\
+                       total = total + i\n\
+                   }\n\
+                   if total > 5 { print(\"big\", total) } else { print(\"small\") }";
+        assert!(run(&mut script, &mut graph, src) == Ok(Value::Nil));
+        assert_eq!(script.output, vec!["big 6".to_string()]);
+        // Variables are kept for the next run
+        assert!(run(&mut script, &mut graph, "total * 2") == Ok(Value::Num(12.0)));
+    }
+
+    #[test]
+    fn builds_graphs() {
+        let mut graph = GraphBuilder::new();
+        let src = "set_dim(\"N\", 4)\n\
+                   let x = node(\"Variable\", \"x\")\n\
+                   set_shape(x, [\"N\", 3])\n\
+                   for i in 0..2 {\n\
+                       let layer = node(\"Dense\", \"dense\" + i)\n\
+                       set_attr(layer, \"activation\", \"tanh\")\n\
+                       connect(x, 0, layer, \"x\")\n\
+                       x = layer\n\
+                   }\n\
+                   shape(\"x\")";
+        let value = run(&mut Script::new(), &mut graph, src).unwrap();
+        assert_eq!(show(&value, &graph), "[N, 3]");
+        assert_eq!(graph.dims(), &[("N".to_string(), 4)][..]);
+
+        let (x, dense0, dense1) = (graph.node_by_name("x").unwrap(), graph.node_by_name("dense0").unwrap(),
+                                   graph.node_by_name("dense1").unwrap());
+        assert!(graph.input_edge(dense0, 0) == Some((x, 0)));
+        assert!(graph.input_edge(dense1, 0) == Some((dense0, 0)));
+        assert!(graph.input_edge(dense1, 1).is_none());
+        assert_eq!(dense1.get(&graph).attrs.get("activation"), Some(&AttrValue::Str("tanh".to_string())));
+    }
+
+    #[test]
+    fn lays_out_only_the_nodes_added() {
+        let mut graph = GraphBuilder::new();
+        let mut script = Script::new();
+        run(&mut script, &mut graph, "node(\"Variable\", \"a\")").unwrap();
+        let a = graph.node_by_name("a").unwrap();
+        a.get_mut(&mut graph).set_pos([500.0, 300.0]);
+
+        run(&mut script, &mut graph, "node(\"ReLU\", \"r\")").unwrap();
+        assert_eq!(a.get(&graph).pos(), [500.0, 300.0]);
+        let r = graph.node_by_name("r").unwrap();
+        assert!(r.get(&graph).pos()[1] >= 300.0 + a.get(&graph).size()[1]);
+    }
+
+    #[test]
+    fn sets_attributes_of_the_right_kind() {
+        let mut graph = GraphBuilder::new();
+        let src = "let c = node(\"Conv2D\", \"c\")\n\
+                   set_attr(c, \"stride\", 2)\n\
+                   let r = node(\"Reshape\", \"r\")\n\
+                   set_attr(r, \"shape\", [-1, 4])";
+        run(&mut Script::new(), &mut graph, src).unwrap();
+        let (c, r) = (graph.node_by_name("c").unwrap(), graph.node_by_name("r").unwrap());
+        assert_eq!(c.get(&graph).attrs.get("stride"), Some(&AttrValue::Int(2)));
+        assert_eq!(r.get(&graph).attrs.get("shape"), Some(&AttrValue::Ints(vec![-1, 4])));
+
+        assert_eq!(error("let d = node(\"Dropout\")\nset_attr(d, \"rate\", \"high\")"),
+                   "line 2: set_attr: 'rate' can't be a string");
+        assert_eq!(error("let c = node(\"Conv2D\")\nset_attr(c, \"stride\", 1.5)"),
+                   "line 2: set_attr: 'stride' can't be a number");
+        assert_eq!(error("let d = node(\"Dense\")\nset_attr(d, \"activation\", [1])"),
+                   "line 2: set_attr: 'activation' can't be a list");
+        assert_eq!(error("let r = node(\"Reshape\")\nset_attr(r, \"shape\", [\"a\"])"),
+                   "line 2: set_attr: expected a number, not a string");
+        assert_eq!(error("let d = node(\"Dropout\")\nset_attr(d, \"size\", 1)"),
+                   "line 2: set_attr: 'Dropout' has no attribute 'size'");
+        assert_eq!(error("set_attr(1, \"rate\", 0.1)"), "line 1: set_attr: expected a node, not a number");
+    }
+
+    #[test]
+    fn tokenizes_numbers_with_one_point() {
+        let tokens: Vec<Token> = tokenize("1.2.3 4.").unwrap().into_iter().map(|(token, _)| token).collect();
+        assert_eq!(tokens, vec![Token::Num(1.2), Token::Sym("."), Token::Num(3.0), Token::Num(4.0), Token::Sym("."),
+                                Token::Newline]);
+        assert_eq!(error("1.2.3"), "line 1: expected the end of the statement but found .");
+    }
+
+    #[test]
+    fn reports_errors() {
+        assert_eq!(error("x = 1"), "line 1: 'x' isn't defined; use 'let x = ...'");
+        assert_eq!(error("\n\nfrobnicate(1)"), "line 3: frobnicate: there's no function 'frobnicate' taking 1 arguments");
+        assert_eq!(error("node(\"Nope\")"), "line 1: node: there's no operation 'Nope'");
+        assert_eq!(error("let a = node(\"ReLU\")\nconnect(a, 0, a, \"y\")"), "line 2: connect: there's no port 'y'");
+        assert_eq!(error("set_mode(\"fast\")"), "line 1: set_mode: the mode should be \"train\" or \"eval\", not \"fast\"");
+        assert_eq!(error("1 + [2]"), "line 1: can't use '+' on a number and a list");
+        assert_eq!(error("[1][3]"), "line 1: index 3 is past the end");
+        assert_eq!(error("[4, 5][-1]"), "line 1: expected a count, not -1");
+        assert_eq!(error("[4, 5][0.5]"), "line 1: expected a count, not 0.5");
+        assert_eq!(error("let x = (1"), "line 1: expected ')' but found the end of the script");
+        assert_eq!(error("}"), "line 1: unexpected '}'");
+        assert_eq!(error("\"abc"), "line 1: the string isn't closed");
+        assert_eq!(error("1 ? 2"), "line 1: unexpected '?'");
+    }
+}
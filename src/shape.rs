@@ -0,0 +1,237 @@
+//! Tensor shapes whose dimensions may be symbolic, such as a batch size that's only known when
+//! the graph is built.
+
+use std::fmt;
+
+use super::tensor;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Dim {
+    Known(usize),
+    /// A dimension named by the user, e.g. `N`, bound to a size with `GraphBuilder::set_dim`.
+    Symbolic(String),
+}
+
+impl Dim {
+    pub fn known(&self) -> Option<usize> {
+        match *self {
+            Dim::Known(d) => Some(d),
+            Dim::Symbolic(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Dim {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Dim::Known(d) => write!(f, "{}", d),
+            Dim::Symbolic(ref name) => write!(f, "{}", name),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Shape {
+    dims: Vec<Dim>,
+}
+
+impl Shape {
+    pub fn new(dims: Vec<Dim>) -> Self {
+        Shape {
+            dims: dims,
+        }
+    }
+
+    pub fn from_dims(dims: &[usize]) -> Self {
+        Shape::new(dims.iter().map(|&d| Dim::Known(d)).collect())
+    }
+
+    pub fn scalar() -> Self {
+        Shape::new(vec![])
+    }
+
+    pub fn matrix(rows: usize, columns: usize) -> Self {
+        Shape::from_dims(&[rows, columns])
+    }
+
+    pub fn rank(&self) -> usize {
+        self.dims.len()
+    }
+
+    pub fn dims(&self) -> &[Dim] {
+        &self.dims
+    }
+
+    pub fn dim(&self, i: usize) -> &Dim {
+        &self.dims[i]
+    }
+
+    /// The last dimension, or 1 for scalars.
+    pub fn last(&self) -> Dim {
+        self.dims.last().cloned().unwrap_or(Dim::Known(1))
+    }
+
+    /// The sizes of all dimensions, if none are symbolic.
+    pub fn known(&self) -> Option<Vec<usize>> {
+        self.dims.iter().map(|d| d.known()).collect()
+    }
+
+    /// The number of elements, if no dimension is symbolic.
+    pub fn num_elements(&self) -> Option<usize> {
+        self.known().map(|dims| tensor::num_elements(&dims))
+    }
+
+    /// The (rows, columns) of a matrix shape.
+    pub fn as_matrix(&self) -> Result<(Dim, Dim), String> {
+        match self.rank() {
+            2 => Ok((self.dims[0].clone(), self.dims[1].clone())),
+            _ => Err(format!("Expected a matrix, not shape {}", self)),
+        }
+    }
+
+    /// Replace symbolic dimensions with their sizes from `bindings`. Fails if one isn't bound.
+    pub fn resolve(&self, bindings: &[(String, usize)]) -> Result<Vec<usize>, String> {
+        self.dims.iter().map(|d| {
+            match *d {
+                Dim::Known(d) => Ok(d),
+                Dim::Symbolic(ref name) => {
+                    bindings.iter()
+                            .find(|&&(ref n, _)| n == name)
+                            .map(|&(_, size)| size)
+                            .ok_or(format!("Dimension '{}' has no size", name))
+                },
+            }
+        }).collect()
+    }
+
+    /// Whether a tensor of shape `dims` fits this shape. Symbolic dimensions fit any size.
+    pub fn matches(&self, dims: &[usize]) -> bool {
+        self.rank() == dims.len() &&
+            self.dims.iter().zip(dims).all(|(d, &size)| d.known().map_or(true, |d| d == size))
+    }
+
+    /// The shape of the result of an element-wise operation on tensors of shapes `self` and
+    /// `other`. Like numpy, shapes are aligned at their last dimension, missing leading
+    /// dimensions count as 1, and a dimension of 1 stretches to match the other shape.
+    pub fn broadcast(&self, other: &Shape) -> Result<Shape, String> {
+        let rank = self.rank().max(other.rank());
+        let mut dims = Vec::with_capacity(rank);
+        for i in 0..rank {
+            let a = self.dim_from_end(rank - 1 - i);
+            let b = other.dim_from_end(rank - 1 - i);
+            dims.push(match (a, b) {
+                (a, b) if a == b => a,
+                (Dim::Known(1), b) => b,
+                (a, Dim::Known(1)) => a,
+                _ => { return Err(format!("Shapes {} and {} can't be broadcast together", self, other)); },
+            });
+        }
+        Ok(Shape::new(dims))
+    }
+
+    /// The `i`th dimension counting from the last, or 1 past the first.
+    fn dim_from_end(&self, i: usize) -> Dim {
+        if i < self.rank() { self.dims[self.rank() - 1 - i].clone() } else { Dim::Known(1) }
+    }
+}
+
+impl fmt::Display for Shape {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.dims.is_empty() {
+            return write!(f, "scalar");
+        }
+        for (i, d) in self.dims.iter().enumerate() {
+            if i > 0 {
+                try!(write!(f, "x"));
+            }
+            try!(write!(f, "{}", d));
+        }
+        Ok(())
+    }
+}
+
+/// Index into a tensor of shape `dims` of the element at `index` in the result of broadcasting
+/// it to `out_dims`.
+pub fn broadcast_index(dims: &[usize], out_dims: &[usize], mut index: usize) -> usize {
+    let offset = out_dims.len() - dims.len();
+    let mut result = 0;
+    let mut stride = 1;
+    for i in (0..out_dims.len()).rev() {
+        let coord = index % out_dims[i];
+        index /= out_dims[i];
+        if i >= offset {
+            let d = dims[i - offset];
+            if d != 1 {
+                result += coord * stride;
+            }
+            stride *= d;
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{broadcast_index, Dim, Shape};
+
+    fn shape(dims: &[&str]) -> Shape {
+        Shape::new(dims.iter().map(|d| {
+            match d.parse() {
+                Ok(size) => Dim::Known(size),
+                Err(_) => Dim::Symbolic(d.to_string()),
+            }
+        }).collect())
+    }
+
+    #[test]
+    fn broadcasts_like_numpy() {
+        assert_eq!(shape(&["3", "4"]).broadcast(&shape(&["4"])), Ok(shape(&["3", "4"])));
+        assert_eq!(shape(&["3", "1"]).broadcast(&shape(&["1", "4"])), Ok(shape(&["3", "4"])));
+        assert_eq!(shape(&["2", "1", "5"]).broadcast(&shape(&["3", "1"])), Ok(shape(&["2", "3", "5"])));
+        assert_eq!(Shape::scalar().broadcast(&shape(&["2", "2"])), Ok(shape(&["2", "2"])));
+        assert!(shape(&["3", "4"]).broadcast(&shape(&["3"])).is_err());
+    }
+
+    #[test]
+    fn broadcasts_symbolic_dimensions() {
+        assert_eq!(shape(&["N", "4"]).broadcast(&shape(&["4"])), Ok(shape(&["N", "4"])));
+        assert_eq!(shape(&["N", "1"]).broadcast(&shape(&["1", "M"])), Ok(shape(&["N", "M"])));
+        assert_eq!(shape(&["N", "4"]).broadcast(&shape(&["N", "4"])), Ok(shape(&["N", "4"])));
+        // Different names may stand for different sizes, and a name for a size other than 4
+        assert!(shape(&["N"]).broadcast(&shape(&["M"])).is_err());
+        assert!(shape(&["N"]).broadcast(&shape(&["4"])).is_err());
+    }
+
+    #[test]
+    fn resolves_symbolic_dimensions() {
+        let bindings = vec![("N".to_string(), 32)];
+        assert_eq!(shape(&["N", "784"]).resolve(&bindings), Ok(vec![32, 784]));
+        assert!(shape(&["M", "784"]).resolve(&bindings).is_err());
+        assert_eq!(shape(&["N", "784"]).known(), None);
+        assert_eq!(shape(&["N", "784"]).num_elements(), None);
+        assert_eq!(shape(&["2", "3"]).num_elements(), Some(6));
+    }
+
+    #[test]
+    fn symbolic_dimensions_match_any_size() {
+        assert!(shape(&["N", "2"]).matches(&[5, 2]));
+        assert!(!shape(&["N", "2"]).matches(&[5, 3]));
+        assert!(!shape(&["N", "2"]).matches(&[2]));
+    }
+
+    #[test]
+    fn shows_dimensions() {
+        assert_eq!(shape(&["N", "28", "28"]).to_string(), "Nx28x28");
+        assert_eq!(Shape::scalar().to_string(), "scalar");
+    }
+
+    #[test]
+    fn indexes_broadcast_tensors() {
+        // A row of 3 stretched over 2 rows
+        let indices: Vec<usize> = (0..6).map(|i| broadcast_index(&[3], &[2, 3], i)).collect();
+        assert_eq!(indices, vec![0, 1, 2, 0, 1, 2]);
+        // A column of 2 stretched over 3 columns
+        let indices: Vec<usize> = (0..6).map(|i| broadcast_index(&[2, 1], &[2, 3], i)).collect();
+        assert_eq!(indices, vec![0, 0, 0, 1, 1, 1]);
+    }
+}
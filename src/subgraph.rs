@@ -0,0 +1,319 @@
+//! Subgraphs: sets of nodes shown as a single composite node with the inputs and outputs that
+//! cross its boundary as ports. Their templates can be saved to a library and placed again like
+//! any other operation.
+//!
+//! Library files are text, one item per line:
+//!
+//! ```text
+//! subgraph NAME
+//! node NAME OPERATION X Y
+//! attr NODE NAME f|i|s|is VALUE
+//! edge SEND_NODE OUTPUT RECV_NODE INPUT
+//! input NAME NODE INPUT [NODE INPUT ...]
+//! output NAME NODE OUTPUT
+//! ```
+//!
+//! Nodes are referred to by their position among the `node` lines, and `is` values are integers
+//! separated by commas.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::str::FromStr;
+
+use super::catalog::OpCatalog;
+use super::op::{AttrValue, Attrs, Operation};
+
+pub const EXTENSION: &'static str = "subgraph";
+/// The palette category of operations loaded from the library.
+pub const CATEGORY: &'static str = "Library";
+
+pub struct SubgraphNode {
+    pub name: String,
+    pub op: Rc<Operation>,
+    pub attrs: Attrs,
+    /// Position relative to the composite node.
+    pub pos: [f64; 2],
+}
+
+pub struct Subgraph {
+    pub name: String,
+    pub nodes: Vec<SubgraphNode>,
+    /// (sending node, output, receiving node, input) for edges between nodes of the subgraph,
+    /// with nodes given by their index in `nodes`.
+    pub edges: Vec<(usize, usize, usize, usize)>,
+    /// The exposed inputs, each named and feeding one or more (node, input) ports.
+    pub inputs: Vec<(String, Vec<(usize, usize)>)>,
+    /// The exposed outputs, each named and coming from a (node, output) port.
+    pub outputs: Vec<(String, (usize, usize))>,
+}
+
+impl Subgraph {
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let file = try!(File::create(path));
+        self.write_to(&mut BufWriter::new(file))
+    }
+
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        try!(writeln!(w, "subgraph {}", word(&self.name)));
+        for node in &self.nodes {
+            try!(writeln!(w, "node {} {} {} {}", word(&node.name), node.op.name, node.pos[0], node.pos[1]));
+        }
+        for (i, node) in self.nodes.iter().enumerate() {
+            for &(ref name, ref value) in node.attrs.iter() {
+                let (kind, value) = match *value {
+                    AttrValue::Float(f) => ("f", f.to_string()),
+                    AttrValue::Int(n) => ("i", n.to_string()),
+                    AttrValue::Str(ref s) => ("s", s.clone()),
+                    AttrValue::Ints(ref ints) => {
+                        let ints: Vec<String> = ints.iter().map(|n| n.to_string()).collect();
+                        ("is", ints.join(","))
+                    },
+                };
+                try!(writeln!(w, "attr {} {} {} {}", i, name, kind, value));
+            }
+        }
+        for &(send_node, send_index, recv_node, recv_index) in &self.edges {
+            try!(writeln!(w, "edge {} {} {} {}", send_node, send_index, recv_node, recv_index));
+        }
+        for &(ref name, ref ports) in &self.inputs {
+            try!(write!(w, "input {}", word(name)));
+            for &(node, index) in ports {
+                try!(write!(w, " {} {}", node, index));
+            }
+            try!(writeln!(w, ""));
+        }
+        for &(ref name, (node, index)) in &self.outputs {
+            try!(writeln!(w, "output {} {} {}", word(name), node, index));
+        }
+        Ok(())
+    }
+
+    /// Read a subgraph, looking up the operations of its nodes in `catalog`.
+    pub fn load(path: &Path, catalog: &OpCatalog) -> Result<Self, String> {
+        let file = try!(File::open(path).map_err(|e| e.to_string()));
+        Subgraph::read_from(BufReader::new(file), catalog)
+    }
+
+    pub fn read_from<R: BufRead>(r: R, catalog: &OpCatalog) -> Result<Self, String> {
+        let mut subgraph = Subgraph {
+            name: String::new(),
+            nodes: vec![],
+            edges: vec![],
+            inputs: vec![],
+            outputs: vec![],
+        };
+        for (line_number, line) in r.lines().enumerate() {
+            let line = try!(line.map_err(|e| e.to_string()));
+            try!(subgraph.read_line(&line, catalog)
+                         .map_err(|e| format!("line {}: {}", line_number + 1, e)));
+        }
+        if subgraph.name.is_empty() {
+            return Err("the subgraph has no name".to_string());
+        }
+        try!(subgraph.check_ports());
+        Ok(subgraph)
+    }
+
+    /// Check that every port referred to exists and every exposed input feeds something.
+    fn check_ports(&self) -> Result<(), String> {
+        let has_input = |(node, index): (usize, usize)| index < self.nodes[node].op.num_inputs();
+        let has_output = |(node, index): (usize, usize)| index < self.nodes[node].op.num_outputs();
+        for &(send_node, send_index, recv_node, recv_index) in &self.edges {
+            if !has_output((send_node, send_index)) || !has_input((recv_node, recv_index)) {
+                return Err(format!("edge {} {} {} {} connects ports that don't exist",
+                                   send_node, send_index, recv_node, recv_index));
+            }
+        }
+        for &(ref name, ref ports) in &self.inputs {
+            if ports.is_empty() || !ports.iter().all(|&port| has_input(port)) {
+                return Err(format!("input '{}' should feed existing ports", name));
+            }
+        }
+        for &(ref name, port) in &self.outputs {
+            if !has_output(port) {
+                return Err(format!("output '{}' comes from a port that doesn't exist", name));
+            }
+        }
+        Ok(())
+    }
+
+    fn read_line(&mut self, line: &str, catalog: &OpCatalog) -> Result<(), String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.first().cloned() {
+            None => { },
+            Some("subgraph") => {
+                self.name = try!(words.get(1).ok_or("expected a name".to_string())).to_string();
+            },
+            Some("node") if words.len() == 5 => {
+                let op = try!(catalog.get(words[2]).ok_or(format!("unknown operation '{}'", words[2])));
+                self.nodes.push(SubgraphNode {
+                    name: words[1].to_string(),
+                    attrs: op.attrs.clone(),
+                    op: op,
+                    pos: [try!(parse(words[3])), try!(parse(words[4]))],
+                });
+            },
+            Some("attr") if words.len() >= 4 => {
+                let node: usize = try!(self.node_index(words[1]));
+                let value = words[4..].join(" ");
+                let value = match words[3] {
+                    "f" => AttrValue::Float(try!(parse(&value))),
+                    "i" => AttrValue::Int(try!(parse(&value))),
+                    "s" => AttrValue::Str(value),
+                    "is" if value.is_empty() => AttrValue::Ints(vec![]),
+                    "is" => AttrValue::Ints(try!(value.split(',').map(parse).collect())),
+                    kind => { return Err(format!("unknown attribute kind '{}'", kind)); },
+                };
+                self.nodes[node].attrs.set(words[2], value);
+            },
+            Some("edge") if words.len() == 5 => {
+                let edge = (try!(self.node_index(words[1])), try!(parse(words[2])),
+                            try!(self.node_index(words[3])), try!(parse(words[4])));
+                self.edges.push(edge);
+            },
+            Some("input") if words.len() >= 2 && words.len() % 2 == 0 => {
+                let mut ports = vec![];
+                for pair in words[2..].chunks(2) {
+                    ports.push((try!(self.node_index(pair[0])), try!(parse(pair[1]))));
+                }
+                self.inputs.push((words[1].to_string(), ports));
+            },
+            Some("output") if words.len() == 4 => {
+                let port = (try!(self.node_index(words[2])), try!(parse(words[3])));
+                self.outputs.push((words[1].to_string(), port));
+            },
+            Some(_) => { return Err(format!("can't read '{}'", line)); },
+        }
+        Ok(())
+    }
+
+    fn node_index(&self, word: &str) -> Result<usize, String> {
+        let i: usize = try!(parse(word));
+        if i >= self.nodes.len() {
+            return Err(format!("there's no node {}", i));
+        }
+        Ok(i)
+    }
+}
+
+/// Names are written as single words, so spaces in them become underscores.
+fn word(name: &str) -> String {
+    name.replace(' ', "_")
+}
+
+fn parse<T: FromStr>(word: &str) -> Result<T, String> {
+    word.parse().map_err(|_| format!("can't read '{}'", word))
+}
+
+/// Add the subgraphs saved in `dir` to `catalog` as operations in the library category. Returns
+/// the operation added for each file, or why it couldn't be loaded; a subgraph can't take the
+/// name of an operation already in the catalog. Files are loaded in order of their names, so
+/// subgraphs can use those loaded before them.
+pub fn load_library(dir: &Path, catalog: &mut OpCatalog) -> Vec<(PathBuf, Result<Rc<Operation>, String>)> {
+    let mut paths: Vec<PathBuf> = match dir.read_dir() {
+        Ok(entries) => entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()).collect(),
+        Err(_) => { return vec![]; },
+    };
+    paths.retain(|path| path.extension().and_then(|ext| ext.to_str()) == Some(EXTENSION));
+    paths.sort();
+    paths.into_iter().map(|path| {
+        let op = Subgraph::load(&path, catalog).and_then(|subgraph| {
+            catalog.try_add(Operation::composite(Rc::new(subgraph), CATEGORY))
+        });
+        (path, op)
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    use super::{load_library, Subgraph, SubgraphNode, CATEGORY, EXTENSION};
+    use super::super::catalog::OpCatalog;
+    use super::super::op::{AttrValue, Attrs};
+
+    /// Two activations in a row, the second a leaky ReLU with its own slope.
+    fn subgraph(name: &str, catalog: &OpCatalog) -> Subgraph {
+        let relu = catalog.get("LeakyReLU").unwrap();
+        let mut attrs = relu.attrs.clone();
+        attrs.set("alpha", AttrValue::Float(0.5));
+        Subgraph {
+            name: name.to_string(),
+            nodes: vec![
+                SubgraphNode { name: "a".to_string(), op: catalog.get("ReLU").unwrap(), attrs: Attrs::new(), pos: [0.0, 0.0] },
+                SubgraphNode { name: "b".to_string(), op: relu, attrs: attrs, pos: [0.0, 80.0] },
+            ],
+            edges: vec![(0, 0, 1, 0)],
+            inputs: vec![("x".to_string(), vec![(0, 0)])],
+            outputs: vec![("y".to_string(), (1, 0))],
+        }
+    }
+
+    fn library_dir(test: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("deeplab-library-test-{}", test));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn reads_what_was_written() {
+        let catalog = OpCatalog::builtin();
+        let mut buf = vec![];
+        subgraph("Twice", &catalog).write_to(&mut buf).unwrap();
+        let read = Subgraph::read_from(&buf[..], &catalog).unwrap();
+        assert_eq!(read.name, "Twice");
+        assert_eq!(read.nodes.len(), 2);
+        assert_eq!(read.nodes[1].op.name, "LeakyReLU");
+        assert_eq!(read.nodes[1].attrs.float("alpha"), 0.5);
+        assert_eq!(read.nodes[1].pos, [0.0, 80.0]);
+        assert_eq!(read.edges, vec![(0, 0, 1, 0)]);
+        assert_eq!(read.inputs, vec![("x".to_string(), vec![(0, 0)])]);
+        assert_eq!(read.outputs, vec![("y".to_string(), (1, 0))]);
+    }
+
+    #[test]
+    fn rejects_ports_that_dont_exist() {
+        let catalog = OpCatalog::builtin();
+        let mut subgraph = subgraph("Twice", &catalog);
+        subgraph.outputs[0].1 = (1, 3);
+        let mut buf = vec![];
+        subgraph.write_to(&mut buf).unwrap();
+        assert!(Subgraph::read_from(&buf[..], &catalog).is_err());
+    }
+
+    #[test]
+    fn loads_every_file_it_can() {
+        let dir = library_dir("files");
+        let mut catalog = OpCatalog::builtin();
+        subgraph("Twice", &catalog).save(&dir.join("twice").with_extension(EXTENSION)).unwrap();
+        File::create(dir.join("broken").with_extension(EXTENSION)).unwrap().write_all(b"node a Nothing 0 0\n").unwrap();
+        let loaded = load_library(&dir, &mut catalog);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(loaded.len(), 2);
+        assert!(loaded[0].0.ends_with("broken.subgraph"));
+        assert_eq!(loaded[0].1.as_ref().err().unwrap(), "line 1: unknown operation 'Nothing'");
+        assert!(loaded[1].1.is_ok());
+        let op = catalog.get("Twice").unwrap();
+        assert_eq!(op.category, CATEGORY);
+        assert_eq!(op.inputs, vec!["x".to_string()]);
+    }
+
+    #[test]
+    fn keeps_names_of_other_operations() {
+        let dir = library_dir("names");
+        let mut catalog = OpCatalog::builtin();
+        subgraph("Dense", &catalog).save(&dir.join("dense").with_extension(EXTENSION)).unwrap();
+        let loaded = load_library(&dir, &mut catalog);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(loaded[0].1.as_ref().err().unwrap(), "there's already an operation called 'Dense'");
+        assert_eq!(catalog.get("Dense").unwrap().category, "Layers");
+    }
+}
@@ -0,0 +1,88 @@
+use matrix;
+
+/// A dense, row-major tensor living in host memory. Used for values that come from outside the
+/// backend, such as imported initializers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tensor {
+    pub shape: Vec<usize>,
+    pub data: Vec<f32>,
+}
+
+impl Tensor {
+    pub fn new(shape: Vec<usize>, data: Vec<f32>) -> Self {
+        assert_eq!(num_elements(&shape), data.len());
+        Tensor {
+            shape: shape,
+            data: data,
+        }
+    }
+
+    pub fn zeros(shape: Vec<usize>) -> Self {
+        let len = num_elements(&shape);
+        Tensor::new(shape, vec![0.0; len])
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// The mean of the absolute values, or 0 for empty tensors.
+    pub fn mean_abs(&self) -> f32 {
+        if self.data.is_empty() {
+            return 0.0;
+        }
+        self.data.iter().fold(0.0, |sum, x| sum + x.abs()) / self.data.len() as f32
+    }
+
+    pub fn is_finite(&self) -> bool {
+        self.data.iter().all(|x| x.is_finite())
+    }
+
+    /// The range and mean of the finite values, and how many values are NaN or infinite.
+    pub fn statistics(&self) -> String {
+        let finite: Vec<f32> = self.data.iter().cloned().filter(|x| x.is_finite()).collect();
+        let nans = self.data.iter().filter(|x| x.is_nan()).count();
+        let infinities = self.data.iter().filter(|x| x.is_infinite()).count();
+        let range = if finite.is_empty() {
+            "no finite values".to_string()
+        } else {
+            let (min, max) = finite.iter().fold((finite[0], finite[0]), |(min, max), &x| (min.min(x), max.max(x)));
+            let mean = finite.iter().fold(0.0, |sum, &x| sum + x) / finite.len() as f32;
+            format!("min {:.4e}, max {:.4e}, mean {:.4e}", min, max, mean)
+        };
+        format!("{}, {} NaN, {} infinite", range, nans, infinities)
+    }
+
+    pub fn shape_2d(&self) -> (usize, usize) {
+        shape_2d(&self.shape)
+    }
+
+    pub fn from_matrix(m: &matrix::Matrix<f32>) -> Self {
+        let mut data = Vec::with_capacity(m.rows()*m.columns());
+        for row in 0..m.rows() {
+            for column in 0..m.columns() {
+                data.push(*m.get(row, column));
+            }
+        }
+        Tensor::new(vec![m.rows(), m.columns()], data)
+    }
+
+    pub fn to_matrix(&self) -> matrix::Matrix<f32> {
+        let (rows, columns) = self.shape_2d();
+        matrix::Matrix::from_vec(rows, columns, self.data.clone())
+    }
+}
+
+pub fn num_elements(shape: &[usize]) -> usize {
+    shape.iter().fold(1, |n, &d| n*d)
+}
+
+/// The (rows, columns) shape the GPU backend stores a tensor as. Scalars become 1x1, vectors
+/// become a single row and any leading dimensions are folded into the rows.
+pub fn shape_2d(shape: &[usize]) -> (usize, usize) {
+    match shape.len() {
+        0 => (1, 1),
+        1 => (1, shape[0]),
+        n => (num_elements(&shape[..n-1]), shape[n-1]),
+    }
+}
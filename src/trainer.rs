@@ -0,0 +1,76 @@
+use std::path::PathBuf;
+
+use matrix;
+
+use super::checkpoint::Checkpoint;
+use super::graph_builder::GraphBuilder;
+
+/// Runs training iterations on a built graph, saving checkpoints along the way.
+pub struct Trainer {
+    /// Stop after this many iterations in total, counting those before a resume.
+    pub iterations: u64,
+    pub iteration: u64,
+    /// Where to save checkpoints, and how many iterations apart. No checkpoints are saved
+    /// automatically if `checkpoint_every` is 0.
+    pub checkpoint_path: Option<PathBuf>,
+    pub checkpoint_every: u64,
+    /// Step size of gradient descent. On the GPU this is what the losses' gradients are seeded
+    /// with.
+    pub learning_rate: f32,
+}
+
+impl Trainer {
+    pub fn new(iterations: u64) -> Self {
+        Trainer {
+            iterations: iterations,
+            iteration: 0,
+            checkpoint_path: None,
+            checkpoint_every: 0,
+            learning_rate: 0.1,
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.iteration >= self.iterations
+    }
+
+    /// Run one iteration, saving a checkpoint afterwards if one is due. Errors, such as values
+    /// that aren't finite, say which iteration they happened in.
+    pub fn step(&mut self, ctx: &matrix::Context, graph: &mut GraphBuilder) -> Result<(), String> {
+        try!(graph.train_step(ctx, self.learning_rate)
+                  .map_err(|e| format!("Iteration {}: {}", self.iteration + 1, e)));
+        self.iteration += 1;
+
+        let checkpoint_due = self.checkpoint_every > 0 && self.iteration % self.checkpoint_every == 0;
+        if checkpoint_due || self.is_done() {
+            try!(self.save_checkpoint(ctx, graph));
+        }
+        Ok(())
+    }
+
+    /// Run the remaining iterations.
+    pub fn run(&mut self, ctx: &matrix::Context, graph: &mut GraphBuilder) -> Result<(), String> {
+        while !self.is_done() {
+            try!(self.step(ctx, graph));
+        }
+        Ok(())
+    }
+
+    pub fn save_checkpoint(&self, ctx: &matrix::Context, graph: &GraphBuilder) -> Result<(), String> {
+        if let Some(ref path) = self.checkpoint_path {
+            let mut checkpoint = graph.checkpoint(ctx);
+            checkpoint.iteration = self.iteration;
+            try!(checkpoint.save(path).map_err(|e| format!("Failed to save checkpoint: {}", e)));
+        }
+        Ok(())
+    }
+
+    /// Restore the variables from a checkpoint and carry on counting iterations from where it
+    /// was taken.
+    pub fn resume(&mut self, ctx: &matrix::Context, graph: &mut GraphBuilder, checkpoint: &Checkpoint)
+                  -> Result<(), String> {
+        try!(graph.restore_checkpoint(ctx, checkpoint));
+        self.iteration = checkpoint.iteration;
+        Ok(())
+    }
+}
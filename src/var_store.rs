@@ -1,10 +1,28 @@
 use dl;
 use matrix;
 
-#[derive(Copy, Clone)]
+use super::dtype::DType;
+use super::shape::Shape;
+use super::tensor::{self, Tensor};
+
+#[derive(Clone)]
 pub struct Variable {
-    pub shape: (usize, usize),
+    pub name: String,
+    /// The declared shape, which may have symbolic dimensions.
+    pub shape: Shape,
+    /// The size of each dimension, resolved from `shape` when the graph is built.
+    pub dims: Vec<usize>,
+    pub dtype: DType,
     pub gpu: Option<dl::VarIndex>,
+    /// Initial value to upload when the variable is built, e.g. an imported weight.
+    pub value: Option<Tensor>,
+}
+
+impl Variable {
+    /// The (rows, columns) shape `dl` stores the variable as.
+    pub fn shape_2d(&self) -> (usize, usize) {
+        tensor::shape_2d(&self.dims)
+    }
 }
 
 pub struct VarStore {
@@ -18,11 +36,16 @@ impl VarStore {
         }
     }
 
-    pub fn add(&mut self, shape: (usize, usize)) -> VarIndex {
-        self.vars.push(Variable { shape: shape, gpu: None });
+    pub fn add(&mut self, name: String, shape: Shape) -> VarIndex {
+        let dims = shape.known().unwrap_or(vec![]);
+        self.vars.push(Variable { name: name, shape: shape, dims: dims, dtype: DType::F32, gpu: None, value: None });
         VarIndex(self.vars.len()-1)
     }
 
+    pub fn find(&self, name: &str) -> Option<VarIndex> {
+        self.vars.iter().position(|v| v.name == name).map(VarIndex)
+    }
+
     pub fn get(&self, v: VarIndex) -> &Variable {
         &self.vars[v.0]
     }